@@ -0,0 +1,97 @@
+//! `tens`: convert between JSON, TENS v2 binary and TENS-Text on stdin/stdout.
+//!
+//! ```text
+//! tens encode [--text]   JSON → TENS binary (or TENS-Text)
+//! tens decode [--text]   TENS binary (or TENS-Text) → JSON
+//! tens text              TENS binary → TENS-Text
+//! tens hash [--text]     JSON (or TENS-Text) → SHA-256 of its TENS binary
+//! ```
+
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use contex_tens_wasm::encoder::{
+    binary_to_text, decode_tens_text, encode_tens_text, hash_tens_binary, TensDecoder, TensEncoder,
+};
+use serde_json::Value;
+
+#[derive(Parser)]
+#[command(name = "tens", version, about = "Convert between JSON, TENS v2 binary and TENS-Text")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Read JSON, write TENS binary
+    Encode {
+        /// Write TENS-Text instead
+        #[arg(long)]
+        text: bool,
+    },
+    /// Read TENS binary, write JSON
+    Decode {
+        /// Read TENS-Text instead
+        #[arg(long)]
+        text: bool,
+    },
+    /// Read TENS binary, write TENS-Text
+    Text,
+    /// Read JSON, print the hash of its TENS binary encoding
+    Hash {
+        /// Read TENS-Text instead
+        #[arg(long)]
+        text: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse().command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tens: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), String> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input).map_err(|e| format!("reading stdin: {}", e))?;
+
+    let output = match command {
+        Command::Encode { text: false } => encode(&parse_json(&input)?)?,
+        Command::Encode { text: true } => encode_tens_text(&parse_json(&input)?, None)?.into_bytes(),
+        Command::Decode { text } => {
+            let value = if text {
+                decode_tens_text(utf8(&input)?)?
+            } else {
+                TensDecoder::new().decode(&input).map_err(|e| e.to_string())?
+            };
+            let mut json = serde_json::to_vec(&value).map_err(|e| e.to_string())?;
+            json.push(b'\n');
+            json
+        }
+        Command::Text => binary_to_text(&input, None).map_err(|e| e.to_string())?.into_bytes(),
+        Command::Hash { text } => {
+            let value = if text { decode_tens_text(utf8(&input)?)? } else { parse_json(&input)? };
+            format!("{}\n", hash_tens_binary(&encode(&value)?)).into_bytes()
+        }
+    };
+
+    io::stdout().write_all(&output).map_err(|e| format!("writing stdout: {}", e))
+}
+
+fn parse_json(input: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(input).map_err(|e| format!("invalid JSON: {}", e))
+}
+
+fn utf8(input: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(input).map_err(|e| format!("invalid UTF-8: {}", e))
+}
+
+fn encode(value: &Value) -> Result<Vec<u8>, String> {
+    TensEncoder::new().try_encode(value).map_err(|e| e.to_string())
+}
@@ -0,0 +1,272 @@
+//! Depth limit for `Serialize` input: a serializer wrapper that fails once
+//! nested `serialize` calls go deeper than a limit, so a runaway structure (or
+//! an `Rc` cycle) errors out instead of overflowing the stack.
+
+use std::cell::Cell;
+
+use serde::ser::{self, Serialize, Serializer};
+
+/// Shared by every level of one serialization; records whether it tripped.
+pub(crate) struct DepthGuard {
+    limit: usize,
+    tripped: Cell<bool>,
+}
+
+impl DepthGuard {
+    pub(crate) fn new(limit: usize) -> Self {
+        DepthGuard { limit, tripped: Cell::new(false) }
+    }
+
+    /// Whether a serialization failed for going past the limit.
+    pub(crate) fn tripped(&self) -> bool {
+        self.tripped.get()
+    }
+
+    /// `value` as the root of a guarded serialization.
+    pub(crate) fn wrap<'a, T: ?Sized>(&'a self, value: &'a T) -> Guarded<'a, T> {
+        Guarded { value, depth: 0, guard: self }
+    }
+}
+
+/// `value`, to be serialized `depth` levels down. Containers, `Some` and
+/// newtype wrappers each add a level.
+pub(crate) struct Guarded<'a, T: ?Sized> {
+    value: &'a T,
+    depth: usize,
+    guard: &'a DepthGuard,
+}
+
+impl<T: Serialize + ?Sized> Serialize for Guarded<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.depth > self.guard.limit {
+            self.guard.tripped.set(true);
+            return Err(ser::Error::custom(format!("nesting deeper than {}", self.guard.limit)));
+        }
+        self.value.serialize(Limited { inner: serializer, depth: self.depth, guard: self.guard })
+    }
+}
+
+/// Forwards to `inner`, wrapping every nested value in a [`Guarded`] one level down.
+struct Limited<'a, S> {
+    inner: S,
+    depth: usize,
+    guard: &'a DepthGuard,
+}
+
+macro_rules! forward_scalars {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, v: $ty) -> Result<S::Ok, S::Error> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'a, S: Serializer> Serializer for Limited<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Limited<'a, S::SerializeSeq>;
+    type SerializeTuple = Limited<'a, S::SerializeTuple>;
+    type SerializeTupleStruct = Limited<'a, S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Limited<'a, S::SerializeTupleVariant>;
+    type SerializeMap = Limited<'a, S::SerializeMap>;
+    type SerializeStruct = Limited<'a, S::SerializeStruct>;
+    type SerializeStructVariant = Limited<'a, S::SerializeStructVariant>;
+
+    forward_scalars!(
+        serialize_bool(bool), serialize_i8(i8), serialize_i16(i16), serialize_i32(i32),
+        serialize_i64(i64), serialize_i128(i128), serialize_u8(u8), serialize_u16(u16),
+        serialize_u32(u32), serialize_u64(u64), serialize_u128(u128), serialize_f32(f32),
+        serialize_f64(f64), serialize_char(char), serialize_str(&str), serialize_bytes(&[u8]),
+        serialize_unit_struct(&'static str),
+    );
+
+    fn serialize_none(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        let guarded = Guarded { value, depth: self.depth + 1, guard: self.guard };
+        self.inner.serialize_some(&guarded)
+    }
+
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, name: &'static str, index: u32, variant: &'static str) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, name: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+        let guarded = Guarded { value, depth: self.depth + 1, guard: self.guard };
+        self.inner.serialize_newtype_struct(name, &guarded)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        let guarded = Guarded { value, depth: self.depth + 1, guard: self.guard };
+        self.inner.serialize_newtype_variant(name, index, variant, &guarded)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        let inner = self.inner.serialize_seq(len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        let inner = self.inner.serialize_tuple(len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, S::Error> {
+        let inner = self.inner.serialize_tuple_struct(name, len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        let inner = self.inner.serialize_tuple_variant(name, index, variant, len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        let inner = self.inner.serialize_map(len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct, S::Error> {
+        let inner = self.inner.serialize_struct(name, len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        let inner = self.inner.serialize_struct_variant(name, index, variant, len)?;
+        Ok(Limited { inner, depth: self.depth + 1, guard: self.guard })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+impl<S: ser::SerializeSeq> ser::SerializeSeq for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_element(&Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeTuple> ser::SerializeTuple for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_element(&Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeTupleStruct> ser::SerializeTupleStruct for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_field(&Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeTupleVariant> ser::SerializeTupleVariant for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_field(&Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeMap> ser::SerializeMap for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    /// Keys are scalars in any representable document, so they pass unguarded.
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), S::Error> {
+        self.inner.serialize_key(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_value(&Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeStruct> ser::SerializeStruct for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_field(key, &Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+        self.inner.skip_field(key)
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: ser::SerializeStructVariant> ser::SerializeStructVariant for Limited<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_field(key, &Guarded { value, depth: self.depth, guard: self.guard })
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+        self.inner.skip_field(key)
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
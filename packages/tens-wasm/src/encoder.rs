@@ -1,9 +1,15 @@
 use serde_json::{Map, Value};
-use std::collections::HashMap;
-use unicode_normalization::UnicodeNormalization;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use unicode_normalization::{is_nfc_quick, is_nfkc_quick, IsNormalized, UnicodeNormalization};
 
+use crate::depth_guard::DepthGuard;
 use crate::schema::SchemaRegistry;
-use crate::utils::{encode_varint, decode_varint};
+use crate::utils::{
+    encode_varint, try_decode_varint, zigzag_encode, zigzag_decode, encode_varint_i64, decode_varint_i64,
+};
+#[cfg(feature = "compression")]
+use crate::utils::decode_varint;
 
 // ── TENS v2 Binary Opcodes (must match TS encoder.ts) ──
 
@@ -11,41 +17,307 @@ const OP_NULL: u8 = 0x00;
 const OP_TRUE: u8 = 0x01;
 const OP_FALSE: u8 = 0x02;
 const OP_INT8: u8 = 0x03;
-// OP_INT16 = 0x04 is reserved but unused in TS
+/// Little-endian i16. Reserved but unused in TS, so only written under
+/// [`NumberPolicy::SmallestInteger`].
+const OP_INT16: u8 = 0x04;
 const OP_INT32: u8 = 0x05;
 const OP_FLOAT64: u8 = 0x06;
 const OP_STRING_REF: u8 = 0x07;
 const OP_ARRAY_START: u8 = 0x08;
 const OP_OBJECT_START: u8 = 0x09;
+/// Object whose keys are all canonical u32 decimals: varint(count) then
+/// varint(key) + value per entry, in ascending numeric key order.
+const OP_INT_KEY_OBJECT: u8 = 0x0A;
+/// Exact decimal number: varint(len) + ASCII JSON number text.
+const OP_DECIMAL: u8 = 0x0B;
+/// Run of nulls inside an array: varint(run_length). Counts toward the
+/// enclosing array's element count; invalid anywhere else.
+const OP_NULL_RUN: u8 = 0x0C;
+/// Array of i32s at one fixed width: varint(count) + width byte (1, 2 or 4)
+/// + count little-endian integers of that width.
+const OP_INT_ARRAY: u8 = 0x0D;
+/// Array of i32s as zigzag varints: varint(count) + count varints.
+const OP_PACKED_INT_ARRAY: u8 = 0x0E;
+/// The string from the most recent `OP_STRING_REF`/`OP_SAME_AS_PREVIOUS`
+/// value, in document order. No payload. Keys do not count as previous.
+const OP_SAME_AS_PREVIOUS: u8 = 0x0F;
+/// Extension value: varint(ext_type) + varint(len) + len payload bytes. The
+/// length framing lets a reader skip types it does not know. No extension
+/// types are defined yet; the encoder never emits this.
+const OP_EXT: u8 = 0x10;
+/// Object of a sparse record schema: varint(schema) + bitmap + present values.
+/// Schemas are numbered per document in order of first use; the first use of
+/// a schema is followed by its definition, varint(field_count) + key refs.
+/// The bitmap has one bit per field (LSB first, ceil(fields / 8) bytes); the
+/// values of set bits follow in field order. Absent fields are missing keys.
+const OP_SCHEMA_OBJECT_SPARSE: u8 = 0x11;
+/// Homogeneous array of objects stored column-wise: varint(count) +
+/// varint(schema) + each field's `count` values in turn, fields sorted.
+/// Schemas are numbered from 1 per document in order of first use; the first
+/// use is followed by its definition, varint(field_count) + key refs.
+const OP_COLUMNAR_ARRAY: u8 = 0x12;
+/// Little-endian i64, for integers past i32 that FLOAT64 would round above
+/// 2^53. Written by default; see [`NumberPolicy::PreferInteger`].
+const OP_INT64: u8 = 0x13;
+/// Little-endian f32, for floats it holds exactly; decodes widened to f64.
+/// Only written with [`EncoderOptions::float32`].
+const OP_FLOAT32: u8 = 0x14;
+/// Raw bytes of a [`BYTES_TAG`] value: varint(len) + len bytes. Only written
+/// with [`EncoderOptions::raw_bytes`].
+const OP_BYTES: u8 = 0x15;
+/// RFC 3339 UTC timestamp as little-endian i64 milliseconds since the Unix
+/// epoch; decodes to the string it was written from. Only written with
+/// [`EncoderOptions::timestamps`].
+const OP_TIMESTAMP: u8 = 0x16;
+/// Integer as a zigzag varint (see [`encode_varint_i64`]), 1 to 10 bytes.
+/// Only written under [`NumberPolicy::Varint`].
+const OP_VARINT: u8 = 0x17;
+/// First and last of the opcodes that are a small integer themselves, no
+/// payload: `OP_SMALL_INT_FIRST..=OP_SMALL_INT_LAST` decode to `opcode - 0x28`,
+/// i.e. -8..=23. Only written with [`EncoderOptions::small_ints`].
+const OP_SMALL_INT_FIRST: u8 = 0x20;
+const OP_SMALL_INT_LAST: u8 = 0x3F;
+/// Integers written as a single `OP_SMALL_INT_FIRST..=OP_SMALL_INT_LAST` byte.
+const SMALL_INTS: std::ops::RangeInclusive<i64> = -8..=23;
+const SMALL_INT_BIAS: i64 = 0x28;
+
+/// Opcodes reserved for application-defined types; see
+/// [`TensEncoder::with_extension`] and [`TensDecoder::with_extension`].
+/// Documents using them only decode with the same handlers registered.
+pub const USER_OPCODES: std::ops::RangeInclusive<u8> = 0xE0..=0xFF;
+
+// The small-integer opcodes must cover exactly `SMALL_INTS` and overlap no
+// other opcode
+const _: () = {
+    let named = [
+        OP_NULL, OP_TRUE, OP_FALSE, OP_INT8, OP_INT16, OP_INT32, OP_FLOAT64, OP_STRING_REF, OP_ARRAY_START,
+        OP_OBJECT_START, OP_INT_KEY_OBJECT, OP_DECIMAL, OP_NULL_RUN, OP_INT_ARRAY, OP_PACKED_INT_ARRAY,
+        OP_SAME_AS_PREVIOUS, OP_EXT, OP_SCHEMA_OBJECT_SPARSE, OP_COLUMNAR_ARRAY, OP_INT64, OP_FLOAT32, OP_BYTES,
+        OP_TIMESTAMP, OP_VARINT,
+    ];
+    let mut i = 0;
+    while i < named.len() {
+        assert!(named[i] < OP_SMALL_INT_FIRST || named[i] > OP_SMALL_INT_LAST);
+        i += 1;
+    }
+    assert!(OP_SMALL_INT_LAST < *USER_OPCODES.start());
+    assert!(OP_SMALL_INT_FIRST as i64 - SMALL_INT_BIAS == *SMALL_INTS.start());
+    assert!(OP_SMALL_INT_LAST as i64 - SMALL_INT_BIAS == *SMALL_INTS.end());
+};
+
+/// Encodes a value as the payload of a user opcode, or `None` to leave it to
+/// the built-in encoding.
+pub type EncodeExtension = fn(&Value) -> Option<Vec<u8>>;
+
+/// Decodes a user opcode's payload (the bytes after the opcode), returning the
+/// value and the payload bytes consumed.
+pub type DecodeExtension = fn(&[u8]) -> Result<(Value, usize), DecodeError>;
+
+/// Key of the placeholder object a lenient decoder yields for an unknown extension.
+pub const UNKNOWN_EXTENSION_KEY: &str = "__unknown_opcode__";
+
+/// TENS v2 header: 4 magic bytes + version byte
+const HEADER_LEN: usize = 5;
+
+/// Length of the header `bytes` open with: `HEADER_LEN`, plus the feature byte
+/// of a v2.1 document.
+fn header_len(bytes: &[u8]) -> usize {
+    match bytes.get(4) {
+        Some(flags) if flags & FLAG_FEATURE_BYTE != 0 => HEADER_LEN + 1,
+        _ => HEADER_LEN,
+    }
+}
+
+/// Magic and version byte that open a binary document. Forks of the format can
+/// pick their own so that upstream decoders reject their documents (and vice
+/// versa) instead of misreading them. `version` shares its byte with the
+/// layout flags, so it must keep the top four bits clear: encoding with a
+/// format that does not fails with `EncodeError::InvalidFormat`, and a
+/// decoder for it rejects every document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TensFormat {
+    pub magic: [u8; 4],
+    pub version: u8,
+}
+
+impl TensFormat {
+    /// "TENS" + version 0x02, as the TS encoder writes.
+    pub const UPSTREAM: TensFormat = TensFormat { magic: *b"TENS", version: 0x02 };
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let [a, b, c, d] = self.magic;
+        [a, b, c, d, self.version]
+    }
+
+    /// Layout flag bits `version` overlaps; 0 for a usable format.
+    fn flag_bits(&self) -> u8 {
+        self.version & VERSION_FLAGS
+    }
+
+    /// Check the first `HEADER_LEN` bytes of a document against this format.
+    fn check_header(&self, header: &[u8]) -> Result<(), DecodeError> {
+        if self.flag_bits() != 0 {
+            return Err(format!(
+                "TENS format version 0x{:02x} overlaps the layout flags 0x{:02x}",
+                self.version, self.flag_bits()
+            ).into());
+        }
+        if header[0..4] != self.magic {
+            return Err("Invalid TENS header magic".into());
+        }
+        if header[4] & !VERSION_FLAGS != self.version {
+            return Err(format!("Unsupported TENS version: {}", header[4]).into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for TensFormat {
+    fn default() -> Self {
+        Self::UPSTREAM
+    }
+}
+
+/// Set on the version byte when the dictionary is stored as a deflate block:
+/// varint(count) + varint(compressed_len) + deflate(entries).
+const FLAG_COMPRESSED_DICT: u8 = 0x80;
+
+/// Set on the version byte when dictionary entries are sorted and front-coded:
+/// varint(shared_prefix_len) + varint(suffix_len) + suffix bytes, the prefix
+/// taken from the previous entry. Applied before compression when both are set.
+const FLAG_FRONT_CODED_DICT: u8 = 0x40;
+
+/// All dictionary-layout flags that may accompany version 2.
+const DICT_FLAGS: u8 = FLAG_COMPRESSED_DICT | FLAG_FRONT_CODED_DICT;
+
+/// Set on the version byte when the dictionary is followed by varint(count)
+/// root values instead of one. Read with [`TensDecoder::decode_multi`].
+const FLAG_MULTI_ROOT: u8 = 0x20;
+
+/// Set on the version byte of a TENS v2.1 document, whose version byte is
+/// followed by a feature byte of `FEATURE_*` bits. Written with
+/// [`EncoderOptions::feature_header`]; without it the header stays the
+/// 5 bytes older decoders read.
+///
+/// v2.1 is a flag like the dictionary layouts rather than a version byte of
+/// its own such as `0x21`: that value reads as version 1 with
+/// [`FLAG_MULTI_ROOT`] set, and a flag still combines with the others.
+/// Decoders that predate it reject `0x12` as an unknown version.
+const FLAG_FEATURE_BYTE: u8 = 0x10;
+
+/// All flags that may accompany version 2.
+const VERSION_FLAGS: u8 = DICT_FLAGS | FLAG_MULTI_ROOT | FLAG_FEATURE_BYTE;
+
+/// Feature bit: the tree contains user opcodes (a handler registered with
+/// [`TensEncoder::with_extension`] claimed a value). A decoder without any
+/// handlers rejects the document up front instead of at the first such value.
+pub const FEATURE_USER_OPCODES: u8 = 0x01;
+
+/// Feature bit: the tree stores numbers INT8/INT32 cannot hold as
+/// `OP_DECIMAL` text ([`EncoderOptions::decimal_numbers`]). Without the
+/// `decimal` feature the decoder rejects the document, since it would round
+/// such numbers to f64.
+pub const FEATURE_DECIMAL_NUMBERS: u8 = 0x02;
+
+/// All feature bits this decoder understands; a document setting any other
+/// is rejected rather than misread.
+const KNOWN_FEATURES: u8 = FEATURE_USER_OPCODES | FEATURE_DECIMAL_NUMBERS;
+
+// ── Encode Errors ──
+
+/// Errors produced while encoding TENS v2 binary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    /// The string table would need an id above the configured `max_string_ref_id`.
+    DictionaryTooLarge { max_id: u32 },
+    /// A container or string length does not fit the u32 varint length prefix.
+    LengthOverflow(usize),
+    /// Streaming writer calls did not form exactly one balanced document.
+    Unbalanced,
+    /// Writing the encoded bytes to the output failed.
+    Io(String),
+    /// Raw JSON input did not parse.
+    InvalidJson(String),
+    /// A `Serialize` input nested deeper than `max_serialize_depth`.
+    DepthExceeded { limit: usize },
+    /// The [`TensFormat`] version sets layout flag bits, so no decoder could
+    /// read the document back.
+    InvalidFormat { version: u8 },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::DictionaryTooLarge { max_id } => {
+                write!(f, "Dictionary too large: string ref ids exceed {}", max_id)
+            }
+            EncodeError::LengthOverflow(len) => {
+                write!(f, "Length {} does not fit a u32 length prefix", len)
+            }
+            EncodeError::Unbalanced => f.write_str("Unbalanced containers in streamed document"),
+            EncodeError::Io(msg) => write!(f, "Write error: {}", msg),
+            EncodeError::InvalidJson(msg) => write!(f, "Invalid JSON: {}", msg),
+            EncodeError::DepthExceeded { limit } => {
+                write!(f, "Serialized input nests deeper than {} levels", limit)
+            }
+            EncodeError::InvalidFormat { version } => {
+                write!(f, "TENS format version 0x{:02x} overlaps the layout flags 0x{:02x}", version, version & VERSION_FLAGS)
+            }
+        }
+    }
+}
 
-/// TENS v2 header: "TENS" + version byte 0x02
-const HEADER: &[u8; 5] = b"TENS\x02";
+impl std::error::Error for EncodeError {}
+
+/// Encode a container/string length as a varint, rejecting lengths above u32::MAX.
+fn encode_len(len: usize) -> Result<Vec<u8>, EncodeError> {
+    u32::try_from(len)
+        .map(encode_varint)
+        .map_err(|_| EncodeError::LengthOverflow(len))
+}
 
 // ── String Table (Dictionary) ──
 
+/// Largest string ref id by default. One below u32::MAX so the entry count
+/// (max id + 1) still fits the u32 dictionary count.
+pub const DEFAULT_MAX_STRING_REF_ID: u32 = u32::MAX - 1;
+
+/// Default for [`EncoderOptions::max_serialize_depth`].
+pub const DEFAULT_MAX_SERIALIZE_DEPTH: usize = 256;
+
 /// Insertion-order string table matching TS StringTable.
 pub struct StringTable {
     map: HashMap<String, u32>,
     entries: Vec<String>,
+    max_id: u32,
 }
 
 impl StringTable {
     pub fn new() -> Self {
+        Self::with_max_id(DEFAULT_MAX_STRING_REF_ID)
+    }
+
+    /// Create a table that refuses to assign ids above `max_id`.
+    pub fn with_max_id(max_id: u32) -> Self {
         StringTable {
             map: HashMap::new(),
             entries: Vec::new(),
+            max_id,
         }
     }
 
     /// Add a string and return its ID. If already present, return existing ID.
-    pub fn add(&mut self, s: &str) -> u32 {
+    pub fn add(&mut self, s: &str) -> Result<u32, EncodeError> {
         if let Some(&id) = self.map.get(s) {
-            return id;
+            return Ok(id);
         }
-        let id = self.entries.len() as u32;
+        let id = match u32::try_from(self.entries.len()) {
+            Ok(id) if id <= self.max_id => id,
+            _ => return Err(EncodeError::DictionaryTooLarge { max_id: self.max_id }),
+        };
         self.entries.push(s.to_string());
         self.map.insert(s.to_string(), id);
-        id
+        Ok(id)
     }
 
     pub fn entries(&self) -> &[String] {
@@ -55,29 +327,219 @@ impl StringTable {
     pub fn len(&self) -> usize {
         self.entries.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reserve room for at least `additional` more distinct strings.
+    pub fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+        self.entries.reserve(additional);
+    }
+
+    /// Total UTF-8 bytes of the entries (the dictionary payload, without
+    /// length prefixes).
+    pub fn total_bytes(&self) -> usize {
+        self.entries.iter().map(String::len).sum()
+    }
+
+    /// Entries the table holds without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity().min(self.map.capacity())
+    }
+
+    /// Reorder entries by their UTF-8 bytes, reassigning ids to match.
+    pub fn sort(&mut self) {
+        self.entries.sort();
+        for (id, entry) in self.entries.iter().enumerate() {
+            if let Some(slot) = self.map.get_mut(entry) {
+                *slot = id as u32;
+            }
+        }
+    }
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Occupancy of an encoder's string table; see [`TensEncoder::string_table_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StringTableStats {
+    /// Distinct strings.
+    pub entries: usize,
+    /// Sum of their UTF-8 lengths.
+    pub total_bytes: usize,
+    /// Entries that fit without reallocating.
+    pub capacity: usize,
 }
 
 // ── Canonicalization ──
 
+/// Largest integer exactly representable as f64 (JS `Number.MAX_SAFE_INTEGER`).
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// Opt-in deviations from the default canonical form. Every flag changes the
+/// canonical value, and so the bytes and hash, relative to TS canonical.ts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CanonicalOptions {
+    /// Replace strings that read as a JSON boolean or number (`"true"`, `"42"`,
+    /// `"-1.5e3"`, after normalization) with that value, so stringly-typed
+    /// upstream data hashes like the typed equivalent. Lossy: `"42"` and `42`
+    /// become indistinguishable. `"4.20"` becomes the number `4.2`, except with
+    /// the `decimal` feature, where the number keeps the text `4.20` (and
+    /// [`EncoderOptions::decimal_numbers`] stores it as written).
+    pub coerce_scalar_strings: bool,
+    /// Unicode normalization form applied to string values and object keys.
+    pub unicode_form: UnicodeForm,
+    /// What NaN and ±Infinity become, in binary and TENS-Text alike.
+    pub non_finite_floats: NonFiniteFloats,
+    /// Round floats to this many significant digits (at least 1), so values
+    /// differing only in noise past them hash identically. Lossy. Integers
+    /// are left alone; a float that rounds to an integer becomes one.
+    pub float_precision: Option<u32>,
+    /// Keep strings' trailing whitespace, for data where it is significant
+    /// (fixed-width fields, Markdown line breaks). By default every line is
+    /// `trim_end`ed. Strings with trailing whitespace then hash differently.
+    pub preserve_line_whitespace: bool,
+}
+
+/// Handling of non-finite floats; see [`CanonicalOptions::non_finite_floats`].
+///
+/// JSON has no NaN or Infinity, so they only arise from FLOAT64 payloads and
+/// from the quoted specials (`"NaN"`, `"Infinity"`, `"-Infinity"`) the TS
+/// encoder writes into TENS-Text `num` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonFiniteFloats {
+    /// Decode them to null, as TS canonical.ts does (JSON-compatible).
+    #[default]
+    Null,
+    /// Keep them as the special strings, which round-trip through both formats.
+    Preserve,
+}
+
+impl NonFiniteFloats {
+    /// The value a non-finite `f` becomes under this policy.
+    fn value(self, f: f64) -> Value {
+        match self {
+            NonFiniteFloats::Null => Value::Null,
+            NonFiniteFloats::Preserve if f.is_nan() => Value::String("NaN".into()),
+            NonFiniteFloats::Preserve if f > 0.0 => Value::String("Infinity".into()),
+            NonFiniteFloats::Preserve => Value::String("-Infinity".into()),
+        }
+    }
+}
+
+/// Unicode normalization form; see [`CanonicalOptions::unicode_form`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnicodeForm {
+    /// NFKC, as TS canonical.ts: compatibility characters fold to their plain
+    /// equivalents (`ﬁ` → `fi`, full-width digits → ASCII).
+    #[default]
+    Nfkc,
+    /// NFC: composes canonically equivalent sequences but keeps compatibility
+    /// characters distinct.
+    Nfc,
+    /// Leave strings as given (trailing whitespace is still stripped, unless
+    /// [`CanonicalOptions::preserve_line_whitespace`] is set).
+    Unchanged,
+}
+
+/// Normalize a string as `canonicalize` does: Unicode normalization per
+/// `opts.unicode_form`, then trailing whitespace stripped from every line
+/// (unless `opts.preserve_line_whitespace`). Leading whitespace is kept.
+pub fn normalize_string(s: &str, opts: &CanonicalOptions) -> String {
+    normalize_str(s, opts).into_owned()
+}
+
+/// [`normalize_string`], borrowing `s` when it is already normalized: the
+/// Unicode quick check says so and no line ends in whitespace. Most strings
+/// in real documents are, and skip the normalization pass and its copies.
+fn normalize_str<'a>(s: &'a str, opts: &CanonicalOptions) -> Cow<'a, str> {
+    let normal_form = match opts.unicode_form {
+        UnicodeForm::Nfkc => is_nfkc_quick(s.chars()) == IsNormalized::Yes,
+        UnicodeForm::Nfc => is_nfc_quick(s.chars()) == IsNormalized::Yes,
+        UnicodeForm::Unchanged => true,
+    };
+    // `lines()` below also drops a final newline and the `\r` of `\r\n`
+    let trimmed = opts.preserve_line_whitespace
+        || !s.ends_with('\n') && s.split('\n').all(|line| !line.ends_with(char::is_whitespace));
+    if normal_form && trimmed {
+        return Cow::Borrowed(s);
+    }
+
+    let normalized: String = match opts.unicode_form {
+        UnicodeForm::Nfkc => s.nfkc().collect(),
+        UnicodeForm::Nfc => s.nfc().collect(),
+        UnicodeForm::Unchanged => s.to_string(),
+    };
+    if opts.preserve_line_whitespace {
+        return Cow::Owned(normalized);
+    }
+    let stripped: Vec<&str> = normalized
+        .lines()
+        .map(|line| line.trim_end())
+        .collect();
+    Cow::Owned(stripped.join("\n"))
+}
+
+/// An object key normalized as `canonicalize` does: Unicode normalization
+/// per `opts.unicode_form` only, so whitespace in keys is kept.
+fn normalize_key<'a>(key: &'a str, opts: &CanonicalOptions) -> Cow<'a, str> {
+    match opts.unicode_form {
+        UnicodeForm::Nfkc if is_nfkc_quick(key.chars()) != IsNormalized::Yes => Cow::Owned(key.nfkc().collect()),
+        UnicodeForm::Nfc if is_nfc_quick(key.chars()) != IsNormalized::Yes => Cow::Owned(key.nfc().collect()),
+        _ => Cow::Borrowed(key),
+    }
+}
+
 /// Canonicalize a JSON value to match TS canonical.ts:
-/// - Object keys sorted lexicographically
-/// - Strings NFKC-normalized, trailing whitespace stripped per line
-/// - Numbers: NaN/Infinity → null, -0 → 0
+/// - Object keys NFKC-normalized, then sorted lexicographically; when two
+///   spellings normalize alike, the value of the one sorting first is kept
+///   (TS keeps keys as given, which only differs for non-normalized keys)
+/// - Strings NFKC-normalized, trailing whitespace stripped per line (see
+///   [`CanonicalOptions::preserve_line_whitespace`])
+/// - Numbers: NaN/Infinity → null (see [`NonFiniteFloats`]), -0 → 0
 /// - Dates: left as strings (JSON has no Date type)
 /// - Arrays: order preserved, null elements stay
 pub fn canonicalize(value: &Value) -> Value {
+    canonicalize_with(value, &CanonicalOptions::default())
+}
+
+/// [`canonicalize`] with the opt-in deviations in `options` applied.
+pub fn canonicalize_with(value: &Value, options: &CanonicalOptions) -> Value {
     match value {
         Value::Null => Value::Null,
         Value::Bool(b) => Value::Bool(*b),
         Value::Number(n) => {
             if let Some(f) = n.as_f64() {
-                if f.is_nan() || f.is_infinite() {
-                    return Value::Null;
+                if !f.is_finite() {
+                    return options.non_finite_floats.value(f);
                 }
+                let f = match options.float_precision {
+                    Some(digits) if n.is_f64() => round_significant(f, digits),
+                    _ => f,
+                };
                 // -0 → 0
                 if f == 0.0 && f.is_sign_negative() {
                     return serde_json::json!(0);
                 }
+                // Integral floats (1e2, 100.0) → integer, so every spelling of
+                // the same number canonicalizes and hashes identically
+                if options.float_precision.is_none() {
+                    if let Some(int) = integral_form(n) {
+                        return Value::Number(int);
+                    }
+                }
+                if n.is_f64() && f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER {
+                    return Value::from(f as i64);
+                }
+                if options.float_precision.is_some() && n.is_f64() {
+                    return Value::from(f);
+                }
                 // Keep as-is (serde_json preserves int vs float)
                 Value::Number(n.clone())
             } else {
@@ -85,1162 +547,8784 @@ pub fn canonicalize(value: &Value) -> Value {
             }
         }
         Value::String(s) => {
-            // NFKC normalize + strip trailing whitespace per line
-            let normalized: String = s.nfkc().collect();
-            let stripped: Vec<&str> = normalized
-                .lines()
-                .map(|line| line.trim_end())
-                .collect();
-            Value::String(stripped.join("\n"))
+            let normalized = normalize_str(s, options);
+            if options.coerce_scalar_strings {
+                if let Some(scalar) = parse_scalar_string(&normalized) {
+                    return canonicalize_with(&scalar, options);
+                }
+            }
+            Value::String(normalized.into_owned())
         }
         Value::Array(arr) => {
-            Value::Array(arr.iter().map(canonicalize).collect())
+            Value::Array(arr.iter().map(|v| canonicalize_with(v, options)).collect())
         }
         Value::Object(obj) => {
-            // Sort keys lexicographically
-            let mut sorted: Vec<(&String, &Value)> = obj.iter().collect();
-            sorted.sort_by(|a, b| a.0.cmp(b.0));
+            // Sort by normalized key, then by original spelling so the first
+            // of several spellings of one key wins deterministically
+            let mut sorted: Vec<(Cow<str>, &String, &Value)> = obj.iter()
+                .map(|(k, v)| (normalize_key(k, options), k, v))
+                .collect();
+            sorted.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
 
             let mut map = Map::new();
-            for (k, v) in sorted {
-                let canonical_val = canonicalize(v);
+            for (key, _, v) in sorted {
+                if map.contains_key(key.as_ref()) {
+                    continue;
+                }
                 // Omit undefined — JSON has no undefined, so nothing to do
-                map.insert(k.clone(), canonical_val);
+                map.insert(key.into_owned(), canonicalize_with(v, options));
             }
             Value::Object(map)
         }
     }
 }
 
-// ── TENS v2 Binary Encoder ──
+/// `f` rounded to `digits` significant decimal digits, via its decimal
+/// representation so the rounding is exact (`0.15` stays `0.2`, not `0.1`).
+fn round_significant(f: f64, digits: u32) -> f64 {
+    let precision = digits.max(1) as usize - 1;
+    format!("{:.*e}", precision, f).parse().unwrap_or(f)
+}
 
-pub struct TensEncoder {
-    pub registry: SchemaRegistry,
-    string_table: StringTable,
+/// The integer spelling of a float-spelled integer (`1.0`, `1e0`, `10e-1`), so
+/// that every spelling of an integer encodes the same; `None` for integers and
+/// fractions. Bounded to the safe-integer range where f64 → i64 is exact.
+#[cfg(not(feature = "decimal"))]
+fn integral_form(n: &serde_json::Number) -> Option<serde_json::Number> {
+    let f = n.as_f64().filter(|_| n.is_f64())?;
+    (f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER).then(|| (f as i64).into())
 }
 
-impl TensEncoder {
-    pub fn new() -> Self {
-        TensEncoder {
-            registry: SchemaRegistry::new(),
-            string_table: StringTable::new(),
-        }
+/// Exponents past this are left alone rather than expanded into digits.
+#[cfg(feature = "decimal")]
+const MAX_INTEGRAL_EXPONENT: i64 = 1000;
+
+/// The integer spelling of a float-spelled integer (`1.0`, `1e0`, `10e-1`), so
+/// that every spelling of an integer encodes the same; `None` for integers and
+/// fractions. The digits are read exactly, so integers past 2^53 keep every
+/// digit (`9007199254740993.0` → `9007199254740993`).
+#[cfg(feature = "decimal")]
+fn integral_form(n: &serde_json::Number) -> Option<serde_json::Number> {
+    if !n.is_f64() {
+        return None;
     }
+    let text = n.to_string();
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.as_str()),
+    };
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(at) => (&unsigned[..at], unsigned[at + 1..].parse::<i64>().ok()?),
+        None => (unsigned, 0),
+    };
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
 
-    /// Encode a JSON value into TENS v2 binary format.
-    /// Returns the raw bytes (header + dictionary + value tree).
-    pub fn encode(&mut self, value: &Value) -> Vec<u8> {
-        // 1. Canonicalize
-        let canonical = canonicalize(value);
+    let mut digits = format!("{}{}", int_part, frac_part);
+    let mut exponent = exponent - frac_part.len() as i64;
+    while exponent < 0 && digits.ends_with('0') {
+        digits.pop();
+        exponent += 1;
+    }
+    if !(0..=MAX_INTEGRAL_EXPONENT).contains(&exponent) {
+        return None;
+    }
+    digits.push_str(&"0".repeat(exponent as usize));
+    let digits = digits.trim_start_matches('0');
+    match digits {
+        "" => Some(0.into()),
+        _ if negative => format!("-{}", digits).parse().ok(),
+        _ => digits.parse().ok(),
+    }
+}
 
-        // 2. Scan pass: collect all strings in DFS order (keys sorted)
-        self.string_table = StringTable::new();
-        self.scan_strings(&canonical);
+/// The boolean or number a string spells in JSON syntax, if it spells one
+/// exactly (no surrounding whitespace, sign prefix or leading zeros).
+fn parse_scalar_string(s: &str) -> Option<Value> {
+    match s {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => s.parse::<serde_json::Number>().ok().map(Value::Number),
+    }
+}
 
-        // 3. Emit binary
-        let mut out = Vec::new();
+// ── Merge ──
 
-        // Header
-        out.extend_from_slice(HEADER);
+/// Deep-merge `overlay` onto `base` and canonicalize the result.
+///
+/// Follows JSON Merge Patch (RFC 7386) semantics:
+/// - Objects merge key by key, recursively; overlay wins on conflict
+/// - A `null` in the overlay deletes the key rather than setting it to null
+/// - Arrays and scalars in the overlay replace the base value wholesale
+pub fn merge(base: &Value, overlay: &Value) -> Value {
+    canonicalize(&merge_values(base, overlay))
+}
 
-        // Dictionary: varint(count), then for each string: varint(utf8_len) + utf8_bytes
-        out.extend_from_slice(&encode_varint(self.string_table.len() as u32));
-        for entry in self.string_table.entries() {
-            let bytes = entry.as_bytes();
-            out.extend_from_slice(&encode_varint(bytes.len() as u32));
-            out.extend_from_slice(bytes);
+fn merge_values(base: &Value, overlay: &Value) -> Value {
+    let Value::Object(patch) = overlay else {
+        return overlay.clone();
+    };
+
+    let mut target = match base {
+        Value::Object(obj) => obj.clone(),
+        _ => Map::new(),
+    };
+    for (key, val) in patch {
+        if val.is_null() {
+            target.remove(key);
+        } else {
+            let merged = merge_values(target.get(key).unwrap_or(&Value::Null), val);
+            target.insert(key.clone(), merged);
         }
+    }
+    Value::Object(target)
+}
 
-        // Value tree
-        self.encode_value(&canonical, &mut out);
+// ── Streaming Canonicalization ──
+
+/// Bounds on untrusted input for [`canonicalize_stream`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanonicalLimits {
+    /// Deepest nesting of arrays and objects (`[[1]]` is 2).
+    pub max_depth: usize,
+    /// Longest string value or key, in UTF-8 bytes.
+    pub max_string_len: usize,
+    /// Most keys in any one object.
+    pub max_keys: usize,
+    /// Most elements in any one array.
+    pub max_array_len: usize,
+    /// Most bytes read from the input, whitespace included.
+    pub max_input_bytes: usize,
+}
 
-        out
+#[cfg(feature = "std")]
+impl Default for CanonicalLimits {
+    fn default() -> Self {
+        CanonicalLimits {
+            max_depth: 64,
+            max_string_len: 1 << 20,
+            max_keys: 10_000,
+            max_array_len: 1 << 20,
+            max_input_bytes: 64 << 20,
+        }
     }
+}
 
-    /// Scan all strings in DFS order to populate the string table.
-    /// Object keys are visited in sorted order (canonical).
-    fn scan_strings(&mut self, value: &Value) {
-        match value {
-            Value::String(s) => {
-                self.string_table.add(s);
-            }
-            Value::Array(arr) => {
-                for item in arr {
-                    self.scan_strings(item);
-                }
-            }
-            Value::Object(obj) => {
-                // Keys are already sorted from canonicalize
-                let mut keys: Vec<&String> = obj.keys().collect();
-                keys.sort();
-                for key in &keys {
-                    self.string_table.add(key);
-                }
-                for key in &keys {
-                    if let Some(val) = obj.get(*key) {
-                        self.scan_strings(val);
-                    }
-                }
-            }
-            _ => {}
+/// Why [`canonicalize_stream`] failed.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanonicalizeError {
+    /// Nesting went past `CanonicalLimits::max_depth`.
+    DepthExceeded { limit: usize },
+    /// A string or key was longer than `CanonicalLimits::max_string_len`.
+    StringTooLong { limit: usize },
+    /// An object had more than `CanonicalLimits::max_keys` keys.
+    TooManyKeys { limit: usize },
+    /// An array had more than `CanonicalLimits::max_array_len` elements.
+    ArrayTooLong { limit: usize },
+    /// The input was longer than `CanonicalLimits::max_input_bytes`.
+    InputTooLarge { limit: usize },
+    /// The input was not a single JSON value.
+    InvalidJson(String),
+    /// Reading the input or writing the output failed.
+    Io(String),
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Display for CanonicalizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CanonicalizeError::DepthExceeded { limit } => write!(f, "Nesting deeper than {}", limit),
+            CanonicalizeError::StringTooLong { limit } => write!(f, "String longer than {} bytes", limit),
+            CanonicalizeError::TooManyKeys { limit } => write!(f, "Object with more than {} keys", limit),
+            CanonicalizeError::ArrayTooLong { limit } => write!(f, "Array with more than {} elements", limit),
+            CanonicalizeError::InputTooLarge { limit } => write!(f, "Input longer than {} bytes", limit),
+            CanonicalizeError::InvalidJson(msg) => write!(f, "Invalid JSON input: {}", msg),
+            CanonicalizeError::Io(msg) => write!(f, "I/O error: {}", msg),
         }
     }
+}
 
-    /// Encode a single value into the output buffer.
-    fn encode_value(&mut self, value: &Value, out: &mut Vec<u8>) {
-        match value {
-            Value::Null => {
-                out.push(OP_NULL);
-            }
-            Value::Bool(b) => {
-                out.push(if *b { OP_TRUE } else { OP_FALSE });
-            }
-            Value::Number(n) => {
-                if let Some(i) = n.as_i64() {
-                    if i >= -128 && i <= 127 {
-                        out.push(OP_INT8);
-                        out.push(i as i8 as u8);
-                    } else if i >= i32::MIN as i64 && i <= i32::MAX as i64 {
-                        out.push(OP_INT32);
-                        out.extend_from_slice(&(i as i32).to_le_bytes());
-                    } else {
-                        // Large integer → float64
-                        out.push(OP_FLOAT64);
-                        out.extend_from_slice(&(i as f64).to_le_bytes());
-                    }
-                } else if let Some(f) = n.as_f64() {
-                    // Check if it's actually an integer value stored as float
-                    if f.fract() == 0.0 && f.abs() < (i32::MAX as f64) {
-                        let i = f as i32;
-                        if i >= -128 && i <= 127 {
-                            out.push(OP_INT8);
-                            out.push(i as i8 as u8);
-                        } else {
-                            out.push(OP_INT32);
-                            out.extend_from_slice(&i.to_le_bytes());
-                        }
-                    } else {
-                        out.push(OP_FLOAT64);
-                        out.extend_from_slice(&f.to_le_bytes());
-                    }
-                }
-            }
-            Value::String(s) => {
-                let id = self.string_table.add(s);
-                out.push(OP_STRING_REF);
-                out.extend_from_slice(&encode_varint(id));
-            }
-            Value::Array(arr) => {
-                out.push(OP_ARRAY_START);
-                out.extend_from_slice(&encode_varint(arr.len() as u32));
-                for item in arr {
-                    self.encode_value(item, out);
-                }
-            }
-            Value::Object(obj) => {
-                // Keys sorted (already canonical)
-                let mut keys: Vec<&String> = obj.keys().collect();
-                keys.sort();
+#[cfg(feature = "std")]
+impl std::error::Error for CanonicalizeError {}
+
+/// Canonicalize untrusted JSON from `reader` into `writer` as compact JSON,
+/// identical to serializing [`canonicalize`] of the parsed input.
+///
+/// The input is never held as a whole: limits are checked as it is read,
+/// arrays and scalars are written out as soon as they are parsed, and only
+/// the members of each open object are buffered (as canonical JSON bytes)
+/// until it closes, to sort them by key. On error, part of the output may
+/// already have been written.
+#[cfg(feature = "std")]
+pub fn canonicalize_stream<R: std::io::Read, W: std::io::Write>(
+    reader: R,
+    writer: W,
+    limits: &CanonicalLimits,
+) -> Result<(), CanonicalizeError> {
+    use serde::de::DeserializeSeed;
+
+    let violation = std::cell::Cell::new(None);
+    let reader = LimitedReader {
+        inner: reader,
+        limit: limits.max_input_bytes,
+        remaining: limits.max_input_bytes,
+        violation: &violation,
+    };
+    let mut writer = std::io::BufWriter::new(writer);
+    let seed = StreamCanonicalizer { limits, depth: 0, violation: &violation, out: &mut writer, comma: false };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    seed.deserialize(&mut deserializer)
+        .and_then(|_| deserializer.end())
+        .map_err(|e| match violation.take() {
+            Some(violation) => violation,
+            None if e.is_io() => CanonicalizeError::Io(e.to_string()),
+            None => CanonicalizeError::InvalidJson(e.to_string()),
+        })?;
+    std::io::Write::flush(&mut writer).map_err(|e| CanonicalizeError::Io(e.to_string()))
+}
 
-                out.push(OP_OBJECT_START);
-                out.extend_from_slice(&encode_varint(keys.len() as u32));
-                for key in &keys {
-                    let key_id = self.string_table.add(key);
-                    out.extend_from_slice(&encode_varint(key_id));
-                    if let Some(val) = obj.get(*key) {
-                        self.encode_value(val, out);
-                    }
-                }
+/// Reads at most `remaining` bytes, then fails with `InputTooLarge` rather
+/// than reporting end of input if there is more.
+#[cfg(feature = "std")]
+struct LimitedReader<'a, R> {
+    inner: R,
+    limit: usize,
+    remaining: usize,
+    violation: &'a std::cell::Cell<Option<CanonicalizeError>>,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> std::io::Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            let mut probe = [0u8; 1];
+            if self.inner.read(&mut probe)? == 0 {
+                return Ok(0);
             }
+            let violation = CanonicalizeError::InputTooLarge { limit: self.limit };
+            self.violation.set(Some(violation));
+            return Err(std::io::Error::other("input exceeds max_input_bytes"));
         }
-    }
-
-    /// Get the string table entries (for inspection/testing).
-    pub fn string_table_entries(&self) -> &[String] {
-        self.string_table.entries()
+        let len = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read;
+        Ok(read)
     }
 }
 
-// ── TENS v2 Binary Decoder ──
-
-pub struct TensDecoder {
-    dictionary: Vec<String>,
+/// Parses one JSON value and writes its canonical form to `out`, recording
+/// the first limit violation in `violation` (serde errors can only carry a
+/// message).
+#[cfg(feature = "std")]
+struct StreamCanonicalizer<'a, 'w> {
+    limits: &'a CanonicalLimits,
+    /// Arrays and objects enclosing the value being parsed.
+    depth: usize,
+    violation: &'a std::cell::Cell<Option<CanonicalizeError>>,
+    out: &'w mut dyn std::io::Write,
+    /// The value follows another array element, so starts with a `,`.
+    comma: bool,
 }
 
-impl TensDecoder {
-    pub fn new() -> Self {
-        TensDecoder {
-            dictionary: Vec::new(),
-        }
+#[cfg(feature = "std")]
+impl<'a> StreamCanonicalizer<'a, '_> {
+    fn fail<E: serde::de::Error>(&self, violation: CanonicalizeError) -> E {
+        let error = E::custom(&violation);
+        self.violation.set(Some(violation));
+        error
     }
 
-    /// Decode TENS v2 binary bytes back into a JSON Value.
-    pub fn decode(&mut self, bytes: &[u8]) -> Result<Value, String> {
-        if bytes.len() < 5 {
-            return Err("Input too short for TENS header".into());
-        }
-        if &bytes[0..4] != b"TENS" {
-            return Err("Invalid TENS header magic".into());
+    fn check_string<E: serde::de::Error>(&self, s: &str) -> Result<(), E> {
+        if s.len() > self.limits.max_string_len {
+            return Err(self.fail(CanonicalizeError::StringTooLong { limit: self.limits.max_string_len }));
         }
-        if bytes[4] != 0x02 {
-            return Err(format!("Unsupported TENS version: {}", bytes[4]));
+        Ok(())
+    }
+
+    /// Depth of the children of a new array or object.
+    fn nested<E: serde::de::Error>(&self) -> Result<usize, E> {
+        if self.depth >= self.limits.max_depth {
+            return Err(self.fail(CanonicalizeError::DepthExceeded { limit: self.limits.max_depth }));
         }
+        Ok(self.depth + 1)
+    }
 
-        let mut pos = 5;
+    /// A canonicalizer for a child value at `depth`, writing to `out`.
+    fn child<'b>(&self, depth: usize, out: &'b mut dyn std::io::Write, comma: bool) -> StreamCanonicalizer<'a, 'b> {
+        StreamCanonicalizer { limits: self.limits, depth, violation: self.violation, out, comma }
+    }
 
-        // Read dictionary
-        let (dict_count, consumed) = decode_varint(&bytes[pos..]);
-        pos += consumed;
+    fn write<E: serde::de::Error>(&mut self, bytes: &[u8]) -> Result<(), E> {
+        self.out.write_all(bytes).map_err(|e| self.fail(CanonicalizeError::Io(e.to_string())))
+    }
 
-        self.dictionary = Vec::with_capacity(dict_count as usize);
-        for _ in 0..dict_count {
-            let (str_len, consumed) = decode_varint(&bytes[pos..]);
-            pos += consumed;
-            let end = pos + str_len as usize;
-            if end > bytes.len() {
-                return Err("Dictionary string extends past end of input".into());
-            }
-            let s = String::from_utf8(bytes[pos..end].to_vec())
-                .map_err(|e| format!("Invalid UTF-8 in dictionary: {}", e))?;
-            self.dictionary.push(s);
-            pos = end;
+    /// Write a complete scalar, after its separating `,` if any.
+    fn write_value<E: serde::de::Error, T: serde::Serialize + ?Sized>(mut self, value: &T) -> Result<(), E> {
+        if self.comma {
+            self.write(b",")?;
         }
+        serde_json::to_writer(&mut *self.out, value).map_err(|e| self.fail(CanonicalizeError::Io(e.to_string())))
+    }
+}
 
-        // Read value tree
-        let (value, _consumed) = self.decode_value(&bytes[pos..])?;
-        Ok(value)
+#[cfg(feature = "std")]
+impl<'de> serde::de::DeserializeSeed<'de> for StreamCanonicalizer<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
     }
+}
 
-    fn decode_value(&self, bytes: &[u8]) -> Result<(Value, usize), String> {
-        if bytes.is_empty() {
-            return Err("Unexpected end of input".into());
-        }
+#[cfg(feature = "std")]
+impl<'de> serde::de::Visitor<'de> for StreamCanonicalizer<'_, '_> {
+    type Value = ();
 
-        let opcode = bytes[0];
-        let mut pos = 1;
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a JSON value")
+    }
 
-        match opcode {
-            OP_NULL => Ok((Value::Null, pos)),
+    fn visit_unit<E: serde::de::Error>(self) -> Result<(), E> {
+        self.write_value(&Value::Null)
+    }
 
-            OP_TRUE => Ok((Value::Bool(true), pos)),
+    fn visit_bool<E: serde::de::Error>(self, b: bool) -> Result<(), E> {
+        self.write_value(&b)
+    }
 
-            OP_FALSE => Ok((Value::Bool(false), pos)),
+    fn visit_i64<E: serde::de::Error>(self, n: i64) -> Result<(), E> {
+        self.write_value(&n)
+    }
 
-            OP_INT8 => {
-                if bytes.len() < 2 {
-                    return Err("INT8: missing byte".into());
-                }
-                let val = bytes[1] as i8 as i64;
-                Ok((serde_json::json!(val), 2))
-            }
+    fn visit_u64<E: serde::de::Error>(self, n: u64) -> Result<(), E> {
+        self.write_value(&n)
+    }
 
-            OP_INT32 => {
-                if bytes.len() < 5 {
-                    return Err("INT32: not enough bytes".into());
-                }
-                let val = i32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as i64;
-                Ok((serde_json::json!(val), 5))
+    fn visit_f64<E: serde::de::Error>(self, f: f64) -> Result<(), E> {
+        self.write_value(&canonicalize(&Value::from(f)))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<(), E> {
+        self.check_string(s)?;
+        self.write_value(&normalize_str(s, &CanonicalOptions::default()))
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        let depth = self.nested()?;
+        self.write(if self.comma { b",[" } else { b"[" })?;
+        let mut len = 0;
+        loop {
+            let (limits, violation) = (self.limits, self.violation);
+            let element = StreamCanonicalizer { limits, depth, violation, out: &mut *self.out, comma: len > 0 };
+            if seq.next_element_seed(element)?.is_none() {
+                break;
             }
+            len += 1;
+            if len > self.limits.max_array_len {
+                return Err(self.fail(CanonicalizeError::ArrayTooLong { limit: self.limits.max_array_len }));
+            }
+        }
+        self.write(b"]")
+    }
 
-            OP_FLOAT64 => {
-                if bytes.len() < 9 {
-                    return Err("FLOAT64: not enough bytes".into());
+    fn visit_map<A: serde::de::MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        let depth = self.nested()?;
+        // Normalized key → (original spelling, canonical JSON of the value), as
+        // in `canonicalize_with`
+        let mut entries: BTreeMap<String, (String, Vec<u8>)> = BTreeMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            // serde_json hands arbitrary-precision numbers over as a one-entry map
+            #[cfg(feature = "decimal")]
+            if key == "$serde_json::private::Number" && entries.is_empty() {
+                let digits: String = map.next_value()?;
+                let number = digits.parse::<serde_json::Number>().map_err(serde::de::Error::custom)?;
+                return self.write_value(&canonicalize(&Value::Number(number)));
+            }
+            self.check_string(&key)?;
+            let normalized = normalize_key(&key, &CanonicalOptions::default()).into_owned();
+            if entries.len() >= self.limits.max_keys && !entries.contains_key(&normalized) {
+                return Err(self.fail(CanonicalizeError::TooManyKeys { limit: self.limits.max_keys }));
+            }
+            let mut value = Vec::new();
+            map.next_value_seed(self.child(depth, &mut value, false))?;
+            match entries.get(&normalized) {
+                Some((first, _)) if *first < key => {}
+                _ => {
+                    entries.insert(normalized, (key, value));
                 }
-                let val = f64::from_le_bytes([
-                    bytes[1], bytes[2], bytes[3], bytes[4],
-                    bytes[5], bytes[6], bytes[7], bytes[8],
-                ]);
-                Ok((serde_json::json!(val), 9))
             }
+        }
 
-            OP_STRING_REF => {
-                let (id, consumed) = decode_varint(&bytes[pos..]);
-                pos += consumed;
-                if (id as usize) >= self.dictionary.len() {
-                    return Err(format!("String ref {} out of bounds (dict size {})", id, self.dictionary.len()));
-                }
-                Ok((Value::String(self.dictionary[id as usize].clone()), pos))
-            }
-
-            OP_ARRAY_START => {
-                let (count, consumed) = decode_varint(&bytes[pos..]);
-                pos += consumed;
-                let mut arr = Vec::with_capacity(count as usize);
-                for _ in 0..count {
-                    let (val, consumed) = self.decode_value(&bytes[pos..])?;
-                    pos += consumed;
-                    arr.push(val);
-                }
-                Ok((Value::Array(arr), pos))
-            }
-
-            OP_OBJECT_START => {
-                let (count, consumed) = decode_varint(&bytes[pos..]);
-                pos += consumed;
-                let mut map = Map::new();
-                for _ in 0..count {
-                    let (key_id, consumed) = decode_varint(&bytes[pos..]);
-                    pos += consumed;
-                    if (key_id as usize) >= self.dictionary.len() {
-                        return Err(format!("Key ref {} out of bounds", key_id));
-                    }
-                    let key = self.dictionary[key_id as usize].clone();
-                    let (val, consumed) = self.decode_value(&bytes[pos..])?;
-                    pos += consumed;
-                    map.insert(key, val);
-                }
-                Ok((Value::Object(map), pos))
+        self.write(if self.comma { b",{" } else { b"{" })?;
+        for (i, (key, (_, value))) in entries.iter().enumerate() {
+            if i > 0 {
+                self.write(b",")?;
             }
-
-            _ => Err(format!("Unknown opcode: 0x{:02x}", opcode)),
+            let key = serde_json::to_vec(key).map_err(serde::de::Error::custom)?;
+            self.write(&key)?;
+            self.write(b":")?;
+            self.write(value)?;
         }
+        self.write(b"}")
     }
 }
 
-// ── TENS-Text Encoder ──
+// ── TENS v2 Binary Encoder ──
 
-/// Infer a TENS-Text type label from a JSON value.
-fn infer_type(value: &Value) -> &'static str {
-    match value {
-        Value::Null => "null",
-        Value::Bool(_) => "bool",
-        Value::Number(_) => "num",
-        Value::String(_) => "str",
-        Value::Array(_) => "str[]", // arrays encoded as repeated fields
-        Value::Object(_) => "str",  // nested objects serialized as string
-    }
+/// Options controlling the binary encoder output.
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    /// Deflate the serialized dictionary. Pays off for documents with many long,
+    /// similar strings (URLs, paths) where the dictionary dominates the payload.
+    #[cfg(feature = "compression")]
+    pub compress_dictionary: bool,
+    /// Largest string ref id the encoder may assign before failing with
+    /// `EncodeError::DictionaryTooLarge`.
+    pub max_string_ref_id: u32,
+    /// Expected number of distinct strings per document, reserved up front
+    /// to avoid regrowing the string table during the scan.
+    pub string_table_capacity: Option<usize>,
+    /// Encode objects whose keys are all canonical u32 decimals (`"0"`, `"17"`,
+    /// not `"01"`) as `OP_INT_KEY_OBJECT`: keys become varints in numeric order
+    /// and stay out of the string table. Changes the bytes, and so the hash.
+    pub int_keys: bool,
+    /// Store numbers that INT8/INT32 cannot hold exactly as `OP_DECIMAL` text
+    /// instead of lossy FLOAT64 (e.g. currency). With the `decimal` feature
+    /// (serde_json `arbitrary_precision`) the original digits survive verbatim,
+    /// including `0.10` and integers beyond 2^53; without it, the shortest
+    /// round-trip f64 text is stored.
+    pub decimal_numbers: bool,
+    /// Collapse runs of at least this many nulls inside an array into a single
+    /// `OP_NULL_RUN`. `None` (the default) always emits one `OP_NULL` each.
+    pub null_run_threshold: Option<usize>,
+    /// How non-empty arrays whose elements all fit an i32 are stored.
+    pub int_arrays: IntArrayEncoding,
+    /// Canonicalization applied by `encode`/`try_encode` before emission.
+    pub canonical: CanonicalOptions,
+    /// Emit `OP_SAME_AS_PREVIOUS` for a string value equal to the previous
+    /// string value, saving the ref varint (e.g. one status repeated across
+    /// sibling records).
+    pub repeat_string_refs: bool,
+    /// Sort the dictionary and store each entry as the length of the prefix it
+    /// shares with the previous one plus the remaining suffix. Shrinks
+    /// dictionaries of paths, URLs and other prefix-heavy strings.
+    pub front_code_dictionary: bool,
+    /// Opcode selection for numbers.
+    pub number_policy: NumberPolicy,
+    /// Store non-integer floats that survive the round trip through f32
+    /// (`(f as f32) as f64 == f`, e.g. sensor readings or ML weights that
+    /// started as f32) as 4-byte `OP_FLOAT32` instead of `OP_FLOAT64`.
+    /// Lossless, but changes the bytes, and so the hash; the TS decoder does
+    /// not read it. Ignored under [`NumberPolicy::AlwaysFloat64`].
+    pub float32: bool,
+    /// Store [`BYTES_TAG`] values (`{"$bytes": "<base64>"}`) as `OP_BYTES`
+    /// with the decoded bytes, instead of as an object holding the base64
+    /// text: a third smaller, and neither key nor text enters the dictionary.
+    /// They decode back to the same shape (standard padded base64). Changes
+    /// the bytes, and so the hash; the TS decoder does not read it.
+    pub raw_bytes: bool,
+    /// Store string values that are RFC 3339 UTC timestamps in their
+    /// canonical spelling, `2024-01-01T00:00:00Z` or `...T00:00:00.250Z`
+    /// (years 0000–9999, milliseconds only when non-zero), as 9-byte
+    /// `OP_TIMESTAMP` instead of dictionary strings. Other spellings
+    /// (offsets, `000` milliseconds, lowercase `t`/`z`) stay strings, so
+    /// every string decodes exactly as written. Changes the bytes, and so the
+    /// hash; the TS decoder does not read it.
+    pub timestamps: bool,
+    /// Store integers in -8..=23 as a single opcode byte that is the value
+    /// itself (`0x20..=0x3F`), instead of 2-byte `OP_INT8`; halves payloads of
+    /// small counts and flags. Takes precedence over [`NumberPolicy`] for
+    /// those integers, except [`NumberPolicy::AlwaysFloat64`]. Changes the
+    /// bytes, and so the hash; the TS decoder does not read it.
+    pub small_ints: bool,
+    /// Encode each element of a non-empty array of objects as
+    /// `OP_SCHEMA_OBJECT_SPARSE` against the union of the elements' keys: keys
+    /// are written once per document and an absent field costs one bit.
+    pub sparse_records: bool,
+    /// Encode a non-empty array of objects that all have the same keys as
+    /// `OP_COLUMNAR_ARRAY`: keys once, then each field's values contiguously,
+    /// which compresses and scans better for analytical data. Takes
+    /// precedence over `sparse_records` for such arrays.
+    pub columnar_arrays: bool,
+    /// Shortest array `int_arrays`, `sparse_records` and `columnar_arrays`
+    /// apply to; shorter arrays keep the generic per-element form, which their
+    /// extra headers would not pay off for. The choice depends only on the
+    /// length, so equal inputs still encode (and hash) equally. 0, the
+    /// default, specializes every non-empty array.
+    pub array_specialization_threshold: usize,
+    /// Deepest nesting `try_encode_serialize` accepts before failing with
+    /// `EncodeError::DepthExceeded`. Containers, `Some` and newtype wrappers
+    /// each count as a level, so an `Rc` cycle or runaway recursion stops
+    /// here instead of overflowing the stack.
+    pub max_serialize_depth: usize,
+    /// Magic and version written to the header.
+    pub format: TensFormat,
+    /// Write a TENS v2.1 header: a feature byte after the version byte
+    /// records which `FEATURE_*` features the document relies on, so a
+    /// decoder lacking one fails up front. Only features the tree actually
+    /// uses are set, not every one enabled. The version byte is `0x12`
+    /// (version 2 with a flag bit), not `0x21`, which would collide with the
+    /// multi-root flag. Off, the 5-byte v2 header is written, byte-identical
+    /// to the TS encoder's.
+    pub feature_header: bool,
 }
 
-/// Check if a string needs quoting in TENS-Text.
-fn needs_quoting(s: &str) -> bool {
-    if s.is_empty() {
-        return true;
-    }
-    if s == "_" || s == "true" || s == "false" {
-        return true;
-    }
-    // Looks like a dict ref @N or #N
-    if (s.starts_with('@') || s.starts_with('#')) && s[1..].parse::<u32>().is_ok() {
-        return true;
-    }
-    // Looks like a number
-    if s.parse::<f64>().is_ok() {
-        return true;
-    }
-    // Contains special characters
-    s.chars().any(|c| {
-        c.is_whitespace() || matches!(c, '"' | '\\' | '|' | '>' | ',' | '=' | '{' | '}' | '[' | ']' | '@' | '#')
-    })
+/// How numbers map to opcodes; see [`EncoderOptions::number_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumberPolicy {
+    /// Integers in i32 range as `OP_INT8`/`OP_INT32`, other integers i64
+    /// holds as `OP_INT64`, everything else as `OP_FLOAT64`. Up to i32 this
+    /// is what the TS encoder does (`Number.isInteger`), so documents whose
+    /// integers all fit i32 keep bytes and hashes equal across the two. The
+    /// TS encoder writes larger integers as `OP_FLOAT64`, which rounds past
+    /// 2^53 (`9007199254740993` decodes as `9007199254740992`); exactness is
+    /// worth more here than parity for those values, whose hashes differ.
+    #[default]
+    PreferInteger,
+    /// Every number as `OP_FLOAT64`, for peers that model all numbers as f64
+    /// without an integer distinction. Integers decode as floats (`42` → `42.0`).
+    AlwaysFloat64,
+    /// Every integer i64 holds at the narrowest of `OP_INT8`, `OP_INT16`,
+    /// `OP_INT32` and `OP_INT64`: [`Self::PreferInteger`] plus `OP_INT16`,
+    /// so integers that need 2 bytes save two bytes each. Integers past i64
+    /// are still `OP_FLOAT64`. The TS decoder does not read `OP_INT16`, so
+    /// this breaks byte and hash parity with it.
+    SmallestInteger,
+    /// Integers outside -16..=15 as zigzag `OP_VARINT`, whose length follows
+    /// the magnitude (-1000 takes 3 bytes instead of `OP_INT32`'s 5, and
+    /// integers past i32 stay exact); those inside as `OP_INT8`. Suits
+    /// payloads of mostly small counts and deltas. The TS decoder does not
+    /// read `OP_VARINT`, so this breaks byte and hash parity with it.
+    Varint,
 }
 
-/// Quote a string with TENS-Text escape rules.
-fn quote_string(s: &str) -> String {
-    let mut out = String::with_capacity(s.len() + 2);
-    out.push('"');
-    for ch in s.chars() {
-        match ch {
-            '"' => out.push_str("\\\""),
-            '\\' => out.push_str("\\\\"),
-            '\n' => out.push_str("\\n"),
-            '\r' => out.push_str("\\r"),
-            '\t' => out.push_str("\\t"),
-            c => out.push(c),
+/// Layout for arrays of integers; see [`EncoderOptions::int_arrays`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntArrayEncoding {
+    /// One `OP_INT8`/`OP_INT32` per element, as the TS encoder does.
+    #[default]
+    Generic,
+    /// `OP_INT_ARRAY`: every element at the width of the widest one.
+    Fixed,
+    /// `OP_PACKED_INT_ARRAY`: every element as a zigzag varint.
+    Packed,
+    /// Whichever of `Fixed` and `Packed` is smaller for the array at hand;
+    /// `Fixed` on a tie. Mostly-small arrays with a few outliers pack better.
+    Auto,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        EncoderOptions {
+            #[cfg(feature = "compression")]
+            compress_dictionary: false,
+            max_string_ref_id: DEFAULT_MAX_STRING_REF_ID,
+            string_table_capacity: None,
+            int_keys: false,
+            decimal_numbers: false,
+            null_run_threshold: None,
+            int_arrays: IntArrayEncoding::Generic,
+            canonical: CanonicalOptions::default(),
+            repeat_string_refs: false,
+            front_code_dictionary: false,
+            number_policy: NumberPolicy::PreferInteger,
+            float32: false,
+            raw_bytes: false,
+            timestamps: false,
+            small_ints: false,
+            sparse_records: false,
+            columnar_arrays: false,
+            array_specialization_threshold: 0,
+            max_serialize_depth: DEFAULT_MAX_SERIALIZE_DEPTH,
+            format: TensFormat::UPSTREAM,
+            feature_header: false,
         }
     }
-    out.push('"');
-    out
 }
 
-/// Format a value for TENS-Text output.
-fn format_tens_text_value(value: &Value, dict_map: &HashMap<String, usize>) -> String {
-    match value {
-        Value::Null => "_".to_string(),
-        Value::Bool(true) => "true".to_string(),
-        Value::Bool(false) => "false".to_string(),
-        Value::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                i.to_string()
-            } else if let Some(f) = n.as_f64() {
-                if f.is_nan() {
-                    "\"NaN\"".to_string()
-                } else if f.is_infinite() {
-                    if f.is_sign_positive() {
-                        "\"Infinity\"".to_string()
-                    } else {
-                        "\"-Infinity\"".to_string()
-                    }
-                } else if f == 0.0 && f.is_sign_negative() {
-                    "-0".to_string()
-                } else {
-                    format!("{}", f)
-                }
-            } else {
-                n.to_string()
-            }
-        }
-        Value::String(s) => {
-            // Check dictionary
-            if let Some(&idx) = dict_map.get(s) {
-                return format!("@{}", idx);
-            }
-            if needs_quoting(s) {
-                quote_string(s)
-            } else {
-                s.clone()
-            }
+/// A unit for counting a string's length. Dictionary entries are always
+/// prefixed with their UTF-8 byte length ([`StringLengthPolicy::Utf8Bytes`]);
+/// the other units describe peers that count differently, such as JS
+/// `String.length` (UTF-16 code units), so a mismatch can be caught up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringLengthPolicy {
+    /// Bytes of the UTF-8 encoding: the TENS dictionary's unit.
+    Utf8Bytes,
+    /// UTF-16 code units, as JS strings count them.
+    Utf16Units,
+    /// Unicode scalar values.
+    Chars,
+}
+
+impl StringLengthPolicy {
+    /// The length of `s` in this unit.
+    pub fn length(self, s: &str) -> usize {
+        match self {
+            StringLengthPolicy::Utf8Bytes => s.len(),
+            StringLengthPolicy::Utf16Units => s.encode_utf16().count(),
+            StringLengthPolicy::Chars => s.chars().count(),
         }
-        Value::Array(_) | Value::Object(_) => {
-            // Shouldn't happen at field level — arrays handled externally
-            let s = serde_json::to_string(value).unwrap_or_default();
-            quote_string(&s)
+    }
+
+    /// Whether a peer counting in this unit agrees with the dictionary's
+    /// length prefixes. Only `Utf8Bytes` does, for every string.
+    pub fn check_dictionary(self) -> Result<(), String> {
+        match self {
+            StringLengthPolicy::Utf8Bytes => Ok(()),
+            other => Err(format!("dictionary entry lengths are UTF-8 byte counts, not {:?}", other)),
         }
     }
 }
 
-/// Encode an array of objects into TENS-Text format.
-pub fn encode_tens_text(data: &Value, encoding: Option<&str>) -> Result<String, String> {
-    let canonical = canonicalize(data);
-    let records = match &canonical {
-        Value::Array(arr) => arr.clone(),
-        Value::Object(_) => vec![canonical.clone()],
-        _ => return Err("TENS-Text requires an array of objects or a single object".into()),
-    };
+/// A field transform for [`TensEncoder::with_transform`].
+type FieldTransform = Box<dyn Fn(&str, &Value) -> Option<Value>>;
 
-    if records.is_empty() {
-        return Ok("@version 1\n".to_string());
+pub struct TensEncoder {
+    pub registry: SchemaRegistry,
+    string_table: StringTable,
+    options: EncoderOptions,
+    /// Applied to every object field before canonicalization.
+    transform: Option<FieldTransform>,
+    /// User opcode handlers, tried in registration order.
+    extensions: Vec<(u8, EncodeExtension)>,
+    /// What the handlers made of each value of the tree just scanned, keyed by
+    /// its address, so the emit pass reuses the scan's result instead of
+    /// running them again. Reset with every scan.
+    extension_claims: HashMap<usize, Option<(u8, Vec<u8>)>>,
+    /// `FEATURE_*` bits the scanned tree will use, for the feature byte.
+    used_features: u8,
+    /// Set by `encode_with_field_hashes` for the root object to fill with
+    /// the hash of each of its fields.
+    field_hashes: Option<BTreeMap<String, String>>,
+    /// Id of the last string value emitted, for `repeat_string_refs`.
+    last_string_ref: Option<u32>,
+    /// Sparse record schemas seen by the scan and emit passes of the current
+    /// document, in order of first use (index = schema number). Tracked apart
+    /// so the passes can interleave, as in `encode_stream_of`.
+    scanned_schemas: Vec<Vec<String>>,
+    emitted_schemas: Vec<Vec<String>>,
+    /// Columnar array shapes of the current document, kept apart per pass
+    /// like the sparse schemas; registry ids are the wire schema numbers.
+    scanned_columns: SchemaRegistry,
+    emitted_columns: SchemaRegistry,
+}
+
+impl TensEncoder {
+    pub fn new() -> Self {
+        Self::with_options(EncoderOptions::default())
     }
 
-    // 1. Extract schema from first record
-    let first = records.first().unwrap();
-    let obj = first.as_object().ok_or("Records must be objects")?;
-    let mut keys: Vec<String> = obj.keys().cloned().collect();
-    keys.sort();
+    pub fn with_options(options: EncoderOptions) -> Self {
+        TensEncoder {
+            registry: SchemaRegistry::new(),
+            string_table: StringTable::new(),
+            options,
+            transform: None,
+            extensions: Vec::new(),
+            extension_claims: HashMap::new(),
+            used_features: 0,
+            field_hashes: None,
+            last_string_ref: None,
+            scanned_schemas: Vec::new(),
+            emitted_schemas: Vec::new(),
+            scanned_columns: SchemaRegistry::new(),
+            emitted_columns: SchemaRegistry::new(),
+        }
+    }
 
-    // Infer types from first record
-    let types: Vec<&str> = keys.iter().map(|k| {
-        infer_type(obj.get(k).unwrap_or(&Value::Null))
-    }).collect();
+    pub fn options(&self) -> &EncoderOptions {
+        &self.options
+    }
 
-    // Determine array fields across all records
-    let mut is_array_field: Vec<bool> = vec![false; keys.len()];
-    for record in &records {
-        if let Some(obj) = record.as_object() {
-            for (i, key) in keys.iter().enumerate() {
-                if let Some(Value::Array(_)) = obj.get(key) {
-                    is_array_field[i] = true;
-                }
-            }
-        }
+    /// Rewrite object fields before canonicalization, e.g. to redact PII so
+    /// the bytes and hash reflect the redacted form. `f` is called with each
+    /// field's key and value, at every depth, and returns the replacement
+    /// (`Some(value.clone())` keeps it) or `None` to drop the field. Fields of
+    /// a replacement object are transformed in turn.
+    ///
+    /// Not applied by [`Self::encode_unchecked`], whose input is final.
+    pub fn with_transform(mut self, f: impl Fn(&str, &Value) -> Option<Value> + 'static) -> Self {
+        self.transform = Some(Box::new(f));
+        self
     }
 
-    // 2. Build dictionary (strings appearing ≥2 times as values)
-    let mut string_counts: HashMap<String, usize> = HashMap::new();
-    for record in &records {
-        if let Some(obj) = record.as_object() {
-            for key in &keys {
-                if let Some(Value::String(s)) = obj.get(key) {
-                    *string_counts.entry(s.clone()).or_insert(0) += 1;
-                }
-            }
+    /// Emit the (canonical) values `encode` claims as `opcode` + its payload.
+    /// Handlers are tried in registration order before the built-in
+    /// encodings, at every depth; strings inside a claimed value are not added
+    /// to the dictionary.
+    ///
+    /// # Panics
+    /// If `opcode` is outside [`USER_OPCODES`].
+    pub fn with_extension(mut self, opcode: u8, encode: EncodeExtension) -> Self {
+        assert!(USER_OPCODES.contains(&opcode), "opcode 0x{:02x} is not a user opcode", opcode);
+        self.extensions.push((opcode, encode));
+        self
+    }
+
+    /// The opcode and payload of the first extension that claims `value`.
+    fn encode_extension(&self, value: &Value) -> Option<(u8, Vec<u8>)> {
+        self.extensions.iter().find_map(|(opcode, encode)| Some((*opcode, encode(value)?)))
+    }
+
+    /// Scan pass: whether an extension claims `value`, remembered for the
+    /// emit pass.
+    fn scan_extension(&mut self, value: &Value) -> bool {
+        if self.extensions.is_empty() {
+            return false;
+        }
+        let claim = self.encode_extension(value);
+        let claimed = claim.is_some();
+        if claimed {
+            self.used_features |= FEATURE_USER_OPCODES;
         }
+        self.extension_claims.insert(value as *const Value as usize, claim);
+        claimed
     }
 
-    let mut dict_entries: Vec<String> = string_counts
-        .iter()
-        .filter(|(_, &count)| count >= 2)
-        .map(|(s, _)| s.clone())
-        .collect();
-    dict_entries.sort();
+    /// Whether `n` is written as `OP_DECIMAL` text.
+    fn is_decimal(&self, n: &serde_json::Number) -> bool {
+        self.options.decimal_numbers && n.as_i64().and_then(|i| i32::try_from(i).ok()).is_none()
+    }
 
-    let dict_map: HashMap<String, usize> = dict_entries
-        .iter()
-        .enumerate()
-        .map(|(i, s)| (s.clone(), i))
-        .collect();
+    /// Emit pass: the scan's claim on `value`, or a fresh one for a value the
+    /// scan did not see (such as the integer standing in for `1.0`).
+    fn emit_extension(&mut self, value: &Value) -> Option<(u8, Vec<u8>)> {
+        if self.extensions.is_empty() {
+            return None;
+        }
+        match self.extension_claims.remove(&(value as *const Value as usize)) {
+            Some(claim) => claim,
+            None => self.encode_extension(value),
+        }
+    }
 
-    // 3. Build output
-    let mut out = String::new();
+    /// Apply the transform, if any, then canonicalize.
+    fn canonicalize_input(&self, value: &Value) -> Value {
+        match &self.transform {
+            Some(f) => canonicalize_with(&transform_fields(value, f), &self.options.canonical),
+            None => canonicalize_with(value, &self.options.canonical),
+        }
+    }
 
-    // Directives
-    out.push_str("@version 1\n");
-    if let Some(enc) = encoding {
-        out.push_str(&format!("@encoding {}\n", enc));
+    /// Encode a JSON value into TENS v2 binary format.
+    /// Returns the raw bytes (header + dictionary + value tree).
+    ///
+    /// # Panics
+    /// If the document exceeds the encoder's size limits; see [`Self::try_encode`].
+    pub fn encode(&mut self, value: &Value) -> Vec<u8> {
+        self.try_encode(value).unwrap_or_else(|e| panic!("TENS encode failed: {}", e))
     }
 
-    // Schema line: @schema <name> field:type field:type?
-    let schema_name = "data";
-    out.push_str(&format!("@schema {}", schema_name));
-    for (i, key) in keys.iter().enumerate() {
-        let type_str = types[i];
-        let suffix = if is_array_field[i] { "[]" } else { "" };
-        out.push_str(&format!(" {}:{}{}", key, type_str, suffix));
+    /// Encode a JSON value into TENS v2 binary format, failing instead of
+    /// panicking when the dictionary or a length prefix would overflow.
+    pub fn try_encode(&mut self, value: &Value) -> Result<Vec<u8>, EncodeError> {
+        // 1. Canonicalize
+        let canonical = self.canonicalize_input(value);
+        self.emit(&canonical)
+    }
+
+    /// Parse UTF-8 JSON bytes and encode the result, without an intermediate
+    /// `String` or host-language object.
+    pub fn try_encode_json_slice(&mut self, json: &[u8]) -> Result<Vec<u8>, EncodeError> {
+        let value: Value = serde_json::from_slice(json)
+            .map_err(|e| EncodeError::InvalidJson(e.to_string()))?;
+        self.try_encode(&value)
     }
-    out.push('\n');
 
-    // Dictionary line
-    if !dict_entries.is_empty() {
-        out.push_str("@dict");
-        for entry in &dict_entries {
-            if needs_quoting(entry) {
-                out.push_str(&format!(" {}", quote_string(entry)));
+    /// Encode any `Serialize` value, e.g. a struct holding `Box<RawValue>`
+    /// fields (with the `raw_value` feature): it is converted straight to a
+    /// `Value`, each raw blob parsed from its text exactly once, with no
+    /// serialize-to-string round trip. Produces the same bytes as encoding the
+    /// equivalent parsed `Value`.
+    ///
+    /// Fails with `EncodeError::DepthExceeded` if the input nests deeper than
+    /// `max_serialize_depth`.
+    pub fn try_encode_serialize<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<Vec<u8>, EncodeError> {
+        let guard = DepthGuard::new(self.options.max_serialize_depth);
+        let value = serde_json::to_value(guard.wrap(value)).map_err(|e| {
+            if guard.tripped() {
+                EncodeError::DepthExceeded { limit: self.options.max_serialize_depth }
             } else {
-                out.push_str(&format!(" {}", entry));
+                EncodeError::InvalidJson(e.to_string())
             }
+        })?;
+        self.try_encode(&value)
+    }
+
+    /// Encode a value that is already canonical, skipping `canonicalize` and its
+    /// full-tree clone. The scan pass still runs.
+    ///
+    /// The caller must guarantee the input is canonical (NFKC strings without
+    /// trailing whitespace, no `-0`/integral floats); otherwise the bytes, and
+    /// so the hash, differ from [`Self::encode`] for the same logical value.
+    ///
+    /// # Panics
+    /// Under the same conditions as [`Self::encode`].
+    pub fn encode_unchecked(&mut self, value: &Value) -> Vec<u8> {
+        self.emit(value).unwrap_or_else(|e| panic!("TENS encode failed: {}", e))
+    }
+
+    /// Encode an object and hash each top-level field, in a single encode.
+    ///
+    /// Returns the same bytes as [`Self::encode`]. A field's hash is
+    /// [`hash_tens_binary`] of the field encoded as a document of its own with
+    /// this encoder's options and extensions, built from the already
+    /// canonical tree, so it depends only on that field's value and is
+    /// unaffected by edits to its siblings. Non-object roots, and a root
+    /// object claimed whole by an extension, yield an empty map.
+    ///
+    /// # Panics
+    /// Under the same conditions as [`Self::encode`].
+    pub fn encode_with_field_hashes(&mut self, value: &Value) -> (Vec<u8>, BTreeMap<String, String>) {
+        let canonical = self.canonicalize_input(value);
+        self.field_hashes = canonical.is_object().then(BTreeMap::new);
+        let bytes = self.emit(&canonical);
+        let field_hashes = self.field_hashes.take().unwrap_or_default();
+        (bytes.unwrap_or_else(|e| panic!("TENS encode failed: {}", e)), field_hashes)
+    }
+
+    /// Emit an object field's value, hashing it into `field_hashes` when the
+    /// object is the root of `encode_with_field_hashes`.
+    fn encode_field<O: ByteSink>(
+        &mut self,
+        key: &str,
+        val: &Value,
+        field_hashes: Option<&mut BTreeMap<String, String>>,
+        out: &mut O,
+    ) -> Result<(), EncodeError> {
+        let Some(field_hashes) = field_hashes else {
+            return self.encode_value(val, out);
+        };
+        // String ids and schemas are numbered per document, so the field is
+        // hashed as a document of its own rather than as its bytes in this one
+        let mut local = TensEncoder::with_options(self.options.clone());
+        local.extensions = self.extensions.clone();
+        field_hashes.insert(key.to_string(), hash_tens_binary(&local.emit(val)?));
+        self.encode_value(val, out)
+    }
+
+    /// Emit an already-canonical value as a complete TENS v2 document.
+    fn emit(&mut self, canonical: &Value) -> Result<Vec<u8>, EncodeError> {
+        if let Some(out) = self.emit_scalar(canonical) {
+            return Ok(out);
         }
-        out.push('\n');
+
+        // 2. Scan pass: collect all strings in DFS order (keys sorted)
+        self.scan_roots(std::slice::from_ref(canonical))?;
+
+        // 3. Emit binary
+        let mut out = self.emit_header_and_dictionary()?;
+
+        // Value tree
+        self.last_string_ref = None;
+        self.emitted_schemas.clear();
+        self.emitted_columns = SchemaRegistry::new();
+        self.encode_value(canonical, &mut out)?;
+
+        Ok(out)
     }
 
-    // Records
-    out.push('\n');
-    for record in &records {
-        if let Some(obj) = record.as_object() {
-            out.push_str(&format!("{}\n", schema_name));
-            for (i, key) in keys.iter().enumerate() {
-                if let Some(val) = obj.get(key) {
-                    if is_array_field[i] {
-                        if let Value::Array(arr) = val {
-                            for item in arr {
-                                out.push_str(&format!("  {} {}\n", key, format_tens_text_value(item, &dict_map)));
-                            }
-                        }
-                    } else {
-                        out.push_str(&format!("  {} {}\n", key, format_tens_text_value(val, &dict_map)));
-                    }
-                }
-            }
+    /// Encode several root values after one header and one shared dictionary:
+    /// the dictionary is followed by varint(count) and the roots in order.
+    /// Strings repeated across roots are stored once, so many small values
+    /// come out smaller than as independent documents. Read it back with
+    /// [`TensDecoder::decode_multi`]; `decode` rejects it.
+    pub fn encode_multi(&mut self, values: &[Value]) -> Result<Vec<u8>, EncodeError> {
+        let roots: Vec<Value> = values.iter()
+            .map(|value| self.canonicalize_input(value))
+            .collect();
+
+        self.scan_roots(&roots)?;
+
+        let mut out = self.emit_header_and_dictionary()?;
+        out[4] |= FLAG_MULTI_ROOT;
+        out.extend_from_slice(&encode_len(roots.len())?);
+        self.last_string_ref = None;
+        self.emitted_schemas.clear();
+        self.emitted_columns = SchemaRegistry::new();
+        for root in &roots {
+            self.encode_value(root, &mut out)?;
         }
+        Ok(out)
     }
 
-    Ok(out)
-}
+    /// Encode `value` as its dictionary section (header included) and its
+    /// value tree, for storing apart; see [`TensDecoder::decode_split`].
+    /// Concatenated, the two are exactly [`Self::encode`]'s output.
+    pub fn encode_split(&mut self, value: &Value) -> Result<(Vec<u8>, Vec<u8>), EncodeError> {
+        let (dictionary, mut trees) = self.encode_split_many(std::slice::from_ref(value))?;
+        Ok((dictionary, trees.pop().unwrap()))
+    }
 
-/// Decode TENS-Text format back into a JSON Value (array of objects).
-pub fn decode_tens_text(input: &str) -> Result<Value, String> {
-    let mut dict: Vec<String> = Vec::new();
-    let mut schema_name = String::new();
-    let mut schema_fields: Vec<(String, String)> = Vec::new(); // (name, type)
-    let mut records: Vec<Value> = Vec::new();
-    let mut current_record: Option<Map<String, Value>> = None;
-    let mut array_fields: std::collections::HashSet<String> = std::collections::HashSet::new();
+    /// Encode `values` as value trees sharing one dictionary section, which
+    /// holds the strings of all of them. Each tree decodes on its own with
+    /// [`TensDecoder::decode_split`] against that dictionary.
+    pub fn encode_split_many(&mut self, values: &[Value]) -> Result<(Vec<u8>, Vec<Vec<u8>>), EncodeError> {
+        let roots: Vec<Value> = values.iter()
+            .map(|value| self.canonicalize_input(value))
+            .collect();
+        self.scan_roots(&roots)?;
+
+        let dictionary = self.emit_header_and_dictionary()?;
+        let mut trees = Vec::with_capacity(roots.len());
+        for root in &roots {
+            // Trees are decoded independently, so none may lean on another's state
+            let mut tree = Vec::new();
+            self.last_string_ref = None;
+            self.emitted_schemas.clear();
+            self.emitted_columns = SchemaRegistry::new();
+            self.encode_value(root, &mut tree)?;
+            trees.push(tree);
+        }
+        Ok((dictionary, trees))
+    }
 
-    for line in input.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
+    /// Scan pass over the roots of one document: rebuild the string table
+    /// from their strings in DFS order (keys sorted).
+    fn scan_roots(&mut self, roots: &[Value]) -> Result<(), EncodeError> {
+        self.extension_claims.clear();
+        self.used_features = 0;
+        self.string_table = StringTable::with_max_id(self.options.max_string_ref_id);
+        if let Some(capacity) = self.options.string_table_capacity {
+            self.string_table.reserve(capacity);
+        }
+        self.scanned_schemas.clear();
+        self.scanned_columns = SchemaRegistry::new();
+        for root in roots {
+            self.scan_strings(root)?;
         }
+        if self.options.front_code_dictionary {
+            self.string_table.sort();
+        }
+        Ok(())
+    }
 
-        if trimmed.starts_with("@version") {
-            continue;
+    /// Magic and version byte, followed by the feature byte with
+    /// `feature_header`.
+    fn header(&self) -> Result<Vec<u8>, EncodeError> {
+        let format = self.options.format;
+        if format.flag_bits() != 0 {
+            return Err(EncodeError::InvalidFormat { version: format.version });
         }
-        if trimmed.starts_with("@encoding") {
-            continue;
+        let mut header = format.header().to_vec();
+        if self.options.feature_header {
+            header[4] |= FLAG_FEATURE_BYTE;
+            header.push(self.used_features);
         }
-        if trimmed.starts_with("@schema") {
-            let parts: Vec<&str> = trimmed.split_whitespace().collect();
-            if parts.len() >= 2 {
-                schema_name = parts[1].to_string();
-                schema_fields.clear();
-                for part in &parts[2..] {
-                    if let Some((name, type_str)) = part.split_once(':') {
-                        if type_str.ends_with("[]") {
-                            array_fields.insert(name.to_string());
-                            schema_fields.push((name.to_string(), type_str.trim_end_matches("[]").to_string()));
-                        } else {
-                            schema_fields.push((name.to_string(), type_str.to_string()));
-                        }
-                    }
-                }
+        Ok(header)
+    }
+
+    /// Header plus the serialized string table, ready for the value tree.
+    fn emit_header_and_dictionary(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut out = Vec::new();
+
+        // Header
+        out.extend_from_slice(&self.header()?);
+
+        // Dictionary: varint(count), then for each string: varint(utf8_len) + utf8_bytes
+        out.extend_from_slice(&encode_len(self.string_table.len())?);
+        let mut dictionary = Vec::new();
+        let mut previous: &[u8] = &[];
+        for entry in self.string_table.entries() {
+            let mut bytes = entry.as_bytes();
+            if self.options.front_code_dictionary {
+                let shared = previous.iter().zip(bytes).take_while(|(a, b)| a == b).count();
+                dictionary.extend_from_slice(&encode_len(shared)?);
+                previous = bytes;
+                bytes = &bytes[shared..];
             }
-            continue;
+            dictionary.extend_from_slice(&encode_len(bytes.len())?);
+            dictionary.extend_from_slice(bytes);
         }
-        if trimmed.starts_with("@dict") {
-            dict = parse_dict_line(trimmed);
-            continue;
+        if self.options.front_code_dictionary {
+            out[4] |= FLAG_FRONT_CODED_DICT;
         }
+        #[cfg(feature = "compression")]
+        let dictionary = if self.options.compress_dictionary {
+            out[4] |= FLAG_COMPRESSED_DICT;
+            deflate_block(&dictionary)
+        } else {
+            dictionary
+        };
+        out.extend_from_slice(&dictionary);
+        Ok(out)
+    }
 
-        // Schema name line → start new record
-        if trimmed == schema_name {
-            if let Some(rec) = current_record.take() {
-                records.push(Value::Object(rec));
-            }
-            current_record = Some(Map::new());
-            continue;
+    /// Encode the items of an iterator as one TENS array, without collecting
+    /// them into a `Vec<Value>` first. Produces the same bytes as encoding the
+    /// collected array.
+    ///
+    /// Each item is canonicalized, scanned and emitted in turn into a buffer for
+    /// the value tree; since the array's scan visits items in order, string ids
+    /// come out as in a whole-document scan. The header, dictionary and element
+    /// count are only known at the end, so the tree bytes are buffered until
+    /// then (the `Value`s themselves are dropped as soon as they are emitted).
+    /// With `front_code_dictionary`, `int_arrays`, `sparse_records` or
+    /// `columnar_arrays` set, the layout depends on every item, so the items
+    /// are collected and encoded as a whole instead.
+    pub fn encode_stream_of<I: Iterator<Item = Value>>(&mut self, items: I) -> Result<Vec<u8>, EncodeError> {
+        if self.options.front_code_dictionary
+            || self.options.int_arrays != IntArrayEncoding::Generic
+            || self.options.sparse_records
+            || self.options.columnar_arrays
+        {
+            return self.try_encode(&Value::Array(items.collect()));
         }
 
-        // Field line (indented)
-        if line.starts_with("  ") && current_record.is_some() {
-            let field_line = trimmed;
-            if let Some((field_name, raw_value)) = field_line.split_once(char::is_whitespace) {
-                let raw_value = raw_value.trim();
-                let parsed = parse_tens_text_value(raw_value, &dict);
-
-                let rec = current_record.as_mut().unwrap();
-                if array_fields.contains(field_name) {
-                    let arr = rec.entry(field_name.to_string())
-                        .or_insert_with(|| Value::Array(Vec::new()));
-                    if let Value::Array(a) = arr {
-                        a.push(parsed);
-                    }
-                } else {
-                    rec.insert(field_name.to_string(), parsed);
-                }
+        self.string_table = StringTable::with_max_id(self.options.max_string_ref_id);
+        self.last_string_ref = None;
+        self.scanned_schemas.clear();
+        self.scanned_columns = SchemaRegistry::new();
+        self.emitted_schemas.clear();
+        self.emitted_columns = SchemaRegistry::new();
+        self.used_features = 0;
+        let mut tree = Vec::new();
+        let mut count = 0;
+        // Nulls are held back so a run can become one OP_NULL_RUN
+        let mut nulls = 0;
+        for item in items {
+            let canonical = self.canonicalize_input(&item);
+            count += 1;
+            if canonical.is_null() {
+                nulls += 1;
+                continue;
             }
+            self.encode_nulls(nulls, &mut tree)?;
+            nulls = 0;
+            self.extension_claims.clear();
+            self.scan_strings(&canonical)?;
+            self.encode_value(&canonical, &mut tree)?;
         }
-    }
-
-    // Flush last record
-    if let Some(rec) = current_record.take() {
-        records.push(Value::Object(rec));
-    }
+        self.encode_nulls(nulls, &mut tree)?;
 
-    if records.len() == 1 {
-        Ok(records.into_iter().next().unwrap())
-    } else {
-        Ok(Value::Array(records))
+        let mut out = self.emit_header_and_dictionary()?;
+        out.push(OP_ARRAY_START);
+        out.extend_from_slice(&encode_len(count)?);
+        out.extend_from_slice(&tree);
+        Ok(out)
     }
-}
 
-/// Parse TENS-Text @dict line into list of entries.
-fn parse_dict_line(line: &str) -> Vec<String> {
-    let content = line.strip_prefix("@dict").unwrap_or("").trim();
-    let mut entries = Vec::new();
-    let mut chars = content.chars().peekable();
+    /// Fast path for null, bool and number roots: no strings, so the scan only
+    /// notes features and no fresh string table is built, just header + empty
+    /// dictionary + value in one right-sized buffer. `None` for roots that
+    /// need the general path.
+    fn emit_scalar(&mut self, canonical: &Value) -> Option<Vec<u8>> {
+        if !matches!(canonical, Value::Null | Value::Bool(_) | Value::Number(_)) {
+            return None;
+        }
+        // A compressed dictionary is a non-empty deflate block even when empty
+        #[cfg(feature = "compression")]
+        if self.options.compress_dictionary {
+            return None;
+        }
+        if self.options.front_code_dictionary {
+            return None;
+        }
 
-    while chars.peek().is_some() {
-        // Skip whitespace
-        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
-            chars.next();
+        // Only replace a table a previous document filled; an empty one owns no heap memory
+        if !self.string_table.is_empty() {
+            self.string_table = StringTable::with_max_id(self.options.max_string_ref_id);
         }
-        if chars.peek().is_none() {
-            break;
+        // Claims of a previous tree may be keyed by addresses reused since
+        self.extension_claims.clear();
+        self.used_features = 0;
+        // For the feature byte; a number stored as a string needs the general path
+        self.scan_strings(canonical).ok()?;
+        if !self.string_table.is_empty() {
+            return None;
         }
+        let mut out = Vec::with_capacity(HEADER_LEN + 2 + 9);
+        out.extend_from_slice(&self.header().ok()?);
+        out.push(0); // dictionary count
+        // Scalars never reference the string table or overflow a length prefix
+        self.encode_value(canonical, &mut out).ok()?;
+        Some(out)
+    }
 
-        if chars.peek() == Some(&'"') {
-            // Quoted string
-            chars.next(); // consume opening quote
-            let mut s = String::new();
-            loop {
-                match chars.next() {
-                    Some('\\') => {
-                        match chars.next() {
-                            Some('n') => s.push('\n'),
-                            Some('r') => s.push('\r'),
-                            Some('t') => s.push('\t'),
-                            Some(c) => s.push(c),
-                            None => break,
+    /// What a number past the f64 range (`1e400`, only possible with
+    /// `arbitrary_precision`) is stored as when `decimal_numbers` does not
+    /// keep its text: the infinity it rounds to, per `non_finite_floats`.
+    fn out_of_range_number(&self, n: &serde_json::Number) -> Value {
+        let f = n.to_string().parse().unwrap_or(f64::NAN);
+        self.options.canonical.non_finite_floats.value(f)
+    }
+
+    /// Scan all strings in DFS order to populate the string table.
+    /// Object keys are visited in sorted order (canonical), each key followed by
+    /// its value — the same order `encode_value` references them and the order
+    /// the TS encoder's scan uses.
+    fn scan_strings(&mut self, value: &Value) -> Result<(), EncodeError> {
+        if self.scan_extension(value) || self.is_raw_bytes(value) {
+            return Ok(());
+        }
+        match value {
+            // Timestamps are stored inline, outside the dictionary
+            Value::String(s) if self.timestamp_millis(s).is_some() => {}
+            Value::String(s) => {
+                self.string_table.add(s)?;
+            }
+            Value::Number(n) if self.is_decimal(&integral_form(n).unwrap_or_else(|| n.clone())) => {
+                self.used_features |= FEATURE_DECIMAL_NUMBERS;
+            }
+            Value::Number(n) if !self.options.decimal_numbers && n.as_i64().is_none() && n.as_f64().is_none() => {
+                if let Value::String(s) = self.out_of_range_number(n) {
+                    self.string_table.add(&s)?;
+                }
+            }
+            Value::Array(arr) => {
+                if let Some(fields) = self.columnar_fields(arr) {
+                    // Same order as emission: the definition's keys on first use, then columns
+                    if self.scanned_columns.get_or_register(&fields, &column_types(arr, &fields)).1 {
+                        for field in &fields {
+                            self.string_table.add(field)?;
                         }
                     }
-                    Some('"') => break,
-                    Some(c) => s.push(c),
-                    None => break,
+                    for field in &fields {
+                        for record in arr {
+                            self.scan_strings(&record[field.as_str()])?;
+                        }
+                    }
+                    return Ok(());
+                }
+                if let Some(fields) = self.sparse_fields(arr) {
+                    // Same order as emission: the definition's keys on first use, then values
+                    if !self.scanned_schemas.contains(&fields) {
+                        for field in &fields {
+                            self.string_table.add(field)?;
+                        }
+                        self.scanned_schemas.push(fields.clone());
+                    }
+                    for record in arr.iter().filter_map(Value::as_object) {
+                        for val in fields.iter().filter_map(|field| record.get(field)) {
+                            self.scan_strings(val)?;
+                        }
+                    }
+                    return Ok(());
+                }
+                for item in arr {
+                    self.scan_strings(item)?;
                 }
             }
-            entries.push(s);
-        } else {
-            // Unquoted token
-            let mut s = String::new();
-            while chars.peek().map(|c| !c.is_whitespace()).unwrap_or(false) {
-                s.push(chars.next().unwrap());
+            Value::Object(obj) => {
+                if let Some(entries) = self.int_key_entries(obj) {
+                    for (_, val) in entries {
+                        self.scan_strings(val)?;
+                    }
+                    return Ok(());
+                }
+
+                // Keys are already sorted from canonicalize
+                let mut keys: Vec<&String> = obj.keys().collect();
+                keys.sort();
+                for key in &keys {
+                    self.string_table.add(key)?;
+                    if let Some(val) = obj.get(*key) {
+                        self.scan_strings(val)?;
+                    }
+                }
             }
-            entries.push(s);
+            _ => {}
         }
+        Ok(())
     }
 
-    entries
-}
+    /// Id of a string or key being emitted. The dictionary is written before
+    /// the tree, so the scan pass must already have added it; in debug builds
+    /// a string first seen here panics instead of becoming a ref past the end
+    /// of the dictionary.
+    fn scanned_id(&mut self, s: &str) -> Result<u32, EncodeError> {
+        let scanned = self.string_table.len();
+        let id = self.string_table.add(s)?;
+        debug_assert!(
+            (id as usize) < scanned,
+            "encode_value emitted {:?}, which scan_strings missed (id {} of {} scanned)",
+            s, id, scanned
+        );
+        Ok(id)
+    }
 
-/// Parse a single TENS-Text value string.
-fn parse_tens_text_value(raw: &str, dict: &[String]) -> Value {
-    match raw {
-        "_" => Value::Null,
-        "true" => Value::Bool(true),
-        "false" => Value::Bool(false),
-        s if s.starts_with('@') => {
-            if let Ok(idx) = s[1..].parse::<usize>() {
-                if idx < dict.len() {
-                    return Value::String(dict[idx].clone());
+    /// Encode a single value into the output buffer.
+    fn encode_value<O: ByteSink>(&mut self, value: &Value, out: &mut O) -> Result<(), EncodeError> {
+        if let Some((opcode, payload)) = self.emit_extension(value) {
+            out.push(opcode);
+            out.extend_from_slice(&payload);
+            return Ok(());
+        }
+        if let Some(data) = value_bytes(value).filter(|_| self.options.raw_bytes) {
+            out.push(OP_BYTES);
+            out.extend_from_slice(&encode_len(data.len())?);
+            out.extend_from_slice(&data);
+            return Ok(());
+        }
+        // Settle `1.0` and `1e0` as the integer before the int and float branches
+        // can disagree (e.g. `1.0` as DECIMAL text, `1` as INT8)
+        if let Some(int) = value.as_number().and_then(integral_form) {
+            return self.encode_value(&Value::Number(int), out);
+        }
+        match value {
+            Value::Null => {
+                out.push(OP_NULL);
+            }
+            Value::Bool(b) => {
+                out.push(if *b { OP_TRUE } else { OP_FALSE });
+            }
+            Value::Number(n) if self.is_decimal(n) => {
+                let text = n.to_string();
+                out.push(OP_DECIMAL);
+                out.extend_from_slice(&encode_len(text.len())?);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Value::Number(n) if self.options.number_policy == NumberPolicy::AlwaysFloat64 => {
+                out.push(OP_FLOAT64);
+                out.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_le_bytes());
+            }
+            Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    if self.options.small_ints && SMALL_INTS.contains(&i) {
+                        out.push((i + SMALL_INT_BIAS) as u8);
+                    } else if self.options.number_policy == NumberPolicy::Varint && !(-16..=15).contains(&i) {
+                        out.push(OP_VARINT);
+                        out.extend_from_slice(&encode_varint_i64(i));
+                    } else if (-128..=127).contains(&i) {
+                        out.push(OP_INT8);
+                        out.push(i as i8 as u8);
+                    } else if let (NumberPolicy::SmallestInteger, Ok(i)) = (self.options.number_policy, i16::try_from(i)) {
+                        out.push(OP_INT16);
+                        out.extend_from_slice(&i.to_le_bytes());
+                    } else if i >= i32::MIN as i64 && i <= i32::MAX as i64 {
+                        out.push(OP_INT32);
+                        out.extend_from_slice(&(i as i32).to_le_bytes());
+                    } else {
+                        // Exact, unlike the TS encoder's FLOAT64 (see `PreferInteger`)
+                        out.push(OP_INT64);
+                        out.extend_from_slice(&i.to_le_bytes());
+                    }
+                } else if let Some(f) = n.as_f64() {
+                    // Integral floats were settled as integers above
+                    if self.options.float32 && (f as f32) as f64 == f {
+                        out.push(OP_FLOAT32);
+                        out.extend_from_slice(&(f as f32).to_le_bytes());
+                    } else {
+                        out.push(OP_FLOAT64);
+                        out.extend_from_slice(&f.to_le_bytes());
+                    }
+                } else {
+                    return self.encode_value(&self.out_of_range_number(n), out);
                 }
             }
-            Value::String(s.to_string())
+            Value::String(s) => {
+                if let Some(millis) = self.timestamp_millis(s) {
+                    out.push(OP_TIMESTAMP);
+                    out.extend_from_slice(&millis.to_le_bytes());
+                    return Ok(());
+                }
+                let id = self.scanned_id(s)?;
+                if self.options.repeat_string_refs && self.last_string_ref == Some(id) {
+                    out.push(OP_SAME_AS_PREVIOUS);
+                } else {
+                    out.push(OP_STRING_REF);
+                    out.extend_from_slice(&encode_varint(id));
+                }
+                self.last_string_ref = Some(id);
+            }
+            Value::Array(arr) => {
+                if let Some(ints) = self.int_array_elements(arr) {
+                    encode_int_array(&ints, self.options.int_arrays, out)?;
+                    return Ok(());
+                }
+                if let Some(fields) = self.columnar_fields(arr) {
+                    return self.encode_columnar_array(arr, &fields, out);
+                }
+                if let Some(fields) = self.sparse_fields(arr) {
+                    out.push(OP_ARRAY_START);
+                    out.extend_from_slice(&encode_len(arr.len())?);
+                    for record in arr.iter().filter_map(Value::as_object) {
+                        self.encode_sparse_record(&fields, record, out)?;
+                    }
+                    return Ok(());
+                }
+
+                out.push(OP_ARRAY_START);
+                out.extend_from_slice(&encode_len(arr.len())?);
+                let mut i = 0;
+                while i < arr.len() {
+                    let run = arr[i..].iter().take_while(|v| v.is_null()).count();
+                    if run > 0 {
+                        self.encode_nulls(run, out)?;
+                        i += run;
+                    } else {
+                        self.encode_value(&arr[i], out)?;
+                        i += 1;
+                    }
+                }
+            }
+            Value::Object(obj) => {
+                // Only the root object hashes its fields
+                let mut field_hashes = self.field_hashes.take();
+                if let Some(entries) = self.int_key_entries(obj) {
+                    out.push(OP_INT_KEY_OBJECT);
+                    out.extend_from_slice(&encode_len(entries.len())?);
+                    for (key, val) in entries {
+                        out.extend_from_slice(&encode_varint(key));
+                        self.encode_field(&key.to_string(), val, field_hashes.as_mut(), out)?;
+                    }
+                    self.field_hashes = field_hashes;
+                    return Ok(());
+                }
+
+                // Keys sorted (already canonical)
+                let mut keys: Vec<&String> = obj.keys().collect();
+                keys.sort();
+
+                out.push(OP_OBJECT_START);
+                out.extend_from_slice(&encode_len(keys.len())?);
+                for key in &keys {
+                    let key_id = self.scanned_id(key)?;
+                    out.extend_from_slice(&encode_varint(key_id));
+                    if let Some(val) = obj.get(*key) {
+                        self.encode_field(key, val, field_hashes.as_mut(), out)?;
+                    }
+                }
+                self.field_hashes = field_hashes;
+            }
         }
-        s if s.starts_with('"') && s.ends_with('"') => {
-            // Unquote
-            let inner = &s[1..s.len() - 1];
-            let mut result = String::new();
-            let mut chars = inner.chars();
-            loop {
-                match chars.next() {
-                    Some('\\') => match chars.next() {
-                        Some('n') => result.push('\n'),
-                        Some('r') => result.push('\r'),
-                        Some('t') => result.push('\t'),
-                        Some(c) => result.push(c),
-                        None => break,
-                    },
-                    Some(c) => result.push(c),
-                    None => break,
+        Ok(())
+    }
+
+    /// Whether `arr` is long enough for a specialized array opcode; see
+    /// [`EncoderOptions::array_specialization_threshold`].
+    fn specializes(&self, arr: &[Value]) -> bool {
+        arr.len() >= self.options.array_specialization_threshold
+    }
+
+    /// With `sparse_records` enabled, the sorted union of the keys of a
+    /// non-empty array whose elements are all objects. `None` otherwise.
+    fn sparse_fields(&self, arr: &[Value]) -> Option<Vec<String>> {
+        if !self.options.sparse_records || arr.is_empty() || !self.specializes(arr)
+            || arr.iter().any(|item| self.is_raw_bytes(item))
+        {
+            return None;
+        }
+        let mut fields = BTreeSet::new();
+        for item in arr {
+            fields.extend(item.as_object()?.keys());
+        }
+        Some(fields.into_iter().cloned().collect())
+    }
+
+    /// With `timestamps` enabled, the milliseconds `OP_TIMESTAMP` stores for
+    /// string value `s`, if it is a timestamp.
+    fn timestamp_millis(&self, s: &str) -> Option<i64> {
+        self.options.timestamps.then(|| parse_timestamp(s)).flatten()
+    }
+
+    /// Whether `value` is a [`BYTES_TAG`] value `raw_bytes` stores as `OP_BYTES`.
+    fn is_raw_bytes(&self, value: &Value) -> bool {
+        self.options.raw_bytes && bytes_tag(value).is_some()
+    }
+
+    /// With `columnar_arrays` enabled, the sorted keys shared by every element
+    /// of a non-empty array of non-empty objects. `None` otherwise.
+    fn columnar_fields(&self, arr: &[Value]) -> Option<Vec<String>> {
+        if !self.options.columnar_arrays || !self.specializes(arr) || arr.iter().any(|item| self.is_raw_bytes(item)) {
+            return None;
+        }
+        let first = arr.first()?.as_object().filter(|obj| !obj.is_empty())?;
+        let same_keys = arr[1..].iter().all(|item| {
+            item.as_object().is_some_and(|obj| obj.len() == first.len() && first.keys().all(|k| obj.contains_key(k)))
+        });
+        if !same_keys {
+            return None;
+        }
+        let mut fields: Vec<String> = first.keys().cloned().collect();
+        fields.sort();
+        Some(fields)
+    }
+
+    /// Emit a homogeneous array of objects as `OP_COLUMNAR_ARRAY`, defining
+    /// its schema if this document has not used it yet.
+    fn encode_columnar_array<O: ByteSink>(&mut self, arr: &[Value], fields: &[String], out: &mut O) -> Result<(), EncodeError> {
+        out.push(OP_COLUMNAR_ARRAY);
+        out.extend_from_slice(&encode_len(arr.len())?);
+        let (schema, is_new) = self.emitted_columns.get_or_register(fields, &column_types(arr, fields));
+        out.extend_from_slice(&encode_varint(schema));
+        if is_new {
+            out.extend_from_slice(&encode_len(fields.len())?);
+            for field in fields {
+                let key_id = self.scanned_id(field)?;
+                out.extend_from_slice(&encode_varint(key_id));
+            }
+        }
+        for field in fields {
+            for record in arr {
+                self.encode_value(&record[field.as_str()], out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit one record as `OP_SCHEMA_OBJECT_SPARSE` against `fields`, defining
+    /// the schema if this document has not used it yet.
+    fn encode_sparse_record<O: ByteSink>(
+        &mut self,
+        fields: &[String],
+        record: &Map<String, Value>,
+        out: &mut O,
+    ) -> Result<(), EncodeError> {
+        out.push(OP_SCHEMA_OBJECT_SPARSE);
+        match self.emitted_schemas.iter().position(|s| s == fields) {
+            Some(schema) => {
+                out.extend_from_slice(&encode_len(schema)?);
+            }
+            None => {
+                out.extend_from_slice(&encode_len(self.emitted_schemas.len())?);
+                out.extend_from_slice(&encode_len(fields.len())?);
+                for field in fields {
+                    let key_id = self.scanned_id(field)?;
+                    out.extend_from_slice(&encode_varint(key_id));
                 }
+                self.emitted_schemas.push(fields.to_vec());
+            }
+        }
+
+        let mut bitmap = vec![0u8; sparse_bitmap_len(fields.len())];
+        for (i, field) in fields.iter().enumerate() {
+            if record.contains_key(field) {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        out.extend_from_slice(&bitmap);
+        for val in fields.iter().filter_map(|field| record.get(field)) {
+            self.encode_value(val, out)?;
+        }
+        Ok(())
+    }
+
+    /// Emit `run` consecutive array nulls: one `OP_NULL_RUN` when the run meets
+    /// `null_run_threshold`, otherwise one `OP_NULL` each.
+    fn encode_nulls<O: ByteSink>(&self, run: usize, out: &mut O) -> Result<(), EncodeError> {
+        if run > 0 && self.options.null_run_threshold.is_some_and(|t| run >= t) {
+            out.push(OP_NULL_RUN);
+            out.extend_from_slice(&encode_len(run)?);
+        } else {
+            for _ in 0..run {
+                out.push(OP_NULL);
+            }
+        }
+        Ok(())
+    }
+
+    /// With `int_keys` enabled, the entries of a non-empty object whose keys are
+    /// all canonical u32 decimals, sorted numerically. `None` otherwise.
+    fn int_key_entries<'a>(&self, obj: &'a Map<String, Value>) -> Option<Vec<(u32, &'a Value)>> {
+        if !self.options.int_keys || obj.is_empty() {
+            return None;
+        }
+        let mut entries = obj.iter()
+            .map(|(key, val)| match key.parse::<u32>() {
+                Ok(n) if n.to_string() == *key => Some((n, val)),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        entries.sort_by_key(|(n, _)| *n);
+        Some(entries)
+    }
+
+    /// With `int_arrays` enabled, the elements of a non-empty array that holds
+    /// only integers in i32 range. `None` otherwise.
+    fn int_array_elements(&self, arr: &[Value]) -> Option<Vec<i32>> {
+        if self.options.int_arrays == IntArrayEncoding::Generic
+            || self.options.number_policy == NumberPolicy::AlwaysFloat64
+            || arr.is_empty()
+            || !self.specializes(arr)
+        {
+            return None;
+        }
+        arr.iter()
+            .map(|v| v.as_i64().and_then(|i| i32::try_from(i).ok()))
+            .collect()
+    }
+
+    /// The unit of the dictionary's entry length prefixes, which is always
+    /// UTF-8 bytes regardless of options.
+    pub fn string_length_policy(&self) -> StringLengthPolicy {
+        StringLengthPolicy::Utf8Bytes
+    }
+
+    /// Occupancy of the string table of the last encoded document.
+    pub fn string_table_stats(&self) -> StringTableStats {
+        StringTableStats {
+            entries: self.string_table.len(),
+            total_bytes: self.string_table.total_bytes(),
+            capacity: self.string_table.capacity(),
+        }
+    }
+
+    /// Get the string table entries (for inspection/testing).
+    pub fn string_table_entries(&self) -> &[String] {
+        self.string_table.entries()
+    }
+}
+
+/// Rebuild `value` with `f` applied to every object field; see
+/// [`TensEncoder::with_transform`].
+fn transform_fields(value: &Value, f: &FieldTransform) -> Value {
+    match value {
+        Value::Array(arr) => Value::Array(arr.iter().map(|v| transform_fields(v, f)).collect()),
+        Value::Object(obj) => Value::Object(
+            obj.iter()
+                .filter_map(|(k, v)| Some((k.clone(), transform_fields(&f(k, v)?, f))))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// Type labels of a columnar array's fields, from its first record, for the
+/// schema registry.
+fn column_types(arr: &[Value], fields: &[String]) -> Vec<String> {
+    fields.iter().map(|field| infer_type(&arr[0][field.as_str()]).to_string()).collect()
+}
+
+/// Bytes in an `OP_SCHEMA_OBJECT_SPARSE` presence bitmap for `fields` fields.
+fn sparse_bitmap_len(fields: usize) -> usize {
+    (fields + 7) >> 3
+}
+
+/// Narrowest little-endian width (1, 2 or 4 bytes) that holds every element.
+fn int_array_width(ints: &[i32]) -> u8 {
+    if ints.iter().all(|&i| i8::try_from(i).is_ok()) {
+        1
+    } else if ints.iter().all(|&i| i16::try_from(i).is_ok()) {
+        2
+    } else {
+        4
+    }
+}
+
+/// Emit `ints` as `OP_INT_ARRAY` or `OP_PACKED_INT_ARRAY` according to `encoding`.
+fn encode_int_array<O: ByteSink>(ints: &[i32], encoding: IntArrayEncoding, out: &mut O) -> Result<(), EncodeError> {
+    let width = int_array_width(ints);
+    let packed: Vec<u8> = ints.iter().flat_map(|&i| encode_varint(zigzag_encode(i))).collect();
+    let use_packed = match encoding {
+        IntArrayEncoding::Packed => true,
+        IntArrayEncoding::Auto => packed.len() < 1 + ints.len() * width as usize,
+        IntArrayEncoding::Fixed | IntArrayEncoding::Generic => false,
+    };
+
+    if use_packed {
+        out.push(OP_PACKED_INT_ARRAY);
+        out.extend_from_slice(&encode_len(ints.len())?);
+        out.extend_from_slice(&packed);
+    } else {
+        out.push(OP_INT_ARRAY);
+        out.extend_from_slice(&encode_len(ints.len())?);
+        out.push(width);
+        for &i in ints {
+            match width {
+                1 => out.push(i as i8 as u8),
+                2 => out.extend_from_slice(&(i as i16).to_le_bytes()),
+                _ => out.extend_from_slice(&i.to_le_bytes()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Destination of value tree bytes: a `Vec` for the in-memory encoders, or a
+/// writer for [`TensEncoder::encode_to_writer`].
+trait ByteSink {
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+
+    fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+}
+
+impl ByteSink for Vec<u8> {
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Vec::extend_from_slice(self, bytes);
+    }
+
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte);
+    }
+}
+
+/// Bytes a [`WriterSink`] gathers before passing them on.
+#[cfg(feature = "std")]
+const WRITER_SINK_CHUNK: usize = 8 * 1024;
+
+/// Passes bytes to a writer in chunks of about [`WRITER_SINK_CHUNK`]. The
+/// encoder cannot fail mid-value on I/O, so the first write error is kept and
+/// reported by `finish`; later bytes are dropped.
+#[cfg(feature = "std")]
+struct WriterSink<'a, W: std::io::Write> {
+    writer: &'a mut W,
+    chunk: Vec<u8>,
+    error: Option<std::io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, W: std::io::Write> WriterSink<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        WriterSink { writer, chunk: Vec::with_capacity(WRITER_SINK_CHUNK), error: None }
+    }
+
+    fn write_chunk(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_all(&self.chunk) {
+                self.error = Some(e);
+            }
+        }
+        self.chunk.clear();
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.write_chunk();
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> ByteSink for WriterSink<'_, W> {
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.chunk.extend_from_slice(bytes);
+        if self.chunk.len() >= WRITER_SINK_CHUNK {
+            self.write_chunk();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl TensEncoder {
+    /// Encode `value` straight into `writer`: the same bytes as
+    /// [`Self::encode`], without collecting the document in a `Vec`. The
+    /// dictionary still has to be complete before anything is written, so the
+    /// scan pass holds it in memory; the value tree then goes out in chunks as
+    /// it is encoded.
+    ///
+    /// Encode errors surface as `io::ErrorKind::InvalidInput`. A dictionary
+    /// overflow is caught by the scan, before the first write.
+    pub fn encode_to_writer<W: std::io::Write>(&mut self, value: &Value, writer: &mut W) -> std::io::Result<()> {
+        let invalid = |e: EncodeError| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let canonical = self.canonicalize_input(value);
+        if let Some(out) = self.emit_scalar(&canonical) {
+            return writer.write_all(&out);
+        }
+
+        self.scan_roots(std::slice::from_ref(&canonical)).map_err(invalid)?;
+        writer.write_all(&self.emit_header_and_dictionary().map_err(invalid)?)?;
+
+        self.last_string_ref = None;
+        self.emitted_schemas.clear();
+        self.emitted_columns = SchemaRegistry::new();
+        let mut sink = WriterSink::new(writer);
+        self.encode_value(&canonical, &mut sink).map_err(invalid)?;
+        sink.finish()
+    }
+}
+
+impl Default for TensEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ── TENS v2 Binary Decoder ──
+
+/// Errors produced while decoding TENS v2 binary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// Structurally invalid input (bad header, truncation, unknown opcode, ...).
+    Malformed(String),
+    /// A dictionary entry that no string or key ref points at (strict validation only).
+    UnusedDictionaryEntry(u32),
+    /// A string or key ref `id` at or past the end of a `len`-entry dictionary.
+    StringRefOutOfBounds { id: u32, len: usize },
+    /// The document's SHA-256 is not the `expected` hex digest.
+    HashMismatch { expected: String, actual: String },
+    /// The document goes past one of the decoder's [`DecodeLimits`]; `limit`
+    /// names the field.
+    LimitExceeded { limit: &'static str, max: usize },
+    /// A dictionary count, shared-prefix or length varint starting at byte
+    /// `at` is cut off by the end of input or does not fit a u32. For a
+    /// compressed dictionary, `at` is an offset into the inflated block.
+    MalformedVarint { at: usize },
+    /// A strict-canonical decoder ([`TensDecoder::new_strict_canonical`]) read
+    /// a document the default encoder would not have written for its value.
+    NonCanonical { reason: String },
+    /// [`binary_to_text`] decoded a root TENS-Text cannot hold; `reason`
+    /// says what it is.
+    UnsupportedRoot { reason: String },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Malformed(msg) => f.write_str(msg),
+            DecodeError::UnusedDictionaryEntry(id) => {
+                write!(f, "Dictionary entry {} is never referenced", id)
+            }
+            DecodeError::StringRefOutOfBounds { id, len } => {
+                write!(f, "String ref {} out of bounds (dict size {})", id, len)
+            }
+            DecodeError::HashMismatch { expected, actual } => {
+                write!(f, "Hash mismatch: expected {}, got {}", expected, actual)
             }
-            // Check for special number strings
-            match result.as_str() {
-                "NaN" | "Infinity" | "-Infinity" => Value::String(result),
-                _ => Value::String(result),
+            DecodeError::LimitExceeded { limit, max } => {
+                write!(f, "Document exceeds decode limit {} = {}", limit, max)
             }
+            DecodeError::MalformedVarint { at } => write!(f, "Malformed varint at byte {}", at),
+            DecodeError::NonCanonical { reason } => write!(f, "Non-canonical TENS document: {}", reason),
+            DecodeError::UnsupportedRoot { reason } => write!(f, "Root cannot be written as TENS-Text: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<String> for DecodeError {
+    fn from(msg: String) -> Self {
+        DecodeError::Malformed(msg)
+    }
+}
+
+impl From<&str> for DecodeError {
+    fn from(msg: &str) -> Self {
+        DecodeError::Malformed(msg.to_string())
+    }
+}
+
+/// Resource bounds checked while decoding, so hostile input fails early
+/// instead of exhausting the stack or memory. Set with
+/// [`TensDecoder::with_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Deepest nesting of arrays and objects (`[[1]]` is 2).
+    pub max_depth: usize,
+    /// Most entries in the dictionary or in any one array or object.
+    pub max_elements: usize,
+    /// Total UTF-8 bytes of the dictionary entries. A compressed dictionary
+    /// is also refused once it inflates past this many bytes, length
+    /// prefixes included.
+    pub max_dictionary_bytes: usize,
+    /// Size of the encoded document: the input slice, or the bytes pulled
+    /// from a reader.
+    pub max_total_size: usize,
+}
+
+impl DecodeLimits {
+    /// Tight bounds for small documents from untrusted sources.
+    pub const fn strict() -> Self {
+        DecodeLimits { max_depth: 64, max_elements: 65_536, max_dictionary_bytes: 1 << 20, max_total_size: 16 << 20 }
+    }
+
+    /// No bounds at all, for input from a trusted encoder.
+    pub const fn unlimited() -> Self {
+        DecodeLimits {
+            max_depth: usize::MAX,
+            max_elements: usize::MAX,
+            max_dictionary_bytes: usize::MAX,
+            max_total_size: usize::MAX,
+        }
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits { max_depth: 512, max_elements: 1 << 24, max_dictionary_bytes: 64 << 20, max_total_size: 1 << 30 }
+    }
+}
+
+/// What the length prefix of a dictionary string counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DictLengthMode {
+    /// UTF-8 bytes, as every TENS v2 encoder writes.
+    #[default]
+    Utf8Bytes,
+    /// UTF-16 code units (JS `string.length`), as written by legacy encoders
+    /// that never converted to bytes. A character outside the BMP counts 2.
+    Utf16Units,
+}
+
+/// Byte length of the first `units` UTF-16 code units of the UTF-8 text at
+/// the start of `bytes`.
+fn utf16_units_to_bytes(bytes: &[u8], units: u32) -> Result<usize, DecodeError> {
+    let (mut pos, mut counted) = (0, 0);
+    while counted < units {
+        let lead = *bytes.get(pos).ok_or("Dictionary string extends past end of input")?;
+        let (width, char_units) = utf8_char_width(lead)?;
+        if counted + char_units > units {
+            return Err("Dictionary string length splits a surrogate pair".into());
+        }
+        pos += width;
+        counted += char_units;
+    }
+    if pos > bytes.len() {
+        return Err("Dictionary string extends past end of input".into());
+    }
+    Ok(pos)
+}
+
+/// UTF-8 byte width and UTF-16 unit count of the character led by `lead`.
+fn utf8_char_width(lead: u8) -> Result<(usize, u32), DecodeError> {
+    match lead {
+        0x00..=0x7F => Ok((1, 1)),
+        0xC2..=0xDF => Ok((2, 1)),
+        0xE0..=0xEF => Ok((3, 1)),
+        0xF0..=0xF4 => Ok((4, 2)),
+        _ => Err(format!("Invalid UTF-8 in dictionary: lead byte 0x{:02x}", lead).into()),
+    }
+}
+
+pub struct TensDecoder {
+    dictionary: Vec<String>,
+    /// UTF-8 bytes in `dictionary`, checked against `limits.max_dictionary_bytes`.
+    dictionary_bytes: usize,
+    /// When set, `referenced[id]` records whether dictionary entry `id` was used.
+    track_references: bool,
+    referenced: Vec<bool>,
+    /// Id the next `OP_SAME_AS_PREVIOUS` repeats.
+    last_string_ref: Option<u32>,
+    /// Decode unknown `OP_EXT` values to a placeholder instead of failing.
+    lenient: bool,
+    /// Field lists of the sparse record schemas defined so far, by number.
+    sparse_schemas: Vec<Vec<String>>,
+    /// Field lists of the columnar array schemas defined so far (schema n at n - 1).
+    columnar_schemas: Vec<Vec<String>>,
+    /// What non-finite FLOAT64 payloads decode to.
+    non_finite_floats: NonFiniteFloats,
+    /// Skip UTF-8 and string ref checks; only set by `decode_trusted`.
+    trusted: bool,
+    /// User opcode handlers.
+    extensions: HashMap<u8, DecodeExtension>,
+    /// Magic and version a document must start with.
+    format: TensFormat,
+    limits: DecodeLimits,
+    /// Arrays and objects open around the value being decoded.
+    depth: usize,
+    dict_length_mode: DictLengthMode,
+    /// Feature byte of the last document read; 0 for a v2 header.
+    features: u8,
+    /// Reject documents that are not in canonical form; see `new_strict_canonical`.
+    strict_canonical: bool,
+    /// Lowest string ref not referenced yet, for the strict-canonical
+    /// first-use order check.
+    next_new_ref: u32,
+}
+
+impl TensDecoder {
+    pub fn new() -> Self {
+        TensDecoder {
+            dictionary: Vec::new(),
+            dictionary_bytes: 0,
+            track_references: false,
+            referenced: Vec::new(),
+            last_string_ref: None,
+            lenient: false,
+            sparse_schemas: Vec::new(),
+            columnar_schemas: Vec::new(),
+            non_finite_floats: NonFiniteFloats::Null,
+            trusted: false,
+            extensions: HashMap::new(),
+            format: TensFormat::UPSTREAM,
+            limits: DecodeLimits::default(),
+            depth: 0,
+            dict_length_mode: DictLengthMode::Utf8Bytes,
+            features: 0,
+            strict_canonical: false,
+            next_new_ref: 0,
+        }
+    }
+
+    /// A decoder that decodes each `OP_EXT` value of an unknown type to
+    /// `{"__unknown_opcode__": <ext_type>}` and carries on, so documents from
+    /// newer encoders partially decode. Bare unknown opcodes still fail: without
+    /// the extension's length there is no way to find the next value.
+    pub fn new_lenient() -> Self {
+        TensDecoder { lenient: true, ..Self::new() }
+    }
+
+    /// A decoder for content-addressed stores, whose [`Self::decode`] and
+    /// [`Self::decode_reader`] fail with `DecodeError::NonCanonical` unless
+    /// the document is exactly what
+    /// the default encoder (with this decoder's format) writes for its value,
+    /// so equal values can never be stored under two hashes. Beyond a plain
+    /// decode it rejects:
+    ///
+    /// - layout flags or a feature byte in the header
+    /// - opcodes the default encoder does not use (`OP_NULL_RUN`,
+    ///   `OP_INT_ARRAY`, `OP_EXT`, user opcodes, ...)
+    /// - `OP_INT32` for a value `OP_INT8` holds, `OP_INT64` for one `OP_INT32`
+    ///   holds, and `OP_FLOAT64` for an integer up to 2^53 or for NaN/±Infinity
+    /// - object keys not in strictly ascending order
+    /// - strings and keys that canonicalization would change
+    /// - dictionary entries that are duplicated, unused, or not in order of
+    ///   first use
+    /// - trailing bytes after the value tree
+    ///
+    /// Anything else that differs (such as an overlong varint) is caught by
+    /// re-encoding the value and comparing bytes, so `decode_reader` reads the
+    /// whole document into memory first. The other decode methods only apply
+    /// the checks that fall inside the value tree.
+    pub fn new_strict_canonical() -> Self {
+        TensDecoder { strict_canonical: true, ..Self::new() }
+    }
+
+    /// A decoder that applies `policy` to non-finite FLOAT64 payloads, to
+    /// match a [`decode_tens_text_with`] using the same policy.
+    pub fn with_non_finite_floats(policy: NonFiniteFloats) -> Self {
+        TensDecoder { non_finite_floats: policy, ..Self::new() }
+    }
+
+    /// Decode user opcode `opcode` with `decode`, the counterpart of
+    /// [`TensEncoder::with_extension`]. [`Self::decode_reader`] cannot hand
+    /// the handlers a slice, and fails on these opcodes.
+    ///
+    /// # Panics
+    /// If `opcode` is outside [`USER_OPCODES`].
+    pub fn with_extension(mut self, opcode: u8, decode: DecodeExtension) -> Self {
+        assert!(USER_OPCODES.contains(&opcode), "opcode 0x{:02x} is not a user opcode", opcode);
+        self.extensions.insert(opcode, decode);
+        self
+    }
+
+    /// Dictionary of the last document read, in id order (entry `i` is string
+    /// ref `i`). Empty before the first decode.
+    pub fn dictionary(&self) -> &[String] {
+        &self.dictionary
+    }
+
+    /// `FEATURE_*` bits of the last document read: its feature byte under a
+    /// v2.1 header (see [`EncoderOptions::feature_header`]), 0 under a v2 one.
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+
+    /// Accept documents of `format` (see [`EncoderOptions::format`]) instead
+    /// of upstream TENS.
+    pub fn with_format(mut self, format: TensFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Enforce `limits` instead of [`DecodeLimits::default`].
+    pub fn with_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Read dictionary string lengths as `mode` counts, for documents from
+    /// legacy encoders. For front-coded dictionaries the shared prefix is
+    /// still a byte count; only the suffix length is affected.
+    pub fn with_dict_length_mode(mut self, mode: DictLengthMode) -> Self {
+        self.dict_length_mode = mode;
+        self
+    }
+
+    /// Record a document's feature byte, failing on features this decoder
+    /// cannot honor.
+    fn accept_features(&mut self, features: u8) -> Result<(), DecodeError> {
+        if features & !KNOWN_FEATURES != 0 {
+            return Err(format!("Unsupported TENS features: 0x{:02x}", features & !KNOWN_FEATURES).into());
+        }
+        if features & FEATURE_USER_OPCODES != 0 && self.extensions.is_empty() {
+            return Err("Document uses user opcodes, but no extension handlers are registered".into());
+        }
+        #[cfg(not(feature = "decimal"))]
+        if features & FEATURE_DECIMAL_NUMBERS != 0 {
+            return Err("Document uses decimal numbers, which require the `decimal` feature".into());
+        }
+        self.features = features;
+        Ok(())
+    }
+
+    /// Fail if `count` entries would go past `limits.max_elements`.
+    fn check_elements(&self, count: u32) -> Result<(), DecodeError> {
+        if count as usize > self.limits.max_elements {
+            return Err(DecodeError::LimitExceeded { limit: "max_elements", max: self.limits.max_elements });
+        }
+        Ok(())
+    }
+
+    /// Fail if opening `levels` more arrays or objects would go past `limits.max_depth`.
+    fn check_depth(&self, levels: usize) -> Result<(), DecodeError> {
+        if self.depth.saturating_add(levels) > self.limits.max_depth {
+            return Err(DecodeError::LimitExceeded { limit: "max_depth", max: self.limits.max_depth });
+        }
+        Ok(())
+    }
+
+    /// Open `levels` arrays or objects; the caller closes them by subtracting
+    /// from `depth` once their contents are decoded. A failed decode leaves
+    /// `depth` unbalanced until the next document resets it.
+    fn enter(&mut self, levels: usize) -> Result<(), DecodeError> {
+        self.check_depth(levels)?;
+        self.depth += levels;
+        Ok(())
+    }
+
+    /// Decode TENS v2 binary bytes back into a JSON Value.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<Value, DecodeError> {
+        let pos = self.read_preamble(bytes)?;
+        if bytes[4] & FLAG_MULTI_ROOT != 0 {
+            return Err("Multi-root TENS document: use decode_multi".into());
+        }
+        if self.strict_canonical && bytes[4] != self.format.version {
+            return Err(non_canonical(format!("header flags 0x{:02x}", bytes[4] & VERSION_FLAGS)));
+        }
+        let (value, consumed) = self.decode_value(&bytes[pos..])?;
+        if self.strict_canonical {
+            self.check_canonical_document(bytes, pos + consumed, &value)?;
+        }
+        Ok(value)
+    }
+
+    /// The whole-document strict-canonical checks, once the value tree
+    /// ending at `end` has decoded to `value`.
+    fn check_canonical_document(&self, bytes: &[u8], end: usize, value: &Value) -> Result<(), DecodeError> {
+        if end < bytes.len() {
+            return Err(non_canonical(format!("{} trailing bytes after the value tree", bytes.len() - end)));
+        }
+        if let Some(entry) = self.dictionary.get(self.next_new_ref as usize) {
+            return Err(non_canonical(format!("dictionary entry {} ({:?}) is never referenced", self.next_new_ref, entry)));
+        }
+        let mut seen = BTreeSet::new();
+        if let Some(entry) = self.dictionary.iter().find(|entry| !seen.insert(entry.as_str())) {
+            return Err(non_canonical(format!("dictionary entry {:?} is duplicated", entry)));
+        }
+        let options = EncoderOptions { format: self.format, ..EncoderOptions::default() };
+        let reencoded = TensEncoder::with_options(options).try_encode(value)
+            .map_err(|e| non_canonical(format!("the value does not re-encode: {}", e)))?;
+        match first_mismatch(bytes, &reencoded) {
+            Some((offset, description)) => {
+                Err(non_canonical(format!("re-encoding differs at byte {} ({})", offset, description)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Decode `bytes` only if their [`hash_tens_binary`] is `expected_hash`
+    /// (hex, either case). The hash is checked before decoding, so a mismatched
+    /// document is never decoded.
+    pub fn decode_and_verify(&mut self, bytes: &[u8], expected_hash: &str) -> Result<Value, DecodeError> {
+        let actual = hash_tens_binary(bytes);
+        if !actual.eq_ignore_ascii_case(expected_hash) {
+            return Err(DecodeError::HashMismatch { expected: expected_hash.to_string(), actual });
+        }
+        self.decode(bytes)
+    }
+
+    /// Decode a value tree from [`TensEncoder::encode_split`] or
+    /// [`TensEncoder::encode_split_many`] against its dictionary section.
+    pub fn decode_split(&mut self, dictionary: &[u8], tree: &[u8]) -> Result<Value, DecodeError> {
+        let pos = self.read_preamble(dictionary)?;
+        if dictionary[4] & FLAG_MULTI_ROOT != 0 {
+            return Err("Multi-root TENS document: use decode_multi".into());
+        }
+        if pos != dictionary.len() {
+            return Err(format!("{} trailing bytes after the dictionary section", dictionary.len() - pos).into());
+        }
+        if dictionary.len().saturating_add(tree.len()) > self.limits.max_total_size {
+            return Err(DecodeError::LimitExceeded { limit: "max_total_size", max: self.limits.max_total_size });
+        }
+        let (value, _consumed) = self.decode_value(tree)?;
+        Ok(value)
+    }
+
+    /// Decode a bare value tree, with no header, against `dictionary` (entry
+    /// `i` is string ref `i`), for trees embedded in another container that
+    /// stores the strings itself. [`TensEncoder::encode_split`]'s tree and
+    /// [`TensEncoder::string_table_entries`] are such a pair.
+    pub fn decode_tree(&mut self, tree: &[u8], dictionary: &[String]) -> Result<Value, DecodeError> {
+        if tree.len() > self.limits.max_total_size {
+            return Err(DecodeError::LimitExceeded { limit: "max_total_size", max: self.limits.max_total_size });
+        }
+        self.check_elements(u32::try_from(dictionary.len()).unwrap_or(u32::MAX))?;
+        let dictionary_bytes: usize = dictionary.iter().map(String::len).sum();
+        if dictionary_bytes > self.limits.max_dictionary_bytes {
+            return Err(DecodeError::LimitExceeded {
+                limit: "max_dictionary_bytes",
+                max: self.limits.max_dictionary_bytes,
+            });
+        }
+        self.dictionary = dictionary.to_vec();
+        self.dictionary_bytes = dictionary_bytes;
+        self.reset_document_state();
+        let (value, _consumed) = self.decode_value(tree)?;
+        Ok(value)
+    }
+
+    /// Decode the roots of a document from [`TensEncoder::encode_multi`]. A
+    /// single-root document decodes to a one-element list.
+    pub fn decode_multi(&mut self, bytes: &[u8]) -> Result<Vec<Value>, DecodeError> {
+        let mut pos = self.read_preamble(bytes)?;
+        if bytes[4] & FLAG_MULTI_ROOT == 0 {
+            let (value, _consumed) = self.decode_value(&bytes[pos..])?;
+            return Ok(vec![value]);
+        }
+        let (count, consumed) = dictionary_varint(bytes, pos, 0)?;
+        self.check_elements(count)?;
+        pos += consumed;
+        let mut roots = Vec::new();
+        for _ in 0..count {
+            let (value, consumed) = self.decode_value(&bytes[pos..])?;
+            pos += consumed;
+            roots.push(value);
+        }
+        Ok(roots)
+    }
+
+    /// Check the header and load the dictionary, resetting per-document state.
+    /// Returns the offset of the value tree.
+    fn read_preamble(&mut self, bytes: &[u8]) -> Result<usize, DecodeError> {
+        if bytes.len() < HEADER_LEN {
+            return Err("Input too short for TENS header".into());
+        }
+        if bytes.len() > self.limits.max_total_size {
+            return Err(DecodeError::LimitExceeded { limit: "max_total_size", max: self.limits.max_total_size });
+        }
+        self.format.check_header(bytes)?;
+
+        let mut pos = header_len(bytes);
+        self.features = 0;
+        if pos > HEADER_LEN {
+            let features = *bytes.get(HEADER_LEN).ok_or("Input too short for TENS header")?;
+            self.accept_features(features)?;
+        }
+
+        // Read dictionary
+        let (dict_count, consumed) = dictionary_varint(bytes, pos, 0)?;
+        pos += consumed;
+        let front_coded = bytes[4] & FLAG_FRONT_CODED_DICT != 0;
+
+        if bytes[4] & FLAG_COMPRESSED_DICT != 0 {
+            #[cfg(feature = "compression")]
+            {
+                let (block, consumed) = inflate_block(&bytes[pos..], self.limits.max_dictionary_bytes)?;
+                pos += consumed;
+                self.read_dictionary(&block, 0, dict_count, front_coded)?;
+            }
+            #[cfg(not(feature = "compression"))]
+            return Err("Compressed dictionary requires the `compression` feature".into());
+        } else {
+            pos += self.read_dictionary(&bytes[pos..], pos, dict_count, front_coded)?;
+        }
+
+        self.reset_document_state();
+        Ok(pos)
+    }
+
+    /// Clear what one document's tree leaves behind, once its dictionary is loaded.
+    fn reset_document_state(&mut self) {
+        self.referenced = if self.track_references {
+            vec![false; self.dictionary.len()]
+        } else {
+            Vec::new()
+        };
+        self.last_string_ref = None;
+        self.sparse_schemas.clear();
+        self.columnar_schemas.clear();
+        self.depth = 0;
+        self.next_new_ref = 0;
+    }
+
+    /// Parse `count` dictionary entries from `bytes`, which start at offset
+    /// `base` (for errors), returning the bytes consumed.
+    fn read_dictionary(&mut self, bytes: &[u8], base: usize, count: u32, front_coded: bool) -> Result<usize, DecodeError> {
+        let mut pos = 0;
+        self.check_elements(count)?;
+        self.dictionary = Vec::with_capacity(count as usize);
+        self.dictionary_bytes = 0;
+        for _ in 0..count {
+            let shared = if front_coded {
+                let (shared, consumed) = dictionary_varint(bytes, pos, base)?;
+                pos += consumed;
+                shared
+            } else {
+                0
+            };
+            let (str_len, consumed) = dictionary_varint(bytes, pos, base)?;
+            pos += consumed;
+            let len = match self.dict_length_mode {
+                DictLengthMode::Utf8Bytes => str_len as usize,
+                DictLengthMode::Utf16Units => utf16_units_to_bytes(&bytes[pos..], str_len)?,
+            };
+            let end = pos.checked_add(len)
+                .filter(|&end| end <= bytes.len())
+                .ok_or("Dictionary string extends past end of input")?;
+            self.push_dictionary_entry(shared, &bytes[pos..end])?;
+            pos = end;
+        }
+        Ok(pos)
+    }
+
+    /// Append the entry made of the first `shared` bytes of the previous entry
+    /// followed by `suffix` (the whole entry when `shared` is 0).
+    fn push_dictionary_entry(&mut self, shared: u32, suffix: &[u8]) -> Result<(), DecodeError> {
+        let previous = self.dictionary.last().map_or(&[][..], |p| p.as_bytes());
+        let prefix = previous.get(..shared as usize)
+            .ok_or_else(|| format!("Dictionary prefix of {} exceeds previous entry", shared))?;
+        let mut entry = Vec::with_capacity(prefix.len() + suffix.len());
+        entry.extend_from_slice(prefix);
+        entry.extend_from_slice(suffix);
+        self.dictionary_bytes += entry.len();
+        if self.dictionary_bytes > self.limits.max_dictionary_bytes {
+            return Err(DecodeError::LimitExceeded {
+                limit: "max_dictionary_bytes",
+                max: self.limits.max_dictionary_bytes,
+            });
+        }
+        let s = if self.trusted {
+            // SAFETY: `trusted` is only set by `decode_trusted`, whose caller
+            // guarantees the input came from a TENS encoder
+            unsafe { String::from_utf8_unchecked(entry) }
+        } else {
+            String::from_utf8(entry).map_err(|e| format!("Invalid UTF-8 in dictionary: {}", e))?
+        };
+        self.dictionary.push(s);
+        Ok(())
+    }
+
+    /// Mark a dictionary entry as used when reference tracking is enabled.
+    fn mark_referenced(&mut self, id: u32) {
+        if let Some(slot) = self.referenced.get_mut(id as usize) {
+            *slot = true;
+        }
+    }
+
+    /// Look up a string or key ref, marking it used. Bounds-checked with `get`,
+    /// so an empty dictionary rejects every id without indexing.
+    fn resolve_ref(&mut self, id: u32) -> Result<String, DecodeError> {
+        if self.trusted {
+            // SAFETY: as in `push_dictionary_entry`; encoder output only
+            // references ids below the dictionary count
+            return Ok(unsafe { self.dictionary.get_unchecked(id as usize) }.clone());
+        }
+        let s = self.dictionary.get(id as usize)
+            .ok_or(DecodeError::StringRefOutOfBounds { id, len: self.dictionary.len() })?
+            .clone();
+        self.mark_referenced(id);
+        if self.strict_canonical {
+            match id.cmp(&self.next_new_ref) {
+                std::cmp::Ordering::Greater => {
+                    return Err(non_canonical(format!(
+                        "string ref {} is used before ref {}: the dictionary is not in order of first use",
+                        id, self.next_new_ref
+                    )));
+                }
+                std::cmp::Ordering::Equal => self.next_new_ref += 1,
+                std::cmp::Ordering::Less => {}
+            }
+        }
+        Ok(s)
+    }
+
+    /// The value of a FLOAT64 payload.
+    fn float_value(&self, f: f64) -> Value {
+        if f.is_finite() {
+            serde_json::json!(f)
+        } else {
+            self.non_finite_floats.value(f)
+        }
+    }
+
+    /// Whether sparse schema number `schema` is defined inline here (its first
+    /// use), failing for numbers that skip ahead.
+    fn sparse_schema_is_new(&self, schema: u32) -> Result<bool, DecodeError> {
+        match (schema as usize).cmp(&self.sparse_schemas.len()) {
+            std::cmp::Ordering::Less => Ok(false),
+            std::cmp::Ordering::Equal => Ok(true),
+            std::cmp::Ordering::Greater => Err(format!("SCHEMA_OBJECT_SPARSE: undefined schema {}", schema).into()),
+        }
+    }
+
+    /// Whether columnar schema number `schema` is defined inline here (its
+    /// first use), failing for 0 and numbers that skip ahead.
+    fn columnar_schema_is_new(&self, schema: u32) -> Result<bool, DecodeError> {
+        match (schema as usize).cmp(&(self.columnar_schemas.len() + 1)) {
+            std::cmp::Ordering::Less if schema > 0 => Ok(false),
+            std::cmp::Ordering::Equal => Ok(true),
+            _ => Err(format!("COLUMNAR_ARRAY: undefined schema {}", schema).into()),
+        }
+    }
+
+    /// The fields of sparse schema `schema` flagged present in `bitmap`.
+    fn sparse_present_fields(&self, schema: u32, bitmap: &[u8]) -> Result<Vec<String>, DecodeError> {
+        let fields = &self.sparse_schemas[schema as usize];
+        let present: Vec<String> = fields.iter().enumerate()
+            .filter(|(i, _)| bitmap[i / 8] & (1 << (i % 8)) != 0)
+            .map(|(_, field)| field.clone())
+            .collect();
+        let set_bits: u32 = bitmap.iter().map(|b| b.count_ones()).sum();
+        if set_bits as usize != present.len() {
+            return Err("SCHEMA_OBJECT_SPARSE: bitmap flags fields past the schema".into());
+        }
+        Ok(present)
+    }
+
+    /// The value for an `OP_EXT` of `ext_type`: a placeholder when lenient.
+    fn unknown_extension(&self, ext_type: u32) -> Result<Value, DecodeError> {
+        if !self.lenient {
+            return Err(format!("Unknown extension type {}", ext_type).into());
+        }
+        let mut placeholder = Map::new();
+        placeholder.insert(UNKNOWN_EXTENSION_KEY.to_string(), Value::from(ext_type));
+        Ok(Value::Object(placeholder))
+    }
+
+    /// The string an `OP_SAME_AS_PREVIOUS` stands for.
+    fn previous_string(&self) -> Result<Value, DecodeError> {
+        let id = self.last_string_ref.ok_or("SAME_AS_PREVIOUS without a preceding string")?;
+        Ok(Value::String(self.dictionary[id as usize].clone()))
+    }
+
+    /// Decode the value at the start of `bytes`, returning it and the bytes it took.
+    fn decode_value(&mut self, bytes: &[u8]) -> Result<(Value, usize), DecodeError> {
+        let mut source = SliceSource { bytes, pos: 0 };
+        let value = self.read_value(&mut source)?;
+        Ok((value, source.pos))
+    }
+
+    fn read_value<S: ByteSource>(&mut self, source: &mut S) -> Result<Value, DecodeError> {
+        let opcode = source.byte()?;
+        self.read_value_from(opcode, source)
+    }
+
+    /// Read the payload of a value whose opcode has already been consumed.
+    fn read_value_from<S: ByteSource>(&mut self, opcode: u8, source: &mut S) -> Result<Value, DecodeError> {
+        if self.strict_canonical && !CANONICAL_OPCODES.contains(&opcode) {
+            return Err(non_canonical(format!(
+                "opcode 0x{:02x} ({}) is not used by the canonical encoding",
+                opcode, opcode_name(opcode)
+            )));
+        }
+
+        match opcode {
+            OP_NULL => Ok(Value::Null),
+
+            OP_TRUE => Ok(Value::Bool(true)),
+
+            OP_FALSE => Ok(Value::Bool(false)),
+
+            OP_INT8 => {
+                let byte = source.byte().map_err(|_| DecodeError::from("INT8: missing byte"))?;
+                Ok(serde_json::json!(byte as i8 as i64))
+            }
+
+            OP_INT16 => {
+                let val = i16::from_le_bytes(source.array().map_err(|_| DecodeError::from("INT16: not enough bytes"))?);
+                Ok(serde_json::json!(val as i64))
+            }
+
+            OP_INT32 => {
+                let val = i32::from_le_bytes(source.array().map_err(|_| DecodeError::from("INT32: not enough bytes"))?);
+                if self.strict_canonical && i8::try_from(val).is_ok() {
+                    return Err(non_canonical(format!("INT32 for {}, which INT8 holds", val)));
+                }
+                Ok(serde_json::json!(val as i64))
+            }
+
+            OP_INT64 => {
+                let val = i64::from_le_bytes(source.array().map_err(|_| DecodeError::from("INT64: not enough bytes"))?);
+                if self.strict_canonical && i32::try_from(val).is_ok() {
+                    return Err(non_canonical(format!("INT64 for {}, which INT32 holds", val)));
+                }
+                Ok(Value::Number(val.into()))
+            }
+
+            OP_VARINT => Ok(Value::Number(source.varint_i64()?.into())),
+
+            OP_SMALL_INT_FIRST..=OP_SMALL_INT_LAST => Ok(Value::Number((opcode as i64 - SMALL_INT_BIAS).into())),
+
+            OP_TIMESTAMP => {
+                let millis = source.array().map_err(|_| DecodeError::from("TIMESTAMP: not enough bytes"))?;
+                timestamp_value(i64::from_le_bytes(millis))
+            }
+
+            OP_FLOAT32 => {
+                let val = f32::from_le_bytes(source.array().map_err(|_| DecodeError::from("FLOAT32: not enough bytes"))?);
+                Ok(self.float_value(val as f64))
+            }
+
+            OP_FLOAT64 => {
+                let val = f64::from_le_bytes(source.array().map_err(|_| DecodeError::from("FLOAT64: not enough bytes"))?);
+                if self.strict_canonical {
+                    if !val.is_finite() {
+                        return Err(non_canonical(format!("FLOAT64 for {}, which canonicalizes to null", val)));
+                    }
+                    if val.fract() == 0.0 && val.abs() <= MAX_SAFE_INTEGER {
+                        return Err(non_canonical(format!("FLOAT64 for the integer {}", val)));
+                    }
+                }
+                Ok(self.float_value(val))
+            }
+
+            OP_STRING_REF => {
+                let id = source.varint()?;
+                let s = self.resolve_ref(id)?;
+                if self.strict_canonical && matches!(normalize_str(&s, &CanonicalOptions::default()), Cow::Owned(_)) {
+                    return Err(non_canonical(format!("string {:?} is not normalized", s)));
+                }
+                self.last_string_ref = Some(id);
+                Ok(Value::String(s))
+            }
+
+            OP_SAME_AS_PREVIOUS => self.previous_string(),
+
+            OP_ARRAY_START => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                self.enter(1)?;
+                // Every element takes at least a byte, which bounds the allocation
+                let mut arr = Vec::with_capacity(source.rest().map_or(0, <[u8]>::len).min(count as usize));
+                while arr.len() < count as usize {
+                    match source.byte()? {
+                        OP_NULL_RUN => push_null_run(&mut arr, source.varint()?, count)?,
+                        opcode => arr.push(self.read_value_from(opcode, source)?),
+                    }
+                }
+                self.depth -= 1;
+                Ok(Value::Array(arr))
+            }
+
+            OP_OBJECT_START => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                self.enter(1)?;
+                let mut map = Map::new();
+                let mut previous_key: Option<String> = None;
+                for index in 0..count {
+                    let mut field = || {
+                        let key = self.resolve_ref(source.varint()?)?;
+                        if self.strict_canonical {
+                            check_canonical_key(&key, previous_key.as_deref())?;
+                        }
+                        Ok((key, self.read_value(source)?))
+                    };
+                    let (key, val) = field().map_err(|e| object_field_error(e, index, count, &map))?;
+                    if self.strict_canonical {
+                        previous_key = Some(key.clone());
+                    }
+                    map.insert(key, val);
+                }
+                self.depth -= 1;
+                Ok(Value::Object(map))
+            }
+
+            OP_DECIMAL => {
+                let len = source.varint()?;
+                let text = source.bytes(len as usize).map_err(|_| DecodeError::from("DECIMAL: not enough bytes"))?;
+                parse_decimal(&text)
+            }
+
+            OP_BYTES => {
+                let len = source.varint()?;
+                let data = source.bytes(len as usize).map_err(|_| DecodeError::from("BYTES: not enough bytes"))?;
+                Ok(bytes_value(&data))
+            }
+
+            OP_INT_KEY_OBJECT => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                self.enter(1)?;
+                let mut map = Map::new();
+                for _ in 0..count {
+                    let key = source.varint()?;
+                    let val = self.read_value(source)?;
+                    map.insert(key.to_string(), val);
+                }
+                self.depth -= 1;
+                Ok(Value::Object(map))
+            }
+
+            OP_INT_ARRAY => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                self.check_depth(1)?;
+                let width = source.byte().map_err(|_| DecodeError::from("INT_ARRAY: missing width"))?;
+                let data = (count as usize).checked_mul(int_array_width_bytes(width)?)
+                    .ok_or_else(|| DecodeError::from("INT_ARRAY: length overflow"))
+                    .and_then(|len| source.bytes(len).map_err(|_| DecodeError::from("INT_ARRAY: not enough bytes")))?;
+                Ok(Value::Array(data
+                    .chunks_exact(width as usize)
+                    .map(|chunk| serde_json::json!(read_int_le(chunk)))
+                    .collect()))
+            }
+
+            OP_PACKED_INT_ARRAY => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                self.check_depth(1)?;
+                let mut arr = Vec::new();
+                for _ in 0..count {
+                    let zigzag = source.varint().map_err(|_| DecodeError::from("PACKED_INT_ARRAY: not enough bytes"))?;
+                    arr.push(serde_json::json!(zigzag_decode(zigzag) as i64));
+                }
+                Ok(Value::Array(arr))
+            }
+
+            OP_SCHEMA_OBJECT_SPARSE => {
+                let schema = source.varint()?;
+                if self.sparse_schema_is_new(schema)? {
+                    let count = source.varint()?;
+                    let mut fields = Vec::new();
+                    for _ in 0..count {
+                        let key_id = source.varint()?;
+                        fields.push(self.resolve_ref(key_id)?);
+                    }
+                    self.sparse_schemas.push(fields);
+                }
+                let bitmap_len = sparse_bitmap_len(self.sparse_schemas[schema as usize].len());
+                let bitmap = source.bytes(bitmap_len)
+                    .map_err(|_| DecodeError::from("SCHEMA_OBJECT_SPARSE: bitmap extends past end of input"))?;
+                let present = self.sparse_present_fields(schema, &bitmap)?;
+                self.enter(1)?;
+                let mut map = Map::new();
+                for field in present {
+                    let val = self.read_value(source)?;
+                    map.insert(field, val);
+                }
+                self.depth -= 1;
+                Ok(Value::Object(map))
+            }
+
+            OP_COLUMNAR_ARRAY => {
+                let count = source.varint()?;
+                self.check_elements(count)?;
+                let schema = source.varint()?;
+                if self.columnar_schema_is_new(schema)? {
+                    let field_count = source.varint()?;
+                    let mut fields = Vec::new();
+                    for _ in 0..field_count {
+                        let key_id = source.varint()?;
+                        fields.push(self.resolve_ref(key_id)?);
+                    }
+                    self.columnar_schemas.push(fields);
+                }
+                let fields = self.columnar_schemas[schema as usize - 1].clone();
+                if fields.is_empty() {
+                    return Err(format!("COLUMNAR_ARRAY: {} records with no fields", count).into());
+                }
+                // An array of objects: the column values sit two levels down.
+                // Rows are added as the first column is read, so a count the
+                // input cannot back is never allocated up front
+                self.enter(2)?;
+                let mut rows = Vec::new();
+                for (i, field) in fields.into_iter().enumerate() {
+                    for row in 0..count as usize {
+                        let val = self.read_value(source)?;
+                        if i == 0 {
+                            rows.push(Map::new());
+                        }
+                        rows[row].insert(field.clone(), val);
+                    }
+                }
+                self.depth -= 2;
+                Ok(Value::Array(rows.into_iter().map(Value::Object).collect()))
+            }
+
+            OP_EXT => {
+                let ext_type = source.varint()?;
+                let len = source.varint()?;
+                source.bytes(len as usize)
+                    .map_err(|_| DecodeError::from("EXT: payload extends past end of input"))?;
+                self.unknown_extension(ext_type)
+            }
+
+            OP_NULL_RUN => Err("NULL_RUN outside of an array".into()),
+
+            _ => match (self.extensions.get(&opcode), source.rest()) {
+                (Some(decode), Some(rest)) => {
+                    let (val, consumed) = decode(rest)?;
+                    if consumed > rest.len() {
+                        return Err(format!(
+                            "Extension 0x{:02x} consumed {} bytes, only {} remain",
+                            opcode, consumed, rest.len()
+                        ).into());
+                    }
+                    source.bytes(consumed)?;
+                    Ok(val)
+                }
+                (Some(_), None) => {
+                    Err(format!("Extension 0x{:02x} decodes from a slice: use decode, not decode_reader", opcode).into())
+                }
+                (None, _) => Err(format!("Unknown opcode: 0x{:02x}", opcode).into()),
+            },
+        }
+    }
+}
+
+/// Where [`TensDecoder::read_value`] pulls a value tree from: a slice, or a
+/// reader consumed on demand ([`TensDecoder::decode_reader`]).
+trait ByteSource {
+    /// The next byte, or "Unexpected end of input".
+    fn byte(&mut self) -> Result<u8, DecodeError>;
+
+    /// The next `len` bytes, without trusting `len` for an up-front allocation.
+    fn bytes(&mut self, len: usize) -> Result<Cow<'_, [u8]>, DecodeError>;
+
+    /// The unread input, for user extension opcodes, which size their own
+    /// payload; `None` when it is not in memory.
+    fn rest(&self) -> Option<&[u8]>;
+
+    /// The next `N` bytes, for fixed-width payloads.
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut buf = [0u8; N];
+        buf.copy_from_slice(&self.bytes(N)?);
+        Ok(buf)
+    }
+
+    /// A LEB128 varint (at most 5 bytes for a u32).
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        let mut val: u32 = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err("Varint longer than 5 bytes".into())
+    }
+
+    /// An `OP_VARINT` payload (at most 10 bytes).
+    fn varint_i64(&mut self) -> Result<i64, DecodeError> {
+        let mut buf = Vec::new();
+        loop {
+            let byte = self.byte().map_err(|_| DecodeError::from("VARINT: malformed or truncated"))?;
+            buf.push(byte);
+            if byte & 0x80 == 0 || buf.len() == 10 {
+                break;
+            }
+        }
+        decode_varint_i64(&buf).map(|(val, _)| val).ok_or_else(|| "VARINT: malformed or truncated".into())
+    }
+}
+
+/// A value tree held in memory, read from `pos`.
+struct SliceSource<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteSource for SliceSource<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or("Unexpected end of input")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Cow<'_, [u8]>, DecodeError> {
+        let start = self.pos;
+        self.pos = start.checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("Unexpected end of input")?;
+        Ok(Cow::Borrowed(&self.bytes[start..self.pos]))
+    }
+
+    fn rest(&self) -> Option<&[u8]> {
+        Some(&self.bytes[self.pos..])
+    }
+}
+
+/// Opcodes the default encoder writes; a strict-canonical decoder rejects the rest.
+const CANONICAL_OPCODES: [u8; 10] = [
+    OP_NULL, OP_TRUE, OP_FALSE, OP_INT8, OP_INT32, OP_INT64, OP_FLOAT64, OP_STRING_REF, OP_ARRAY_START, OP_OBJECT_START,
+];
+
+fn non_canonical(reason: String) -> DecodeError {
+    DecodeError::NonCanonical { reason }
+}
+
+/// Fail unless `key` is normalized and sorts after `previous`, the key before
+/// it in the same object.
+fn check_canonical_key(key: &str, previous: Option<&str>) -> Result<(), DecodeError> {
+    if matches!(normalize_key(key, &CanonicalOptions::default()), Cow::Owned(_)) {
+        return Err(non_canonical(format!("key {:?} is not normalized", key)));
+    }
+    match previous {
+        Some(previous) if previous >= key => {
+            Err(non_canonical(format!("key {:?} follows {:?}: keys are not in ascending order", key, previous)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read the dictionary varint at `pos` of `bytes`, which start at offset
+/// `base`, as (value, bytes consumed).
+fn dictionary_varint(bytes: &[u8], pos: usize, base: usize) -> Result<(u32, usize), DecodeError> {
+    try_decode_varint(bytes.get(pos..).unwrap_or(&[])).ok_or(DecodeError::MalformedVarint { at: base + pos })
+}
+
+/// Validate an `OP_INT_ARRAY` width byte, returning it as an element size.
+fn int_array_width_bytes(width: u8) -> Result<usize, DecodeError> {
+    match width {
+        1 | 2 | 4 => Ok(width as usize),
+        _ => Err(format!("INT_ARRAY: invalid width {}", width).into()),
+    }
+}
+
+/// Sign-extend one little-endian `OP_INT_ARRAY` element of 1, 2 or 4 bytes.
+fn read_int_le(chunk: &[u8]) -> i64 {
+    match *chunk {
+        [b] => b as i8 as i64,
+        [b0, b1] => i16::from_le_bytes([b0, b1]) as i64,
+        [b0, b1, b2, b3] => i32::from_le_bytes([b0, b1, b2, b3]) as i64,
+        _ => unreachable!("width validated by int_array_width_bytes"),
+    }
+}
+
+/// Expand an `OP_NULL_RUN` into `arr`, which must not exceed the array's declared `count`.
+fn push_null_run(arr: &mut Vec<Value>, run: u32, count: u32) -> Result<(), DecodeError> {
+    if run == 0 || run as usize > (count as usize).saturating_sub(arr.len()) {
+        return Err(format!("NULL_RUN of {} does not fit array of {}", run, count).into());
+    }
+    arr.resize(arr.len() + run as usize, Value::Null);
+    Ok(())
+}
+
+/// Say where in an object a field failed to decode: its index and the keys
+/// read before it, so a stream cut mid-object points at the missing field.
+/// Errors from nested objects collect one such suffix per level, innermost first.
+fn object_field_error(err: DecodeError, index: u32, count: u32, read: &Map<String, Value>) -> DecodeError {
+    match err {
+        DecodeError::Malformed(msg) => {
+            let keys: Vec<&str> = read.keys().map(String::as_str).collect();
+            format!("{} (object field {} of {}, after keys [{}])", msg, index, count, keys.join(", ")).into()
+        }
+        other => other,
+    }
+}
+
+/// Parse the ASCII payload of an `OP_DECIMAL` back into a JSON number.
+fn parse_decimal(text: &[u8]) -> Result<Value, DecodeError> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|t| t.parse::<serde_json::Number>().ok())
+        .map(Value::Number)
+        .ok_or_else(|| format!("DECIMAL: invalid number text {:?}", String::from_utf8_lossy(text)).into())
+}
+
+impl Default for TensDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ── Reader-based Decoding ──
+
+/// Read exactly `buf.len()` bytes, mapping a short read to a truncation error.
+#[cfg(feature = "std")]
+fn read_into<R: std::io::Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), DecodeError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => DecodeError::from("Unexpected end of input"),
+        _ => DecodeError::from(format!("Read error: {}", e)),
+    })
+}
+
+#[cfg(feature = "std")]
+fn read_byte<R: std::io::Read>(reader: &mut R) -> Result<u8, DecodeError> {
+    let mut byte = [0u8; 1];
+    read_into(reader, &mut byte)?;
+    Ok(byte[0])
+}
+
+/// Read `len` bytes without trusting `len` for the up-front allocation.
+#[cfg(feature = "std")]
+fn read_bytes<R: std::io::Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, DecodeError> {
+    use std::io::Read;
+
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)
+        .map_err(|e| format!("Read error: {}", e))?;
+    if buf.len() < len {
+        return Err("Unexpected end of input".into());
+    }
+    Ok(buf)
+}
+
+/// Read the UTF-8 bytes of `units` UTF-16 code units, one character at a time.
+#[cfg(feature = "std")]
+fn read_utf16_units<R: std::io::Read>(reader: &mut R, units: u32) -> Result<Vec<u8>, DecodeError> {
+    let mut buf = Vec::new();
+    let mut counted = 0;
+    let truncated = |_| DecodeError::from("Dictionary string extends past end of input");
+    while counted < units {
+        let lead = read_byte(reader).map_err(truncated)?;
+        let (width, char_units) = utf8_char_width(lead)?;
+        if counted + char_units > units {
+            return Err("Dictionary string length splits a surrogate pair".into());
+        }
+        buf.push(lead);
+        let start = buf.len();
+        buf.resize(start + width - 1, 0);
+        read_into(reader, &mut buf[start..]).map_err(truncated)?;
+        counted += char_units;
+    }
+    Ok(buf)
+}
+
+/// A value tree pulled from a reader on demand.
+#[cfg(feature = "std")]
+struct ReaderSource<R>(R);
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> ByteSource for ReaderSource<R> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        read_byte(&mut self.0)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Cow<'_, [u8]>, DecodeError> {
+        read_bytes(&mut self.0, len).map(Cow::Owned)
+    }
+
+    fn rest(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Read a LEB128 varint one byte at a time (at most 5 bytes for a u32).
+#[cfg(feature = "std")]
+fn read_varint<R: std::io::Read>(reader: &mut R) -> Result<u32, DecodeError> {
+    ReaderSource(reader).varint()
+}
+
+#[cfg(feature = "std")]
+impl TensDecoder {
+    /// Decode a TENS v2 document from a reader, pulling bytes on demand
+    /// instead of requiring the whole input in memory. A strict-canonical
+    /// decoder reads the whole document first, as its checks compare bytes.
+    pub fn decode_reader<R: std::io::Read>(&mut self, reader: R) -> Result<Value, DecodeError> {
+        // Reads past the size limit see end of input; a document that fails
+        // with the budget spent is reported as too large instead
+        let max_total_size = self.limits.max_total_size;
+        if self.strict_canonical {
+            use std::io::Read;
+
+            let mut bytes = Vec::new();
+            reader.take(u64::try_from(max_total_size).unwrap_or(u64::MAX).saturating_add(1))
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("Read error: {}", e))?;
+            return self.decode(&bytes);
+        }
+        let mut reader = reader.take(u64::try_from(max_total_size).unwrap_or(u64::MAX));
+        self.read_document(&mut reader).map_err(|e| match reader.limit() {
+            0 => DecodeError::LimitExceeded { limit: "max_total_size", max: max_total_size },
+            _ => e,
+        })
+    }
+
+    fn read_document<R: std::io::Read>(&mut self, reader: &mut R) -> Result<Value, DecodeError> {
+        let mut header = [0u8; HEADER_LEN];
+        read_into(reader, &mut header)
+            .map_err(|_| DecodeError::from("Input too short for TENS header"))?;
+        self.format.check_header(&header)?;
+        if header[4] & FLAG_MULTI_ROOT != 0 {
+            return Err("Multi-root TENS document: use decode_multi".into());
+        }
+        self.features = 0;
+        if header[4] & FLAG_FEATURE_BYTE != 0 {
+            let features = read_byte(reader).map_err(|_| DecodeError::from("Input too short for TENS header"))?;
+            self.accept_features(features)?;
+        }
+
+        // Read dictionary
+        let dict_count = read_varint(reader)?;
+        let front_coded = header[4] & FLAG_FRONT_CODED_DICT != 0;
+        if header[4] & FLAG_COMPRESSED_DICT != 0 {
+            #[cfg(feature = "compression")]
+            {
+                let len = read_varint(reader)?;
+                let block = inflate(&read_bytes(reader, len as usize)?, self.limits.max_dictionary_bytes)?;
+                self.read_dictionary(&block, 0, dict_count, front_coded)?;
+            }
+            #[cfg(not(feature = "compression"))]
+            return Err("Compressed dictionary requires the `compression` feature".into());
+        } else {
+            self.check_elements(dict_count)?;
+            self.dictionary = Vec::new();
+            self.dictionary_bytes = 0;
+            for _ in 0..dict_count {
+                let shared = if front_coded { read_varint(reader)? } else { 0 };
+                let str_len = read_varint(reader)?;
+                let bytes = match self.dict_length_mode {
+                    DictLengthMode::Utf8Bytes => read_bytes(reader, str_len as usize)
+                        .map_err(|_| DecodeError::from("Dictionary string extends past end of input"))?,
+                    DictLengthMode::Utf16Units => read_utf16_units(reader, str_len)?,
+                };
+                self.push_dictionary_entry(shared, &bytes)?;
+            }
+        }
+
+        // Read value tree
+        self.reset_document_state();
+        self.read_value(&mut ReaderSource(reader))
+    }
+}
+
+// ── Dictionary Compression ──
+
+/// Deflate `bytes` into a length-prefixed block: varint(compressed_len) + deflate data.
+#[cfg(feature = "compression")]
+fn deflate_block(bytes: &[u8]) -> Vec<u8> {
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    // Writing into a Vec cannot fail
+    encoder.write_all(bytes).expect("in-memory deflate");
+    let compressed = encoder.finish().expect("in-memory deflate");
+
+    let mut out = encode_varint(compressed.len() as u32);
+    out.extend_from_slice(&compressed);
+    out
+}
+
+/// Inflate a block written by `deflate_block`, returning (inflated, bytes_consumed).
+#[cfg(feature = "compression")]
+fn inflate_block(bytes: &[u8], max: usize) -> Result<(Vec<u8>, usize), DecodeError> {
+    let (len, consumed) = decode_varint(bytes);
+    let end = consumed.checked_add(len as usize)
+        .filter(|&end| end <= bytes.len())
+        .ok_or("Compressed dictionary extends past end of input")?;
+    Ok((inflate(&bytes[consumed..end], max)?, end))
+}
+
+/// Inflate raw deflate data, stopping with `max_dictionary_bytes` exceeded
+/// as soon as it yields more than `max` bytes.
+#[cfg(feature = "compression")]
+fn inflate(data: &[u8], max: usize) -> Result<Vec<u8>, DecodeError> {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(data)
+        .take((max as u64).saturating_add(1))
+        .read_to_end(&mut inflated)
+        .map_err(|e| format!("Invalid compressed dictionary: {}", e))?;
+    if inflated.len() > max {
+        return Err(DecodeError::LimitExceeded { limit: "max_dictionary_bytes", max });
+    }
+    Ok(inflated)
+}
+
+// ── Binary Validation ──
+
+/// Options for [`validate_binary`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValidateOptions {
+    /// Reject dictionary entries that are never referenced. A well-formed encoder
+    /// never emits them, so their presence points at a buggy encoder or tampering.
+    pub reject_unused_dictionary: bool,
+}
+
+/// Check that `bytes` is a well-formed TENS v2 document, discarding the decoded value.
+pub fn validate_binary(bytes: &[u8], options: &ValidateOptions) -> Result<(), DecodeError> {
+    let mut decoder = TensDecoder::new();
+    decoder.track_references = options.reject_unused_dictionary;
+    decoder.decode(bytes)?;
+
+    if let Some(id) = decoder.referenced.iter().position(|used| !used) {
+        return Err(DecodeError::UnusedDictionaryEntry(id as u32));
+    }
+    Ok(())
+}
+
+/// Whether decoding `bytes` and re-encoding the result with the default
+/// encoder reproduces them exactly.
+///
+/// Output of the default encoder is always stable; `false` means the input is
+/// non-canonical (e.g. a wider int opcode than needed), lossy, or written
+/// with non-default options, which [`is_stable_with`] checks against.
+pub fn is_stable(bytes: &[u8]) -> Result<bool, DecodeError> {
+    is_stable_with(bytes, &EncoderOptions::default())
+}
+
+/// [`is_stable`], re-encoding with `options`: output of an encoder using the
+/// same options is always stable.
+pub fn is_stable_with(bytes: &[u8], options: &EncoderOptions) -> Result<bool, DecodeError> {
+    let value = TensDecoder::new().decode(bytes)?;
+    Ok(TensEncoder::with_options(options.clone()).try_encode(&value).is_ok_and(|reencoded| reencoded == bytes))
+}
+
+// ── Trusted Decoding ──
+
+#[cfg(feature = "trusted_decode")]
+impl TensDecoder {
+    /// [`Self::decode`] without UTF-8 validation of dictionary entries or
+    /// bounds checks on string refs, for pipelines that decode their own
+    /// encoder's output. Reads of the byte slice itself stay bounds-checked.
+    ///
+    /// # Safety
+    /// **Never pass untrusted bytes.** `bytes` must be the unmodified output
+    /// of a TENS encoder. Invalid UTF-8 or an out-of-range string ref is
+    /// undefined behavior, not an error. Check integrity first (e.g. compare
+    /// [`hash_tens_binary`] against a trusted hash) if the bytes crossed a
+    /// trust boundary; otherwise use [`Self::decode`].
+    ///
+    /// # Panics
+    /// On malformed structure the checked paths still catch (truncation,
+    /// unknown opcodes).
+    pub unsafe fn decode_trusted(&mut self, bytes: &[u8]) -> Value {
+        self.trusted = true;
+        let result = self.decode(bytes);
+        self.trusted = false;
+        result.unwrap_or_else(|e| panic!("TENS decode failed: {}", e))
+    }
+}
+
+// ── Ordered Decoding ──
+
+/// A decoded JSON value whose objects iterate in sorted key order, whatever
+/// `serde_json`'s `preserve_order` feature does to [`Map`].
+#[cfg(feature = "ordered")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderedValue {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    Array(Vec<OrderedValue>),
+    Object(indexmap::IndexMap<String, OrderedValue>),
+}
+
+#[cfg(feature = "ordered")]
+impl From<Value> for OrderedValue {
+    /// Convert, sorting the keys of every object.
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => OrderedValue::Null,
+            Value::Bool(b) => OrderedValue::Bool(b),
+            Value::Number(n) => OrderedValue::Number(n),
+            Value::String(s) => OrderedValue::String(s),
+            Value::Array(arr) => OrderedValue::Array(arr.into_iter().map(OrderedValue::from).collect()),
+            Value::Object(obj) => {
+                let mut map: indexmap::IndexMap<String, OrderedValue> = obj.into_iter()
+                    .map(|(k, v)| (k, OrderedValue::from(v)))
+                    .collect();
+                map.sort_keys();
+                OrderedValue::Object(map)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ordered")]
+impl From<OrderedValue> for Value {
+    fn from(value: OrderedValue) -> Self {
+        match value {
+            OrderedValue::Null => Value::Null,
+            OrderedValue::Bool(b) => Value::Bool(b),
+            OrderedValue::Number(n) => Value::Number(n),
+            OrderedValue::String(s) => Value::String(s),
+            OrderedValue::Array(arr) => Value::Array(arr.into_iter().map(Value::from).collect()),
+            OrderedValue::Object(obj) => Value::Object(obj.into_iter().map(|(k, v)| (k, Value::from(v))).collect()),
+        }
+    }
+}
+
+#[cfg(feature = "ordered")]
+impl TensDecoder {
+    /// [`Self::decode`], with every object's keys in sorted order. Encoder
+    /// output is already sorted on the wire; this also sorts documents from
+    /// producers that wrote keys in another order.
+    pub fn decode_ordered(&mut self, bytes: &[u8]) -> Result<OrderedValue, DecodeError> {
+        self.decode(bytes).map(OrderedValue::from)
+    }
+}
+
+/// Encoded size of one value in each format; see [`size_report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeReport {
+    /// Compact JSON.
+    pub json_bytes: usize,
+    /// TENS v2 binary with default options.
+    pub tens_binary_bytes: usize,
+    /// TENS-Text, or `None` when the value is not an object or array of objects.
+    pub tens_text_bytes: Option<usize>,
+    /// The part of `tens_binary_bytes` taken by the dictionary (count + entries).
+    pub dictionary_bytes: usize,
+}
+
+/// Encode `value` once as JSON, TENS binary and TENS-Text and report the
+/// sizes, for picking a format per dataset.
+///
+/// # Panics
+/// Under the same conditions as [`TensEncoder::encode`].
+pub fn size_report(value: &Value) -> SizeReport {
+    let mut encoder = TensEncoder::new();
+    let binary = encoder.encode(value);
+    let dictionary = encoder.emit_header_and_dictionary()
+        .unwrap_or_else(|e| panic!("TENS encode failed: {}", e));
+    SizeReport {
+        json_bytes: value.to_string().len(),
+        tens_binary_bytes: binary.len(),
+        tens_text_bytes: encode_tens_text(value, None).ok().map(|text| text.len()),
+        dictionary_bytes: dictionary.len() - HEADER_LEN,
+    }
+}
+
+/// Every dictionary string of `value` (keys and string values, after
+/// canonicalization) with the number of times the document uses it, most
+/// frequent first; ties keep dictionary order. For spotting strings worth
+/// moving out of band.
+pub fn dictionary_frequencies(value: &Value) -> Vec<(String, usize)> {
+    /// Counts in scan order; `index` maps each string to its slot.
+    #[derive(Default)]
+    struct Tally<'a> {
+        index: HashMap<&'a str, usize>,
+        counts: Vec<(&'a str, usize)>,
+    }
+
+    impl<'a> Tally<'a> {
+        fn add(&mut self, s: &'a str) {
+            let counts = &mut self.counts;
+            let slot = *self.index.entry(s).or_insert_with(|| {
+                counts.push((s, 0));
+                counts.len() - 1
+            });
+            counts[slot].1 += 1;
+        }
+
+        fn walk(&mut self, value: &'a Value) {
+            match value {
+                Value::String(s) => self.add(s),
+                Value::Array(arr) => arr.iter().for_each(|item| self.walk(item)),
+                Value::Object(obj) => {
+                    // Canonical maps iterate sorted, the scan pass's order
+                    for (key, field) in obj {
+                        self.add(key);
+                        self.walk(field);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let canonical = canonicalize(value);
+    let mut tally = Tally::default();
+    tally.walk(&canonical);
+    tally.counts.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+    tally.counts.into_iter().map(|(s, n)| (s.to_string(), n)).collect()
+}
+
+// ── Value Tree Walking ──
+
+/// A part of an encoded document, as [`TreeWalker`] names it in spans.
+#[derive(Debug, Clone, Copy)]
+enum Part {
+    DictionaryCount,
+    CompressedDictionaryLength,
+    CompressedDictionaryBlock,
+    SharedPrefixLength(u32),
+    EntryLength(u32),
+    EntryBytes(u32),
+    RootCount,
+    Opcode,
+    Payload,
+    StringRef,
+    RunLength,
+    TextLength,
+    NumberText,
+    Length,
+    Bytes,
+    ExtensionType,
+    PayloadLength,
+    ElementCount,
+    EntryCount,
+    Key(u32),
+    Width,
+    Elements,
+    Element(u32),
+    RecordCount,
+    SchemaNumber,
+    SchemaFieldCount,
+    SchemaKey(u32),
+    PresenceBitmap,
+}
+
+impl std::fmt::Display for Part {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Part::DictionaryCount => write!(f, "dictionary count"),
+            Part::CompressedDictionaryLength => write!(f, "compressed dictionary length"),
+            Part::CompressedDictionaryBlock => write!(f, "compressed dictionary block"),
+            Part::SharedPrefixLength(id) => write!(f, "shared prefix length of dictionary entry {}", id),
+            Part::EntryLength(id) => write!(f, "length of dictionary entry {}", id),
+            Part::EntryBytes(id) => write!(f, "bytes of dictionary entry {}", id),
+            Part::RootCount => write!(f, "root count"),
+            Part::Opcode => write!(f, "opcode"),
+            Part::Payload => write!(f, "payload"),
+            Part::StringRef => write!(f, "string ref"),
+            Part::RunLength => write!(f, "run length"),
+            Part::TextLength => write!(f, "text length"),
+            Part::NumberText => write!(f, "number text"),
+            Part::Length => write!(f, "length"),
+            Part::Bytes => write!(f, "bytes"),
+            Part::ExtensionType => write!(f, "extension type"),
+            Part::PayloadLength => write!(f, "payload length"),
+            Part::ElementCount => write!(f, "element count"),
+            Part::EntryCount => write!(f, "entry count"),
+            Part::Key(i) => write!(f, "key {}", i),
+            Part::Width => write!(f, "width"),
+            Part::Elements => write!(f, "elements"),
+            Part::Element(i) => write!(f, "element {}", i),
+            Part::RecordCount => write!(f, "record count"),
+            Part::SchemaNumber => write!(f, "schema number"),
+            Part::SchemaFieldCount => write!(f, "schema field count"),
+            Part::SchemaKey(i) => write!(f, "schema key {}", i),
+            Part::PresenceBitmap => write!(f, "presence bitmap"),
+        }
+    }
+}
+
+/// Bytes `start..end` of a document hold `part`, of the value with opcode
+/// `value.0` at offset `value.1` when inside the value tree.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    part: Part,
+    value: Option<(u8, usize)>,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.part, self.value) {
+            (Part::Opcode, Some((opcode, at))) => write!(f, "opcode {} of the value at {}", opcode_name(opcode), at),
+            (part, Some((opcode, at))) => write!(f, "{} of the {} at {}", part, opcode_name(opcode), at),
+            (part, None) => write!(f, "{}", part),
+        }
+    }
+}
+
+/// A value as [`TreeWalker`] reads it, without its children: its own bytes
+/// are `at..end`.
+struct TreeValue<'a> {
+    at: usize,
+    end: usize,
+    opcode: u8,
+    kind: TreeValueKind<'a>,
+}
+
+enum TreeValueKind<'a> {
+    /// `OP_NULL`, `OP_TRUE` or `OP_FALSE`.
+    Literal,
+    Int(i64),
+    Float32(f32),
+    Float64(f64),
+    Timestamp(i64),
+    /// `OP_STRING_REF`, or the `OP_SAME_AS_PREVIOUS` repeating it.
+    String(u32),
+    Decimal(&'a [u8]),
+    Bytes(usize),
+    Extension { ext_type: u32, len: usize },
+    Array { count: u32 },
+    /// `OP_OBJECT_START` or `OP_INT_KEY_OBJECT`; its keys and values follow.
+    Object { count: u32 },
+    /// `OP_INT_ARRAY` elements of `width` bytes each.
+    IntArray { count: u32, width: u8, data: &'a [u8] },
+    /// `OP_PACKED_INT_ARRAY` zigzag varints.
+    PackedIntArray { count: u32, data: &'a [u8] },
+    /// `defined` when this value's bytes define schema `schema`, whose key
+    /// refs are `keys`; the presence bitmap and values follow.
+    Sparse { schema: u32, defined: bool, keys: &'a [u32] },
+    /// As for `Sparse`; `count` records of `keys.len()` values follow, column
+    /// by column.
+    Columnar { count: u32, schema: u32, defined: bool, keys: &'a [u32] },
+}
+
+/// An object key: a string ref, or the integer of an `OP_INT_KEY_OBJECT`.
+#[derive(Debug, Clone, Copy)]
+enum TreeKey {
+    Ref(u32),
+    Int(u32),
+}
+
+/// What [`TreeWalker`] reports as it steps through a document. Every method
+/// defaults to doing nothing; returning `Err` stops the walk.
+trait TreeVisitor {
+    type Error: From<DecodeError>;
+
+    /// Called for every span before it is checked against the input, so the
+    /// span may run past the end.
+    fn span(&mut self, _span: Span) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn dictionary(&mut self, _start: usize, _end: usize, _count: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn compressed_dictionary(&mut self, _start: usize, _end: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Entry `id`, sharing `shared` leading bytes with the one before it.
+    fn dictionary_entry(&mut self, _start: usize, _end: usize, _id: u32, _shared: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn roots(&mut self, _start: usize, _end: usize, _count: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// A value `depth` arrays and objects down, before its children.
+    fn value(&mut self, _value: &TreeValue, _depth: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The key of an object entry `depth` down, before its value.
+    fn key(&mut self, _start: usize, _end: usize, _key: TreeKey, _depth: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// An `OP_NULL_RUN` of `run` elements `depth` down.
+    fn null_run(&mut self, _start: usize, _end: usize, _run: u32, _depth: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The presence bitmap of a sparse object, whose `present` keys' values
+    /// follow `depth` down.
+    fn present(&mut self, _start: usize, _end: usize, _present: &[u32], _depth: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Steps through a document as `TensDecoder::read_value` reads it, without
+/// building values, for the diagnostics that describe its bytes. Nesting is
+/// bounded by the default [`DecodeLimits::max_depth`], as decoding is.
+struct TreeWalker<'a, V> {
+    bytes: &'a [u8],
+    visitor: V,
+    /// Key refs of the sparse schemas defined so far.
+    sparse_schemas: Vec<Vec<u32>>,
+    /// Key refs of the columnar schemas defined so far (schema n at n - 1).
+    columnar_schemas: Vec<Vec<u32>>,
+    /// The last string ref, for `OP_SAME_AS_PREVIOUS`.
+    last_string: Option<u32>,
+    /// Arrays and objects enclosing the value being walked.
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a, V: TreeVisitor> TreeWalker<'a, V> {
+    /// A walker over `bytes`, which must hold at least a header.
+    fn new(bytes: &'a [u8], visitor: V) -> Self {
+        TreeWalker {
+            bytes,
+            visitor,
+            sparse_schemas: Vec::new(),
+            columnar_schemas: Vec::new(),
+            last_string: None,
+            depth: 0,
+            max_depth: DecodeLimits::default().max_depth,
+        }
+    }
+
+    /// Step over `len` bytes at `start`.
+    fn span(&mut self, start: usize, len: usize, part: Part, value: Option<(u8, usize)>) -> Result<usize, V::Error> {
+        let span = Span { start, end: start.saturating_add(len), part, value };
+        self.visitor.span(span)?;
+        if span.end > self.bytes.len() {
+            return Err(DecodeError::from(format!("Unexpected end of input in the {} ({} bytes)", span, self.bytes.len())).into());
+        }
+        Ok(span.end)
+    }
+
+    /// Step over the varint at `start`, read as the decoder reads it.
+    fn varint(&mut self, start: usize, part: Part, value: Option<(u8, usize)>) -> Result<(u32, usize), V::Error> {
+        let mut source = SliceSource { bytes: self.bytes, pos: start.min(self.bytes.len()) };
+        let result = source.varint();
+        let span = Span { start, end: source.pos.max(start + 1), part, value };
+        self.visitor.span(span)?;
+        let val = result.map_err(|e| DecodeError::from(format!("{} in the {}", e, span)))?;
+        Ok((val, source.pos))
+    }
+
+    fn enter(&mut self, levels: usize) -> Result<(), V::Error> {
+        if self.depth.saturating_add(levels) > self.max_depth {
+            return Err(DecodeError::LimitExceeded { limit: "max_depth", max: self.max_depth }.into());
+        }
+        self.depth += levels;
+        Ok(())
+    }
+
+    /// Step over the dictionary, returning the offset of the value tree.
+    fn dictionary(&mut self) -> Result<usize, V::Error> {
+        let flags = self.bytes[4];
+        let start = header_len(self.bytes);
+        let (count, mut pos) = self.varint(start, Part::DictionaryCount, None)?;
+        self.visitor.dictionary(start, pos, count)?;
+        if flags & FLAG_COMPRESSED_DICT != 0 {
+            let (len, block) = self.varint(pos, Part::CompressedDictionaryLength, None)?;
+            let end = self.span(block, len as usize, Part::CompressedDictionaryBlock, None)?;
+            self.visitor.compressed_dictionary(pos, end)?;
+            return Ok(end);
+        }
+        for id in 0..count {
+            let entry = pos;
+            let mut shared = 0;
+            if flags & FLAG_FRONT_CODED_DICT != 0 {
+                (shared, pos) = self.varint(pos, Part::SharedPrefixLength(id), None)?;
+            }
+            let (len, bytes) = self.varint(pos, Part::EntryLength(id), None)?;
+            pos = self.span(bytes, len as usize, Part::EntryBytes(id), None)?;
+            self.visitor.dictionary_entry(entry, pos, id, shared)?;
+        }
+        Ok(pos)
+    }
+
+    /// Step over the roots of the value tree at `pos`, returning their end.
+    fn roots(&mut self, mut pos: usize) -> Result<usize, V::Error> {
+        let roots = if self.bytes[4] & FLAG_MULTI_ROOT != 0 {
+            let (count, next) = self.varint(pos, Part::RootCount, None)?;
+            self.visitor.roots(pos, next, count)?;
+            pos = next;
+            count
+        } else {
+            1
+        };
+        for _ in 0..roots {
+            pos = self.value(pos)?;
+        }
+        Ok(pos)
+    }
+
+    /// Report a value with no children, returning its end.
+    fn leaf(&mut self, at: usize, end: usize, kind: TreeValueKind) -> Result<usize, V::Error> {
+        self.visitor.value(&TreeValue { at, end, opcode: self.bytes[at], kind }, self.depth)?;
+        Ok(end)
+    }
+
+    /// The `N` payload bytes at `start`, and their end.
+    fn fixed<const N: usize>(&mut self, start: usize, of: Option<(u8, usize)>) -> Result<([u8; N], usize), V::Error> {
+        let end = self.span(start, N, Part::Payload, of)?;
+        Ok((self.bytes[start..end].try_into().unwrap(), end))
+    }
+
+    /// Step over the value at `at` and its children, returning its end.
+    fn value(&mut self, at: usize) -> Result<usize, V::Error> {
+        let opcode = *self.bytes.get(at)
+            .ok_or_else(|| DecodeError::from(format!("Unexpected end of input: value expected at {}", at)))?;
+        let of = Some((opcode, at));
+        let start = self.span(at, 1, Part::Opcode, of)?;
+        match opcode {
+            OP_NULL | OP_TRUE | OP_FALSE => self.leaf(at, start, TreeValueKind::Literal),
+            OP_SMALL_INT_FIRST..=OP_SMALL_INT_LAST => {
+                self.leaf(at, start, TreeValueKind::Int(opcode as i64 - SMALL_INT_BIAS))
+            }
+            OP_INT8 => {
+                let ([byte], end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Int(byte as i8 as i64))
+            }
+            OP_INT16 => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Int(i16::from_le_bytes(payload) as i64))
+            }
+            OP_INT32 => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Int(i32::from_le_bytes(payload) as i64))
+            }
+            OP_INT64 => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Int(i64::from_le_bytes(payload)))
+            }
+            OP_VARINT => {
+                let mut source = SliceSource { bytes: self.bytes, pos: start };
+                let result = source.varint_i64();
+                let span = Span { start, end: source.pos.max(start + 1), part: Part::Payload, value: of };
+                self.visitor.span(span)?;
+                let val = result.map_err(|e| DecodeError::from(format!("{} in the {}", e, span)))?;
+                self.leaf(at, source.pos, TreeValueKind::Int(val))
+            }
+            OP_TIMESTAMP => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Timestamp(i64::from_le_bytes(payload)))
+            }
+            OP_FLOAT32 => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Float32(f32::from_le_bytes(payload)))
+            }
+            OP_FLOAT64 => {
+                let (payload, end) = self.fixed(start, of)?;
+                self.leaf(at, end, TreeValueKind::Float64(f64::from_le_bytes(payload)))
+            }
+            OP_STRING_REF => {
+                let (id, end) = self.varint(start, Part::StringRef, of)?;
+                self.last_string = Some(id);
+                self.leaf(at, end, TreeValueKind::String(id))
+            }
+            OP_SAME_AS_PREVIOUS => {
+                let id = self.last_string.ok_or_else(|| DecodeError::from("SAME_AS_PREVIOUS without a preceding string"))?;
+                self.leaf(at, start, TreeValueKind::String(id))
+            }
+            OP_DECIMAL => {
+                let (len, text) = self.varint(start, Part::TextLength, of)?;
+                let end = self.span(text, len as usize, Part::NumberText, of)?;
+                self.leaf(at, end, TreeValueKind::Decimal(&self.bytes[text..end]))
+            }
+            OP_BYTES => {
+                let (len, data) = self.varint(start, Part::Length, of)?;
+                let end = self.span(data, len as usize, Part::Bytes, of)?;
+                self.leaf(at, end, TreeValueKind::Bytes(len as usize))
+            }
+            OP_EXT => {
+                let (ext_type, pos) = self.varint(start, Part::ExtensionType, of)?;
+                let (len, payload) = self.varint(pos, Part::PayloadLength, of)?;
+                let end = self.span(payload, len as usize, Part::Payload, of)?;
+                self.leaf(at, end, TreeValueKind::Extension { ext_type, len: len as usize })
+            }
+            OP_INT_ARRAY => {
+                let (count, width_at) = self.varint(start, Part::ElementCount, of)?;
+                let data = self.span(width_at, 1, Part::Width, of)?;
+                let width = self.bytes[width_at];
+                let len = (count as usize).checked_mul(int_array_width_bytes(width)?)
+                    .ok_or_else(|| DecodeError::from("INT_ARRAY: length overflow"))?;
+                let end = self.span(data, len, Part::Elements, of)?;
+                self.leaf(at, end, TreeValueKind::IntArray { count, width, data: &self.bytes[data..end] })
+            }
+            OP_PACKED_INT_ARRAY => {
+                let (count, data) = self.varint(start, Part::ElementCount, of)?;
+                let mut end = data;
+                for i in 0..count {
+                    end = self.varint(end, Part::Element(i), of)?.1;
+                }
+                self.leaf(at, end, TreeValueKind::PackedIntArray { count, data: &self.bytes[data..end] })
+            }
+            OP_ARRAY_START => {
+                let (count, mut pos) = self.varint(start, Part::ElementCount, of)?;
+                self.leaf(at, pos, TreeValueKind::Array { count })?;
+                self.enter(1)?;
+                let mut filled = 0;
+                while filled < count {
+                    if self.bytes.get(pos) != Some(&OP_NULL_RUN) {
+                        pos = self.value(pos)?;
+                        filled += 1;
+                        continue;
+                    }
+                    let run_of = Some((OP_NULL_RUN, pos));
+                    self.span(pos, 1, Part::Opcode, run_of)?;
+                    let (run, end) = self.varint(pos + 1, Part::RunLength, run_of)?;
+                    if run == 0 || run > count - filled {
+                        return Err(DecodeError::from(format!("NULL_RUN of {} does not fit array of {}", run, count)).into());
+                    }
+                    self.visitor.null_run(pos, end, run, self.depth)?;
+                    filled += run;
+                    pos = end;
+                }
+                self.depth -= 1;
+                Ok(pos)
+            }
+            OP_OBJECT_START | OP_INT_KEY_OBJECT => {
+                let (count, mut pos) = self.varint(start, Part::EntryCount, of)?;
+                self.leaf(at, pos, TreeValueKind::Object { count })?;
+                self.enter(1)?;
+                for i in 0..count {
+                    let (key, value) = self.varint(pos, Part::Key(i), of)?;
+                    let key = if opcode == OP_OBJECT_START { TreeKey::Ref(key) } else { TreeKey::Int(key) };
+                    self.visitor.key(pos, value, key, self.depth)?;
+                    pos = self.value(value)?;
+                }
+                self.depth -= 1;
+                Ok(pos)
+            }
+            OP_SCHEMA_OBJECT_SPARSE => {
+                let (schema, mut pos) = self.varint(start, Part::SchemaNumber, of)?;
+                let defined = schema as usize == self.sparse_schemas.len();
+                if defined {
+                    let (count, next) = self.varint(pos, Part::SchemaFieldCount, of)?;
+                    pos = next;
+                    let mut keys = Vec::new();
+                    for i in 0..count {
+                        let (key, next) = self.varint(pos, Part::SchemaKey(i), of)?;
+                        keys.push(key);
+                        pos = next;
+                    }
+                    self.sparse_schemas.push(keys);
+                }
+                let keys = self.sparse_schemas.get(schema as usize)
+                    .ok_or_else(|| DecodeError::from(format!("SCHEMA_OBJECT_SPARSE: undefined schema {}", schema)))?;
+                let value = TreeValue { at, end: pos, opcode, kind: TreeValueKind::Sparse { schema, defined, keys } };
+                self.visitor.value(&value, self.depth)?;
+
+                let bitmap = pos;
+                let mut pos = self.span(bitmap, sparse_bitmap_len(keys.len()), Part::PresenceBitmap, of)?;
+                let keys = &self.sparse_schemas[schema as usize];
+                let present: Vec<u32> = keys.iter().enumerate()
+                    .filter(|(i, _)| self.bytes[bitmap + i / 8] & (1 << (i % 8)) != 0)
+                    .map(|(_, &key)| key)
+                    .collect();
+                self.enter(1)?;
+                self.visitor.present(bitmap, pos, &present, self.depth)?;
+                for _ in 0..present.len() {
+                    pos = self.value(pos)?;
+                }
+                self.depth -= 1;
+                Ok(pos)
+            }
+            OP_COLUMNAR_ARRAY => {
+                let (count, pos) = self.varint(start, Part::RecordCount, of)?;
+                let (schema, mut pos) = self.varint(pos, Part::SchemaNumber, of)?;
+                let defined = schema as usize == self.columnar_schemas.len() + 1;
+                if defined {
+                    let (fields, next) = self.varint(pos, Part::SchemaFieldCount, of)?;
+                    pos = next;
+                    let mut keys = Vec::new();
+                    for i in 0..fields {
+                        let (key, next) = self.varint(pos, Part::SchemaKey(i), of)?;
+                        keys.push(key);
+                        pos = next;
+                    }
+                    self.columnar_schemas.push(keys);
+                }
+                let keys = (schema as usize).checked_sub(1)
+                    .and_then(|i| self.columnar_schemas.get(i))
+                    .ok_or_else(|| DecodeError::from(format!("COLUMNAR_ARRAY: undefined schema {}", schema)))?;
+                if keys.is_empty() {
+                    return Err(DecodeError::from(format!("COLUMNAR_ARRAY: {} records with no fields", count)).into());
+                }
+                let fields = keys.len();
+                let value = TreeValue { at, end: pos, opcode, kind: TreeValueKind::Columnar { count, schema, defined, keys } };
+                self.visitor.value(&value, self.depth)?;
+                // An array of objects: the column values sit two levels down
+                self.enter(2)?;
+                for _ in 0..fields.saturating_mul(count as usize) {
+                    pos = self.value(pos)?;
+                }
+                self.depth -= 2;
+                Ok(pos)
+            }
+            OP_NULL_RUN => Err(DecodeError::from("NULL_RUN outside of an array").into()),
+            _ => Err(DecodeError::from(format!("Unknown opcode: 0x{:02x} at {}", opcode, at)).into()),
+        }
+    }
+}
+
+// ── Mismatch Diagnostics ──
+
+/// Find the first byte at which two encodings differ, e.g. Rust and TS output
+/// for the same input. Returns the offset and what each side holds there: the
+/// byte and the part of the document it belongs to (header, dictionary entry,
+/// or a value's opcode or payload, with that value's offset). `None` if equal;
+/// a length difference is a mismatch at the end of the shorter slice. Values
+/// nested past the default [`DecodeLimits::max_depth`] are not located.
+pub fn first_mismatch(a: &[u8], b: &[u8]) -> Option<(usize, String)> {
+    let offset = a.iter().zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))?;
+    Some((offset, format!("a: {}; b: {}", describe_offset(a, offset), describe_offset(b, offset))))
+}
+
+fn describe_offset(bytes: &[u8], offset: usize) -> String {
+    match bytes.get(offset) {
+        Some(byte) => format!("0x{:02x}, {}", byte, locate_offset(bytes, offset)),
+        None => format!("end of input ({} bytes)", bytes.len()),
+    }
+}
+
+/// The part of a TENS document that byte `offset` belongs to.
+fn locate_offset(bytes: &[u8], offset: usize) -> String {
+    match offset {
+        0..=3 => return "header magic".into(),
+        4 => return "version byte".into(),
+        5 if header_len(bytes) > HEADER_LEN => return "feature byte".into(),
+        _ => {}
+    }
+    let mut walker = TreeWalker::new(bytes, OffsetLocator { target: offset });
+    match walker.dictionary().and_then(|tree| walker.roots(tree)) {
+        Ok(_) => "trailing bytes after the value tree".into(),
+        Err(Located(context)) => context,
+    }
+}
+
+/// Stops a [`TreeWalker`] at the span that contains `target`.
+struct OffsetLocator {
+    target: usize,
+}
+
+/// Why a walk for [`OffsetLocator`] stopped: the description of the span
+/// holding the target, or of what kept the walk from reaching it.
+struct Located(String);
+
+impl From<DecodeError> for Located {
+    fn from(e: DecodeError) -> Self {
+        Located(format!("{} (cannot locate further)", e))
+    }
+}
+
+impl TreeVisitor for OffsetLocator {
+    type Error = Located;
+
+    fn span(&mut self, span: Span) -> Result<(), Located> {
+        if (span.start..span.end).contains(&self.target) {
+            return Err(Located(span.to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        OP_NULL => "NULL",
+        OP_TRUE => "TRUE",
+        OP_FALSE => "FALSE",
+        OP_INT8 => "INT8",
+        OP_INT16 => "INT16",
+        OP_INT32 => "INT32",
+        OP_INT64 => "INT64",
+        OP_FLOAT32 => "FLOAT32",
+        OP_FLOAT64 => "FLOAT64",
+        OP_STRING_REF => "STRING_REF",
+        OP_ARRAY_START => "ARRAY_START",
+        OP_OBJECT_START => "OBJECT_START",
+        OP_INT_KEY_OBJECT => "INT_KEY_OBJECT",
+        OP_DECIMAL => "DECIMAL",
+        OP_BYTES => "BYTES",
+        OP_TIMESTAMP => "TIMESTAMP",
+        OP_VARINT => "VARINT",
+        OP_SMALL_INT_FIRST..=OP_SMALL_INT_LAST => "SMALL_INT",
+        OP_NULL_RUN => "NULL_RUN",
+        OP_INT_ARRAY => "INT_ARRAY",
+        OP_PACKED_INT_ARRAY => "PACKED_INT_ARRAY",
+        OP_SAME_AS_PREVIOUS => "SAME_AS_PREVIOUS",
+        OP_EXT => "EXT",
+        OP_SCHEMA_OBJECT_SPARSE => "SCHEMA_OBJECT_SPARSE",
+        OP_COLUMNAR_ARRAY => "COLUMNAR_ARRAY",
+        _ => "unknown",
+    }
+}
+
+// ── Hex Dump ──
+
+/// Bytes shown per hex dump line; longer spans end in "..".
+const HEX_DUMP_BYTES: usize = 8;
+
+/// Annotated listing of a document, for debugging round trips: one line per
+/// header field, dictionary entry and value giving its offset, its bytes and
+/// their meaning, with values indented by depth. `{"a": 1}` dumps as
+///
+/// ```text
+/// 0000 54 45 4e 53                magic "TENS"
+/// 0004 02                         version 2, flags 0x00
+/// 0005 01                         dictionary count=1
+/// 0006 01 61                      dict[0]="a"
+/// 0008 09 01                      OP_OBJECT_START count=1
+/// 000a 00                           key dict[0]="a"
+/// 000b 03 01                        OP_INT8 1
+/// ```
+///
+/// Compressed dictionaries are listed as one block. User extension opcodes
+/// cannot be sized and fail as unknown, as does nesting past the default
+/// [`DecodeLimits::max_depth`].
+pub fn hex_dump(bytes: &[u8]) -> Result<String, DecodeError> {
+    let mut decoder = TensDecoder::new();
+    decoder.read_preamble(bytes)?;
+    let dump = HexDump { bytes, dictionary: &decoder.dictionary, out: String::new() };
+    let mut walker = TreeWalker::new(bytes, dump);
+
+    walker.visitor.line(0, 4, 0, format!("magic {:?}", String::from_utf8_lossy(&bytes[..4])));
+    walker.visitor.line(4, 5, 0, format!("version {}, flags 0x{:02x}", bytes[4] & !VERSION_FLAGS, bytes[4] & VERSION_FLAGS));
+    if header_len(bytes) > HEADER_LEN {
+        walker.visitor.line(5, 6, 0, format!("features 0x{:02x}", bytes[5]));
+    }
+    let tree = walker.dictionary()?;
+    let end = walker.roots(tree)?;
+    if end < bytes.len() {
+        walker.visitor.line(end, bytes.len(), 0, "trailing bytes");
+    }
+    Ok(walker.visitor.out)
+}
+
+/// The [`TreeVisitor`] behind [`hex_dump`]: the output so far.
+struct HexDump<'a> {
+    bytes: &'a [u8],
+    dictionary: &'a [String],
+    out: String,
+}
+
+impl HexDump<'_> {
+    /// Append a line for `bytes[start..end]` described by `text`.
+    fn line(&mut self, start: usize, end: usize, depth: usize, text: impl std::fmt::Display) {
+        let span = &self.bytes[start..end];
+        let mut hex: Vec<String> = span.iter().take(HEX_DUMP_BYTES).map(|b| format!("{:02x}", b)).collect();
+        if span.len() > HEX_DUMP_BYTES {
+            hex.push("..".into());
+        }
+        let width = HEX_DUMP_BYTES * 3 + 3;
+        self.out.push_str(&format!("{:04x} {:<width$}{}{}\n", start, hex.join(" "), "  ".repeat(depth), text));
+    }
+
+    /// `dict[id]="..."` for a string or key ref.
+    fn string(&self, id: u32) -> String {
+        match self.dictionary.get(id as usize) {
+            Some(s) => format!("dict[{}]={:?}", id, s),
+            None => format!("dict[{}] (out of bounds)", id),
+        }
+    }
+
+    /// The names of schema keys, as a list.
+    fn keys(&self, ids: &[u32]) -> String {
+        let names: Vec<String> = ids.iter()
+            .map(|&id| self.dictionary.get(id as usize).cloned().unwrap_or_else(|| format!("<{}>", id)))
+            .collect();
+        format!("[{}]", names.join(", "))
+    }
+}
+
+impl TreeVisitor for HexDump<'_> {
+    type Error = DecodeError;
+
+    fn dictionary(&mut self, start: usize, end: usize, count: u32) -> Result<(), DecodeError> {
+        self.line(start, end, 0, format!("dictionary count={}", count));
+        Ok(())
+    }
+
+    fn compressed_dictionary(&mut self, start: usize, end: usize) -> Result<(), DecodeError> {
+        self.line(start, end, 0, format!("compressed dictionary block, {} bytes", end - start));
+        Ok(())
+    }
+
+    fn dictionary_entry(&mut self, start: usize, end: usize, id: u32, shared: u32) -> Result<(), DecodeError> {
+        let text = match shared {
+            0 => self.string(id),
+            n => format!("{} (shared prefix {})", self.string(id), n),
+        };
+        self.line(start, end, 0, text);
+        Ok(())
+    }
+
+    fn roots(&mut self, start: usize, end: usize, count: u32) -> Result<(), DecodeError> {
+        self.line(start, end, 0, format!("root count={}", count));
+        Ok(())
+    }
+
+    fn value(&mut self, value: &TreeValue, depth: usize) -> Result<(), DecodeError> {
+        let name = format!("OP_{}", opcode_name(value.opcode));
+        let text = match value.kind {
+            TreeValueKind::Literal => name,
+            TreeValueKind::String(_) if value.opcode == OP_SAME_AS_PREVIOUS => name,
+            TreeValueKind::String(id) => format!("{} {}", name, self.string(id)),
+            TreeValueKind::Int(val) => format!("{} {}", name, val),
+            TreeValueKind::Float32(val) => format!("{} {}", name, val),
+            TreeValueKind::Float64(val) => format!("{} {}", name, val),
+            TreeValueKind::Timestamp(millis) => {
+                let text = format_timestamp(millis).unwrap_or_else(|| "(out of range)".into());
+                format!("{} {} {}", name, millis, text)
+            }
+            TreeValueKind::Decimal(text) => format!("{} {}", name, String::from_utf8_lossy(text)),
+            TreeValueKind::Bytes(len) => format!("{} len={}", name, len),
+            TreeValueKind::Extension { ext_type, len } => format!("{} type={} len={}", name, ext_type, len),
+            TreeValueKind::Array { count } | TreeValueKind::Object { count } => format!("{} count={}", name, count),
+            TreeValueKind::IntArray { count, width, data } => {
+                let ints: Vec<String> = data.chunks_exact(width as usize).map(|chunk| read_int_le(chunk).to_string()).collect();
+                format!("{} count={} width={} [{}]", name, count, width, ints.join(", "))
+            }
+            TreeValueKind::PackedIntArray { count, data } => {
+                let mut source = SliceSource { bytes: data, pos: 0 };
+                let ints: Vec<String> = (0..count)
+                    .map(|_| source.varint().map(|zigzag| zigzag_decode(zigzag).to_string()))
+                    .collect::<Result<_, _>>()?;
+                format!("{} count={} [{}]", name, count, ints.join(", "))
+            }
+            TreeValueKind::Sparse { schema, defined, keys } => match defined {
+                true => format!("{} schema={} defines {}", name, schema, self.keys(keys)),
+                false => format!("{} schema={}", name, schema),
+            },
+            TreeValueKind::Columnar { count, schema, defined, keys } => match defined {
+                true => format!("{} count={} schema={} defines {}", name, count, schema, self.keys(keys)),
+                false => format!("{} count={} schema={}", name, count, schema),
+            },
+        };
+        self.line(value.at, value.end, depth, text);
+        Ok(())
+    }
+
+    fn key(&mut self, start: usize, end: usize, key: TreeKey, depth: usize) -> Result<(), DecodeError> {
+        let key = match key {
+            TreeKey::Ref(id) => self.string(id),
+            TreeKey::Int(n) => n.to_string(),
+        };
+        self.line(start, end, depth, format!("key {}", key));
+        Ok(())
+    }
+
+    fn null_run(&mut self, start: usize, end: usize, run: u32, depth: usize) -> Result<(), DecodeError> {
+        self.line(start, end, depth, format!("OP_NULL_RUN run={}", run));
+        Ok(())
+    }
+
+    fn present(&mut self, start: usize, end: usize, present: &[u32], depth: usize) -> Result<(), DecodeError> {
+        let present = self.keys(present);
+        self.line(start, end, depth, format!("present {}", present));
+        Ok(())
+    }
+}
+
+// ── Decoded Size Estimation ──
+
+const VALUE_SIZE: usize = std::mem::size_of::<Value>();
+const STRING_SIZE: usize = std::mem::size_of::<String>();
+
+/// Estimate the memory, in bytes, that decoding `bytes` would take, without
+/// building any `Value`s: an inline `Value` per value, plus string bytes, a
+/// `Value` slot per array element and a key `String` and `Value` per object
+/// entry. Allocator slack and map node overhead are not counted, so treat it
+/// as a lower bound. Use it to reject oversized documents before decoding.
+///
+/// Only the dictionary is materialized. Multi-root documents sum their roots;
+/// user extension opcodes cannot be sized and fail as unknown. Nesting past
+/// the default [`DecodeLimits::max_depth`] fails as it would when decoding.
+pub fn estimate_decoded_size(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let mut decoder = TensDecoder::new();
+    let tree = decoder.read_preamble(bytes)?;
+    let mut walker = TreeWalker::new(bytes, SizeEstimator { dictionary: &decoder.dictionary, total: 0 });
+    walker.roots(tree)?;
+    Ok(walker.visitor.total)
+}
+
+/// The [`TreeVisitor`] behind [`estimate_decoded_size`]: sums what each value
+/// adds to the decoded tree, which is the tree's total.
+struct SizeEstimator<'a> {
+    dictionary: &'a [String],
+    total: usize,
+}
+
+impl SizeEstimator<'_> {
+    fn string_len(&self, id: u32) -> Result<usize, DecodeError> {
+        self.dictionary.get(id as usize)
+            .map(String::len)
+            .ok_or(DecodeError::StringRefOutOfBounds { id, len: self.dictionary.len() })
+    }
+
+    /// An object entry: its key `String` and `Value`.
+    fn entry(&self, key_len: usize) -> usize {
+        STRING_SIZE + key_len + VALUE_SIZE
+    }
+
+    /// Heap of `count` `Value` slots.
+    fn slots(count: u32) -> Result<usize, DecodeError> {
+        (count as usize).checked_mul(VALUE_SIZE).ok_or_else(|| "Element count overflows usize".into())
+    }
+
+    fn add(&mut self, size: usize) {
+        self.total = self.total.saturating_add(size);
+    }
+}
+
+impl TreeVisitor for SizeEstimator<'_> {
+    type Error = DecodeError;
+
+    fn value(&mut self, value: &TreeValue, depth: usize) -> Result<(), DecodeError> {
+        // Values inside arrays and objects sit in their parent's slots
+        if depth == 0 {
+            self.add(VALUE_SIZE);
+        }
+        let heap = match value.kind {
+            TreeValueKind::Literal | TreeValueKind::Int(_) | TreeValueKind::Float32(_) | TreeValueKind::Float64(_) => 0,
+            TreeValueKind::Timestamp(millis) => format_timestamp(millis).map_or(0, |text| text.len()),
+            TreeValueKind::String(id) => self.string_len(id)?,
+            TreeValueKind::Decimal(text) => text.len(),
+            // Decodes to a one-entry object holding the base64 text
+            TreeValueKind::Bytes(len) => self.entry(BYTES_TAG.len()) + (len / 3 + (len % 3).min(1)) * 4,
+            // At most the lenient placeholder: one short key and a number
+            TreeValueKind::Extension { .. } => self.entry(UNKNOWN_EXTENSION_KEY.len()),
+            TreeValueKind::Array { count }
+            | TreeValueKind::IntArray { count, .. }
+            | TreeValueKind::PackedIntArray { count, .. } => Self::slots(count)?,
+            // Entries are counted as their keys are read
+            TreeValueKind::Object { .. } | TreeValueKind::Sparse { .. } => 0,
+            // A `Value` slot and an object per record, an entry per field
+            TreeValueKind::Columnar { count, keys, .. } => {
+                let mut record = 0usize;
+                for &key in keys {
+                    record = record.saturating_add(self.entry(self.string_len(key)?));
+                }
+                (count as usize).checked_mul(record)
+                    .and_then(|entries| entries.checked_add(Self::slots(count).ok()?))
+                    .ok_or_else(|| DecodeError::from("Element count overflows usize"))?
+            }
+        };
+        self.add(heap);
+        Ok(())
+    }
+
+    fn key(&mut self, _start: usize, _end: usize, key: TreeKey, _depth: usize) -> Result<(), DecodeError> {
+        let key_len = match key {
+            TreeKey::Ref(id) => self.string_len(id)?,
+            TreeKey::Int(n) => n.to_string().len(),
+        };
+        self.add(self.entry(key_len));
+        Ok(())
+    }
+
+    fn present(&mut self, _start: usize, _end: usize, present: &[u32], _depth: usize) -> Result<(), DecodeError> {
+        for &key in present {
+            self.add(self.entry(self.string_len(key)?));
+        }
+        Ok(())
+    }
+}
+
+// ── Timestamps ──
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// Milliseconds since the Unix epoch of an RFC 3339 UTC timestamp spelled
+/// exactly as [`format_timestamp`] would spell it; `None` for any other string.
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let b = s.as_bytes();
+    if !matches!(b.len(), 20 | 24) || b[4] != b'-' || b[7] != b'-' || b[10] != b'T'
+        || b[13] != b':' || b[16] != b':' || b[b.len() - 1] != b'Z'
+    {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        b[range].iter().try_fold(0, |n, &c| c.is_ascii_digit().then(|| n * 10 + (c - b'0') as i64))
+    };
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    let millis = match b.len() {
+        24 if b[19] == b'.' => num(20..23)?,
+        24 => return None,
+        _ => 0,
+    };
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let total = days_from_civil(year, month, day) * MILLIS_PER_DAY + ((hour * 60 + minute) * 60 + second) * 1000 + millis;
+    // `.000` is not the canonical spelling, and would not decode back to itself
+    (format_timestamp(total)? == s).then_some(total)
+}
+
+/// The canonical RFC 3339 UTC spelling of `millis` since the Unix epoch:
+/// `YYYY-MM-DDTHH:MM:SSZ`, with `.mmm` before the `Z` when the milliseconds
+/// are non-zero. `None` outside years 0000–9999.
+fn format_timestamp(millis: i64) -> Option<String> {
+    let (year, month, day) = civil_from_days(millis.div_euclid(MILLIS_PER_DAY));
+    if !(0..=9999).contains(&year) {
+        return None;
+    }
+    let of_day = millis.rem_euclid(MILLIS_PER_DAY);
+    let (seconds, fraction) = (of_day / 1000, of_day % 1000);
+    let mut text = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60
+    );
+    if fraction != 0 {
+        text.push_str(&format!(".{:03}", fraction));
+    }
+    text.push('Z');
+    Some(text)
+}
+
+/// The string an `OP_TIMESTAMP` payload decodes to.
+fn timestamp_value(millis: i64) -> Result<Value, DecodeError> {
+    format_timestamp(millis)
+        .map(Value::String)
+        .ok_or_else(|| format!("TIMESTAMP: {} ms is outside years 0000-9999", millis).into())
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date (Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year.div_euclid(400), year.rem_euclid(400));
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01, as (year, month, day); inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (era * 400 + year_of_era + i64::from(month <= 2), month, day)
+}
+
+// ── TENS-Text Bytes ──
+
+/// Key of the single-entry object that stands for a byte string,
+/// `{"$bytes": "<base64>"}` (standard alphabet, padded). JSON has no bytes
+/// type, so this convention is what keeps bytes apart from strings: TENS-Text
+/// types such fields `bytes` and writes them as `b:<base64>`, and reads them
+/// back into the same object. Binary TENS encodes it as an ordinary object,
+/// or as raw `OP_BYTES` with [`EncoderOptions::raw_bytes`].
+pub const BYTES_TAG: &str = "$bytes";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The [`BYTES_TAG`] value holding `data`.
+pub fn bytes_value(data: &[u8]) -> Value {
+    let mut map = Map::new();
+    map.insert(BYTES_TAG.to_string(), Value::String(base64_encode(data)));
+    Value::Object(map)
+}
+
+/// The bytes held by a [`BYTES_TAG`] value, or `None` if `value` is not one.
+pub fn value_bytes(value: &Value) -> Option<Vec<u8>> {
+    base64_decode(bytes_tag(value)?)
+}
+
+/// The base64 text of a well-formed [`BYTES_TAG`] value.
+fn bytes_tag(value: &Value) -> Option<&str> {
+    let obj = value.as_object().filter(|obj| obj.len() == 1)?;
+    let text = obj.get(BYTES_TAG)?.as_str()?;
+    // Only the canonical spelling, so decoding gives back the same text
+    base64_decode(text).filter(|data| base64_encode(data) == text).map(|_| text)
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() / 3 * 4 + 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode padded standard base64, rejecting anything else.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    if bytes.len() & 3 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let last = index == bytes.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n = n << 6 | digit;
+        }
+        n <<= 6 * padding as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+// ── TENS-Text Encoder ──
+
+/// Infer a TENS-Text type label from a JSON value.
+fn infer_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "num",
+        Value::String(_) => "str",
+        Value::Array(_) => "str[]", // arrays encoded as repeated fields
+        Value::Object(_) if bytes_tag(value).is_some() => "bytes",
+        Value::Object(_) => "str",  // nested objects serialized as string
+    }
+}
+
+/// Check if a string needs quoting in TENS-Text.
+fn needs_quoting(s: &str) -> bool {
+    if s == "true" || s == "false" {
+        return true;
+    }
+    // Looks like a number
+    if s.parse::<f64>().is_ok() {
+        return true;
+    }
+    needs_quoting_in_str_field(s)
+}
+
+/// Whether a string needs quoting in a `@version 2` `str` field, where bare
+/// number and boolean lookalikes already read back as strings.
+fn needs_quoting_in_str_field(s: &str) -> bool {
+    if s.is_empty() || s == "_" {
+        return true;
+    }
+    // A leading sigil reads as a dict ref (@N, #N) or a comment, whatever
+    // follows; a `b:` prefix reads as bytes
+    if s.starts_with('@') || s.starts_with('#') || s.starts_with("b:") {
+        return true;
+    }
+    // Contains special characters. ':' is not one: only `@schema` field
+    // definitions split on it, and values never appear there
+    s.chars().any(|c| {
+        c.is_whitespace() || matches!(c, '"' | '\\' | '|' | '>' | ',' | '=' | '{' | '}' | '[' | ']' | '@' | '#')
+    })
+}
+
+/// Quote a string with TENS-Text escape rules.
+fn quote_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format a value for TENS-Text output.
+///
+/// `str_field` marks a scalar `str` field of a type-directed (`@version 2`)
+/// document, where only ambiguity with `_`, refs and syntax forces quoting.
+fn format_tens_text_value(value: &Value, dict_map: &HashMap<String, usize>, str_field: bool) -> String {
+    match value {
+        Value::Null => "_".to_string(),
+        Value::Bool(true) => "true".to_string(),
+        Value::Bool(false) => "false".to_string(),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.to_string()
+            } else if let Some(f) = n.as_f64() {
+                if f.is_nan() {
+                    "\"NaN\"".to_string()
+                } else if f.is_infinite() {
+                    if f.is_sign_positive() {
+                        "\"Infinity\"".to_string()
+                    } else {
+                        "\"-Infinity\"".to_string()
+                    }
+                } else if f == 0.0 && f.is_sign_negative() {
+                    "-0".to_string()
+                } else {
+                    format!("{}", f)
+                }
+            } else {
+                n.to_string()
+            }
+        }
+        Value::String(s) => {
+            // Check dictionary
+            if let Some(&idx) = dict_map.get(s) {
+                return format!("@{}", idx);
+            }
+            let quote = if str_field { needs_quoting_in_str_field(s) } else { needs_quoting(s) };
+            if quote {
+                quote_string(s)
+            } else {
+                s.clone()
+            }
+        }
+        Value::Object(_) if bytes_tag(value).is_some() => {
+            format!("b:{}", bytes_tag(value).unwrap())
+        }
+        Value::Array(_) | Value::Object(_) => {
+            // Shouldn't happen at field level — arrays handled externally
+            let s = serde_json::to_string(value).unwrap_or_default();
+            quote_string(&s)
+        }
+    }
+}
+
+/// Options for [`encode_tens_text_with`].
+#[derive(Debug, Clone)]
+pub struct TextOptions {
+    /// Emitted as `@encoding <name>`.
+    pub encoding: Option<String>,
+    /// Emit `@version 2`, whose scalar `str` fields read every bare value as a
+    /// string (`42` stays `"42"`), so such strings need no quotes. A field a
+    /// version-1 document would type `str` but that also holds numbers or
+    /// booleans is typed `any` instead and parsed by value syntax.
+    pub type_directed: bool,
+    /// List schema fields (and record lines) in the key order of the first
+    /// record of each shape instead of sorted. Needs the `preserve_order`
+    /// feature for that order to survive JSON parsing; without it every map
+    /// is already sorted. Canonical hashing still uses sorted keys.
+    pub preserve_order: bool,
+    /// Canonicalization applied before encoding, as for binary.
+    pub canonical: CanonicalOptions,
+    /// Close each record with `@checksum <hex>`, a [`record_checksum`] of
+    /// its lines, so a reader can skip records corrupted in place.
+    pub record_checksums: bool,
+    /// Fields whose values are always written literally (quoted if needed),
+    /// never as `@N` dictionary refs, e.g. a key column kept readable. Their
+    /// values don't count toward the dictionary either.
+    pub no_dict_fields: Vec<String>,
+    /// End the output with a newline (the default), or stop right after the
+    /// last line's text. Decoding reads both alike.
+    pub trailing_newline: bool,
+    /// Wrap a root TENS-Text cannot hold with [`to_text_encodable`] instead
+    /// of failing.
+    pub wrap_scalars: bool,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        TextOptions {
+            encoding: None,
+            type_directed: false,
+            preserve_order: false,
+            canonical: CanonicalOptions::default(),
+            record_checksums: false,
+            no_dict_fields: Vec::new(),
+            trailing_newline: true,
+            wrap_scalars: false,
+        }
+    }
+}
+
+/// Apply `options.trailing_newline` to a finished document.
+fn finish_text(mut out: String, options: &TextOptions) -> String {
+    if !options.trailing_newline && out.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+/// A distinct record shape (sorted key set) in a TENS-Text document.
+struct TextShape {
+    /// Sorted keys: the shape's identity.
+    key_set: Vec<String>,
+    /// Keys in output order.
+    keys: Vec<String>,
+    types: Vec<&'static str>,
+    is_array: Vec<bool>,
+}
+
+impl TextShape {
+    fn field(&self, key: &str) -> Option<(&'static str, bool)> {
+        let i = self.keys.iter().position(|k| k == key)?;
+        Some((self.types[i], self.is_array[i]))
+    }
+}
+
+/// Append an `@schema <name> [extends <base>] field:type ...` line.
+fn push_schema_line<'a>(
+    out: &mut String,
+    name: &str,
+    extends: Option<&str>,
+    fields: impl Iterator<Item = (&'a String, &'static str, bool)>,
+) {
+    out.push_str(&format!("@schema {}", name));
+    if let Some(base) = extends {
+        out.push_str(&format!(" extends {}", base));
+    }
+    for (key, type_str, is_array) in fields {
+        let suffix = if is_array { "[]" } else { "" };
+        out.push_str(&format!(" {}:{}{}", key, type_str, suffix));
+    }
+    out.push('\n');
+}
+
+/// Encode an array of objects into TENS-Text format.
+///
+/// Records sharing a key set share a schema. A single shape is emitted as
+/// `@schema data`; several shapes are emitted as `data1`, `data2`, ... and the
+/// fields common to all of them (same type) are factored into
+/// `@schema base`, which each child `extends`.
+pub fn encode_tens_text(data: &Value, encoding: Option<&str>) -> Result<String, String> {
+    let options = TextOptions { encoding: encoding.map(str::to_string), ..Default::default() };
+    encode_tens_text_with(data, &options)
+}
+
+/// [`encode_tens_text`] with explicit [`TextOptions`].
+pub fn encode_tens_text_with(data: &Value, options: &TextOptions) -> Result<String, String> {
+    let version = if options.type_directed { 2 } else { 1 };
+    let canonical = canonicalize_with(data, &options.canonical);
+    let canonical = if options.wrap_scalars { to_text_encodable(&canonical) } else { canonical };
+    let records = match &canonical {
+        Value::Array(arr) => arr.clone(),
+        Value::Object(_) => vec![canonical.clone()],
+        _ => return Err("TENS-Text requires an array of objects or a single object".into()),
+    };
+
+    if records.is_empty() {
+        return Ok(finish_text(format!("@version {}\n", version), options));
+    }
+
+    // 1. Group records by shape, in first-appearance order
+    records.first().unwrap().as_object().ok_or("Records must be objects")?;
+    let mut shapes: Vec<TextShape> = Vec::new();
+    let mut shaped_records: Vec<(usize, &Map<String, Value>)> = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let Some(obj) = record.as_object() else {
+            continue;
+        };
+        let mut key_set: Vec<String> = obj.keys().cloned().collect();
+        key_set.sort();
+
+        let idx = match shapes.iter().position(|s| s.key_set == key_set) {
+            Some(idx) => idx,
+            None => {
+                // Canonicalization sorted the keys; take the author's order
+                // from the input, normalized as canonicalization did, keeping
+                // the first of several spellings of one key
+                let keys = match (options.preserve_order, data) {
+                    (true, Value::Array(arr)) => arr[i].as_object(),
+                    (true, Value::Object(o)) => Some(o),
+                    _ => None,
+                }.map(|input| {
+                    let mut keys: Vec<String> = Vec::with_capacity(input.len());
+                    for key in input.keys() {
+                        let key = normalize_key(key, &options.canonical);
+                        if !keys.iter().any(|k| *k == key) {
+                            keys.push(key.into_owned());
+                        }
+                    }
+                    keys
+                }).unwrap_or_else(|| key_set.clone());
+                // Infer types from the first record of this shape
+                let types = keys.iter().map(|k| {
+                    infer_type(obj.get(k).unwrap_or(&Value::Null))
+                }).collect();
+                let is_array = vec![false; keys.len()];
+                shapes.push(TextShape { key_set, keys, types, is_array });
+                shapes.len() - 1
+            }
+        };
+
+        // Determine array fields across all records of the shape
+        let shape = &mut shapes[idx];
+        for (i, key) in shape.keys.iter().enumerate() {
+            if let Some(Value::Array(_)) = obj.get(key) {
+                shape.is_array[i] = true;
+            }
+        }
+        shaped_records.push((idx, obj));
+    }
+
+    // Version 2 reads bare values in str fields as strings, so a str field
+    // that also holds numbers or booleans must fall back to syntax-directed parsing
+    if options.type_directed {
+        for (idx, obj) in &shaped_records {
+            let shape = &mut shapes[*idx];
+            for (i, key) in shape.keys.iter().enumerate() {
+                if shape.types[i] == "str" && matches!(obj.get(key), Some(Value::Number(_) | Value::Bool(_))) {
+                    shape.types[i] = "any";
+                }
+            }
+        }
+    }
+
+    // Fields every shape declares identically form the shared base
+    let base_keys: Vec<&String> = if shapes.len() > 1 {
+        shapes[0].keys.iter().filter(|key| {
+            let first = shapes[0].field(key);
+            shapes[1..].iter().all(|s| s.field(key) == first)
+        }).collect()
+    } else {
+        Vec::new()
+    };
+
+    // 2. Build dictionary (strings appearing ≥2 times as values)
+    let dict_entries = text_dictionary(shaped_records.iter().map(|(_, obj)| *obj), options);
+    let dict_map: HashMap<String, usize> = dict_entries
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (s.clone(), i))
+        .collect();
+
+    // 3. Build output
+    let mut out = String::new();
+
+    // Directives
+    out.push_str(&format!("@version {}\n", version));
+    if let Some(enc) = &options.encoding {
+        out.push_str(&format!("@encoding {}\n", enc));
+    }
+
+    // Schema lines: @schema <name> [extends <base>] field:type field:type[]
+    let schema_names: Vec<String> = if shapes.len() == 1 {
+        vec!["data".to_string()]
+    } else {
+        (1..=shapes.len()).map(|i| format!("data{}", i)).collect()
+    };
+    let base_name = "base";
+    if !base_keys.is_empty() {
+        let base = &shapes[0];
+        let fields = base_keys.iter().map(|key| {
+            let (type_str, is_array) = base.field(key).unwrap();
+            (*key, type_str, is_array)
+        });
+        push_schema_line(&mut out, base_name, None, fields);
+    }
+    for (shape, name) in shapes.iter().zip(&schema_names) {
+        let extends = if base_keys.is_empty() { None } else { Some(base_name) };
+        let fields = (0..shape.keys.len())
+            .filter(|&i| !base_keys.contains(&&shape.keys[i]))
+            .map(|i| (&shape.keys[i], shape.types[i], shape.is_array[i]));
+        push_schema_line(&mut out, name, extends, fields);
+    }
+
+    push_dict_line(&mut out, &dict_entries);
+
+    // Records
+    out.push('\n');
+    for (idx, obj) in &shaped_records {
+        push_text_record(&mut out, &schema_names[*idx], &shapes[*idx], obj, &dict_map, options);
+    }
+
+    Ok(finish_text(out, options))
+}
+
+/// Field holding a wrapped root; see [`to_text_encodable`].
+pub const WRAPPED_VALUE_FIELD: &str = "value";
+
+/// Coerce `value` into a shape TENS-Text can encode. An object, or an array
+/// of objects (including `[]`), is returned as is. Anything else becomes a
+/// single record whose [`WRAPPED_VALUE_FIELD`] holds it: `42` becomes
+/// `{"value": 42}` and `[1, 2, 3]` becomes `{"value": [1, 2, 3]}`, a repeated
+/// field, so a one-element array stays distinct from a scalar.
+///
+/// Scalars and arrays of scalars round-trip through [`from_text_encodable`];
+/// objects and arrays nested in a wrapped array are written as JSON strings,
+/// as in any other field, and come back as strings.
+pub fn to_text_encodable(value: &Value) -> Value {
+    match value {
+        Value::Object(_) => value.clone(),
+        Value::Array(items) if items.iter().all(Value::is_object) => value.clone(),
+        _ => serde_json::json!({ WRAPPED_VALUE_FIELD: value }),
+    }
+}
+
+/// Undo [`to_text_encodable`] on a decoded document: a lone object whose only
+/// field is [`WRAPPED_VALUE_FIELD`] gives back that field's value. A document
+/// that was such an object before encoding is unwrapped too, so only apply
+/// this to documents written with [`TextOptions::wrap_scalars`].
+pub fn from_text_encodable(value: Value) -> Value {
+    match value {
+        Value::Object(mut obj) if obj.len() == 1 && obj.contains_key(WRAPPED_VALUE_FIELD) => {
+            obj.remove(WRAPPED_VALUE_FIELD).unwrap()
+        }
+        other => other,
+    }
+}
+
+/// Encode several tables, each a list of records under its own schema name,
+/// as one TENS-Text document with one `@dict` shared by all of them: strings
+/// repeated anywhere across the tables are stored once. Each table gets a
+/// single `@schema` declaring the union of its records' keys, typed from the
+/// first record holding each key. Tables without records are left out, as
+/// they would not survive decoding. Read back with [`decode_tens_text_tables`].
+pub fn encode_tens_text_tables(tables: &BTreeMap<String, Vec<Value>>, options: &TextOptions) -> Result<String, String> {
+    let version = if options.type_directed { 2 } else { 1 };
+    let mut canonical: Vec<(&String, Vec<Value>)> = Vec::new();
+    for (name, records) in tables.iter().filter(|(_, records)| !records.is_empty()) {
+        if name.is_empty() || name.contains(char::is_whitespace) || name.starts_with(['@', '#'])
+            || RESERVED_SCHEMA_NAMES.contains(&name.as_str())
+        {
+            return Err(format!("'{}' is not a valid schema name", name));
+        }
+        let records: Vec<Value> = records.iter().map(|record| canonicalize_with(record, &options.canonical)).collect();
+        if !records.iter().all(Value::is_object) {
+            return Err(format!("table '{}': records must be objects", name));
+        }
+        canonical.push((name, records));
+    }
+
+    let mut shapes = Vec::new();
+    for (_, records) in &canonical {
+        let objects = || records.iter().filter_map(Value::as_object);
+        let keys: Vec<String> = objects().flat_map(|obj| obj.keys().cloned()).collect::<BTreeSet<_>>().into_iter().collect();
+        let types = keys.iter().map(|key| {
+            let mut values = objects().filter_map(|obj| obj.get(key));
+            let first = infer_type(values.next().unwrap_or(&Value::Null));
+            if options.type_directed && first == "str" && values.any(|v| matches!(v, Value::Number(_) | Value::Bool(_))) {
+                "any"
+            } else {
+                first
+            }
+        }).collect();
+        let is_array = keys.iter().map(|key| objects().any(|obj| obj.get(key).is_some_and(Value::is_array))).collect();
+        shapes.push(TextShape { key_set: keys.clone(), keys, types, is_array });
+    }
+
+    let dict_entries = text_dictionary(canonical.iter().flat_map(|(_, records)| records.iter().filter_map(Value::as_object)), options);
+    let dict_map: HashMap<String, usize> = dict_entries.iter().enumerate().map(|(i, s)| (s.clone(), i)).collect();
+
+    let mut out = format!("@version {}\n", version);
+    if let Some(enc) = &options.encoding {
+        out.push_str(&format!("@encoding {}\n", enc));
+    }
+    for ((name, _), shape) in canonical.iter().zip(&shapes) {
+        let fields = (0..shape.keys.len()).map(|i| (&shape.keys[i], shape.types[i], shape.is_array[i]));
+        push_schema_line(&mut out, name, None, fields);
+    }
+    push_dict_line(&mut out, &dict_entries);
+
+    out.push('\n');
+    for ((name, records), shape) in canonical.iter().zip(&shapes) {
+        for obj in records.iter().filter_map(Value::as_object) {
+            push_text_record(&mut out, name, shape, obj, &dict_map, options);
+        }
+    }
+    Ok(finish_text(out, options))
+}
+
+/// The `@dict` entries for `records`: string values occurring at least twice
+/// outside `no_dict_fields`, sorted.
+fn text_dictionary<'a>(records: impl Iterator<Item = &'a Map<String, Value>>, options: &TextOptions) -> Vec<String> {
+    let mut string_counts: HashMap<&str, usize> = HashMap::new();
+    for obj in records {
+        for (key, val) in obj.iter() {
+            if options.no_dict_fields.contains(key) {
+                continue;
+            }
+            if let Value::String(s) = val {
+                *string_counts.entry(s.as_str()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut dict_entries: Vec<String> = string_counts
+        .into_iter()
+        .filter(|&(_, count)| count >= 2)
+        .map(|(s, _)| s.to_string())
+        .collect();
+    dict_entries.sort();
+    dict_entries
+}
+
+/// Append the `@dict` line, if there are entries.
+fn push_dict_line(out: &mut String, dict_entries: &[String]) {
+    if dict_entries.is_empty() {
+        return;
+    }
+    out.push_str("@dict");
+    for entry in dict_entries {
+        if needs_quoting(entry) {
+            out.push_str(&format!(" {}", quote_string(entry)));
+        } else {
+            out.push_str(&format!(" {}", entry));
+        }
+    }
+    out.push('\n');
+}
+
+/// Append one record of schema `name`: the name line, a line per field (per
+/// element for array fields) and, if enabled, its `@checksum`.
+fn push_text_record(
+    out: &mut String,
+    name: &str,
+    shape: &TextShape,
+    obj: &Map<String, Value>,
+    dict_map: &HashMap<String, usize>,
+    options: &TextOptions,
+) {
+    let no_dict = HashMap::new();
+    let record_start = out.len();
+    out.push_str(&format!("{}\n", name));
+    for (i, key) in shape.keys.iter().enumerate() {
+        let dict_map = if options.no_dict_fields.contains(key) { &no_dict } else { dict_map };
+        if let Some(val) = obj.get(key) {
+            if shape.is_array[i] {
+                if let Value::Array(arr) = val {
+                    for item in arr {
+                        out.push_str(&format!("  {} {}\n", key, format_tens_text_value(item, dict_map, false)));
+                    }
+                }
+            } else {
+                let str_field = options.type_directed && shape.types[i] == "str";
+                out.push_str(&format!("  {} {}\n", key, format_tens_text_value(val, dict_map, str_field)));
+            }
+        }
+    }
+    if options.record_checksums {
+        let checksum = record_checksum(out[record_start..].lines());
+        out.push_str(&format!("@checksum {}\n", checksum));
+    }
+}
+
+/// The `@checksum` of a TENS-Text record: the first 8 hex digits of the
+/// SHA-256 of its lines (the record name line, then its field lines), each
+/// trimmed and joined with `\n`, so indentation and line endings don't count.
+pub fn record_checksum<'a>(lines: impl IntoIterator<Item = &'a str>) -> String {
+    let text: Vec<&str> = lines.into_iter().map(str::trim).collect();
+    let mut hash = hash_tens_binary(text.join("\n").as_bytes());
+    hash.truncate(8);
+    hash
+}
+
+/// A schema declared by an `@schema` directive; see [`parse_tens_text_schemas`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TensTextSchema {
+    /// (name, type) pairs, inherited fields first
+    fields: Vec<(String, String)>,
+    array_fields: std::collections::HashSet<String>,
+}
+
+impl TensTextSchema {
+    /// `(name, type)` pairs, inherited fields first. Array fields carry their
+    /// element type, without the `[]`.
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    /// Whether `field` was declared with `[]`.
+    pub fn is_array(&self, field: &str) -> bool {
+        self.array_fields.contains(field)
+    }
+}
+
+/// Directive words, which a schema may not be named after: its record-start
+/// lines would read like a directive missing its `@`.
+const RESERVED_SCHEMA_NAMES: [&str; 5] = ["version", "schema", "dict", "encoding", "meta"];
+
+/// Parse the body of an `@schema <name> [extends <base>] field:type ...`
+/// line against the schemas declared before it. Schema names that are
+/// reserved words, or start with `@` or `#` (directive and comment lines),
+/// are rejected.
+fn parse_schema_directive(
+    line: &str,
+    schemas: &HashMap<String, TensTextSchema>,
+) -> Result<(String, TensTextSchema), String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return Err(format!("malformed @schema directive '{}'", line));
+    }
+    if RESERVED_SCHEMA_NAMES.contains(&parts[1]) || parts[1].starts_with(['@', '#']) {
+        return Err(format!("schema name '{}' is reserved", parts[1]));
+    }
+    let mut fields = &parts[2..];
+    // @schema <child> extends <base> → base fields first
+    let mut schema = match fields {
+        ["extends", base, rest @ ..] => {
+            fields = rest;
+            schemas.get(*base).cloned()
+                .ok_or_else(|| format!("schema '{}' extends unknown schema '{}'", parts[1], base))?
+        }
+        _ => TensTextSchema::default(),
+    };
+    for part in fields {
+        // Type labels never contain ':', so a field name may (as TS lastIndexOf)
+        if let Some((name, type_str)) = part.rsplit_once(':') {
+            if type_str.ends_with("[]") {
+                schema.array_fields.insert(name.to_string());
+                schema.fields.push((name.to_string(), type_str.trim_end_matches("[]").to_string()));
+            } else {
+                schema.fields.push((name.to_string(), type_str.to_string()));
+            }
+        }
+    }
+    Ok((parts[1].to_string(), schema))
+}
+
+/// The schemas declared in a TENS-Text document, by name, without decoding
+/// its records. Errors are prefixed with the line number as in
+/// [`decode_tens_text`].
+pub fn parse_tens_text_schemas(input: &str) -> Result<HashMap<String, TensTextSchema>, String> {
+    let mut schemas = HashMap::new();
+    for (index, line) in input.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.split_whitespace().next() == Some("@schema") {
+            let (name, schema) = parse_schema_directive(trimmed, &schemas)
+                .map_err(|msg| format!("line {}: {}", index + 1, msg))?;
+            schemas.insert(name, schema);
+        }
+    }
+    Ok(schemas)
+}
+
+/// Decode TENS-Text format back into a JSON Value (array of objects).
+///
+/// Errors are prefixed with the 1-based line number, e.g.
+/// `line 12: unknown directive '@foo'`. Unlike the TS decoder, unknown
+/// directives are rejected rather than skipped.
+///
+/// `@version 1` (also the default without the directive) parses every value by
+/// its syntax: bare `42` is a number wherever it appears. `@version 2` makes
+/// scalar `str` fields type-directed: any bare value other than `_` and `@N`
+/// refs is taken as a string.
+///
+/// # Corrupt records
+/// A record whose `@checksum` does not match is **dropped without an
+/// error**: the result simply lacks it, so a file with one corrupt line still
+/// decodes. Use [`decode_tens_text_with_warnings`] to learn which records were
+/// skipped, or [`validate_text`] to find them before decoding.
+pub fn decode_tens_text(input: &str) -> Result<Value, String> {
+    decode_tens_text_with(input, &CanonicalOptions::default())
+}
+
+/// [`decode_tens_text`] with the float specials in `num` fields handled per
+/// `options.non_finite_floats`, as [`TensDecoder::with_non_finite_floats`]
+/// handles non-finite FLOAT64 payloads. Like it, silently drops records that
+/// fail their `@checksum`.
+pub fn decode_tens_text_with(input: &str, options: &CanonicalOptions) -> Result<Value, String> {
+    decode_tens_text_with_warnings(input, options).map(|(value, _)| value)
+}
+
+/// [`decode_tens_text_with`], also returning a warning per record dropped for
+/// failing its `@checksum`, e.g. `line 9: record 'data' checksum mismatch
+/// (expected 1a2b3c4d, found 5e6f7a8b); skipped`.
+///
+/// A `@checksum` line closes the record before it, so field lines after it
+/// must start a new record. Records without one are taken as they are.
+pub fn decode_tens_text_with_warnings(input: &str, options: &CanonicalOptions) -> Result<(Value, Vec<String>), String> {
+    let (records, warnings) = decode_tens_text_records(input, options)?;
+    let mut records: Vec<Value> = records.into_iter().map(|(_, record)| record).collect();
+    let value = if records.len() == 1 {
+        records.pop().unwrap()
+    } else {
+        Value::Array(records)
+    };
+    Ok((value, warnings))
+}
+
+/// Decode a document from [`encode_tens_text_tables`] back into its tables:
+/// records grouped by the name of their schema, in document order. Records
+/// that fail their `@checksum` are dropped, as by [`decode_tens_text`].
+pub fn decode_tens_text_tables(input: &str) -> Result<BTreeMap<String, Vec<Value>>, String> {
+    let (records, _) = decode_tens_text_records(input, &CanonicalOptions::default())?;
+    let mut tables: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (schema, record) in records {
+        tables.entry(schema).or_default().push(record);
+    }
+    Ok(tables)
+}
+
+/// Decoded records, each with the name of its schema.
+type NamedRecords = Vec<(String, Value)>;
+
+/// The records of a TENS-Text document and the checksum warnings of
+/// [`decode_tens_text_with_warnings`].
+fn decode_tens_text_records(input: &str, options: &CanonicalOptions) -> Result<(NamedRecords, Vec<String>), String> {
+    let mut dict: Vec<String> = Vec::new();
+    let mut schemas: HashMap<String, TensTextSchema> = HashMap::new();
+    let mut records: NamedRecords = Vec::new();
+    let mut current_record: Option<(Map<String, Value>, String)> = None;
+    // Lines of the current record, for its checksum
+    let mut record_lines: Vec<&str> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut version = 1;
+
+    for (index, line) in input.lines().enumerate() {
+        let line_error = |msg: String| format!("line {}: {}", index + 1, msg);
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let directive = trimmed.split_whitespace().next().unwrap_or("");
+        match directive {
+            "@version" => {
+                version = match trimmed.split_whitespace().nth(1) {
+                    Some("1") => 1,
+                    Some("2") => 2,
+                    v => return Err(line_error(format!("unsupported @version {}", v.unwrap_or("(missing)")))),
+                };
+                continue;
+            }
+            "@encoding" => continue,
+            "@schema" => {
+                let (name, schema) = parse_schema_directive(trimmed, &schemas).map_err(line_error)?;
+                schemas.insert(name, schema);
+                continue;
+            }
+            "@dict" => {
+                dict = parse_dict_line(trimmed);
+                continue;
+            }
+            "@checksum" => {
+                let Some((rec, schema_name)) = current_record.take() else {
+                    return Err(line_error("@checksum outside of a record".into()));
+                };
+                let expected = trimmed.split_whitespace().nth(1).unwrap_or("");
+                let found = record_checksum(record_lines.drain(..));
+                if found.eq_ignore_ascii_case(expected) {
+                    records.push((schema_name, Value::Object(rec)));
+                } else {
+                    warnings.push(line_error(format!(
+                        "record '{}' checksum mismatch (expected {}, found {}); skipped",
+                        schema_name, expected, found
+                    )));
+                }
+                continue;
+            }
+            d if d.starts_with('@') => {
+                return Err(line_error(format!("unknown directive '{}'", d)));
+            }
+            _ => {}
+        }
+
+        // Schema name line → start new record
+        if schemas.contains_key(trimmed) {
+            if let Some((rec, schema_name)) = current_record.take() {
+                records.push((schema_name, Value::Object(rec)));
+            }
+            current_record = Some((Map::new(), trimmed.to_string()));
+            record_lines.clear();
+            record_lines.push(trimmed);
+            continue;
+        }
+
+        // Field line (indented)
+        if !line.starts_with("  ") {
+            continue;
+        }
+        record_lines.push(trimmed);
+        let Some((rec, schema_name)) = current_record.as_mut() else {
+            return Err(line_error(format!("field line outside of a record '{}'", trimmed)));
+        };
+        let Some((field_name, raw_value)) = trimmed.split_once(char::is_whitespace) else {
+            return Err(line_error(format!("malformed field line '{}'", trimmed)));
+        };
+        let raw_value = raw_value.trim();
+        let schema = &schemas[schema_name.as_str()];
+        let field_type = schema.fields.iter()
+            .find(|(name, _)| name == field_name)
+            .map(|(_, type_str)| type_str.as_str());
+        let is_array = schema.array_fields.contains(field_name);
+        let parsed = match raw_value {
+            // Version 2: bare text in a scalar str field is the string itself
+            s if version >= 2 && field_type == Some("str") && !is_array
+                && s != "_" && !s.starts_with('"') && !s.starts_with('@') && !s.starts_with("b:") => Value::String(s.to_string()),
+            s => parse_tens_text_value(s, &dict),
+        };
+        let parsed = if field_type == Some("num") && is_float_special(&parsed) {
+            options.non_finite_floats.value(parsed.as_str().unwrap().parse().unwrap())
+        } else {
+            parsed
+        };
+
+        if is_array {
+            let arr = rec.entry(field_name.to_string())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(a) = arr {
+                a.push(parsed);
+            }
+        } else {
+            rec.insert(field_name.to_string(), parsed);
+        }
+    }
+
+    // Flush last record
+    if let Some((rec, schema_name)) = current_record.take() {
+        records.push((schema_name, Value::Object(rec)));
+    }
+    Ok((records, warnings))
+}
+
+/// Parse TENS-Text @dict line into list of entries.
+fn parse_dict_line(line: &str) -> Vec<String> {
+    let content = line.strip_prefix("@dict").unwrap_or("").trim();
+    let mut entries = Vec::new();
+    let mut chars = content.chars().peekable();
+
+    while chars.peek().is_some() {
+        // Skip whitespace
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        if chars.peek() == Some(&'"') {
+            // Quoted string
+            chars.next(); // consume opening quote
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => {
+                        match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('r') => s.push('\r'),
+                            Some('t') => s.push('\t'),
+                            Some(c) => s.push(c),
+                            None => break,
+                        }
+                    }
+                    Some('"') => break,
+                    Some(c) => s.push(c),
+                    None => break,
+                }
+            }
+            entries.push(s);
+        } else {
+            // Unquoted token
+            let mut s = String::new();
+            while chars.peek().map(|c| !c.is_whitespace()).unwrap_or(false) {
+                s.push(chars.next().unwrap());
+            }
+            entries.push(s);
+        }
+    }
+
+    entries
+}
+
+/// Whether a parsed value is one of the quoted float specials
+/// (`"NaN"`, `"Infinity"`, `"-Infinity"`) the TS encoder emits.
+///
+/// JSON has no representation for them, so in a `num` field they decode per
+/// [`NonFiniteFloats`] (null by default), as non-finite FLOAT64 payloads do in
+/// binary. In any other field they are ordinary strings.
+fn is_float_special(value: &Value) -> bool {
+    matches!(value.as_str(), Some("NaN" | "Infinity" | "-Infinity"))
+}
+
+/// Parse a single TENS-Text value string.
+fn parse_tens_text_value(raw: &str, dict: &[String]) -> Value {
+    match raw {
+        "_" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        s if s.starts_with('@') => {
+            if let Ok(idx) = s[1..].parse::<usize>() {
+                if idx < dict.len() {
+                    return Value::String(dict[idx].clone());
+                }
+            }
+            Value::String(s.to_string())
+        }
+        s if s.starts_with("b:") && base64_decode(&s[2..]).is_some() => {
+            let mut map = Map::new();
+            map.insert(BYTES_TAG.to_string(), Value::String(s[2..].to_string()));
+            Value::Object(map)
+        }
+        s if s.starts_with('"') && s.ends_with('"') => {
+            // Unquote
+            let inner = &s[1..s.len() - 1];
+            let mut result = String::new();
+            let mut chars = inner.chars();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('n') => result.push('\n'),
+                        Some('r') => result.push('\r'),
+                        Some('t') => result.push('\t'),
+                        Some(c) => result.push(c),
+                        None => break,
+                    },
+                    Some(c) => result.push(c),
+                    None => break,
+                }
+            }
+            // Float specials stay strings here; see `is_float_special`
+            Value::String(result)
+        }
+        s => {
+            // Try parsing as number
+            if let Ok(i) = s.parse::<i64>() {
+                serde_json::json!(i)
+            } else if let Ok(f) = s.parse::<f64>() {
+                serde_json::json!(f)
+            } else {
+                Value::String(s.to_string())
+            }
+        }
+    }
+}
+
+// ── Binary ↔ TENS-Text Transcoding ──
+
+/// Decode TENS v2 binary and re-emit it as TENS-Text.
+///
+/// The binary root must be an object or an array of objects, the shapes
+/// TENS-Text can represent; anything else fails with
+/// `DecodeError::UnsupportedRoot`. So does an array of exactly one object:
+/// its text is a single record, which decodes as the bare object.
+pub fn binary_to_text(bytes: &[u8], encoding: Option<&str>) -> Result<String, DecodeError> {
+    let value = TensDecoder::new().decode(bytes)?;
+    let reason = match &value {
+        Value::Object(_) => None,
+        Value::Array(items) => match items.iter().find(|item| !item.is_object()) {
+            Some(item) => Some(format!("array holding a {}", json_type_name(item))),
+            None if items.len() == 1 => Some("array of one object, which would read back as the bare object".to_string()),
+            None => None,
+        },
+        scalar => Some(json_type_name(scalar).to_string()),
+    };
+    if let Some(reason) = reason {
+        return Err(DecodeError::UnsupportedRoot { reason });
+    }
+    Ok(encode_tens_text(&value, encoding)?)
+}
+
+/// Parse TENS-Text and encode it as TENS v2 binary.
+///
+/// A text document with a single record decodes to a bare object, so it
+/// encodes as an object root rather than a one-element array.
+pub fn text_to_binary(text: &str) -> Result<Vec<u8>, String> {
+    let value = decode_tens_text(text)?;
+    TensEncoder::new().try_encode(&value).map_err(|e| e.to_string())
+}
+
+// ── TENS-Text Schema Validation ──
+
+/// One way a value fails to conform to a [`TensTextSchema`]. Paths name the
+/// field as `name`, prefixed with `[i].` for the records of an array and
+/// suffixed with `[j]` for array elements; the root itself is `""`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaViolation {
+    /// The record has a field the schema does not declare.
+    UnexpectedField { path: String },
+    /// The schema declares a field the record lacks.
+    MissingField { path: String },
+    /// The value's JSON type does not match the declared type.
+    TypeMismatch { path: String, expected: String, found: &'static str },
+    /// A field declared with `[]` holds a single value.
+    ScalarWhereArray { path: String },
+}
+
+/// Check that `value`, a record or an array of records, conforms to `schema`,
+/// collecting every violation rather than stopping at the first.
+///
+/// Null conforms to every type, as TENS-Text writes it `_` in any field;
+/// `any` accepts every scalar, and `str` also accepts objects, which the
+/// encoder writes into `str` fields as JSON. Other type names are unchecked.
+pub fn validate_against_schema(value: &Value, schema: &TensTextSchema) -> Result<(), Vec<SchemaViolation>> {
+    let mut violations = Vec::new();
+    match value {
+        Value::Array(records) => {
+            for (i, record) in records.iter().enumerate() {
+                validate_record(record, schema, &format!("[{}]", i), &mut violations);
+            }
+        }
+        record => validate_record(record, schema, "", &mut violations),
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+fn validate_record(record: &Value, schema: &TensTextSchema, path: &str, violations: &mut Vec<SchemaViolation>) {
+    let Value::Object(obj) = record else {
+        violations.push(SchemaViolation::TypeMismatch {
+            path: path.to_string(),
+            expected: "object".into(),
+            found: json_type_name(record),
+        });
+        return;
+    };
+    let field_path = |name: &str| if path.is_empty() { name.to_string() } else { format!("{}.{}", path, name) };
+
+    for key in obj.keys() {
+        if !schema.fields.iter().any(|(name, _)| name == key) {
+            violations.push(SchemaViolation::UnexpectedField { path: field_path(key) });
+        }
+    }
+    for (name, type_str) in &schema.fields {
+        let path = field_path(name);
+        match obj.get(name) {
+            None => violations.push(SchemaViolation::MissingField { path }),
+            Some(Value::Array(items)) if schema.is_array(name) => {
+                for (j, item) in items.iter().enumerate() {
+                    check_field_type(item, type_str, format!("{}[{}]", path, j), violations);
+                }
+            }
+            Some(Value::Null) => {}
+            Some(_) if schema.is_array(name) => violations.push(SchemaViolation::ScalarWhereArray { path }),
+            Some(val) => check_field_type(val, type_str, path, violations),
+        }
+    }
+}
+
+fn check_field_type(value: &Value, type_str: &str, path: String, violations: &mut Vec<SchemaViolation>) {
+    let conforms = match (type_str, value) {
+        (_, Value::Null) => true,
+        ("str", Value::String(_) | Value::Object(_)) => true,
+        ("num", Value::Number(_)) | ("bool", Value::Bool(_)) => true,
+        ("bytes", v) => bytes_tag(v).is_some(),
+        ("any", v) => !v.is_array(),
+        ("str" | "num" | "bool" | "null", _) => false,
+        _ => true,
+    };
+    if !conforms {
+        violations.push(SchemaViolation::TypeMismatch {
+            path,
+            expected: type_str.to_string(),
+            found: json_type_name(value),
+        });
+    }
+}
+
+/// The JSON type of `value`, as named in violation reports.
+fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "num",
+        Value::String(_) => "str",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// ── TENS-Text Linting ──
+
+/// How serious a [`TextDiagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// [`decode_tens_text`] rejects the document, or reads the line as
+    /// something other than what it looks like.
+    Error,
+    /// The document decodes, but probably not as intended (a record dropped
+    /// for a bad checksum, a value overwritten, ...).
+    Warning,
+}
+
+/// One problem found by [`validate_text`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextDiagnostic {
+    /// 1-based line number.
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Check the syntax of a TENS-Text document without decoding its records,
+/// reporting every problem rather than stopping at the first as
+/// [`decode_tens_text`] does: directive order, fields against their record's
+/// schema, `@N` refs against the dictionary, quoting, and record checksums.
+/// A clean document gives no diagnostics.
+pub fn validate_text(input: &str) -> Vec<TextDiagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut schemas: HashMap<String, TensTextSchema> = HashMap::new();
+    let mut dict_len = 0;
+    let mut dict_line = None;
+    let mut seen_content = false;
+    // Schema of the open record and the scalar fields it has set
+    let mut record: Option<(String, BTreeSet<String>)> = None;
+    let mut record_lines: Vec<&str> = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let mut report = |severity: Severity, message: String| {
+            diagnostics.push(TextDiagnostic { line: index + 1, severity, message });
+        };
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let directive = trimmed.split_whitespace().next().unwrap_or("");
+        match directive {
+            "@version" => {
+                if !matches!(trimmed.split_whitespace().nth(1), Some("1" | "2")) {
+                    report(Severity::Error, format!("unsupported {}", trimmed));
+                }
+                if seen_content {
+                    report(Severity::Warning, "@version after other directives or records".into());
+                }
+                continue;
+            }
+            "@encoding" => {}
+            "@schema" => match parse_schema_directive(trimmed, &schemas) {
+                Ok((name, schema)) => {
+                    if schemas.contains_key(&name) {
+                        report(Severity::Warning, format!("schema '{}' redeclared", name));
+                    }
+                    schemas.insert(name, schema);
+                }
+                Err(msg) => report(Severity::Error, msg),
+            },
+            "@dict" => {
+                if let Some(previous) = dict_line {
+                    report(Severity::Warning, format!("@dict replaces the @dict on line {}", previous));
+                }
+                if let Err(msg) = check_dict_quoting(trimmed) {
+                    report(Severity::Error, msg);
+                }
+                dict_len = parse_dict_line(trimmed).len();
+                dict_line = Some(index + 1);
+            }
+            "@checksum" => {
+                if record.take().is_none() {
+                    report(Severity::Error, "@checksum outside of a record".into());
+                } else {
+                    let expected = trimmed.split_whitespace().nth(1).unwrap_or("");
+                    let found = record_checksum(record_lines.drain(..));
+                    if !found.eq_ignore_ascii_case(expected) {
+                        report(Severity::Warning, format!(
+                            "checksum mismatch (expected {}, found {}); the record is skipped",
+                            expected, found
+                        ));
+                    }
+                }
+            }
+            d if d.starts_with('@') => report(Severity::Error, format!("unknown directive '{}'", d)),
+            _ if schemas.contains_key(trimmed) => {
+                record = Some((trimmed.to_string(), BTreeSet::new()));
+                record_lines.clear();
+                record_lines.push(trimmed);
+            }
+            _ if !line.starts_with("  ") => {
+                report(Severity::Error, format!("'{}' is neither a declared schema nor an indented field", trimmed));
+            }
+            _ => {
+                record_lines.push(trimmed);
+                let Some((schema_name, set_fields)) = record.as_mut() else {
+                    report(Severity::Error, format!("field line outside of a record '{}'", trimmed));
+                    continue;
+                };
+                let Some((field_name, raw_value)) = trimmed.split_once(char::is_whitespace) else {
+                    report(Severity::Error, format!("malformed field line '{}'", trimmed));
+                    continue;
+                };
+                let schema = &schemas[schema_name.as_str()];
+                if !schema.fields.iter().any(|(name, _)| name == field_name) {
+                    report(Severity::Error, format!("field '{}' is not in schema '{}'", field_name, schema_name));
+                } else if !schema.is_array(field_name) && !set_fields.insert(field_name.to_string()) {
+                    report(Severity::Warning, format!("field '{}' set twice; the last value wins", field_name));
+                }
+                if let Err(msg) = check_text_value(raw_value.trim(), dict_len) {
+                    report(Severity::Error, msg);
+                }
+            }
+        }
+        seen_content = true;
+    }
+    diagnostics
+}
+
+/// Check one field value: quoted strings must close exactly at its end, and
+/// `@N` refs must fall inside a `dict_len`-entry dictionary.
+fn check_text_value(raw: &str, dict_len: usize) -> Result<(), String> {
+    if raw.starts_with('"') {
+        return match quoted_len(raw) {
+            Some(len) if len == raw.len() => Ok(()),
+            Some(_) => Err(format!("text after the closing quote in {}", raw)),
+            None => Err(format!("unterminated quoted string {}", raw)),
+        };
+    }
+    match raw.strip_prefix('@').map(str::parse::<usize>) {
+        Some(Ok(id)) if id >= dict_len => {
+            Err(format!("dictionary ref {} out of range (dict has {} entries)", raw, dict_len))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that every quoted entry of an `@dict` line is closed and followed
+/// by whitespace or the end of the line.
+fn check_dict_quoting(line: &str) -> Result<(), String> {
+    let mut rest = line.strip_prefix("@dict").unwrap_or("").trim_start();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('"') {
+            let len = quoted_len(rest).ok_or_else(|| format!("unterminated quoted dictionary entry {}", rest))?;
+            if rest[len..].starts_with(|c: char| !c.is_whitespace()) {
+                return Err(format!("text after the closing quote in dictionary entry {}", rest));
+            }
+            len
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        rest = rest[end..].trim_start();
+    }
+    Ok(())
+}
+
+/// Byte length of the quoted string at the start of `s`, through its closing
+/// quote, or `None` if it is never closed.
+fn quoted_len(s: &str) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next()?;
+            }
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// ── TENS-Text Diff ──
+
+/// A difference between the records at one position in two TENS-Text documents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordDiff {
+    /// Only the second document has a record at `index`.
+    Added { index: usize, record: Value },
+    /// Only the first document has a record at `index`.
+    Removed { index: usize, record: Value },
+    /// Both documents have a record at `index`, with these field differences.
+    Changed { index: usize, fields: Vec<FieldDiff> },
+}
+
+/// A difference in one field of a record present in both documents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldDiff {
+    Added { field: String, value: Value },
+    Removed { field: String, value: Value },
+    Changed { field: String, old: Value, new: Value },
+}
+
+/// Compare two TENS-Text documents record by record (matched by position).
+///
+/// Both sides are decoded first, so schema field order, dictionary layout and
+/// `@N` refs versus inline strings make no difference; only the data does.
+/// Fields are reported in sorted order. Empty when the data is identical.
+pub fn text_diff(a: &str, b: &str) -> Result<Vec<RecordDiff>, String> {
+    let old = text_records(decode_tens_text(a)?);
+    let new = text_records(decode_tens_text(b)?);
+
+    let mut diffs = Vec::new();
+    for index in 0..old.len().max(new.len()) {
+        match (old.get(index), new.get(index)) {
+            (Some(old), Some(new)) => {
+                let fields = field_diffs(old, new);
+                if !fields.is_empty() {
+                    diffs.push(RecordDiff::Changed { index, fields });
+                }
+            }
+            (Some(old), None) => diffs.push(RecordDiff::Removed { index, record: old.clone() }),
+            (None, Some(new)) => diffs.push(RecordDiff::Added { index, record: new.clone() }),
+            (None, None) => unreachable!(),
+        }
+    }
+    Ok(diffs)
+}
+
+/// The records of a decoded TENS-Text document, which is a bare object when
+/// it holds exactly one.
+fn text_records(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(records) => records,
+        record => vec![record],
+    }
+}
+
+fn field_diffs(old: &Value, new: &Value) -> Vec<FieldDiff> {
+    let empty = Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let fields: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    fields.into_iter()
+        .filter_map(|field| match (old.get(field), new.get(field)) {
+            (Some(o), Some(n)) if o == n => None,
+            (Some(o), Some(n)) => Some(FieldDiff::Changed { field: field.clone(), old: o.clone(), new: n.clone() }),
+            (Some(o), None) => Some(FieldDiff::Removed { field: field.clone(), value: o.clone() }),
+            (None, Some(n)) => Some(FieldDiff::Added { field: field.clone(), value: n.clone() }),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+// ── TENS-Text File IO ──
+
+/// Read a TENS-Text file and decode it. A leading UTF-8 BOM is stripped.
+#[cfg(feature = "std")]
+pub fn read_tens_text_file<P: AsRef<std::path::Path>>(path: P) -> Result<Value, String> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    decode_tens_text(text.strip_prefix('\u{feff}').unwrap_or(&text))
+}
+
+/// Encode a value as TENS-Text with `options` and write it to a file
+/// (UTF-8, no BOM).
+#[cfg(feature = "std")]
+pub fn write_tens_text_file<P: AsRef<std::path::Path>>(
+    path: P,
+    value: &Value,
+    options: &TextOptions,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let text = encode_tens_text_with(value, options)?;
+    std::fs::write(path, text)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// ── SHA-256 Hashing ──
+
+use sha2::{Sha256, Digest};
+
+/// Compute SHA-256 hex hash of TENS binary bytes (matches TS hashing.ts).
+pub fn hash_tens_binary(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    let result = hasher.finalize();
+    hex_encode(&result)
+}
+
+/// A value's canonical TENS encoding, for keying maps on content identity:
+/// equal values (after canonicalization) encode to equal bytes, so they
+/// compare and hash equal without computing a SHA-256.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CanonicalBytes(Vec<u8>);
+
+impl CanonicalBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The content hash, as [`hash_tens_binary`].
+    pub fn hash_hex(&self) -> String {
+        hash_tens_binary(&self.0)
+    }
+}
+
+impl From<&Value> for CanonicalBytes {
+    /// # Panics
+    /// Under the same conditions as [`TensEncoder::encode`].
+    fn from(value: &Value) -> Self {
+        CanonicalBytes(TensEncoder::new().encode(value))
+    }
+}
+
+/// A rolling SHA-256 over a sequence of documents, for checking a log of
+/// values against one digest. Equals [`hash_tens_binary`] of the values'
+/// canonical encodings concatenated in order; each encoding is
+/// self-delimiting, so distinct sequences cannot feed the same bytes.
+pub struct TensHasher {
+    hasher: Sha256,
+    encoder: TensEncoder,
+}
+
+impl TensHasher {
+    pub fn new() -> Self {
+        TensHasher { hasher: Sha256::new(), encoder: TensEncoder::new() }
+    }
+
+    /// Append `value`'s canonical encoding to the digest.
+    ///
+    /// # Panics
+    /// Under the same conditions as [`TensEncoder::encode`].
+    pub fn update_value(&mut self, value: &Value) {
+        self.hasher.update(self.encoder.encode(value));
+    }
+
+    /// Hex digest of every value fed so far, in order.
+    pub fn finalize(self) -> String {
+        hex_encode(&self.hasher.finalize())
+    }
+}
+
+impl Default for TensHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ── Tests ──
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::utils::{encode_varint, decode_varint, try_decode_varint, encode_varint_i64, decode_varint_i64};
+
+    // ── Varint tests ──
+
+    #[test]
+    fn test_varint_single_byte() {
+        let encoded = encode_varint(0);
+        assert_eq!(encoded, vec![0]);
+        let (val, len) = decode_varint(&encoded);
+        assert_eq!(val, 0);
+        assert_eq!(len, 1);
+    }
+
+    #[test]
+    fn test_varint_127() {
+        let encoded = encode_varint(127);
+        assert_eq!(encoded, vec![127]);
+        let (val, _) = decode_varint(&encoded);
+        assert_eq!(val, 127);
+    }
+
+    #[test]
+    fn test_varint_128() {
+        let encoded = encode_varint(128);
+        assert_eq!(encoded, vec![0x80, 0x01]);
+        let (val, len) = decode_varint(&encoded);
+        assert_eq!(val, 128);
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn test_varint_300() {
+        let encoded = encode_varint(300);
+        let (val, _) = decode_varint(&encoded);
+        assert_eq!(val, 300);
+    }
+
+    #[test]
+    fn test_varint_large() {
+        let encoded = encode_varint(100_000);
+        let (val, _) = decode_varint(&encoded);
+        assert_eq!(val, 100_000);
+    }
+
+    #[test]
+    fn test_varint_overlong() {
+        // Continuation bits past the fifth byte used to overflow the shift
+        assert_eq!(decode_varint(&[0xFF; 10]), (u32::MAX, 5));
+        assert_eq!(decode_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).1, 5);
+        let mut bytes = b"TENS\x02\x00".to_vec();
+        for op in [OP_ARRAY_START, OP_OBJECT_START, OP_STRING_REF, OP_INT_ARRAY] {
+            bytes.truncate(6);
+            bytes.push(op);
+            bytes.extend_from_slice(&[0xFF; 12]);
+            assert!(TensDecoder::new().decode(&bytes).is_err(), "opcode {:#04x}", op);
+        }
+    }
+
+    #[test]
+    fn test_try_decode_varint() {
+        assert_eq!(try_decode_varint(&encode_varint(300)), Some((300, 2)));
+        assert_eq!(try_decode_varint(&encode_varint(u32::MAX)), Some((u32::MAX, 5)));
+        assert_eq!(try_decode_varint(&[]), None);
+        assert_eq!(try_decode_varint(&[0x80]), None);
+        assert_eq!(try_decode_varint(&[0xFF, 0xFF, 0xFF, 0xFF, 0x10]), None);
+        assert_eq!(try_decode_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]), None);
+    }
+
+    #[test]
+    fn test_decode_malformed_dictionary_varint() {
+        let mut dec = TensDecoder::new();
+        assert_eq!(dec.decode(b"TENS\x02\x80"), Err(DecodeError::MalformedVarint { at: 5 }));
+        // A string length cut off after the count
+        assert_eq!(dec.decode(b"TENS\x02\x02\x01a\xFF"), Err(DecodeError::MalformedVarint { at: 8 }));
+        // Too long for a u32
+        assert_eq!(
+            dec.decode(b"TENS\x02\xFF\xFF\xFF\xFF\x7F"),
+            Err(DecodeError::MalformedVarint { at: 5 })
+        );
+    }
+
+    // ── Header tests ──
+
+    #[test]
+    fn test_binary_header() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(null));
+        assert_eq!(&bytes[0..5], b"TENS\x02");
+    }
+
+    // ── Null encoding ──
+
+    #[test]
+    fn test_encode_null() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(null));
+        // Header(5) + dict_count varint(1 byte = 0) + OP_NULL(1)
+        assert_eq!(bytes.len(), 7);
+        assert_eq!(bytes[5], 0); // dict count = 0
+        assert_eq!(bytes[6], OP_NULL);
+    }
+
+    // ── Boolean encoding ──
+
+    #[test]
+    fn test_encode_true() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(true));
+        assert_eq!(bytes[6], OP_TRUE);
+    }
+
+    #[test]
+    fn test_encode_false() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(false));
+        assert_eq!(bytes[6], OP_FALSE);
+    }
+
+    // ── Number encoding ──
+
+    #[test]
+    fn test_encode_int8_zero() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(0));
+        assert_eq!(bytes[6], OP_INT8);
+        assert_eq!(bytes[7], 0u8);
+    }
+
+    #[test]
+    fn test_encode_int8_positive() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(42));
+        assert_eq!(bytes[6], OP_INT8);
+        assert_eq!(bytes[7], 42u8);
+    }
+
+    #[test]
+    fn test_encode_int8_negative() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(-1));
+        assert_eq!(bytes[6], OP_INT8);
+        assert_eq!(bytes[7], 0xFFu8); // -1 as i8 = 0xFF
+    }
+
+    #[test]
+    fn test_encode_int8_max() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(127));
+        assert_eq!(bytes[6], OP_INT8);
+        assert_eq!(bytes[7], 127u8);
+    }
+
+    #[test]
+    fn test_encode_int8_min() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(-128));
+        assert_eq!(bytes[6], OP_INT8);
+        assert_eq!(bytes[7], 0x80u8); // -128 as i8 = 0x80
+    }
+
+    #[test]
+    fn test_encode_int32() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(1000));
+        assert_eq!(bytes[6], OP_INT32);
+        let val = i32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+        assert_eq!(val, 1000);
+    }
+
+    #[test]
+    fn test_encode_int32_negative() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(-500));
+        assert_eq!(bytes[6], OP_INT32);
+        let val = i32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+        assert_eq!(val, -500);
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn test_encode_float64() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(3.14));
+        assert_eq!(bytes[6], OP_FLOAT64);
+        let val = f64::from_le_bytes([
+            bytes[7], bytes[8], bytes[9], bytes[10],
+            bytes[11], bytes[12], bytes[13], bytes[14],
+        ]);
+        assert!((val - 3.14).abs() < f64::EPSILON);
+    }
+
+    // ── String encoding ──
+
+    #[test]
+    fn test_encode_string() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!("hello"));
+        // Dict: 1 entry "hello"
+        assert_eq!(bytes[5], 1); // dict count
+        // Dict[0]: varint(5) + "hello"
+        assert_eq!(bytes[6], 5); // string length
+        assert_eq!(&bytes[7..12], b"hello");
+        // Value: STRING_REF + varint(0)
+        assert_eq!(bytes[12], OP_STRING_REF);
+        assert_eq!(bytes[13], 0); // string table index 0
+    }
+
+    #[test]
+    fn test_string_dedup() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(["hello", "hello", "world"]));
+        // Should have 2 dict entries: "hello" and "world"
+        assert_eq!(bytes[5], 2); // dict count
+    }
+
+    #[test]
+    fn test_string_table_max_id() {
+        let mut table = StringTable::with_max_id(1);
+        assert_eq!(table.add("a"), Ok(0));
+        assert_eq!(table.add("b"), Ok(1));
+        assert_eq!(table.add("a"), Ok(0)); // existing entries still resolve
+        assert_eq!(table.add("c"), Err(EncodeError::DictionaryTooLarge { max_id: 1 }));
+    }
+
+    #[test]
+    #[allow(clippy::needless_update)] // further options exist behind features
+    fn test_encode_dictionary_too_large() {
+        let options = EncoderOptions { max_string_ref_id: 2, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        assert!(enc.try_encode(&json!(["a", "b", "c"])).is_ok());
+        assert_eq!(
+            enc.try_encode(&json!(["a", "b", "c", "d"])),
+            Err(EncodeError::DictionaryTooLarge { max_id: 2 })
+        );
+    }
+
+    // ── Array encoding ──
+
+    #[test]
+    fn test_number_policy() {
+        let bytes = TensEncoder::new().encode(&json!(42));
+        assert_eq!(bytes[6..], [OP_INT8, 42]);
+
+        let options = EncoderOptions { number_policy: NumberPolicy::AlwaysFloat64, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&json!(42));
+        assert_eq!(bytes[6], OP_FLOAT64);
+        assert_eq!(bytes[7..], 42.0f64.to_le_bytes());
+        let decoded = TensDecoder::new().decode(&bytes).unwrap();
+        assert!(decoded.is_f64());
+        assert_eq!(decoded, json!(42.0));
+
+        // Applies inside containers too
+        let bytes = enc.encode(&json!([1, -300, 2.5]));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!([1.0, -300.0, 2.5]));
+    }
+
+    #[test]
+    fn test_smallest_integer_policy() {
+        let options = EncoderOptions { number_policy: NumberPolicy::SmallestInteger, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        for n in [128, -129, 32767, -32768] {
+            let bytes = enc.encode(&json!(n));
+            assert_eq!(bytes[6..], [[OP_INT16].as_slice(), &(n as i16).to_le_bytes()].concat(), "{}", n);
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!(n));
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), json!(n));
+            // TS parity by default
+            assert_eq!(TensEncoder::new().encode(&json!(n))[6], OP_INT32);
+        }
+        for (n, opcode) in [(127, OP_INT8), (-128, OP_INT8), (32768, OP_INT32), (-32769, OP_INT32)] {
+            assert_eq!(enc.encode(&json!(n))[6], opcode, "{}", n);
+        }
+
+        let value = json!({"counts": [5, 300, -20000, 100000]});
+        let bytes = enc.encode(&value);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(bytes.len() < TensEncoder::new().encode(&value).len());
+        assert!(hex_dump(&bytes).unwrap().contains("OP_INT16 -20000"));
+        assert_eq!(estimate_decoded_size(&bytes).unwrap(), estimate_decoded_size(&TensEncoder::new().encode(&value)).unwrap());
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x04\x01").is_err());
+    }
+
+    #[test]
+    fn test_smallest_integer_policy_int64() {
+        let options = EncoderOptions { number_policy: NumberPolicy::SmallestInteger, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        for n in [i64::MAX, i64::MIN, 9_007_199_254_740_993, i32::MAX as i64 + 1] {
+            let bytes = enc.encode(&json!(n));
+            assert_eq!(bytes[6..], [[OP_INT64].as_slice(), &n.to_le_bytes()].concat(), "{}", n);
+            let decoded = TensDecoder::new().decode(&bytes).unwrap();
+            assert_eq!(decoded.as_i64(), Some(n));
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap().as_i64(), Some(n));
+            // Past i32 the default policy writes the same
+            assert_eq!(TensEncoder::new().encode(&json!(n)), bytes);
+        }
+
+        let bytes = enc.encode(&json!([i64::MIN, 1]));
+        assert!(hex_dump(&bytes).unwrap().contains(&format!("OP_INT64 {}", i64::MIN)));
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x13\x01\x02").is_err());
+    }
+
+    #[test]
+    fn test_varint_i64() {
+        let mut samples: Vec<i64> = (i16::MIN as i64..=i16::MAX as i64).collect();
+        samples.extend([i64::MIN, i64::MIN + 1, i64::MAX, i64::MAX - 1, i32::MIN as i64, i32::MAX as i64 + 1]);
+        samples.extend((0..63).flat_map(|shift| [1i64 << shift, -(1i64 << shift), (1i64 << shift) - 1]));
+        samples.extend((i64::MIN..=i64::MAX - 0x0123_4567_89AB_CDEF).step_by(0x0123_4567_89AB_CDEF));
+        for n in samples {
+            let bytes = encode_varint_i64(n);
+            assert_eq!(decode_varint_i64(&bytes), Some((n, bytes.len())), "{}", n);
+        }
+        assert_eq!(encode_varint_i64(-64), [0x7F]);
+        assert_eq!(encode_varint_i64(64), [0x80, 0x01]);
+        assert_eq!(encode_varint_i64(i64::MIN).len(), 10);
+        // Truncated, and a tenth byte past the top bit
+        assert_eq!(decode_varint_i64(&[0x80, 0x80]), None);
+        assert_eq!(decode_varint_i64(&[0xFF; 9]), None);
+        assert_eq!(decode_varint_i64(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02]), None);
+    }
+
+    #[test]
+    fn test_varint_policy() {
+        let options = EncoderOptions { number_policy: NumberPolicy::Varint, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        // -16..=15 stay INT8; outside it the payload follows the magnitude
+        assert_eq!(enc.encode(&json!(-1))[6..], [OP_INT8, 0xFF]);
+        assert_eq!(enc.encode(&json!(15))[6..], [OP_INT8, 15]);
+        assert_eq!(enc.encode(&json!(-1000))[6..], [[OP_VARINT].as_slice(), &encode_varint_i64(-1000)].concat());
+        assert_eq!(enc.encode(&json!(-1000)).len() - 6, 3);
+        for n in [-17, 16, 127, -1000, i32::MAX as i64 + 1, 9_007_199_254_740_993, i64::MIN, i64::MAX] {
+            let bytes = enc.encode(&json!(n));
+            assert_eq!(bytes[6], OP_VARINT, "{}", n);
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap().as_i64(), Some(n));
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap().as_i64(), Some(n));
+        }
+        assert_eq!(TensEncoder::new().encode(&json!(-1000))[6], OP_INT32);
+
+        let value = json!({"deltas": [-300, 2, 70000, -5], "total": 69697, "ratio": 0.5});
+        let bytes = enc.encode(&value);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("OP_VARINT 70000"));
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x17\x80").is_err());
+        #[cfg(feature = "std")]
+        assert!(TensDecoder::new().decode_reader(&b"TENS\x02\x00\x17\x80"[..]).is_err());
+    }
+
+    #[test]
+    fn test_small_ints() {
+        let options = EncoderOptions { small_ints: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        for n in SMALL_INTS {
+            let bytes = enc.encode(&json!(n));
+            assert_eq!(bytes[6..], [(n + SMALL_INT_BIAS) as u8], "{}", n);
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap().as_i64(), Some(n));
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap().as_i64(), Some(n));
+        }
+        assert_eq!(enc.encode(&json!(-8))[6], OP_SMALL_INT_FIRST);
+        assert_eq!(enc.encode(&json!(23))[6], OP_SMALL_INT_LAST);
+        assert_eq!(enc.encode(&json!(24))[6..], [OP_INT8, 24]);
+        assert_eq!(enc.encode(&json!(-9))[6..], [OP_INT8, -9i8 as u8]);
+        assert_eq!(enc.encode(&json!(3.0))[6], 3 + SMALL_INT_BIAS as u8);
+        assert_eq!(TensEncoder::new().encode(&json!(3))[6..], [OP_INT8, 3]);
+
+        let value = json!({"counts": [0, 1, 23, 24, -8, -9, 1000], "flag": 1});
+        let bytes = enc.encode(&value);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("OP_SMALL_INT -8"));
+        assert_eq!(estimate_decoded_size(&bytes).unwrap(), estimate_decoded_size(&TensEncoder::new().encode(&value)).unwrap());
+        assert!(TensDecoder::new_strict_canonical().decode(&enc.encode(&json!(5))).is_err());
+    }
+
+    #[test]
+    fn test_float32() {
+        let options = EncoderOptions { float32: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let single = json!(1.1_f32 as f64);
+        let bytes = enc.encode(&single);
+        assert_eq!(bytes[6..], [[OP_FLOAT32].as_slice(), &1.1_f32.to_le_bytes()].concat());
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), single);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), single);
+        assert_eq!(TensEncoder::new().encode(&single)[6], OP_FLOAT64);
+
+        let double = json!(1.123456789012345);
+        let bytes = enc.encode(&double);
+        assert_eq!(bytes[6], OP_FLOAT64);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), double);
+
+        let value = json!({"weights": [0.5, -1.25, 0.1, 7]});
+        let bytes = enc.encode(&value);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("OP_FLOAT32 -1.25"));
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x14\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_raw_bytes() {
+        let options = EncoderOptions { raw_bytes: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let blob: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        for data in [&[][..], &[0xFF], &blob] {
+            let value = bytes_value(data);
+            let bytes = enc.encode(&value);
+            // Empty dictionary, then the opcode, the length varint and the bytes
+            let mut expected = vec![0, OP_BYTES];
+            expected.extend_from_slice(&encode_varint(data.len() as u32));
+            expected.extend_from_slice(data);
+            assert_eq!(bytes[5..], expected, "{} bytes", data.len());
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), value);
+            assert_eq!(estimate_decoded_size(&bytes).unwrap(), estimate_decoded_size(&TensEncoder::new().encode(&value)).unwrap());
+        }
+        assert_eq!(enc.encode(&bytes_value(&blob))[7..9], [0xAC, 0x02]);
+
+        // Nested, in arrays the specialized layouts would otherwise claim
+        let value = json!({"files": [bytes_value(b"abc"), bytes_value(b"de")], "name": "x"});
+        let options = EncoderOptions { raw_bytes: true, columnar_arrays: true, sparse_records: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&value);
+        assert_eq!(enc.string_table_entries(), ["files", "name", "x"]);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("OP_BYTES len=3"));
+
+        // A malformed tag stays an ordinary object
+        let not_base64 = json!({"$bytes": "not base64!"});
+        assert_eq!(TensDecoder::new().decode(&enc.encode(&not_base64)).unwrap(), not_base64);
+        // As does base64 with non-zero padding bits, which would not round-trip
+        let loose = json!({"$bytes": "QR=="});
+        assert_eq!(enc.encode(&loose), TensEncoder::new().encode(&loose));
+        assert_eq!(TensDecoder::new().decode(&enc.encode(&loose)).unwrap(), loose);
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x15\x02\x01").is_err());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let options = EncoderOptions { timestamps: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&json!("2024-01-01T00:00:00Z"));
+        assert_eq!(bytes[5..], [[0, OP_TIMESTAMP].as_slice(), &1_704_067_200_000i64.to_le_bytes()].concat());
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!("2024-01-01T00:00:00Z"));
+        assert!(TensEncoder::new().encode(&json!("2024-01-01T00:00:00Z")).ends_with(&[OP_STRING_REF, 0]));
+
+        for (text, millis) in [
+            ("1970-01-01T00:00:00Z", 0),
+            ("1969-12-31T23:59:59.999Z", -1),
+            ("2024-02-29T12:30:45.250Z", 1_709_209_845_250),
+            ("0000-01-01T00:00:00Z", -62_167_219_200_000),
+            ("9999-12-31T23:59:59.999Z", 253_402_300_799_999),
+        ] {
+            assert_eq!(parse_timestamp(text), Some(millis), "{}", text);
+            let bytes = enc.encode(&json!([text]));
+            assert_eq!(bytes[8], OP_TIMESTAMP, "{}", text);
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!([text]));
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), json!([text]));
+        }
+
+        // Anything but the canonical spelling stays a string, as written
+        let strings = [
+            "hello", "2024-01-01", "2024-01-01T00:00:00.000Z", "2024-01-01T00:00:00+01:00",
+            "2024-01-01t00:00:00z", "2023-02-29T00:00:00Z", "2024-01-01T24:00:00Z", "2024-01-01T00:00:60Z",
+        ];
+        let value = json!({"at": "2024-01-01T00:00:00Z", "notes": strings});
+        let bytes = enc.encode(&value);
+        assert_eq!(enc.string_table_entries().len(), 2 + strings.len());
+        assert!(!enc.string_table_entries().iter().any(|s| s == "2024-01-01T00:00:00Z"));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("OP_TIMESTAMP 1704067200000 2024-01-01T00:00:00Z"));
+        assert_eq!(estimate_decoded_size(&bytes).unwrap(), estimate_decoded_size(&TensEncoder::new().encode(&value)).unwrap());
+
+        let mut far = b"TENS\x02\x00\x16".to_vec();
+        far.extend_from_slice(&i64::MAX.to_le_bytes());
+        assert!(TensDecoder::new().decode(&far).unwrap_err().to_string().contains("outside years 0000-9999"));
+    }
+
+    #[test]
+    fn test_encode_empty_array() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!([]));
+        assert_eq!(bytes[6], OP_ARRAY_START);
+        assert_eq!(bytes[7], 0); // length 0
+    }
+
+    #[test]
+    fn test_encode_array() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!([1, 2, 3]));
+        assert_eq!(bytes[6], OP_ARRAY_START);
+        assert_eq!(bytes[7], 3); // length 3
+        // Each element: OP_INT8 + byte
+        assert_eq!(bytes[8], OP_INT8);
+        assert_eq!(bytes[9], 1);
+        assert_eq!(bytes[10], OP_INT8);
+        assert_eq!(bytes[11], 2);
+        assert_eq!(bytes[12], OP_INT8);
+        assert_eq!(bytes[13], 3);
+    }
+
+    #[test]
+    fn test_null_run_roundtrip() {
+        let mut items = vec![Value::Null; 1000];
+        items.extend([json!(1), Value::Null, Value::Null, json!("x"), Value::Null]);
+        let original = Value::Array(items);
+
+        let options = EncoderOptions { null_run_threshold: Some(3), ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&original);
+
+        // [1000 nulls] → one run; the two- and one-null stretches stay OP_NULL
+        let tree = &bytes[bytes.len() - 13..];
+        assert_eq!(tree, &[OP_ARRAY_START, 0xED, 0x07, OP_NULL_RUN, 0xE8, 0x07,
+            OP_INT8, 1, OP_NULL, OP_NULL, OP_STRING_REF, 0, OP_NULL][..]);
+        assert!(bytes.len() < 30);
+
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+    }
+
+    #[test]
+    fn test_null_run_below_threshold_is_linear() {
+        // A run shorter than the threshold is scanned once, not once per null
+        // (quadratic: 10^10 steps here)
+        let original = Value::Array(vec![Value::Null; 200_000]);
+        for threshold in [None, Some(300_000)] {
+            let options = EncoderOptions { null_run_threshold: threshold, ..Default::default() };
+            let bytes = TensEncoder::with_options(options).encode(&original);
+            assert_eq!(bytes.len(), 6 + 1 + 3 + 200_000);
+            assert!(bytes[10..].iter().all(|&b| b == OP_NULL));
+        }
+    }
+
+    #[test]
+    fn test_null_run_overflowing_array() {
+        // Array of 2 containing a run of 3
+        let bytes = b"TENS\x02\x00\x08\x02\x0C\x03";
+        assert!(TensDecoder::new().decode(bytes).is_err());
+        // Run at the root
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x0C\x03").is_err());
+    }
+
+    #[test]
+    fn test_int_array_fixed_vs_packed() {
+        let original = json!([1, 2, 3, 100000, 4, 5]);
+        let encode = |int_arrays| {
+            let options = EncoderOptions { int_arrays, ..Default::default() };
+            TensEncoder::with_options(options).encode(&original)
+        };
+
+        let fixed = encode(IntArrayEncoding::Fixed);
+        let packed = encode(IntArrayEncoding::Packed);
+        // Fixed: one 100000 forces width 4 on all six elements
+        assert_eq!(fixed[6..9], [OP_INT_ARRAY, 6, 4]);
+        assert_eq!(fixed.len(), 9 + 6 * 4);
+        // Packed: five 1-byte varints + 3 bytes for zigzag(100000)
+        assert_eq!(packed[6..], [OP_PACKED_INT_ARRAY, 6, 2, 4, 6, 0xC0, 0x9A, 0x0C, 8, 10]);
+        assert!(packed.len() < fixed.len());
+        assert!(packed.len() < encode(IntArrayEncoding::Generic).len());
+        assert_eq!(encode(IntArrayEncoding::Auto), packed);
+        // Values needing 5-byte varints keep the fixed form under Auto
+        let wide = json!([2000000000, -2000000000, 1500000000]);
+        let options = EncoderOptions { int_arrays: IntArrayEncoding::Auto, ..Default::default() };
+        assert_eq!(TensEncoder::with_options(options).encode(&wide)[6], OP_INT_ARRAY);
+
+        for bytes in [&fixed, &packed] {
+            assert_eq!(TensDecoder::new().decode(bytes).unwrap(), original);
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn test_array_specialization_threshold() {
+        let options = EncoderOptions {
+            int_arrays: IntArrayEncoding::Fixed,
+            columnar_arrays: true,
+            array_specialization_threshold: 16,
+            ..Default::default()
+        };
+        let encode = |value: &Value| TensEncoder::with_options(options.clone()).encode(value);
+
+        let short = json!([1, 2, 3]);
+        let long = Value::Array((0..1000).map(|i| json!(i % 100)).collect());
+        assert_eq!(encode(&short), TensEncoder::new().encode(&short));
+        assert_eq!(encode(&long)[6..9], [OP_INT_ARRAY, 0xE8, 0x07]);
+
+        let records = |n: usize| Value::Array((0..n).map(|i| json!({"id": i})).collect());
+        // Tree after the one-entry dictionary ["id"]
+        assert_eq!(encode(&records(3))[9], OP_ARRAY_START);
+        assert_eq!(encode(&records(16))[9], OP_COLUMNAR_ARRAY);
+
+        for value in [short, long, records(3), records(16)] {
+            let bytes = encode(&value);
+            assert_eq!(bytes, encode(&value));
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_int_array_malformed() {
+        // Width 3 is not a valid element size
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x0D\x01\x03\x00\x00\x00").is_err());
+        // Two 2-byte elements declared, one present
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x0D\x02\x02\x00\x00").is_err());
+        // Two packed elements declared, one present
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x0E\x02\x02").is_err());
+    }
+
+    #[test]
+    fn test_repeat_string_refs() {
+        let original = json!([
+            {"id": 1, "status": "active"},
+            {"id": 2, "status": "active"},
+            {"id": 3, "status": "idle"},
+        ]);
+        let options = EncoderOptions { repeat_string_refs: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options).encode(&original);
+
+        // Dictionary: id=0, status=1, active=2, idle=3. Only the second
+        // "active" follows an equal string value; keys in between don't count.
+        assert!(bytes.ends_with(&[
+            OP_ARRAY_START, 3,
+            OP_OBJECT_START, 2, 0, OP_INT8, 1, 1, OP_STRING_REF, 2,
+            OP_OBJECT_START, 2, 0, OP_INT8, 2, 1, OP_SAME_AS_PREVIOUS,
+            OP_OBJECT_START, 2, 0, OP_INT8, 3, 1, OP_STRING_REF, 3,
+        ]));
+        assert_eq!(bytes.len() + 1, TensEncoder::new().encode(&original).len());
+
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+    }
+
+    #[test]
+    fn test_same_as_previous_without_previous() {
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x0F").is_err());
+        // A key ref is not a previous string
+        assert!(TensDecoder::new().decode(b"TENS\x02\x01\x01k\x09\x01\x00\x0F").is_err());
+    }
+
+    #[test]
+    fn test_sparse_records() {
+        let fields = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let records: Vec<Value> = (0..100)
+            .map(|r| {
+                // Each record carries a different half of the fields
+                let obj: Map<String, Value> = fields.iter().enumerate()
+                    .filter(|(f, _)| (r + f) % 2 == 0)
+                    .map(|(f, name)| (name.to_string(), json!(r * 10 + f)))
+                    .collect();
+                Value::Object(obj)
+            })
+            .collect();
+        let original = Value::Array(records);
+        let null_filled = Value::Array(original.as_array().unwrap().iter()
+            .map(|rec| Value::Object(fields.iter()
+                .map(|f| (f.to_string(), rec.get(*f).cloned().unwrap_or(Value::Null)))
+                .collect()))
+            .collect());
+
+        let options = EncoderOptions { sparse_records: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options.clone()).encode(&original);
+        let dense = TensEncoder::new().encode(&null_filled);
+        assert!(bytes.len() * 4 < dense.len() * 3, "{} vs {}", bytes.len(), dense.len());
+        assert!(bytes.len() < TensEncoder::new().encode(&original).len());
+
+        // Record 0 defines schema 0 (10 keys), record 1 reuses it
+        let tree = &bytes[6 + 20..];
+        assert_eq!(tree[..5], [OP_ARRAY_START, 100, OP_SCHEMA_OBJECT_SPARSE, 0, 10]);
+        assert_eq!(tree[15..17], [0b0101_0101, 0b01]);
+
+        // Absent fields come back missing, not null
+        let decoded = TensDecoder::new().decode(&bytes).unwrap();
+        assert_eq!(decoded, original);
+        assert!(decoded[0].get("b").is_none());
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+
+        // Present nulls, nested sparse arrays and a second schema in one document
+        let nested = json!({"x": [{"k": null}, {"k": 1, "m": [{"p": "q"}, {}]}], "y": [{"z": 1}]});
+        let bytes = TensEncoder::with_options(options).encode(&nested);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), nested);
+        let strict = ValidateOptions { reject_unused_dictionary: true };
+        assert!(validate_binary(&bytes, &strict).is_ok());
+    }
+
+    #[test]
+    fn test_encode_multi() {
+        let roots = vec![
+            json!({"service": "billing-api", "region": "eu-west-1", "status": "healthy"}),
+            json!({"service": "billing-api", "region": "eu-west-1", "status": "degraded"}),
+            json!({"service": "search-api", "region": "eu-west-1", "status": "healthy"}),
+        ];
+        let bytes = TensEncoder::new().encode_multi(&roots).unwrap();
+        assert_eq!(bytes[4], 0x02 | FLAG_MULTI_ROOT);
+
+        let independent: usize = roots.iter().map(|root| TensEncoder::new().encode(root).len()).sum();
+        assert!(bytes.len() < independent, "{} vs {}", bytes.len(), independent);
+
+        assert_eq!(TensDecoder::new().decode_multi(&bytes).unwrap(), roots);
+        assert!(TensDecoder::new().decode(&bytes).is_err());
+        #[cfg(feature = "std")]
+        assert!(TensDecoder::new().decode_reader(bytes.as_slice()).is_err());
+
+        // A plain document is a single root; an empty list is just a count
+        let single = TensEncoder::new().encode(&roots[0]);
+        assert_eq!(TensDecoder::new().decode_multi(&single).unwrap(), vec![roots[0].clone()]);
+        let empty = TensEncoder::new().encode_multi(&[]).unwrap();
+        assert_eq!(empty, b"TENS\x22\x00\x00");
+        assert!(TensDecoder::new().decode_multi(&empty).unwrap().is_empty());
+
+        // The root count must be present and within max_elements
+        assert_eq!(
+            TensDecoder::new().decode_multi(b"TENS\x22\x01\x01x"),
+            Err(DecodeError::MalformedVarint { at: 8 })
+        );
+        let limits = DecodeLimits { max_elements: 2, ..Default::default() };
+        assert_eq!(
+            TensDecoder::new().with_limits(limits).decode_multi(&bytes),
+            Err(DecodeError::LimitExceeded { limit: "max_elements", max: 2 })
+        );
+    }
+
+    #[test]
+    fn test_encode_split() {
+        let value = json!({"name": "Alice", "tags": ["admin", "ops"], "score": 9.5});
+        let (dictionary, tree) = TensEncoder::new().encode_split(&value).unwrap();
+        assert_eq!([dictionary.as_slice(), tree.as_slice()].concat(), TensEncoder::new().encode(&value));
+        assert_eq!(TensDecoder::new().decode_split(&dictionary, &tree).unwrap(), value);
+
+        // Two trees against one shared dictionary, sparse schemas defined in each
+        let values = [
+            json!([{"user": "alice", "role": "admin"}, {"user": "bob"}]),
+            json!([{"user": "carol"}, {"user": "alice", "role": "ops"}]),
+        ];
+        let options = EncoderOptions { sparse_records: true, repeat_string_refs: true, ..Default::default() };
+        let (dictionary, trees) = TensEncoder::with_options(options).encode_split_many(&values).unwrap();
+        assert_eq!(trees.len(), 2);
+        let mut decoder = TensDecoder::new();
+        for (tree, value) in trees.iter().zip(&values) {
+            assert_eq!(decoder.decode_split(&dictionary, tree).unwrap(), *value);
+        }
+
+        // The dictionary section must be exactly header + dictionary
+        let padded = [dictionary.as_slice(), &[0]].concat();
+        assert!(TensDecoder::new().decode_split(&padded, &trees[0]).is_err());
+        let (scalar_dictionary, scalar_tree) = TensEncoder::new().encode_split(&json!(7)).unwrap();
+        assert_eq!(scalar_dictionary, b"TENS\x02\x00");
+        assert_eq!(TensDecoder::new().decode_split(&scalar_dictionary, &scalar_tree).unwrap(), json!(7));
+    }
+
+    #[test]
+    fn test_decode_tree() {
+        let value = json!({"name": "Alice", "tags": ["admin", "ops", "admin"], "nested": {"name": "Bob"}});
+        let mut enc = TensEncoder::new();
+        let (_header_and_dictionary, tree) = enc.encode_split(&value).unwrap();
+        let dictionary = enc.string_table_entries().to_vec();
+        assert_eq!(tree[0], OP_OBJECT_START);
+
+        let mut dec = TensDecoder::new();
+        assert_eq!(dec.decode_tree(&tree, &dictionary).unwrap(), value);
+        assert_eq!(dec.dictionary(), dictionary.as_slice());
+        assert_eq!(
+            dec.decode_tree(&tree, &dictionary[..2]),
+            Err(DecodeError::StringRefOutOfBounds { id: 2, len: 2 })
+        );
+        assert_eq!(dec.decode_tree(&[OP_INT8, 5], &[]).unwrap(), json!(5));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_encode_to_writer() {
+        // Large enough for the tree to go out in several chunks
+        let records: Vec<Value> = (0..2000)
+            .map(|i| json!({"id": i, "name": format!("user-{}", i % 50), "scores": [i, null, null, 3.5]}))
+            .collect();
+        let docs = [json!(7), json!("x"), json!({"a": [1, 2], "b": null}), Value::Array(records)];
+        let option_sets = [
+            EncoderOptions::default(),
+            EncoderOptions { columnar_arrays: true, null_run_threshold: Some(2), ..Default::default() },
+            EncoderOptions { sparse_records: true, int_arrays: IntArrayEncoding::Auto, ..Default::default() },
+        ];
+        for options in &option_sets {
+            for doc in &docs {
+                let mut encoder = TensEncoder::with_options(options.clone());
+                let mut written = Vec::new();
+                encoder.encode_to_writer(doc, &mut written).unwrap();
+                assert_eq!(written, TensEncoder::with_options(options.clone()).encode(doc));
+            }
+        }
+
+        // Write errors reach the caller
+        struct Full;
+        impl std::io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = TensEncoder::new().encode_to_writer(&docs[3], &mut Full).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+
+        let mut encoder = TensEncoder::with_options(EncoderOptions { max_string_ref_id: 1, ..Default::default() });
+        let mut written = Vec::new();
+        let err = encoder.encode_to_writer(&json!({"a": "b", "c": "d"}), &mut written).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn test_sparse_record_malformed() {
+        // Schema 1 used before schema 0 is defined
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x11\x01").is_err());
+        // One-field schema with bit 1 set
+        assert!(TensDecoder::new().decode(b"TENS\x02\x01\x01a\x11\x00\x01\x00\x02").is_err());
+        // Missing bitmap
+        assert!(TensDecoder::new().decode(b"TENS\x02\x01\x01a\x11\x00\x01\x00").is_err());
+    }
+
+    #[test]
+    fn test_columnar_arrays() {
+        const REGIONS: [&str; 3] = ["eu", "us", "ap"];
+        let records: Vec<Value> = (0..1000)
+            .map(|i| json!({"id": i, "active": i % 3 == 0, "region": REGIONS[i % 3], "score": i as f64 / 8.0}))
+            .collect();
+        let original = canonicalize(&Value::Array(records));
+        let options = EncoderOptions { columnar_arrays: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options.clone()).encode(&original);
+        let rows = TensEncoder::new().encode(&original);
+        assert!(bytes.len() < rows.len(), "{} vs {}", bytes.len(), rows.len());
+
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+        assert_eq!(estimate_decoded_size(&bytes).unwrap(), estimate_decoded_size(&rows).unwrap());
+
+        // The second array of the same shape reuses schema 1 without a definition
+        let pair = json!({"a": [{"k": 1, "v": "x"}], "b": [{"k": 2, "v": "y"}, {"k": 3, "v": "y"}]});
+        let bytes = TensEncoder::with_options(options).encode(&pair);
+        let tree = &bytes[6 + 6 * 2..];
+        assert_eq!(tree[..7], [OP_OBJECT_START, 2, 0, OP_COLUMNAR_ARRAY, 1, 1, 2]);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), pair);
+        let strict = ValidateOptions { reject_unused_dictionary: true };
+        assert!(validate_binary(&bytes, &strict).is_ok());
+
+        // Mixed shapes and empty objects stay row-wise
+        let mixed = json!([{"a": 1}, {"b": 2}, {}]);
+        let bytes = TensEncoder::with_options(EncoderOptions { columnar_arrays: true, ..Default::default() }).encode(&mixed);
+        assert_eq!(bytes, TensEncoder::new().encode(&mixed));
+
+        // Schema 0, a skipped schema number and a field-less definition
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x12\x01\x00").is_err());
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x12\x01\x02").is_err());
+        assert!(TensDecoder::new().decode(b"TENS\x02\x00\x12\x05\x01\x00").is_err());
+    }
+
+    // ── Object encoding ──
+
+    #[test]
+    fn test_encode_empty_object() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!({}));
+        assert_eq!(bytes[6], OP_OBJECT_START);
+        assert_eq!(bytes[7], 0); // 0 fields
+    }
+
+    #[test]
+    fn test_string_table_stats() {
+        let doc = json!({"name": "héllo", "tags": ["a", "bb", "a"]});
+        let mut enc = TensEncoder::new();
+        enc.encode(&doc);
+        // "name", "héllo", "tags", "a", "bb"
+        let stats = enc.string_table_stats();
+        assert_eq!(stats.entries, 5);
+        assert_eq!(stats.total_bytes, 4 + 6 + 4 + 1 + 2);
+        assert_eq!(stats.total_bytes, enc.string_table_entries().iter().map(String::len).sum::<usize>());
+
+        let options = EncoderOptions { string_table_capacity: Some(1000), ..Default::default() };
+        let mut reserved = TensEncoder::with_options(options);
+        assert_eq!(reserved.encode(&doc), enc.encode(&doc));
+        assert!(reserved.string_table_stats().capacity >= 1000);
+        assert_eq!(reserved.string_table_stats().total_bytes, stats.total_bytes);
+    }
+
+    #[test]
+    fn test_dictionary_lengths_are_utf8_bytes() {
+        let enc = TensEncoder::new();
+        let policy = enc.string_length_policy();
+        assert_eq!(policy, StringLengthPolicy::Utf8Bytes);
+        assert!(policy.check_dictionary().is_ok());
+        assert!(StringLengthPolicy::Utf16Units.check_dictionary().is_err());
+
+        // One entry: "héllo" is 6 UTF-8 bytes but 5 chars and 5 UTF-16 units
+        let bytes = TensEncoder::new().encode(&json!("héllo"));
+        assert_eq!(bytes[5..8], [1, 6, b'h']);
+        assert_eq!(StringLengthPolicy::Utf8Bytes.length("héllo"), 6);
+        assert_eq!(StringLengthPolicy::Chars.length("héllo"), 5);
+        assert_eq!(StringLengthPolicy::Utf16Units.length("héllo"), 5);
+        assert_eq!(StringLengthPolicy::Utf16Units.length("😀"), 2);
+    }
+
+    #[test]
+    fn test_encode_object_sorted_keys() {
+        let mut enc = TensEncoder::new();
+        enc.encode(&json!({"b": 2, "a": 1}));
+        // Dict should be: "a", "b" (sorted key scan order)
+        let entries = enc.string_table_entries();
+        assert_eq!(entries, &["a", "b"]);
+    }
+
+    #[test]
+    fn test_int_key_object_roundtrip() {
+        let mut map = Map::new();
+        for i in 0..1000u32 {
+            map.insert(i.to_string(), json!(i % 7));
+        }
+        let original = Value::Object(map);
+
+        let plain = TensEncoder::new().encode(&original);
+        let options = EncoderOptions { int_keys: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&original);
+
+        assert_eq!(bytes[6], OP_INT_KEY_OBJECT);
+        assert!(enc.string_table_entries().is_empty());
+        assert!(bytes.len() * 2 < plain.len(), "{} vs {}", bytes.len(), plain.len());
+
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+    }
+
+    #[test]
+    fn test_int_key_object_numeric_order() {
+        let options = EncoderOptions { int_keys: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let bytes = enc.encode(&json!({"10": "ten", "2": "two"}));
+        // Values are emitted in numeric key order: "two" gets id 0
+        assert_eq!(enc.string_table_entries(), &["two", "ten"]);
+        assert!(bytes.ends_with(&[OP_INT_KEY_OBJECT, 2, 2, OP_STRING_REF, 0, 10, OP_STRING_REF, 1]));
+
+        // Non-canonical numeric keys fall back to a regular object
+        enc.encode(&json!({"01": 1, "2": 2}));
+        assert_eq!(enc.string_table_entries(), &["01", "2"]);
+    }
+
+    #[test]
+    fn test_decimal_numbers_roundtrip() {
+        let options = EncoderOptions { decimal_numbers: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let original: Value = serde_json::from_str(
+            r#"{"price": 19.99, "sum": 0.30000000000000004, "count": 3, "big": 9007199254740993}"#
+        ).unwrap();
+        let bytes = enc.encode(&original);
+        let decoded = TensDecoder::new().decode(&bytes).unwrap();
+
+        assert_eq!(decoded["price"].to_string(), "19.99");
+        assert_eq!(decoded["sum"].to_string(), "0.30000000000000004");
+        assert_eq!(decoded, original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+
+        // Small integers keep their compact opcodes
+        let bytes = enc.encode(&json!(3));
+        assert_eq!(bytes[6], OP_INT8);
+        let bytes = enc.encode(&json!(19.99));
+        assert_eq!(bytes[6], OP_DECIMAL);
+        assert_eq!(&bytes[8..], b"19.99");
+    }
+
+    #[cfg(feature = "decimal")]
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_number_beyond_f64_range() {
+        let huge: Value = serde_json::from_str("[1e400, -1e400]").unwrap();
+        let bytes = TensEncoder::new().encode(&huge);
+        assert_eq!(&bytes[bytes.len() - 2..], &[OP_NULL, OP_NULL]);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!([null, null]));
+
+        let canonical = CanonicalOptions { non_finite_floats: NonFiniteFloats::Preserve, ..Default::default() };
+        let options = EncoderOptions { canonical, ..Default::default() };
+        let bytes = TensEncoder::with_options(options).encode(&huge);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!(["Infinity", "-Infinity"]));
+
+        let options = EncoderOptions { decimal_numbers: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options).encode(&huge);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), huge);
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_decimal_numbers_preserve_digits() {
+        let options = EncoderOptions { decimal_numbers: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        for text in ["0.10", "12345678901234567890.123456789", "-0.000100"] {
+            let original: Value = serde_json::from_str(text).unwrap();
+            let decoded = TensDecoder::new().decode(&enc.encode(&original)).unwrap();
+            assert_eq!(decoded.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_decimal_invalid_text() {
+        let mut bytes = b"TENS\x02\x00".to_vec();
+        bytes.extend_from_slice(&[OP_DECIMAL, 3]);
+        bytes.extend_from_slice(b"1.x");
+        assert!(TensDecoder::new().decode(&bytes).is_err());
+    }
+
+    // ── Canonicalization tests ──
+
+    #[test]
+    fn test_canonicalize_sorts_keys() {
+        let val = json!({"c": 3, "a": 1, "b": 2});
+        let canonical = canonicalize(&val);
+        let obj = canonical.as_object().unwrap();
+        let keys: Vec<&String> = obj.keys().collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_canonicalize_nested_sort() {
+        let val = json!({"z": {"b": 1, "a": 2}, "m": 3});
+        let canonical = canonicalize(&val);
+        let outer_keys: Vec<&String> = canonical.as_object().unwrap().keys().collect();
+        assert_eq!(outer_keys, vec!["m", "z"]);
+        let inner_keys: Vec<&String> = canonical["z"].as_object().unwrap().keys().collect();
+        assert_eq!(inner_keys, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_canonicalize_integral_floats() {
+        let spellings: Vec<Value> = vec![
+            serde_json::from_str("1e2").unwrap(),
+            json!(100.0),
+            json!(100),
+        ];
+        for val in &spellings {
+            assert_eq!(canonicalize(val), json!(100));
+        }
+
+        let expected = TensEncoder::new().encode(&json!(100));
+        for val in &spellings {
+            assert_eq!(TensEncoder::new().encode(val), expected, "bytes differ for {}", val);
+        }
+
+        // Non-integral floats are untouched
+        assert!(canonicalize(&json!(2.5)).is_f64());
+    }
+
+    #[test]
+    fn test_encode_scalar_fast_path() {
+        let mut enc = TensEncoder::new();
+        for i in 0..1_000_000 {
+            let bytes = enc.encode(&json!(i));
+            assert_eq!(&bytes[..6], b"TENS\x02\x00");
+        }
+        // No string table was ever allocated
+        assert_eq!(enc.string_table.map.capacity(), 0);
+        assert_eq!(enc.string_table.entries.capacity(), 0);
+
+        // Same bytes as the general path, and no stale dictionary after a string document
+        assert_eq!(enc.encode(&json!(42)), b"TENS\x02\x00\x03\x2A");
+        let mut float = b"TENS\x02\x00\x06".to_vec();
+        float.extend_from_slice(&(-1.5f64).to_le_bytes());
+        assert_eq!(enc.encode(&json!(-1.5)), float);
+        enc.encode(&json!({"a": "b"}));
+        assert_eq!(enc.encode(&json!(true)), b"TENS\x02\x00\x01");
+        assert!(enc.string_table_entries().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_string() {
+        let opts = CanonicalOptions::default();
+        // Only trailing whitespace goes, per line; leading indentation stays
+        assert_eq!(normalize_string("  a  \n\tb\t\n  c", &opts), "  a\n\tb\n  c");
+        // NFKC: ligature and full-width digits fold
+        assert_eq!(normalize_string("\u{FB01}le \u{FF11}\u{FF12}", &opts), "file 12");
+        // Identical to the string arm of canonicalize
+        for s in ["x \r\ny ", "\u{FB01}\n\n", " lead", ""] {
+            assert_eq!(canonicalize(&json!(s)), json!(normalize_string(s, &opts)));
+        }
+
+        let nfc = CanonicalOptions { unicode_form: UnicodeForm::Nfc, ..Default::default() };
+        assert_eq!(normalize_string("e\u{301}\u{FB01} ", &nfc), "\u{E9}\u{FB01}");
+        let unchanged = CanonicalOptions { unicode_form: UnicodeForm::Unchanged, ..Default::default() };
+        assert_eq!(normalize_string("e\u{301} ", &unchanged), "e\u{301}");
+    }
+
+    #[test]
+    fn test_canonicalize_normalizes_keys() {
+        let nfc = json!({"caf\u{e9}": "x", "b": {"\u{fb01}le": 1}});
+        let nfd = json!({"cafe\u{301}": "x", "b": {"file": 1}});
+        assert_eq!(canonicalize(&nfc), canonicalize(&nfd));
+        assert_eq!(canonicalize(&nfd), json!({"b": {"file": 1}, "caf\u{e9}": "x"}));
+        assert_eq!(TensEncoder::new().encode(&nfc), TensEncoder::new().encode(&nfd));
+        assert_eq!(hash_tens_binary(&TensEncoder::new().encode(&nfc)), hash_tens_binary(&TensEncoder::new().encode(&nfd)));
+
+        // NFC keeps compatibility characters; whitespace in keys is never stripped
+        let nfc_only = CanonicalOptions { unicode_form: UnicodeForm::Nfc, ..Default::default() };
+        assert_eq!(canonicalize_with(&json!({"\u{fb01}": 1, "a ": 2}), &nfc_only), json!({"\u{fb01}": 1, "a ": 2}));
+
+        // Two spellings in one object: the one sorting first keeps its value
+        let both = json!({"cafe\u{301}": "nfd", "caf\u{e9}": "nfc"});
+        assert_eq!(canonicalize(&both), json!({"caf\u{e9}": "nfd"}));
+        #[cfg(feature = "std")]
+        {
+            let mut out = Vec::new();
+            canonicalize_stream(both.to_string().as_bytes(), &mut out, &CanonicalLimits::default()).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), canonicalize(&both).to_string());
+        }
+    }
+
+    #[test]
+    fn test_normalize_str_borrows_normalized() {
+        // The full pass, which the borrowing shortcut must agree with
+        fn full_pass(s: &str) -> String {
+            let normalized: String = s.nfkc().collect();
+            normalized.lines().map(str::trim_end).collect::<Vec<_>>().join("\n")
+        }
+        let opts = CanonicalOptions::default();
+
+        let doc: Vec<Value> = (0..5000)
+            .map(|i| json!({"name": format!("user {}", i), "bio": "caf\u{E9}\nline two", "tags": ["", "a b", "\u{4E16}\u{754C}"]}))
+            .collect();
+        let mut strings = 0;
+        for record in &doc {
+            for (key, value) in record.as_object().unwrap() {
+                let values = value.as_array().cloned().unwrap_or_else(|| vec![value.clone()]);
+                for s in std::iter::once(key.as_str()).chain(values.iter().filter_map(Value::as_str)) {
+                    assert!(matches!(normalize_str(s, &opts), Cow::Borrowed(_)), "{:?} was copied", s);
+                    strings += 1;
+                }
+            }
+        }
+        assert_eq!(strings, 5000 * 8);
+
+        for s in ["a\n", "a\r\nb", "a \nb", "\n", "x\u{A0}", "\u{FB01}", "e\u{301}", "a\n\nb", "\u{2028}", "ok", ""] {
+            let normalized = normalize_str(s, &opts);
+            assert_eq!(normalized, full_pass(s), "{:?}", s);
+            assert_eq!(matches!(normalized, Cow::Borrowed(_)), full_pass(s) == s, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_coerce_scalar_strings() {
+        let options = CanonicalOptions { coerce_scalar_strings: true, ..Default::default() };
+        let val = json!({"a": "true", "b": "42", "c": "42abc", "d": "-1.5", "e": ["false", "4.0 "], "f": " 7"});
+        assert_eq!(canonicalize_with(&val, &options), json!({
+            "a": true, "b": 42, "c": "42abc", "d": -1.5, "e": [false, 4], "f": " 7",
+        }));
+        // Off by default
+        assert_eq!(canonicalize(&val)["b"], json!("42"));
+
+        let encode = |v: &Value| {
+            let canonical = CanonicalOptions { coerce_scalar_strings: true, ..Default::default() };
+            TensEncoder::with_options(EncoderOptions { canonical, ..Default::default() }).encode(v)
+        };
+        assert_eq!(encode(&json!({"n": "42", "ok": "true"})), encode(&json!({"n": 42, "ok": true})));
+    }
+
+    #[test]
+    fn test_integer_spellings_encode_identically() {
+        let parse = |text: &str| serde_json::from_str::<Value>(text).unwrap();
+        let spellings = ["1", "1.0", "1e0", "1E0", "10e-1", "0.1e1", "1.000"];
+        let option_sets = [
+            EncoderOptions::default(),
+            EncoderOptions { decimal_numbers: true, ..Default::default() },
+            EncoderOptions { number_policy: NumberPolicy::AlwaysFloat64, ..Default::default() },
+        ];
+        for options in &option_sets {
+            let expected = TensEncoder::with_options(options.clone()).encode(&json!(1));
+            for text in spellings {
+                let value = parse(text);
+                assert_eq!(TensEncoder::with_options(options.clone()).encode(&value), expected, "{}", text);
+                // Also when canonicalization is skipped
+                assert_eq!(TensEncoder::with_options(options.clone()).encode_unchecked(&value), expected, "{}", text);
+            }
+        }
+
+        // Larger integers and negative zero too
+        let decimal = EncoderOptions { decimal_numbers: true, ..Default::default() };
+        let encode = |text: &str| TensEncoder::with_options(decimal.clone()).encode_unchecked(&parse(text));
+        assert_eq!(encode("1e11"), encode("100000000000"));
+        assert_eq!(encode("-0.0"), encode("0"));
+        assert_ne!(encode("2.5"), encode("2"));
+
+        // Past 2^53 only the exact digits of the `decimal` feature can tell
+        #[cfg(feature = "decimal")]
+        {
+            assert_eq!(encode("9007199254740993.0"), encode("9007199254740993"));
+            assert_eq!(encode("900719925474099.30e1"), encode("9007199254740993"));
+            assert_eq!(canonicalize(&parse("-12.5e1")), parse("-125"));
+            assert_eq!(canonicalize(&parse("1.25")), parse("1.25"));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)] // measurements of π, not the constant
+    fn test_canonicalize_float_precision() {
+        let options = CanonicalOptions { float_precision: Some(6), ..Default::default() };
+        let (a, b) = (json!(3.1415926), json!(3.1415927));
+        assert_eq!(canonicalize_with(&a, &options), json!(3.14159));
+        assert_eq!(canonicalize_with(&a, &options), canonicalize_with(&b, &options));
+        assert_ne!(canonicalize(&a), canonicalize(&b));
+
+        let hash = |v: &Value, float_precision| {
+            let canonical = CanonicalOptions { float_precision, ..Default::default() };
+            hash_tens_binary(&TensEncoder::with_options(EncoderOptions { canonical, ..Default::default() }).encode(v))
+        };
+        assert_eq!(hash(&a, Some(6)), hash(&b, Some(6)));
+        assert_ne!(hash(&a, None), hash(&b, None));
+
+        // Integers untouched; floats rounding to an integer become one
+        let val = json!({"n": 123456789, "x": 2.9999999, "y": -0.000012345678, "z": 1.5e300});
+        assert_eq!(canonicalize_with(&val, &options), json!({"n": 123456789, "x": 3, "y": -0.0000123457, "z": 1.5e300}));
+    }
+
+    #[test]
+    fn test_canonicalize_preserve_line_whitespace() {
+        let value = json!({"line": "a  \nb  "});
+        let options = CanonicalOptions { preserve_line_whitespace: true, ..Default::default() };
+        assert_eq!(canonicalize(&value), json!({"line": "a\nb"}));
+        assert_eq!(canonicalize_with(&value, &options), value);
+        // Still Unicode-normalized, and the final newline survives too
+        assert_eq!(normalize_string("\u{FB01}  \r\n", &options), "fi  \r\n");
+        assert!(matches!(normalize_str("a  \n", &options), Cow::Borrowed(_)));
+
+        let encode = |canonical| TensEncoder::with_options(EncoderOptions { canonical, ..Default::default() }).encode(&value);
+        let bytes = encode(options);
+        assert_ne!(hash_tens_binary(&bytes), hash_tens_binary(&encode(CanonicalOptions::default())));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), value);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_canonicalize_stream() {
+        let stream = |input: &str, limits: &CanonicalLimits| {
+            let mut out = Vec::new();
+            canonicalize_stream(input.as_bytes(), &mut out, limits).map(|_| String::from_utf8(out).unwrap())
+        };
+        let limits = CanonicalLimits::default();
+        let input = r#"{"z": [1.0, -0.0, 2.5, "ﬁ  "], "a": {"y": null, "b": true}, "n": 1e2}"#;
+        let expected = canonicalize(&serde_json::from_str(input).unwrap()).to_string();
+        assert_eq!(stream(input, &limits).unwrap(), expected);
+        assert_eq!(expected, r#"{"a":{"b":true,"y":null},"n":100,"z":[1,0,2.5,"fi"]}"#);
+
+        let tight = CanonicalLimits { max_depth: 3, max_string_len: 8, max_keys: 2, max_array_len: 3, max_input_bytes: 32 };
+        let deep = "[".repeat(100) + &"]".repeat(100);
+        assert_eq!(stream(&deep, &tight), Err(CanonicalizeError::DepthExceeded { limit: 3 }));
+        assert!(stream("[[[1]]]", &tight).is_ok());
+        assert_eq!(stream(r#"["123456789"]"#, &tight), Err(CanonicalizeError::StringTooLong { limit: 8 }));
+        assert_eq!(stream(r#"{"123456789": 1}"#, &tight), Err(CanonicalizeError::StringTooLong { limit: 8 }));
+        assert_eq!(stream(r#"{"a": 1, "b": 2, "c": 3}"#, &tight), Err(CanonicalizeError::TooManyKeys { limit: 2 }));
+        assert_eq!(stream("[[1, 2, 3], [4]]", &tight).unwrap(), "[[1,2,3],[4]]");
+        assert_eq!(stream("[1, 2, 3, 4]", &tight), Err(CanonicalizeError::ArrayTooLong { limit: 3 }));
+        assert_eq!(stream(&(" ".repeat(31) + "1"), &tight).unwrap(), "1");
+        assert_eq!(stream(&(" ".repeat(32) + "1"), &tight), Err(CanonicalizeError::InputTooLarge { limit: 32 }));
+        assert!(matches!(stream("[1, 2", &limits), Err(CanonicalizeError::InvalidJson(_))));
+        assert!(matches!(stream("1 2", &limits), Err(CanonicalizeError::InvalidJson(_))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_canonicalize_stream_does_not_buffer_arrays() {
+        // A writer that fails once anything reaches it: an array whose
+        // closing bracket never arrives must already have been written
+        struct Refuse;
+        impl std::io::Write for Refuse {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("refused"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let input = "[".to_string() + &"\"x\", ".repeat(1 << 14);
+        let result = canonicalize_stream(input.as_bytes(), Refuse, &CanonicalLimits::default());
+        assert!(matches!(result, Err(CanonicalizeError::Io(_))), "{:?}", result);
+    }
+
+    // ── Merge tests ──
+
+    #[test]
+    fn test_merge_nested_objects() {
+        let base = json!({"db": {"host": "localhost", "port": 5432}, "name": "app"});
+        let overlay = json!({"db": {"port": 6543, "user": "admin"}});
+        let merged = merge(&base, &overlay);
+        assert_eq!(merged, json!({
+            "db": {"host": "localhost", "port": 6543, "user": "admin"},
+            "name": "app"
+        }));
+        assert_eq!(merged, canonicalize(&merged));
+        let keys: Vec<&String> = merged["db"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["host", "port", "user"]);
+    }
+
+    #[test]
+    fn test_merge_replaces_arrays() {
+        let base = json!({"tags": ["a", "b", "c"], "n": 1});
+        let overlay = json!({"tags": ["z"]});
+        assert_eq!(merge(&base, &overlay), json!({"n": 1, "tags": ["z"]}));
+    }
+
+    #[test]
+    fn test_merge_null_deletes() {
+        let base = json!({"keep": 1, "drop": {"x": 1}, "nested": {"a": 1, "b": 2}});
+        let overlay = json!({"drop": null, "nested": {"b": null}, "fresh": {"c": null, "d": 4}});
+        let merged = merge(&base, &overlay);
+        assert_eq!(merged, json!({"fresh": {"d": 4}, "keep": 1, "nested": {"a": 1}}));
+        assert_eq!(merged, canonicalize(&merged));
+    }
+
+    // ── Round-trip tests ──
+
+    #[test]
+    fn test_roundtrip_null() {
+        let mut enc = TensEncoder::new();
+        let mut dec = TensDecoder::new();
+        let bytes = enc.encode(&json!(null));
+        let decoded = dec.decode(&bytes).unwrap();
+        assert_eq!(decoded, json!(null));
+    }
+
+    #[test]
+    fn test_roundtrip_bool() {
+        let mut enc = TensEncoder::new();
+        let mut dec = TensDecoder::new();
+        let bytes = enc.encode(&json!(true));
+        assert_eq!(dec.decode(&bytes).unwrap(), json!(true));
+
+        let bytes = enc.encode(&json!(false));
+        assert_eq!(dec.decode(&bytes).unwrap(), json!(false));
+    }
+
+    #[test]
+    fn test_roundtrip_integers() {
+        let mut enc = TensEncoder::new();
+        for val in &[0, 1, -1, 42, -128, 127, 128, -500, 1000, 100_000, -100_000] {
+            let bytes = enc.encode(&json!(val));
+            let mut dec = TensDecoder::new();
+            let decoded = dec.decode(&bytes).unwrap();
+            assert_eq!(decoded.as_i64().unwrap(), *val as i64, "roundtrip failed for {}", val);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_integers_beyond_i32() {
+        let vals = [i64::MIN, i64::MAX, i32::MIN as i64 - 1, i32::MAX as i64 + 1];
+
+        // Exact as OP_DECIMAL text
+        let mut enc = TensEncoder::with_options(EncoderOptions { decimal_numbers: true, ..Default::default() });
+        for val in vals {
+            let bytes = enc.encode(&json!(val));
+            assert_eq!(bytes[6], OP_DECIMAL, "opcode for {}", val);
+            let decoded = TensDecoder::new().decode(&bytes).unwrap();
+            assert_eq!(decoded.as_i64(), Some(val), "roundtrip failed for {}", val);
+        }
+
+        // And by default as OP_INT64, including values FLOAT64 would round
+        let mut enc = TensEncoder::new();
+        for val in vals.into_iter().chain([9_007_199_254_740_993, -9_007_199_254_740_993]) {
+            let bytes = enc.encode(&json!(val));
+            assert_eq!(bytes[6..], [[OP_INT64].as_slice(), &val.to_le_bytes()].concat(), "{}", val);
+            for decoded in [TensDecoder::new().decode(&bytes), TensDecoder::new_strict_canonical().decode(&bytes)] {
+                assert_eq!(decoded.unwrap().as_i64(), Some(val), "roundtrip failed for {}", val);
+            }
+        }
+        assert_ne!(-9_007_199_254_740_993i64 as f64 as i64, -9_007_199_254_740_993);
+
+        // An integral float past i32 is the integer too
+        assert_eq!(enc.encode(&json!(4294967296.0)), enc.encode(&json!(4_294_967_296i64)));
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn test_roundtrip_float() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!(3.14159));
+        let mut dec = TensDecoder::new();
+        let decoded = dec.decode(&bytes).unwrap();
+        assert!((decoded.as_f64().unwrap() - 3.14159).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_roundtrip_string() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!("hello world"));
+        let mut dec = TensDecoder::new();
+        let decoded = dec.decode(&bytes).unwrap();
+        assert_eq!(decoded.as_str().unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_roundtrip_array() {
+        let mut enc = TensEncoder::new();
+        let original = json!([1, "two", true, null, 3.5]);
+        let bytes = enc.encode(&original);
+        let mut dec = TensDecoder::new();
+        let decoded = dec.decode(&bytes).unwrap();
+
+        let arr = decoded.as_array().unwrap();
+        assert_eq!(arr.len(), 5);
+        assert_eq!(arr[0].as_i64().unwrap(), 1);
+        assert_eq!(arr[1].as_str().unwrap(), "two");
+        assert!(arr[2].as_bool().unwrap());
+        assert!(arr[3].is_null());
+    }
+
+    #[test]
+    fn test_roundtrip_object() {
+        let mut enc = TensEncoder::new();
+        let original = json!({"name": "Alice", "age": 30, "active": true});
+        let bytes = enc.encode(&original);
+        let mut dec = TensDecoder::new();
+        let decoded = dec.decode(&bytes).unwrap();
+
+        assert_eq!(decoded["name"].as_str().unwrap(), "Alice");
+        assert_eq!(decoded["age"].as_i64().unwrap(), 30);
+        assert!(decoded["active"].as_bool().unwrap());
+    }
+
+    #[test]
+    fn test_roundtrip_nested() {
+        let mut enc = TensEncoder::new();
+        let original = json!({
+            "users": [
+                {"name": "Alice", "scores": [100, 95, 88]},
+                {"name": "Bob", "scores": [72, 85]}
+            ],
+            "meta": {"version": 2, "format": "tens"}
+        });
+        let bytes = enc.encode(&original);
+        let mut dec = TensDecoder::new();
+        let decoded = dec.decode(&bytes).unwrap();
+
+        assert_eq!(decoded["users"][0]["name"].as_str().unwrap(), "Alice");
+        assert_eq!(decoded["users"][1]["scores"][0].as_i64().unwrap(), 72);
+        assert_eq!(decoded["meta"]["version"].as_i64().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let doc = json!({"tags": ["alpha", "beta", "gamma"], "nested": [[[1]]]});
+        let bytes = TensEncoder::new().encode(&doc);
+        let limited = |limits: DecodeLimits| TensDecoder::new().with_limits(limits).decode(&bytes);
+        let exceeded = |limit: &'static str, max: usize| Err(DecodeError::LimitExceeded { limit, max });
+
+        let tight = DecodeLimits { max_depth: 3, ..DecodeLimits::unlimited() };
+        assert_eq!(limited(tight), exceeded("max_depth", 3));
+        let tight = DecodeLimits { max_elements: 2, ..DecodeLimits::unlimited() };
+        assert_eq!(limited(tight), exceeded("max_elements", 2));
+        let tight = DecodeLimits { max_dictionary_bytes: 16, ..DecodeLimits::unlimited() };
+        assert_eq!(limited(tight), exceeded("max_dictionary_bytes", 16));
+        let tight = DecodeLimits { max_total_size: bytes.len() - 1, ..DecodeLimits::unlimited() };
+        assert_eq!(limited(tight), exceeded("max_total_size", bytes.len() - 1));
+        #[cfg(feature = "std")]
+        assert_eq!(
+            TensDecoder::new().with_limits(tight).decode_reader(&bytes[..]),
+            exceeded("max_total_size", bytes.len() - 1)
+        );
+
+        assert_eq!(limited(DecodeLimits::unlimited()).unwrap(), doc);
+        assert_eq!(limited(DecodeLimits::strict()).unwrap(), doc);
+        assert_eq!(limited(DecodeLimits { max_depth: 4, ..DecodeLimits::unlimited() }).unwrap(), doc);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().with_limits(DecodeLimits::unlimited()).decode_reader(&bytes[..]).unwrap(), doc);
+    }
+
+    #[test]
+    fn test_decode_utf16_dictionary_lengths() {
+        // "h€😀" is 8 UTF-8 bytes but 4 UTF-16 units; then a 1-unit "é"
+        let mut doc = b"TENS\x02\x02\x04".to_vec();
+        doc.extend_from_slice("h€😀".as_bytes());
+        doc.push(0x01);
+        doc.extend_from_slice("é".as_bytes());
+        doc.extend_from_slice(&[OP_ARRAY_START, 0x02, OP_STRING_REF, 0x00, OP_STRING_REF, 0x01]);
+
+        let mut legacy = TensDecoder::new().with_dict_length_mode(DictLengthMode::Utf16Units);
+        assert_eq!(legacy.decode(&doc).unwrap(), json!(["h€😀", "é"]));
+        #[cfg(feature = "std")]
+        assert_eq!(legacy.decode_reader(&doc[..]).unwrap(), json!(["h€😀", "é"]));
+        assert!(TensDecoder::new().decode(&doc).is_err());
+
+        // A length ending inside a surrogate pair
+        let split = b"TENS\x02\x01\x02h\xF0\x9F\x98\x80\x00";
+        assert!(legacy.decode(split).unwrap_err().to_string().contains("surrogate pair"));
+    }
+
+    #[test]
+    fn test_decoder_dictionary() {
+        let original = json!({
+            "users": [
+                {"name": "Alice", "scores": [100, 95, 88]},
+                {"name": "Bob", "scores": [72, 85]}
+            ],
+            "meta": {"version": 2, "format": "tens"}
+        });
+        let mut dec = TensDecoder::new();
+        assert!(dec.dictionary().is_empty());
+        dec.decode(&TensEncoder::new().encode(&original)).unwrap();
+        assert_eq!(dec.dictionary(), ["meta", "format", "tens", "version", "users", "name", "Alice", "scores", "Bob"]);
+
+        // Replaced by the next document
+        dec.decode(&TensEncoder::new().encode(&json!(["x"]))).unwrap();
+        assert_eq!(dec.dictionary(), ["x"]);
+    }
+
+    #[test]
+    fn test_roundtrip_deep_mixed_nesting() {
+        // object → array → object → array → object → scalar
+        let original = json!({
+            "a": [
+                {"b": [{"c": "x", "e": [1, "y"]}, "z"], "f": {"g": ["deep", {"h": null}]}},
+                "tail"
+            ],
+            "d": "w"
+        });
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&original);
+
+        // Each key is followed by its value's strings, matching emit order
+        assert_eq!(
+            enc.string_table_entries(),
+            &["a", "b", "c", "x", "e", "y", "z", "f", "g", "deep", "h", "tail", "d", "w"]
+        );
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+    }
+
+    #[test]
+    fn test_roundtrip_nested_arrays() {
+        let variants = [
+            EncoderOptions::default(),
+            EncoderOptions { int_arrays: IntArrayEncoding::Auto, ..EncoderOptions::default() },
+            EncoderOptions { repeat_string_refs: true, null_run_threshold: Some(1), ..EncoderOptions::default() },
+            EncoderOptions { front_code_dictionary: true, ..EncoderOptions::default() },
+        ];
+        let roundtrip = |original: Value| {
+            for options in &variants {
+                let bytes = TensEncoder::with_options(options.clone()).encode(&original);
+                assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original, "{:?}", options);
+                #[cfg(feature = "std")]
+                assert_eq!(TensDecoder::new().decode_reader(&bytes[..]).unwrap(), original, "{:?}", options);
+            }
+        };
+        roundtrip(json!([[1, 2], [3, [4, 5]]]));
+        roundtrip(json!([[1, 2], [], [3]]));
+        roundtrip(json!([[null, "x"], [null, null, ["x", "y"]], [["y"], "x"]]));
+        roundtrip(json!([[[[["bottom", 1]]], "mid"], [[["bottom"]]]]));
+
+        // Strings repeated across sibling arrays share one dictionary entry
+        let original = json!([["a"], ["a", "b"]]);
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&original);
+        assert_eq!(enc.string_table_entries(), &["a", "b"]);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+
+        // Strings at varying depths: ids are assigned in emit order
+        let original = json!([[[["deep"]], "shallow"], "top", [["shallow", [["deep", "deeper"]]]]]);
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&original);
+        assert_eq!(enc.string_table_entries(), &["deep", "shallow", "top", "deeper"]);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+    }
+
+    #[test]
+    fn test_scan_and_emit_agree_on_string_ids() {
+        // Encoding in a debug build asserts every emitted string was scanned
+        let records: Vec<Value> = (0..4)
+            .map(|i| json!({"id": i, "tags": ["t", format!("t{}", i % 2)], "meta": {"k": "v", "n": [null, "x"]}}))
+            .collect();
+        let sparse: Vec<Value> = (0..3).map(|i| if i == 1 { json!({"a": "x"}) } else { json!({"a": "y", "b": [i]}) }).collect();
+        let doc = json!({
+            "records": records,
+            "sparse": sparse,
+            "ints": {"1": "one", "20": ["x", {"2": "two"}]},
+            "nested": [[["deep", {"deeper": ["deepest", "x"]}]], "top"],
+        });
+        let variants = [
+            EncoderOptions::default(),
+            EncoderOptions { columnar_arrays: true, int_keys: true, ..EncoderOptions::default() },
+            EncoderOptions { sparse_records: true, repeat_string_refs: true, ..EncoderOptions::default() },
+            EncoderOptions { front_code_dictionary: true, ..EncoderOptions::default() },
+        ];
+        for options in variants {
+            let bytes = TensEncoder::with_options(options).encode(&doc);
+            assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), doc);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "which scan_strings missed")]
+    fn test_emitting_unscanned_string_panics() {
+        let _ = TensEncoder::new().scanned_id("never scanned");
+    }
+
+    #[test]
+    fn test_string_refs_first_seen_in_id_order() {
+        // Walking the value tree, every new id must be exactly the next id
+        fn walk(bytes: &[u8], pos: &mut usize, next: &mut u32) {
+            let op = bytes[*pos];
+            *pos += 1;
+            let read = |pos: &mut usize| {
+                let (v, n) = decode_varint(&bytes[*pos..]);
+                *pos += n;
+                v
+            };
+            match op {
+                OP_STRING_REF => {
+                    let id = read(pos);
+                    assert!(id <= *next, "ref {} before id {}", id, next);
+                    *next = (*next).max(id + 1);
+                }
+                OP_ARRAY_START => {
+                    for _ in 0..read(pos) {
+                        walk(bytes, pos, next);
+                    }
+                }
+                OP_OBJECT_START => {
+                    for _ in 0..read(pos) {
+                        let id = read(pos);
+                        assert!(id <= *next, "key {} before id {}", id, next);
+                        *next = (*next).max(id + 1);
+                        walk(bytes, pos, next);
+                    }
+                }
+                OP_INT8 => *pos += 1,
+                OP_INT32 => *pos += 4,
+                OP_FLOAT64 => *pos += 8,
+                _ => {}
+            }
+        }
+
+        let doc = json!({"z": [{"y": ["x", {"w": "v"}]}, {"u": "t"}], "s": {"r": ["q", "p"]}});
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&doc);
+        let dict_len: usize = enc.string_table_entries().iter().map(|e| e.len() + 1).sum();
+        let mut pos = 6 + dict_len;
+        let mut next = 0;
+        walk(&bytes, &mut pos, &mut next);
+        assert_eq!(pos, bytes.len());
+        assert_eq!(next as usize, enc.string_table_entries().len());
+    }
+
+    #[test]
+    fn test_with_transform() {
+        let mut encoder = TensEncoder::new().with_transform(|key, value| match key {
+            "ssn" => Some(json!("***")),
+            "internal" => None,
+            _ => Some(value.clone()),
+        });
+        let raw = json!({"name": "Ada", "ssn": "123-45-6789", "internal": 1,
+            "contacts": [{"name": "Bob", "ssn": "987-65-4321"}]});
+        let redacted = json!({"name": "Ada", "ssn": "***", "contacts": [{"name": "Bob", "ssn": "***"}]});
+
+        let bytes = encoder.encode(&raw);
+        let direct = TensEncoder::new().encode(&redacted);
+        assert_eq!(hash_tens_binary(&bytes), hash_tens_binary(&direct));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), redacted);
+
+        let stream = encoder.encode_stream_of(vec![raw.clone()].into_iter()).unwrap();
+        assert_eq!(stream, TensEncoder::new().encode(&json!([redacted])));
+    }
+
+    #[test]
+    fn test_fixed_width_bytes_are_little_endian() {
+        // Literal bytes, so the assertions hold on any host endianness
+        assert_eq!(TensEncoder::new().encode(&json!(1000)), b"TENS\x02\x00\x05\xE8\x03\x00\x00");
+        assert_eq!(TensEncoder::new().encode(&json!(-2)), b"TENS\x02\x00\x03\xFE");
+        assert_eq!(
+            TensEncoder::new().encode(&json!(1.5)),
+            b"TENS\x02\x00\x06\x00\x00\x00\x00\x00\x00\xF8\x3F"
+        );
+        let options = EncoderOptions { int_arrays: IntArrayEncoding::Fixed, ..Default::default() };
+        assert_eq!(
+            TensEncoder::with_options(options).encode(&json!([1000, -1])),
+            b"TENS\x02\x00\x0D\x02\x02\xE8\x03\xFF\xFF"
+        );
+
+        assert_eq!(TensDecoder::new().decode(b"TENS\x02\x00\x05\xE8\x03\x00\x00").unwrap(), json!(1000));
+        assert_eq!(TensDecoder::new().decode(b"TENS\x02\x00\x06\x00\x00\x00\x00\x00\x00\xF8\x3F").unwrap(), json!(1.5));
+    }
+
+    #[cfg(feature = "trusted_decode")]
+    #[test]
+    fn test_decode_trusted_matches_decode() {
+        let docs = [
+            json!({"name": "Ada", "tags": ["a", "b", "a"], "nested": {"x": [1, 2.5, null, true]}}),
+            json!(["ünïcödé", "日本語", ""]),
+            json!(42),
+        ];
+        for doc in &docs {
+            let bytes = TensEncoder::new().encode(doc);
+            let checked = TensDecoder::new().decode(&bytes).unwrap();
+            // SAFETY: the bytes come straight from the encoder
+            let trusted = unsafe { TensDecoder::new().decode_trusted(&bytes) };
+            assert_eq!(trusted, checked);
+        }
+    }
+
+    #[cfg(feature = "ordered")]
+    #[test]
+    fn test_decode_ordered() {
+        // {"b": 1, "a": {"d": 2, "c": 3}} with keys out of order on the wire
+        let bytes = b"TENS\x02\x04\x01b\x01a\x01d\x01c\x09\x02\x00\x03\x01\x01\x09\x02\x02\x03\x02\x03\x03\x03";
+        let ordered = TensDecoder::new().decode_ordered(bytes).unwrap();
+        let OrderedValue::Object(outer) = &ordered else { panic!("expected object") };
+        assert_eq!(outer.keys().collect::<Vec<_>>(), ["a", "b"]);
+        let OrderedValue::Object(inner) = &outer["a"] else { panic!("expected object") };
+        assert_eq!(inner.keys().collect::<Vec<_>>(), ["c", "d"]);
+
+        assert_eq!(Value::from(ordered), json!({"b": 1, "a": {"d": 2, "c": 3}}));
+    }
+
+    #[test]
+    fn test_user_opcode_extension() {
+        const OP_GEO: u8 = 0xE0;
+        // {"geo": [lat, lon]} as two little-endian f64s
+        fn encode_geo(value: &Value) -> Option<Vec<u8>> {
+            let obj = value.as_object().filter(|obj| obj.len() == 1)?;
+            let [lat, lon] = obj.get("geo")?.as_array()?.as_slice() else { return None };
+            let mut payload = lat.as_f64()?.to_le_bytes().to_vec();
+            payload.extend_from_slice(&lon.as_f64()?.to_le_bytes());
+            Some(payload)
+        }
+        fn decode_geo(bytes: &[u8]) -> Result<(Value, usize), DecodeError> {
+            let payload = bytes.get(..16).ok_or_else(|| DecodeError::from("GEO: not enough bytes"))?;
+            let lat = f64::from_le_bytes(payload[..8].try_into().unwrap());
+            let lon = f64::from_le_bytes(payload[8..].try_into().unwrap());
+            Ok((json!({"geo": [lat, lon]}), 16))
+        }
+
+        let doc = json!({"name": "Office", "location": {"geo": [52.52, 13.405]}, "tags": ["hq"]});
+        let mut encoder = TensEncoder::new().with_extension(OP_GEO, encode_geo);
+        let bytes = encoder.encode(&doc);
+        assert!(bytes.windows(17).any(|w| w[0] == OP_GEO && w[1..9] == 52.52f64.to_le_bytes()));
+
+        let mut decoder = TensDecoder::new().with_extension(OP_GEO, decode_geo);
+        assert_eq!(decoder.decode(&bytes).unwrap(), doc);
+        // The claimed value's key never enters the dictionary
+        assert_eq!(encoder.string_table_entries(), ["location", "name", "Office", "tags", "hq"]);
+        assert!(TensDecoder::new().decode(&bytes).unwrap_err().to_string().contains("0xe0"));
+    }
+
+    #[test]
+    fn test_user_opcode_extension_runs_once_per_value() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn encode_tagged(value: &Value) -> Option<Vec<u8>> {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            Some(value.as_object()?.get("tag")?.as_str()?.as_bytes().to_vec())
+        }
+
+        // Once each for the root, both elements and the unclaimed one's two
+        // values: the emit pass reuses the scan's claims
+        let doc = json!([{"tag": "x"}, {"n": 1, "tag": 2}]);
+        let mut encoder = TensEncoder::new().with_extension(0xE0, encode_tagged);
+        let bytes = encoder.encode(&doc);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 5);
+        assert!(bytes.windows(2).any(|w| w == [0xE0, b'x']));
+    }
+
+    #[test]
+    fn test_user_opcode_consumed_past_end() {
+        let bytes = b"TENS\x02\x00\xE0\x01\x02";
+        let mut decoder = TensDecoder::new().with_extension(0xE0, |_| Ok((Value::Null, 3)));
+        assert_eq!(decoder.decode(bytes).unwrap_err().to_string(), "Extension 0xe0 consumed 3 bytes, only 2 remain");
+        let mut decoder = TensDecoder::new().with_extension(0xE0, |_| Ok((Value::Null, 2)));
+        assert_eq!(decoder.decode(bytes).unwrap(), Value::Null);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a user opcode")]
+    fn test_user_opcode_outside_range() {
+        let _ = TensDecoder::new().with_extension(OP_FLOAT64, |_| Ok((Value::Null, 0)));
+    }
+
+    #[cfg(feature = "raw_value")]
+    #[test]
+    fn test_encode_serialize_raw_value() {
+        use serde_json::value::RawValue;
+
+        #[derive(serde::Serialize)]
+        struct Event {
+            id: u32,
+            payload: Box<RawValue>,
+        }
+        let payload = r#"{"z": [1, 2.0, "x"], "a": {"b": null}}"#;
+        let event = Event { id: 7, payload: RawValue::from_string(payload.to_string()).unwrap() };
+
+        let bytes = TensEncoder::new().try_encode_serialize(&event).unwrap();
+        let parsed = json!({"id": 7, "payload": serde_json::from_str::<Value>(payload).unwrap()});
+        assert_eq!(bytes, TensEncoder::new().encode(&parsed));
+
+        // Not representable as JSON: map keys must be strings
+        let invalid: BTreeMap<Vec<u8>, u8> = [(vec![1], 1)].into_iter().collect();
+        assert!(matches!(TensEncoder::new().try_encode_serialize(&invalid), Err(EncodeError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn test_encode_serialize_depth_limit() {
+        #[derive(serde::Serialize)]
+        struct Node {
+            child: Option<Box<Node>>,
+        }
+        fn chain(depth: usize) -> Node {
+            (0..depth).fold(Node { child: None }, |node, _| Node { child: Some(Box::new(node)) })
+        }
+
+        let deep = chain(10_000);
+        assert_eq!(
+            TensEncoder::new().try_encode_serialize(&deep),
+            Err(EncodeError::DepthExceeded { limit: DEFAULT_MAX_SERIALIZE_DEPTH })
+        );
+
+        // Each node adds a struct level and a `Some` level: chain(n) reaches 2n + 1
+        let shallow = chain(3);
+        let expected = json!({"child": {"child": {"child": {"child": null}}}});
+        assert_eq!(TensEncoder::new().try_encode_serialize(&shallow).unwrap(), TensEncoder::new().encode(&expected));
+        let options = EncoderOptions { max_serialize_depth: 7, ..EncoderOptions::default() };
+        assert!(TensEncoder::with_options(options.clone()).try_encode_serialize(&chain(3)).is_ok());
+        assert_eq!(
+            TensEncoder::with_options(options).try_encode_serialize(&chain(4)),
+            Err(EncodeError::DepthExceeded { limit: 7 })
+        );
+    }
+
+    // ── Hash tests ──
+
+    #[test]
+    fn test_hash_deterministic() {
+        let mut enc = TensEncoder::new();
+        let bytes1 = enc.encode(&json!({"a": 1}));
+        let bytes2 = enc.encode(&json!({"a": 1}));
+        assert_eq!(hash_tens_binary(&bytes1), hash_tens_binary(&bytes2));
+    }
+
+    #[test]
+    fn test_hash_different_for_different_values() {
+        let mut enc = TensEncoder::new();
+        let bytes1 = enc.encode(&json!({"a": 1}));
+        let bytes2 = enc.encode(&json!({"a": 2}));
+        assert_ne!(hash_tens_binary(&bytes1), hash_tens_binary(&bytes2));
+    }
+
+    #[test]
+    fn test_empty_document_hashes() {
+        // Format-version-pinned constants: downstream systems hardcode these
+        // sentinels, so they may only change with a TENS version bump. A
+        // failure here means the encoding of these documents changed.
+        let cases = [
+            (json!(null), &b"TENS\x02\x00\x00"[..], "a04c3a062b632dac22d9dfb104f90b963745db3f3aada5524ab2c4e49354300f"),
+            (json!({}), b"TENS\x02\x00\x09\x00", "70d479e9030b636d395c0cd88e5e7a84ddcf2b0810fbdf11a345a47357240426"),
+            (json!([]), b"TENS\x02\x00\x08\x00", "8ecf15142d9a391d2d055f8aac68ac5498f1140d028bff2ac0bd0d1454db7fca"),
+            (json!(""), b"TENS\x02\x01\x00\x07\x00", "1e849cc8d8d77f9facf56d92bdb36efc6fe67ccf825ed100ae5b8927561ca9c0"),
+        ];
+        for (value, bytes, hash) in cases {
+            let encoded = TensEncoder::new().encode(&value);
+            assert_eq!(encoded, bytes, "{}", value);
+            assert_eq!(hash_tens_binary(&encoded), hash, "{}", value);
         }
-        s => {
-            // Try parsing as number
-            if let Ok(i) = s.parse::<i64>() {
-                serde_json::json!(i)
-            } else if let Ok(f) = s.parse::<f64>() {
-                serde_json::json!(f)
-            } else {
-                Value::String(s.to_string())
-            }
+    }
+
+    #[test]
+    fn test_canonical_bytes_as_map_key() {
+        let mut cache: HashMap<CanonicalBytes, &str> = HashMap::new();
+        cache.insert(CanonicalBytes::from(&json!({"a": 1, "b": [1.0, "x"]})), "first");
+        // Same value after canonicalization: key order and 1.0 vs 1 don't matter
+        cache.insert(CanonicalBytes::from(&json!({"b": [1, "x"], "a": 1})), "second");
+        assert_eq!(cache.len(), 1);
+        cache.insert(CanonicalBytes::from(&json!({"a": 2, "b": [1, "x"]})), "third");
+        assert_eq!(cache.len(), 2);
+
+        let key = CanonicalBytes::from(&json!({"a": 1, "b": [1, "x"]}));
+        assert_eq!(cache[&key], "second");
+        assert_eq!(key.hash_hex(), hash_tens_binary(&TensEncoder::new().encode(&json!({"a": 1, "b": [1, "x"]}))));
+        assert_eq!(key.as_bytes(), TensEncoder::new().encode(&json!({"b": [1, "x"], "a": 1})));
+    }
+
+    #[test]
+    fn test_tens_hasher_matches_concatenation() {
+        let values = [json!({"b": 2, "a": 1}), json!(["x", 1.5]), json!("tail")];
+        let mut hasher = TensHasher::new();
+        let mut concatenated = Vec::new();
+        for value in &values {
+            hasher.update_value(value);
+            concatenated.extend(TensEncoder::new().encode(value));
+        }
+        assert_eq!(hasher.finalize(), hash_tens_binary(&concatenated));
+
+        let mut reversed = TensHasher::new();
+        for value in values.iter().rev() {
+            reversed.update_value(value);
         }
+        assert_ne!(reversed.finalize(), hash_tens_binary(&concatenated));
     }
-}
 
-// ── SHA-256 Hashing ──
+    #[test]
+    fn test_hash_length() {
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode(&json!({"test": "data"}));
+        let hash = hash_tens_binary(&bytes);
+        assert_eq!(hash.len(), 64); // SHA-256 = 32 bytes = 64 hex chars
+    }
 
-use sha2::{Sha256, Digest};
+    #[test]
+    fn test_encode_unchecked_matches_encode() {
+        let canonical = canonicalize(&json!({
+            "users": [{"name": "Alice", "score": 95}, {"name": "Bob", "score": 2.5}],
+            "meta": {"tags": ["a", "b"], "note": null}
+        }));
+        let mut enc = TensEncoder::new();
+        assert_eq!(enc.encode_unchecked(&canonical), enc.encode(&canonical));
 
-/// Compute SHA-256 hex hash of TENS binary bytes (matches TS hashing.ts).
-pub fn hash_tens_binary(bytes: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(bytes);
-    let result = hasher.finalize();
-    hex_encode(&result)
-}
+        // Non-canonical input is emitted as given
+        let raw = json!("trailing   ");
+        assert_ne!(enc.encode_unchecked(&raw), enc.encode(&raw));
+    }
 
-fn hex_encode(bytes: &[u8]) -> String {
-    bytes.iter().map(|b| format!("{:02x}", b)).collect()
-}
+    #[test]
+    fn test_encode_json_slice() {
+        let records: Vec<Value> = (0..200)
+            .map(|i| json!({"id": i, "name": format!("user-{}", i), "tags": ["a", "b"], "score": i as f64 / 4.0}))
+            .collect();
+        let doc = Value::Array(records);
+        let json_bytes = serde_json::to_vec(&doc).unwrap();
+        assert!(json_bytes.len() > 8 * 1024);
+
+        let bytes = TensEncoder::new().try_encode_json_slice(&json_bytes).unwrap();
+        assert_eq!(bytes, TensEncoder::new().encode(&doc));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), canonicalize(&doc));
+
+        assert!(matches!(
+            TensEncoder::new().try_encode_json_slice(b"{\"a\": "),
+            Err(EncodeError::InvalidJson(_))
+        ));
+    }
 
-// ── Tests ──
+    #[test]
+    fn test_encode_stream_of() {
+        let item = |i: usize| match i % 5 {
+            0 => json!({"id": i, "name": format!("user-{}", i % 7), "tags": ["a", "b"]}),
+            1 => json!(format!("s{}", i % 3)),
+            2 | 3 => Value::Null,
+            _ => json!(i as f64 + 0.5),
+        };
+        let collected = Value::Array((0..500).map(item).collect());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-    use crate::utils::{encode_varint, decode_varint};
+        let mut enc = TensEncoder::new();
+        let bytes = enc.encode_stream_of((0..500).map(item)).unwrap();
+        assert_eq!(bytes, TensEncoder::new().encode(&collected));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), collected);
 
-    // ── Varint tests ──
+        let options = EncoderOptions { null_run_threshold: Some(2), repeat_string_refs: true, ..Default::default() };
+        let streamed = TensEncoder::with_options(options.clone()).encode_stream_of((0..500).map(item)).unwrap();
+        assert_eq!(streamed, TensEncoder::with_options(options).encode(&collected));
+
+        let empty = TensEncoder::new().encode_stream_of(std::iter::empty()).unwrap();
+        assert_eq!(empty, TensEncoder::new().encode(&json!([])));
+    }
 
     #[test]
-    fn test_varint_single_byte() {
-        let encoded = encode_varint(0);
-        assert_eq!(encoded, vec![0]);
-        let (val, len) = decode_varint(&encoded);
-        assert_eq!(val, 0);
-        assert_eq!(len, 1);
+    fn test_encode_with_field_hashes() {
+        let doc = json!({
+            "name": "Alice",
+            "tags": ["admin", "ops"],
+            "meta": {"version": 2, "owner": "Alice"}
+        });
+        let mut enc = TensEncoder::new();
+        let (bytes, field_hashes) = enc.encode_with_field_hashes(&doc);
+
+        assert_eq!(bytes, TensEncoder::new().encode(&doc));
+        assert_eq!(field_hashes.keys().collect::<Vec<_>>(), vec!["meta", "name", "tags"]);
+
+        for (key, value) in doc.as_object().unwrap() {
+            assert_eq!(field_hashes[key], hash_tens_binary(&TensEncoder::new().encode(value)));
+        }
+
+        // Fields whose values are unchanged keep their hash
+        let edited = json!({"name": "Alice", "tags": ["admin", "dev"], "meta": {"version": 2, "owner": "Alice"}});
+        let (bytes, edited_hashes) = enc.encode_with_field_hashes(&edited);
+        assert_eq!(bytes, TensEncoder::new().encode(&edited));
+        assert_eq!(edited_hashes["meta"], field_hashes["meta"]);
+        assert_eq!(edited_hashes["name"], field_hashes["name"]);
+        assert_ne!(edited_hashes["tags"], field_hashes["tags"]);
+
+        // Strings added to an earlier field shift document ids, not field hashes
+        let (_, before) = enc.encode_with_field_hashes(&json!({"a": "x", "b": ["y", "z"]}));
+        let (_, after) = enc.encode_with_field_hashes(&json!({"a": ["w", "x"], "b": ["y", "z"]}));
+        assert_ne!(after["a"], before["a"]);
+        assert_eq!(after["b"], before["b"]);
+
+        assert!(enc.encode_with_field_hashes(&json!([1])).1.is_empty());
     }
 
     #[test]
-    fn test_varint_127() {
-        let encoded = encode_varint(127);
-        assert_eq!(encoded, vec![127]);
-        let (val, _) = decode_varint(&encoded);
-        assert_eq!(val, 127);
+    fn test_encode_with_field_hashes_schema_reuse() {
+        // The second field reuses the sparse schema the first one defined
+        let options = EncoderOptions { sparse_records: true, ..Default::default() };
+        let doc = json!({"a": [{"x": 1}, {"y": 2}], "b": [{"x": 3}, {"y": 4}]});
+        let other = json!({"a": [{"p": 1}, {"q": 2}], "b": [{"p": 3}, {"q": 4}]});
+        let mut enc = TensEncoder::with_options(options.clone());
+        let (bytes, hashes) = enc.encode_with_field_hashes(&doc);
+        assert_eq!(bytes, TensEncoder::with_options(options.clone()).encode(&doc));
+        for (key, value) in doc.as_object().unwrap() {
+            assert_eq!(hashes[key], hash_tens_binary(&TensEncoder::with_options(options.clone()).encode(value)));
+        }
+        let (_, other_hashes) = enc.encode_with_field_hashes(&other);
+        assert_ne!(hashes["b"], other_hashes["b"]);
     }
 
     #[test]
-    fn test_varint_128() {
-        let encoded = encode_varint(128);
-        assert_eq!(encoded, vec![0x80, 0x01]);
-        let (val, len) = decode_varint(&encoded);
-        assert_eq!(val, 128);
-        assert_eq!(len, 2);
+    fn test_first_mismatch() {
+        let good = TensEncoder::new().encode(&json!({"a": 1, "b": "x"}));
+        assert_eq!(first_mismatch(&good, &good), None);
+
+        // Tree: OBJECT_START 2, key 0, INT8 1, key 1, STRING_REF 2
+        let tree = 5 + 1 + 3 * 2;
+        let mut skewed = good.clone();
+        skewed[tree + 3] = OP_INT32;
+        assert_eq!(first_mismatch(&good, &skewed), Some((tree + 3, format!(
+            "a: 0x03, opcode INT8 of the value at {0}; b: 0x05, opcode INT32 of the value at {0}", tree + 3
+        ))));
+
+        let mut skewed = good.clone();
+        skewed[9] = b'z';
+        let (offset, description) = first_mismatch(&good, &skewed).unwrap();
+        assert_eq!(offset, 9);
+        assert!(description.contains("bytes of dictionary entry 1"), "{}", description);
+
+        let (offset, description) = first_mismatch(&good, &good[..good.len() - 1]).unwrap();
+        assert_eq!(offset, good.len() - 1);
+        assert!(description.ends_with("b: end of input (19 bytes)"), "{}", description);
+        assert!(description.contains("string ref of the STRING_REF"), "{}", description);
     }
 
     #[test]
-    fn test_varint_300() {
-        let encoded = encode_varint(300);
-        let (val, _) = decode_varint(&encoded);
-        assert_eq!(val, 300);
+    fn test_first_mismatch_after_null_run() {
+        // A run stands for several elements: the walk must not count it as one
+        let options = EncoderOptions { null_run_threshold: Some(2), ..Default::default() };
+        let good = TensEncoder::with_options(options).encode(&json!({"a": [null, null, null], "b": 1}));
+        // Tree: OBJECT_START 2, key 0, ARRAY_START 3, NULL_RUN 3, key 1, INT8 1
+        let key = 10 + 7;
+        let mut skewed = good.clone();
+        skewed[key] = 0;
+        let (offset, description) = first_mismatch(&good, &skewed).unwrap();
+        assert_eq!(offset, key);
+        assert_eq!(description, "a: 0x01, key 1 of the OBJECT_START at 10; b: 0x00, key 1 of the OBJECT_START at 10");
+
+        let roots = TensEncoder::new().encode_multi(&[json!(1), json!("a")]).unwrap();
+        let mut skewed = roots.clone();
+        skewed[8] = 3;
+        let (offset, description) = first_mismatch(&roots, &skewed).unwrap();
+        assert_eq!(offset, 8);
+        assert_eq!(description, "a: 0x02, root count; b: 0x03, root count");
     }
 
     #[test]
-    fn test_varint_large() {
-        let encoded = encode_varint(100_000);
-        let (val, _) = decode_varint(&encoded);
-        assert_eq!(val, 100_000);
+    fn test_first_mismatch_depth_limit() {
+        let nested = |depth: usize, leaf: u8| {
+            let mut bytes = b"TENS\x02\x00".to_vec();
+            bytes.extend([OP_ARRAY_START, 1].repeat(depth));
+            bytes.push(leaf);
+            bytes
+        };
+        let max = DecodeLimits::default().max_depth;
+        // Nesting up to the limit needs more stack than a test thread has in
+        // debug builds
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || {
+                let (offset, description) = first_mismatch(&nested(max, OP_NULL), &nested(max, OP_TRUE)).unwrap();
+                assert_eq!(offset, 6 + 2 * max);
+                assert!(description.contains("opcode NULL of the value at"), "{}", description);
+
+                let (offset, description) = first_mismatch(&nested(200_000, OP_NULL), &nested(200_000, OP_TRUE)).unwrap();
+                assert_eq!(offset, 6 + 2 * 200_000);
+                let unlocated = format!("{} (cannot locate further)", DecodeError::LimitExceeded { limit: "max_depth", max });
+                assert_eq!(description, format!("a: 0x00, {0}; b: 0x01, {0}", unlocated));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
     }
 
-    // ── Header tests ──
+    #[test]
+    fn test_dictionary_frequencies() {
+        let records: Vec<Value> = (0..50)
+            .map(|i| json!({"id": i, "status": "healthy", "previous": "healthy", "host": format!("h{}", i % 2)}))
+            .collect();
+        let frequencies = dictionary_frequencies(&json!({"records": records}));
+        assert_eq!(frequencies[0], ("healthy".to_string(), 100));
+        // Keys count too; equal counts keep first-seen (dictionary) order
+        let rest: Vec<(&str, usize)> = frequencies[1..].iter().map(|(s, n)| (s.as_str(), *n)).collect();
+        assert_eq!(rest, [("host", 50), ("id", 50), ("previous", 50), ("status", 50), ("h0", 25), ("h1", 25), ("records", 1)]);
+    }
 
     #[test]
-    fn test_binary_header() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(null));
-        assert_eq!(&bytes[0..5], b"TENS\x02");
+    fn test_estimate_decoded_size() {
+        // Inline Value plus owned heap, counted the way the estimate models it
+        fn measured(value: &Value) -> usize {
+            std::mem::size_of_val(value) + match value {
+                Value::String(s) => s.len(),
+                Value::Array(arr) => arr.iter().map(measured).sum(),
+                Value::Object(obj) => obj.iter().map(|(k, v)| std::mem::size_of_val(k) + k.len() + measured(v)).sum(),
+                _ => 0,
+            }
+        }
+
+        let doc = json!({
+            "users": [
+                {"name": "Ada", "roles": ["admin", "dev"], "meta": {"age": 36, "active": true}},
+                {"name": "Grace", "roles": ["dev"], "meta": {"age": 45, "active": null}}
+            ],
+            "totals": [1, 2, 3, null, null, null, null],
+            "note": "a longer free-text string that dominates the payload"
+        });
+        let actual = measured(&doc);
+        let options = EncoderOptions {
+            null_run_threshold: Some(2),
+            int_arrays: IntArrayEncoding::Packed,
+            sparse_records: true,
+            ..Default::default()
+        };
+        for bytes in [TensEncoder::new().encode(&doc), TensEncoder::with_options(options).encode(&doc)] {
+            let estimate = estimate_decoded_size(&bytes).unwrap();
+            assert!(estimate * 2 > actual && estimate < actual * 2, "estimate {} vs measured {}", estimate, actual);
+        }
+
+        assert_eq!(estimate_decoded_size(b"TENS\x02\x00\x00").unwrap(), VALUE_SIZE);
+        assert!(estimate_decoded_size(b"TENS\x02\x00\x08\x02\x00").is_err());
     }
 
-    // ── Null encoding ──
+    #[test]
+    fn test_estimate_decoded_size_depth_limit() {
+        let nested = |depth: usize| {
+            let mut bytes = b"TENS\x02\x00".to_vec();
+            bytes.extend([OP_ARRAY_START, 1].repeat(depth));
+            bytes.push(OP_NULL);
+            bytes
+        };
+        let max = DecodeLimits::default().max_depth;
+        let exceeded = Err(DecodeError::LimitExceeded { limit: "max_depth", max });
+        // Nesting up to the limit needs more stack than a test thread has in
+        // debug builds
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || {
+                assert!(estimate_decoded_size(&nested(max)).is_ok());
+                assert_eq!(estimate_decoded_size(&nested(max + 1)), exceeded);
+                assert_eq!(estimate_decoded_size(&nested(200_000)), exceeded);
+                // hex_dump walks the same way
+                assert!(hex_dump(&nested(max)).is_ok());
+                assert_eq!(hex_dump(&nested(max + 1)).unwrap_err(), DecodeError::LimitExceeded { limit: "max_depth", max });
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    // ── TENS-Text tests ──
 
     #[test]
-    fn test_encode_null() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(null));
-        // Header(5) + dict_count varint(1 byte = 0) + OP_NULL(1)
-        assert_eq!(bytes.len(), 7);
-        assert_eq!(bytes[5], 0); // dict count = 0
-        assert_eq!(bytes[6], OP_NULL);
+    fn test_tens_text_basic() {
+        let data = json!([
+            {"name": "Alice", "age": 30},
+            {"name": "Bob", "age": 25}
+        ]);
+        let text = encode_tens_text(&data, None).unwrap();
+        assert!(text.starts_with("@version 1"));
+        assert!(text.contains("@schema"));
+        assert!(text.contains("Alice"));
+        assert!(text.contains("Bob"));
     }
 
-    // ── Boolean encoding ──
+    #[test]
+    fn test_tens_text_dict_dedup() {
+        let data = json!([
+            {"status": "active", "name": "A"},
+            {"status": "active", "name": "B"},
+            {"status": "active", "name": "C"}
+        ]);
+        let text = encode_tens_text(&data, None).unwrap();
+        assert!(text.contains("@dict"));
+        assert!(text.contains("active"));
+        // "active" appears 3 times → should be in dict
+        // Records should reference @0
+        assert!(text.contains("@0"));
+    }
 
     #[test]
-    fn test_encode_true() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(true));
-        assert_eq!(bytes[6], OP_TRUE);
+    fn test_tens_text_roundtrip() {
+        let data = json!([
+            {"name": "Alice", "score": 95},
+            {"name": "Bob", "score": 88}
+        ]);
+        let text = encode_tens_text(&data, None).unwrap();
+        let decoded = decode_tens_text(&text).unwrap();
+        let arr = decoded.as_array().unwrap();
+        assert_eq!(arr.len(), 2);
+        assert_eq!(arr[0]["name"].as_str().unwrap(), "Alice");
+        assert_eq!(arr[1]["score"].as_i64().unwrap(), 88);
     }
 
     #[test]
-    fn test_encode_false() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(false));
-        assert_eq!(bytes[6], OP_FALSE);
+    fn test_tens_text_tables_share_dictionary() {
+        let mut tables = BTreeMap::new();
+        tables.insert("users".to_string(), vec![
+            json!({"name": "alice", "role": "admin"}),
+            json!({"name": "bob", "role": "admin"}),
+        ]);
+        tables.insert("orders".to_string(), vec![
+            json!({"id": 1, "owner": "alice", "tags": ["new", "gift"]}),
+            json!({"id": 2, "owner": "carol"}),
+        ]);
+        let text = encode_tens_text_tables(&tables, &TextOptions::default()).unwrap();
+        assert_eq!(text.matches("@dict").count(), 1);
+        assert!(text.contains("@dict admin alice\n"), "{}", text);
+        assert!(text.contains("@schema users "));
+        assert!(text.contains("@schema orders "));
+        assert!(text.contains("  owner @1\n"));
+        assert_eq!(decode_tens_text_tables(&text).unwrap(), tables);
+
+        tables.insert("bad name".to_string(), vec![json!({"a": 1})]);
+        assert!(encode_tens_text_tables(&tables, &TextOptions::default()).is_err());
     }
 
-    // ── Number encoding ──
+    #[test]
+    fn test_tens_text_trailing_newline() {
+        let data = json!([{"name": "Alice", "tags": ["x", "y"]}, {"name": "Bob", "tags": ["x"]}]);
+        for trailing_newline in [true, false] {
+            let options = TextOptions { trailing_newline, ..TextOptions::default() };
+            let text = encode_tens_text_with(&data, &options).unwrap();
+            assert_eq!(text.ends_with('\n'), trailing_newline, "{:?}", text);
+            assert!(!text.ends_with("\n\n"));
+            assert!(text.ends_with("  tags x") || text.ends_with("  tags x\n"));
+
+            // Decoding ignores the policy, and re-encoding reapplies it
+            let decoded = decode_tens_text(&text).unwrap();
+            assert_eq!(decoded, data);
+            assert_eq!(encode_tens_text_with(&decoded, &options).unwrap(), text);
+
+            let empty = encode_tens_text_with(&json!([]), &options).unwrap();
+            assert_eq!(empty, if trailing_newline { "@version 1\n" } else { "@version 1" });
+        }
+    }
 
     #[test]
-    fn test_encode_int8_zero() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(0));
-        assert_eq!(bytes[6], OP_INT8);
-        assert_eq!(bytes[7], 0u8);
+    fn test_tens_text_wrap_scalars() {
+        assert_eq!(to_text_encodable(&json!(42)), json!({"value": 42}));
+        assert_eq!(to_text_encodable(&json!([{"a": 1}])), json!([{"a": 1}]));
+        assert!(encode_tens_text(&json!(42), None).is_err());
+
+        let options = TextOptions { wrap_scalars: true, ..TextOptions::default() };
+        for original in [json!(42), json!("hello world"), json!([1, 2, 3]), json!([7]), json!(null), json!({"a": 1})] {
+            let text = encode_tens_text_with(&original, &options).unwrap();
+            let decoded = decode_tens_text(&text).unwrap();
+            assert_eq!(from_text_encodable(decoded), original, "{}", text);
+        }
     }
 
     #[test]
-    fn test_encode_int8_positive() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(42));
-        assert_eq!(bytes[6], OP_INT8);
-        assert_eq!(bytes[7], 42u8);
+    fn test_tens_text_null_value() {
+        let data = json!([{"val": null}]);
+        let text = encode_tens_text(&data, None).unwrap();
+        assert!(text.contains("  val _"));
     }
 
     #[test]
-    fn test_encode_int8_negative() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(-1));
-        assert_eq!(bytes[6], OP_INT8);
-        assert_eq!(bytes[7], 0xFFu8); // -1 as i8 = 0xFF
+    fn test_tens_text_schema_extends_roundtrip() {
+        let data = json!([
+            {"id": 1, "createdAt": "2024-01-01", "name": "Widget"},
+            {"id": 2, "createdAt": "2024-01-02", "price": 9.5, "tags": ["new", "sale"]},
+            {"id": 3, "createdAt": "2024-01-03", "name": "Gadget"}
+        ]);
+        let text = encode_tens_text(&data, None).unwrap();
+        assert!(text.contains("@schema base createdAt:str id:num\n"));
+        assert!(text.contains("@schema data1 extends base name:str\n"));
+        assert!(text.contains("@schema data2 extends base price:num tags:"));
+
+        assert_eq!(decode_tens_text(&text).unwrap(), data);
     }
 
     #[test]
-    fn test_encode_int8_max() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(127));
-        assert_eq!(bytes[6], OP_INT8);
-        assert_eq!(bytes[7], 127u8);
+    fn test_tens_text_decode_extends() {
+        let text = "@version 1\n\
+            @schema base id:num tags:str[]\n\
+            @schema user extends base name:str\n\
+            \n\
+            user\n  id 1\n  tags a\n  tags b\n  name Ann\n";
+        assert_eq!(decode_tens_text(text).unwrap(), json!({"id": 1, "name": "Ann", "tags": ["a", "b"]}));
+
+        assert!(decode_tens_text("@schema user extends missing name:str\n").is_err());
     }
 
     #[test]
-    fn test_encode_int8_min() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(-128));
-        assert_eq!(bytes[6], OP_INT8);
-        assert_eq!(bytes[7], 0x80u8); // -128 as i8 = 0x80
+    fn test_tens_text_preserve_order() {
+        let data: Value = serde_json::from_str(r#"{"z":1,"a":2}"#).unwrap();
+        let sorted = encode_tens_text(&data, None).unwrap();
+        assert!(sorted.contains("@schema data a:num z:num\n"));
+
+        let options = TextOptions { preserve_order: true, ..Default::default() };
+        let text = encode_tens_text_with(&data, &options).unwrap();
+        let decoded = decode_tens_text(&text).unwrap();
+        assert_eq!(decoded, data);
+        #[cfg(feature = "preserve_order")]
+        {
+            assert!(text.contains("@schema data z:num a:num\n"));
+            let keys: Vec<&String> = decoded.as_object().unwrap().keys().collect();
+            assert_eq!(keys, ["z", "a"]);
+        }
+        #[cfg(not(feature = "preserve_order"))]
+        assert_eq!(text, sorted);
+
+        // Keys are taken in their normalized spelling, so none is dropped
+        let data = json!([{"\u{fb01}eld": "x", "b": 1}]);
+        let text = encode_tens_text_with(&data, &options).unwrap();
+        assert_eq!(decode_tens_text(&text).unwrap(), json!({"field": "x", "b": 1}));
+        #[cfg(feature = "preserve_order")]
+        assert!(text.contains("@schema data field:str b:num\n"));
     }
 
     #[test]
-    fn test_encode_int32() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(1000));
-        assert_eq!(bytes[6], OP_INT32);
-        let val = i32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
-        assert_eq!(val, 1000);
+    fn test_tens_text_versions() {
+        let body = "@schema data code:str\n\ndata\n  code 42\n";
+        // Version 1 (explicit or default) parses by syntax
+        assert_eq!(decode_tens_text(&format!("@version 1\n{}", body)).unwrap(), json!({"code": 42}));
+        assert_eq!(decode_tens_text(body).unwrap(), json!({"code": 42}));
+        // Version 2 follows the str field type
+        assert_eq!(decode_tens_text(&format!("@version 2\n{}", body)).unwrap(), json!({"code": "42"}));
+        assert!(decode_tens_text(&format!("@version 3\n{}", body)).unwrap_err().starts_with("line 1: unsupported @version"));
+
+        let data = json!([{"code": "42", "ok": "true", "n": 1}, {"code": "_", "ok": "x", "n": 2}]);
+        let v1 = encode_tens_text(&data, None).unwrap();
+        assert!(v1.starts_with("@version 1\n"));
+        assert!(v1.contains("  code \"42\"\n"));
+        assert_eq!(decode_tens_text(&v1).unwrap(), data);
+
+        let options = TextOptions { type_directed: true, ..Default::default() };
+        let v2 = encode_tens_text_with(&data, &options).unwrap();
+        assert!(v2.starts_with("@version 2\n"));
+        assert!(v2.contains("  code 42\n"));
+        assert!(v2.contains("  code \"_\"\n"));
+        assert_eq!(decode_tens_text(&v2).unwrap(), data);
+
+        // A str field that also holds numbers is typed `any` and stays lossless
+        let mixed = json!([{"code": "42"}, {"code": 7}]);
+        let v2 = encode_tens_text_with(&mixed, &options).unwrap();
+        assert!(v2.contains("code:any"));
+        assert_eq!(decode_tens_text(&v2).unwrap(), mixed);
     }
 
     #[test]
-    fn test_encode_int32_negative() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(-500));
-        assert_eq!(bytes[6], OP_INT32);
-        let val = i32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
-        assert_eq!(val, -500);
+    fn test_tens_text_error_line_numbers() {
+        let text = "@version 1\n@schema data name:str\n\n@foo bar\n";
+        assert_eq!(decode_tens_text(text).unwrap_err(), "line 4: unknown directive '@foo'");
+
+        let text = "@schema data name:str\n\ndata\n  name Alice\n  age\n";
+        assert_eq!(decode_tens_text(text).unwrap_err(), "line 5: malformed field line 'age'");
+
+        let text = "@schema data name:str\n  name Alice\n";
+        assert_eq!(decode_tens_text(text).unwrap_err(), "line 2: field line outside of a record 'name Alice'");
+
+        let text = "@schema child extends base x:num\n";
+        assert_eq!(decode_tens_text(text).unwrap_err(), "line 1: schema 'child' extends unknown schema 'base'");
     }
 
     #[test]
-    fn test_encode_float64() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(3.14));
-        assert_eq!(bytes[6], OP_FLOAT64);
-        let val = f64::from_le_bytes([
-            bytes[7], bytes[8], bytes[9], bytes[10],
-            bytes[11], bytes[12], bytes[13], bytes[14],
+    fn test_tens_text_record_checksums() {
+        let data = json!([
+            {"id": 1, "name": "Alice"},
+            {"id": 2, "name": "Bob"},
+            {"id": 3, "name": "Carol"},
+        ]);
+        let options = TextOptions { record_checksums: true, ..Default::default() };
+        let text = encode_tens_text_with(&data, &options).unwrap();
+        assert_eq!(text.matches("@checksum ").count(), 3);
+        let checksum = record_checksum(["data", "id 1", "name Alice"]);
+        assert!(text.contains(&format!("  name Alice\n@checksum {}\n", checksum)));
+        assert_eq!(decode_tens_text(&text).unwrap(), data);
+
+        // Flip one character of Bob's name: only his record is dropped
+        let corrupted = text.replace("name Bob", "name Bib");
+        let (value, warnings) = decode_tens_text_with_warnings(&corrupted, &CanonicalOptions::default()).unwrap();
+        assert_eq!(value, json!([data[0], data[2]]));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("record 'data' checksum mismatch"), "{}", warnings[0]);
+        assert_eq!(decode_tens_text(&corrupted).unwrap(), value);
+
+        // Without the option nothing changes; a stray @checksum is an error
+        assert!(!encode_tens_text(&data, None).unwrap().contains("@checksum"));
+        assert!(decode_tens_text("@checksum 00000000\n").unwrap_err().starts_with("line 1: @checksum outside"));
+    }
+
+    #[test]
+    fn test_tens_text_bytes() {
+        let data = json!([
+            {"id": 1, "payload": bytes_value(b"Hello"), "note": "SGVsbG8="},
+            {"id": 2, "payload": bytes_value(&[0, 255, 16, 1]), "note": "b:SGVsbG8="},
+        ]);
+        assert_eq!(data[0]["payload"], json!({"$bytes": "SGVsbG8="}));
+        let text = encode_tens_text(&data, None).unwrap();
+        assert!(text.contains("payload:bytes"), "{}", text);
+        assert!(text.contains("  payload b:SGVsbG8=\n"));
+        // Base64-looking strings stay strings; a literal `b:` string is quoted
+        assert!(text.contains("  note \"SGVsbG8=\"\n"));
+        assert!(text.contains("  note \"b:SGVsbG8=\"\n"));
+
+        let decoded = decode_tens_text(&text).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(value_bytes(&decoded[1]["payload"]).unwrap(), [0, 255, 16, 1]);
+        assert_eq!(value_bytes(&decoded[1]["note"]), None);
+        let schemas = parse_tens_text_schemas(&text).unwrap();
+        assert_eq!(validate_against_schema(&decoded, &schemas["data"]), Ok(()));
+
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|i| i * 37).collect();
+            assert_eq!(value_bytes(&bytes_value(&bytes)).unwrap(), bytes);
+        }
+        // Malformed base64 after `b:` is just a string
+        assert_eq!(decode_tens_text("@schema data x:str\n\ndata\n  x b:abc\n").unwrap(), json!({"x": "b:abc"}));
+    }
+
+    #[test]
+    fn test_tens_text_no_dict_fields() {
+        let data = json!([
+            {"owner": "alice", "reviewer": "bob"},
+            {"owner": "bob", "reviewer": "alice"},
+            {"owner": "alice", "reviewer": "alice"},
+        ]);
+        let dictionarized = encode_tens_text(&data, None).unwrap();
+        assert!(dictionarized.contains("  owner @0\n"), "{}", dictionarized);
+
+        let options = TextOptions { no_dict_fields: vec!["owner".into()], ..Default::default() };
+        let text = encode_tens_text_with(&data, &options).unwrap();
+        assert!(text.contains("  owner alice\n") && text.contains("  owner bob\n"), "{}", text);
+        assert!(!text.contains("  owner @"));
+        // Other fields still use the dictionary, which only counts their values
+        assert!(text.contains("@dict alice\n") && text.contains("  reviewer @0\n"), "{}", text);
+        assert_eq!(decode_tens_text(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn test_tens_text_separator_characters() {
+        let data = json!([
+            {"url": "http://example.com/a", "query": "a=1|b=2", "ns:id": "x:y"},
+            {"url": "http://example.com/a", "query": "k=v", "ns:id": "urn:isbn:0451450523"},
+            {"url": "mailto:bob", "query": "a=1|b=2", "ns:id": "|"},
+        ]);
+        let text = encode_tens_text(&data, None).unwrap();
+        // Colons stay bare; '=' and '|' are quoted, in fields and the dictionary
+        assert!(text.contains("@schema data ns:id:str query:str url:str\n"), "{}", text);
+        assert!(text.contains("@dict \"a=1|b=2\" http://example.com/a\n"), "{}", text);
+        assert!(text.contains("  ns:id urn:isbn:0451450523\n") && text.contains("  url mailto:bob\n"));
+        assert!(text.contains("  query \"k=v\"\n"));
+        assert_eq!(decode_tens_text(&text).unwrap(), data);
+
+        let schemas = parse_tens_text_schemas(&text).unwrap();
+        assert_eq!(schemas["data"].fields()[0], ("ns:id".to_string(), "str".to_string()));
+    }
+
+    #[test]
+    fn test_tens_text_array_nulls_and_empty_strings() {
+        let data = json!([
+            {"label": "", "tags": [null, "", "x"]},
+            {"label": "", "tags": ["_", null, "\"\""]},
+            {"label": "_", "tags": [""]},
+            {"label": null, "tags": [null]},
         ]);
-        assert!((val - 3.14).abs() < f64::EPSILON);
+        for type_directed in [false, true] {
+            let options = TextOptions { type_directed, ..Default::default() };
+            let text = encode_tens_text_with(&data, &options).unwrap();
+            // null is bare `_`; "" and "_" are quoted or dictionary refs, never bare
+            assert!(text.contains("  tags _\n") && text.contains("  tags \"_\"\n"), "{}", text);
+            assert!(!text.lines().any(|line| line.trim() == "tags" || line.trim() == "label"), "{}", text);
+            assert_eq!(decode_tens_text(&text).unwrap(), data, "{}", text);
+        }
     }
 
-    // ── String encoding ──
+    #[test]
+    fn test_tens_text_reserved_schema_names() {
+        let text = "@schema dict name:str\n\ndict\n  name Alice\n";
+        assert_eq!(decode_tens_text(text).unwrap_err(), "line 1: schema name 'dict' is reserved");
+        assert!(parse_tens_text_schemas(text).is_err());
+        for name in ["version", "schema", "encoding", "meta", "@data", "#data"] {
+            let text = format!("@schema {} name:str\n", name);
+            assert!(decode_tens_text(&text).unwrap_err().contains("is reserved"), "{}", name);
+        }
+        // Field names may still be directive words
+        let text = "@schema data dict:str\n\ndata\n  dict Alice\n";
+        assert_eq!(decode_tens_text(text).unwrap(), json!({"dict": "Alice"}));
+    }
 
     #[test]
-    fn test_encode_string() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!("hello"));
-        // Dict: 1 entry "hello"
-        assert_eq!(bytes[5], 1); // dict count
-        // Dict[0]: varint(5) + "hello"
-        assert_eq!(bytes[6], 5); // string length
-        assert_eq!(&bytes[7..12], b"hello");
-        // Value: STRING_REF + varint(0)
-        assert_eq!(bytes[12], OP_STRING_REF);
-        assert_eq!(bytes[13], 0); // string table index 0
+    fn test_tens_text_float_specials() {
+        let text = "@schema data x:num label:str\n\
+            data\n  x \"NaN\"\n  label \"NaN\"\n\
+            data\n  x \"-Infinity\"\n  label \"Infinity\"\n";
+        let decoded = decode_tens_text(text).unwrap();
+        assert_eq!(decoded, json!([
+            {"x": null, "label": "NaN"},
+            {"x": null, "label": "Infinity"}
+        ]));
+
+        // Same as the binary path: `[{"x": 1.5}, {"x": NaN}]` with a raw NaN
+        // FLOAT64 payload (a `Value` cannot hold NaN) decodes alike
+        let mut binary = b"TENS\x02\x01\x01x".to_vec();
+        binary.extend_from_slice(&[OP_ARRAY_START, 2, OP_OBJECT_START, 1, 0, OP_FLOAT64]);
+        binary.extend_from_slice(&1.5f64.to_le_bytes());
+        binary.extend_from_slice(&[OP_OBJECT_START, 1, 0, OP_FLOAT64]);
+        binary.extend_from_slice(&f64::NAN.to_le_bytes());
+        let via_binary = TensDecoder::new().decode(&binary).unwrap();
+        let via_text = decode_tens_text("@schema data x:num\ndata\n  x 1.5\ndata\n  x \"NaN\"\n").unwrap();
+        assert_eq!(via_binary, json!([{"x": 1.5}, {"x": null}]));
+        assert_eq!(via_binary, via_text);
     }
 
     #[test]
-    fn test_string_dedup() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(["hello", "hello", "world"]));
-        // Should have 2 dict entries: "hello" and "world"
-        assert_eq!(bytes[5], 2); // dict count
+    fn test_non_finite_floats_across_formats() {
+        let mut binary = b"TENS\x02\x00".to_vec();
+        binary.push(OP_FLOAT64);
+        binary.extend_from_slice(&f64::NAN.to_le_bytes());
+        let text = "@schema data x:num\ndata\n  x \"NaN\"\n";
+
+        for (policy, expected) in [(NonFiniteFloats::Null, json!(null)), (NonFiniteFloats::Preserve, json!("NaN"))] {
+            let options = CanonicalOptions { non_finite_floats: policy, ..Default::default() };
+            let via_binary = TensDecoder::with_non_finite_floats(policy).decode(&binary).unwrap();
+            let via_text = decode_tens_text_with(text, &options).unwrap();
+            assert_eq!(via_binary, expected);
+            assert_eq!(via_text["x"], expected);
+        }
+
+        // Preserved specials survive both formats, even in a num-typed field
+        let canonical = CanonicalOptions { non_finite_floats: NonFiniteFloats::Preserve, ..Default::default() };
+        let data = json!([{"x": 1.5}, {"x": "-Infinity"}]);
+        let options = TextOptions { canonical, ..Default::default() };
+        let text = encode_tens_text_with(&data, &options).unwrap();
+        assert!(text.contains("x:num"));
+        assert_eq!(decode_tens_text_with(&text, &canonical).unwrap(), data);
+        let encoder_options = EncoderOptions { canonical, ..Default::default() };
+        let bytes = TensEncoder::with_options(encoder_options).encode(&data);
+        assert_eq!(TensDecoder::with_non_finite_floats(NonFiniteFloats::Preserve).decode(&bytes).unwrap(), data);
     }
 
-    // ── Array encoding ──
+    #[test]
+    fn test_tens_text_sigil_strings() {
+        let data = json!([{"handle": "@handle", "tag": "#tag"}, {"handle": "@123", "tag": "#0"}]);
+        for type_directed in [false, true] {
+            let options = TextOptions { type_directed, ..Default::default() };
+            let text = encode_tens_text_with(&data, &options).unwrap();
+            for literal in ["\"@handle\"", "\"@123\"", "\"#tag\"", "\"#0\""] {
+                assert!(text.contains(literal), "{} not quoted in:\n{}", literal, text);
+            }
+            assert_eq!(decode_tens_text(&text).unwrap(), data);
+        }
+    }
 
     #[test]
-    fn test_encode_empty_array() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!([]));
-        assert_eq!(bytes[6], OP_ARRAY_START);
-        assert_eq!(bytes[7], 0); // length 0
+    fn test_validate_against_schema() {
+        let schemas = parse_tens_text_schemas(
+            "@schema base id:num\n@schema user extends base name:str tags:str[] active:bool\n",
+        ).unwrap();
+        let user = &schemas["user"];
+        assert_eq!(user.fields()[0], ("id".to_string(), "num".to_string()));
+        assert!(user.is_array("tags"));
+
+        let conforming = json!({"id": 1, "name": "Ada", "tags": ["x", "y"], "active": null});
+        assert_eq!(validate_against_schema(&conforming, user), Ok(()));
+
+        let records = json!([
+            conforming,
+            {"id": "2", "name": "Bob", "tags": "x", "active": true, "extra": 1},
+            {"id": 3, "tags": ["x", 4], "active": false},
+        ]);
+        assert_eq!(validate_against_schema(&records, user).unwrap_err(), vec![
+            SchemaViolation::UnexpectedField { path: "[1].extra".into() },
+            SchemaViolation::TypeMismatch { path: "[1].id".into(), expected: "num".into(), found: "str" },
+            SchemaViolation::ScalarWhereArray { path: "[1].tags".into() },
+            SchemaViolation::MissingField { path: "[2].name".into() },
+            SchemaViolation::TypeMismatch { path: "[2].tags[1]".into(), expected: "str".into(), found: "num" },
+        ]);
+
+        assert_eq!(
+            parse_tens_text_schemas("@schema a x:num\n@schema b extends c y:str\n").unwrap_err(),
+            "line 2: schema 'b' extends unknown schema 'c'"
+        );
     }
 
     #[test]
-    fn test_encode_array() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!([1, 2, 3]));
-        assert_eq!(bytes[6], OP_ARRAY_START);
-        assert_eq!(bytes[7], 3); // length 3
-        // Each element: OP_INT8 + byte
-        assert_eq!(bytes[8], OP_INT8);
-        assert_eq!(bytes[9], 1);
-        assert_eq!(bytes[10], OP_INT8);
-        assert_eq!(bytes[11], 2);
-        assert_eq!(bytes[12], OP_INT8);
-        assert_eq!(bytes[13], 3);
+    fn test_validate_text() {
+        let data = json!([
+            {"name": "Ada", "note": "say \"hi\"", "tags": ["x", "y"]},
+            {"name": "Bob", "note": "say \"hi\"", "tags": ["x"]}
+        ]);
+        let options = TextOptions { record_checksums: true, ..TextOptions::default() };
+        let clean = encode_tens_text_with(&data, &options).unwrap();
+        assert_eq!(validate_text(&clean), vec![]);
+
+        let diagnostic = |line, severity, message: &str| TextDiagnostic { line, severity, message: message.into() };
+        let out_of_range = "@schema r name:str\n@dict Ada\n\nr\n  name @0\nr\n  name @1\n";
+        assert_eq!(validate_text(out_of_range), vec![
+            diagnostic(7, Severity::Error, "dictionary ref @1 out of range (dict has 1 entries)"),
+        ]);
+
+        let orphan = "  name Ada\n@schema r name:str\nr\n  name \"Bob\n  name Cy\n  age 3\n";
+        assert_eq!(validate_text(orphan), vec![
+            diagnostic(1, Severity::Error, "field line outside of a record 'name Ada'"),
+            diagnostic(4, Severity::Error, "unterminated quoted string \"Bob"),
+            diagnostic(5, Severity::Warning, "field 'name' set twice; the last value wins"),
+            diagnostic(6, Severity::Error, "field 'age' is not in schema 'r'"),
+        ]);
     }
 
-    // ── Object encoding ──
+    #[test]
+    fn test_size_report() {
+        let records: Vec<Value> = (0..50)
+            .map(|i| json!({"id": i, "status": "active", "region": "eu-west-1", "tags": ["alpha", "beta"]}))
+            .collect();
+        let doc = Value::Array(records);
+        let report = size_report(&doc);
+        assert_eq!(report.json_bytes, serde_json::to_string(&doc).unwrap().len());
+        assert_eq!(report.tens_binary_bytes, TensEncoder::new().encode(&doc).len());
+        assert!(report.tens_binary_bytes < report.json_bytes);
+        assert!(report.tens_text_bytes.is_some_and(|len| len > 0));
+        // A one-byte entry count, then per entry a one-byte length followed by its UTF-8 bytes
+        let strings = ["id", "region", "eu-west-1", "status", "active", "tags", "alpha", "beta"];
+        assert_eq!(report.dictionary_bytes, 1 + strings.iter().map(|s| 1 + s.len()).sum::<usize>());
+
+        let scalar = size_report(&json!(7));
+        assert_eq!(scalar, SizeReport { json_bytes: 1, tens_binary_bytes: 8, tens_text_bytes: None, dictionary_bytes: 1 });
+    }
 
     #[test]
-    fn test_encode_empty_object() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!({}));
-        assert_eq!(bytes[6], OP_OBJECT_START);
-        assert_eq!(bytes[7], 0); // 0 fields
+    fn test_binary_to_text_roundtrip() {
+        let data = json!([
+            {"name": "Alice", "role": "admin", "score": 95},
+            {"name": "Bob", "role": "admin", "score": 88}
+        ]);
+        let binary = TensEncoder::new().encode(&data);
+        let text = binary_to_text(&binary, None).unwrap();
+        assert_eq!(text, encode_tens_text(&data, None).unwrap());
+        assert_eq!(text_to_binary(&text).unwrap(), binary);
     }
 
     #[test]
-    fn test_encode_object_sorted_keys() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!({"b": 2, "a": 1}));
-        // Dict should be: "a", "b" (sorted key scan order)
-        let entries = enc.string_table_entries();
-        assert_eq!(entries, &["a", "b"]);
+    fn test_text_to_binary_roundtrip() {
+        let text = encode_tens_text(&json!([
+            {"id": 1, "tags": ["a", "b"]},
+            {"id": 2, "tags": ["c"]}
+        ]), None).unwrap();
+        let binary = text_to_binary(&text).unwrap();
+        assert_eq!(binary_to_text(&binary, None).unwrap(), text);
     }
 
-    // ── Canonicalization tests ──
+    #[test]
+    fn test_text_diff() {
+        let a = "@version 1\n@schema data name:str role:str age:num\n@dict admin\n\n\
+            data\n  name Alice\n  role @0\n  age 30\n\
+            data\n  name Bob\n  role user\n  age 25\n";
+        // Same data: different field order, dictionary and ref usage
+        let b = "@version 1\n@schema data age:num role:str name:str\n@dict Bob user\n\n\
+            data\n  age 30\n  role admin\n  name Alice\n\
+            data\n  age 25\n  role @1\n  name @0\n";
+        assert_eq!(text_diff(a, b).unwrap(), vec![]);
+
+        let changed = b.replace("age 25", "age 26");
+        assert_eq!(text_diff(a, &changed).unwrap(), vec![RecordDiff::Changed {
+            index: 1,
+            fields: vec![FieldDiff::Changed { field: "age".into(), old: json!(25), new: json!(26) }],
+        }]);
+
+        let one_record = "@schema data name:str role:str age:num\n\ndata\n  name Alice\n  role admin\n  age 30\n";
+        assert_eq!(text_diff(a, one_record).unwrap(), vec![RecordDiff::Removed {
+            index: 1,
+            record: json!({"name": "Bob", "role": "user", "age": 25}),
+        }]);
+
+        assert!(text_diff(a, "@bogus\n").is_err());
+    }
 
     #[test]
-    fn test_canonicalize_sorts_keys() {
-        let val = json!({"c": 3, "a": 1, "b": 2});
-        let canonical = canonicalize(&val);
-        let obj = canonical.as_object().unwrap();
-        let keys: Vec<&String> = obj.keys().collect();
-        assert_eq!(keys, vec!["a", "b", "c"]);
+    fn test_binary_to_text_rejects_scalar_root() {
+        let binary = TensEncoder::new().encode(&json!(42));
+        assert_eq!(binary_to_text(&binary, None), Err(DecodeError::UnsupportedRoot { reason: "num".into() }));
+        let binary = TensEncoder::new().encode(&json!([{"a": 1}, "b"]));
+        assert_eq!(binary_to_text(&binary, None), Err(DecodeError::UnsupportedRoot { reason: "array holding a str".into() }));
     }
 
     #[test]
-    fn test_canonicalize_nested_sort() {
-        let val = json!({"z": {"b": 1, "a": 2}, "m": 3});
-        let canonical = canonicalize(&val);
-        let outer_keys: Vec<&String> = canonical.as_object().unwrap().keys().collect();
-        assert_eq!(outer_keys, vec!["m", "z"]);
-        let inner_keys: Vec<&String> = canonical["z"].as_object().unwrap().keys().collect();
-        assert_eq!(inner_keys, vec!["a", "b"]);
+    fn test_binary_to_text_one_record_array() {
+        // One record reads back as a bare object, so the array is refused
+        // rather than silently turned into one
+        let binary = TensEncoder::new().encode(&json!([{"a": 1}]));
+        let err = binary_to_text(&binary, None).unwrap_err();
+        assert!(matches!(&err, DecodeError::UnsupportedRoot { reason } if reason.starts_with("array of one object")), "{}", err);
+
+        let object = TensEncoder::new().encode(&json!({"a": 1}));
+        let text = binary_to_text(&object, None).unwrap();
+        assert_eq!(text_to_binary(&text).unwrap(), object);
+        let empty = TensEncoder::new().encode(&json!([]));
+        assert_eq!(text_to_binary(&binary_to_text(&empty, None).unwrap()).unwrap(), empty);
     }
 
-    // ── Round-trip tests ──
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_tens_text_file_roundtrip() {
+        let data = json!([
+            {"name": "Alice", "score": 95},
+            {"name": "Bob", "score": 88}
+        ]);
+        let path = std::env::temp_dir().join(format!("tens_text_{}.tens", std::process::id()));
+        write_tens_text_file(&path, &data, &TextOptions::default()).unwrap();
+        let decoded = read_tens_text_file(&path);
+
+        // Same content behind a BOM must decode identically
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("\u{feff}{}", text)).unwrap();
+        let decoded_bom = read_tens_text_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded.unwrap(), data);
+        assert_eq!(decoded_bom.unwrap(), data);
+
+        // Options reach the encoder
+        let options = TextOptions { encoding: Some("cl100k_base".into()), ..Default::default() };
+        write_tens_text_file(&path, &data, &options).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.contains("@encoding cl100k_base"));
+    }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_roundtrip_null() {
-        let mut enc = TensEncoder::new();
-        let mut dec = TensDecoder::new();
-        let bytes = enc.encode(&json!(null));
-        let decoded = dec.decode(&bytes).unwrap();
-        assert_eq!(decoded, json!(null));
+    fn test_read_tens_text_file_missing() {
+        let path = std::env::temp_dir().join("tens_text_does_not_exist.tens");
+        assert!(read_tens_text_file(&path).is_err());
     }
 
+    // ── Reader decoding tests ──
+
+    #[cfg(feature = "std")]
     #[test]
-    fn test_roundtrip_bool() {
-        let mut enc = TensEncoder::new();
-        let mut dec = TensDecoder::new();
-        let bytes = enc.encode(&json!(true));
-        assert_eq!(dec.decode(&bytes).unwrap(), json!(true));
+    fn test_decode_reader_matches_slice_decode() {
+        let original = json!({
+            "users": [
+                {"name": "Alice", "scores": [100, 95, 88], "ratio": 0.5},
+                {"name": "Bob", "scores": [72, -70000], "active": false}
+            ],
+            "meta": {"version": 2, "format": "tens", "note": null}
+        });
+        let bytes = TensEncoder::new().encode(&original);
 
-        let bytes = enc.encode(&json!(false));
-        assert_eq!(dec.decode(&bytes).unwrap(), json!(false));
+        let from_slice = TensDecoder::new().decode(&bytes).unwrap();
+        let from_reader = TensDecoder::new().decode_reader(std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(from_reader, from_slice);
+        assert_eq!(from_reader, original);
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_roundtrip_integers() {
-        let mut enc = TensEncoder::new();
-        for val in &[0, 1, -1, 42, -128, 127, 128, -500, 1000, 100_000, -100_000] {
-            let bytes = enc.encode(&json!(val));
-            let mut dec = TensDecoder::new();
-            let decoded = dec.decode(&bytes).unwrap();
-            assert_eq!(decoded.as_i64().unwrap(), *val as i64, "roundtrip failed for {}", val);
+    fn test_decode_reader_shares_value_decoding() {
+        let original = json!({
+            "rows": [{"id": 1, "tag": "a"}, {"id": 2, "tag": "b"}, {"id": 3, "tag": "a"}],
+            "sparse": [{"x": 1, "y": [1, -2, 300]}, {"x": 2}, {"y": [4]}],
+            "gaps": [null, null, null, null, 1.5, "a"],
+            "big": 9007199254740993i64,
+        });
+        let options = EncoderOptions {
+            columnar_arrays: true,
+            sparse_records: true,
+            int_arrays: IntArrayEncoding::Packed,
+            null_run_threshold: Some(2),
+            ..Default::default()
+        };
+        let bytes = TensEncoder::with_options(options).encode(&original);
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+
+        // Cut anywhere in the value tree, both paths fail the same way
+        let tree = TensDecoder::new().read_preamble(&bytes).unwrap();
+        for len in tree..bytes.len() {
+            let from_slice = TensDecoder::new().decode(&bytes[..len]).unwrap_err();
+            let from_reader = TensDecoder::new().decode_reader(&bytes[..len]).unwrap_err();
+            assert_eq!(from_reader, from_slice, "truncation at {}", len);
         }
+
+        // User opcodes size their own payload, which needs the whole input
+        let mut dec = TensDecoder::new().with_extension(0xE0, |_| Ok((Value::Null, 0)));
+        let doc = b"TENS\x02\x00\xE0";
+        assert_eq!(dec.decode(doc).unwrap(), Value::Null);
+        let err = dec.decode_reader(&doc[..]).unwrap_err().to_string();
+        assert_eq!(err, "Extension 0xe0 decodes from a slice: use decode, not decode_reader");
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_roundtrip_float() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!(3.14159));
-        let mut dec = TensDecoder::new();
-        let decoded = dec.decode(&bytes).unwrap();
-        assert!((decoded.as_f64().unwrap() - 3.14159).abs() < f64::EPSILON);
+    fn test_decode_reader_truncated() {
+        let bytes = TensEncoder::new().encode(&json!({"name": "Alice"}));
+        for len in 0..bytes.len() {
+            let result = TensDecoder::new().decode_reader(&bytes[..len]);
+            assert!(result.is_err(), "truncation at {} should fail", len);
+        }
     }
 
+    // ── Dictionary compression tests ──
+
+    #[cfg(feature = "compression")]
     #[test]
-    fn test_roundtrip_string() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!("hello world"));
+    fn test_compressed_dictionary_roundtrip() {
+        let urls: Vec<Value> = (0..100)
+            .map(|i| json!(format!("https://api.example.com/v1/resources/items/{}/details", i)))
+            .collect();
+        let original = Value::Array(urls);
+
+        let plain = TensEncoder::new().encode(&original);
+        let mut enc = TensEncoder::with_options(EncoderOptions { compress_dictionary: true, ..Default::default() });
+        let compressed = enc.encode(&original);
+
+        assert_eq!(compressed[4], 0x02 | FLAG_COMPRESSED_DICT);
+        assert!(compressed.len() < plain.len() / 2, "{} vs {}", compressed.len(), plain.len());
+
         let mut dec = TensDecoder::new();
-        let decoded = dec.decode(&bytes).unwrap();
-        assert_eq!(decoded.as_str().unwrap(), "hello world");
+        assert_eq!(dec.decode(&compressed).unwrap(), original);
     }
 
+    #[cfg(feature = "compression")]
     #[test]
-    fn test_roundtrip_array() {
-        let mut enc = TensEncoder::new();
-        let original = json!([1, "two", true, null, 3.5]);
+    fn test_compressed_dictionary_decode_reader() {
+        let original = json!(["https://example.com/a", "https://example.com/b"]);
+        let mut enc = TensEncoder::with_options(EncoderOptions { compress_dictionary: true, ..Default::default() });
         let bytes = enc.encode(&original);
-        let mut dec = TensDecoder::new();
-        let decoded = dec.decode(&bytes).unwrap();
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+    }
 
-        let arr = decoded.as_array().unwrap();
-        assert_eq!(arr.len(), 5);
-        assert_eq!(arr[0].as_i64().unwrap(), 1);
-        assert_eq!(arr[1].as_str().unwrap(), "two");
-        assert_eq!(arr[2].as_bool().unwrap(), true);
-        assert!(arr[3].is_null());
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_dictionary_inflation_limit() {
+        // 4 MiB of one letter deflates to a few KiB; inflation must stop at
+        // the limit instead of producing the whole entry first
+        let mut block = encode_varint(4 << 20);
+        block.resize(block.len() + (4 << 20), b'a');
+        let mut bytes = b"TENS\x82\x01".to_vec();
+        bytes.extend(deflate_block(&block));
+        bytes.extend([OP_STRING_REF, 0]);
+        assert!(bytes.len() < 16 << 10, "{}", bytes.len());
+
+        let limits = DecodeLimits { max_dictionary_bytes: 1 << 10, ..DecodeLimits::default() };
+        let exceeded = Err(DecodeError::LimitExceeded { limit: "max_dictionary_bytes", max: 1 << 10 });
+        assert_eq!(TensDecoder::new().with_limits(limits).decode(&bytes), exceeded);
+        assert_eq!(TensDecoder::new().with_limits(limits).decode_reader(bytes.as_slice()), exceeded);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!("a".repeat(4 << 20)));
     }
 
+    #[cfg(not(feature = "compression"))]
     #[test]
-    fn test_roundtrip_object() {
-        let mut enc = TensEncoder::new();
-        let original = json!({"name": "Alice", "age": 30, "active": true});
-        let bytes = enc.encode(&original);
+    fn test_compressed_dictionary_requires_feature() {
         let mut dec = TensDecoder::new();
-        let decoded = dec.decode(&bytes).unwrap();
+        assert!(dec.decode(b"TENS\x82\x00\x00\x00").is_err());
+    }
 
-        assert_eq!(decoded["name"].as_str().unwrap(), "Alice");
-        assert_eq!(decoded["age"].as_i64().unwrap(), 30);
-        assert_eq!(decoded["active"].as_bool().unwrap(), true);
+    // ── Front-coded dictionary tests ──
+
+    #[test]
+    fn test_front_coded_dictionary_roundtrip() {
+        let paths: Vec<Value> = (0..100).map(|i| json!(format!("/a/b/c{}", i))).collect();
+        let original = Value::Array(paths);
+
+        let plain = TensEncoder::new().encode(&original);
+        let options = EncoderOptions { front_code_dictionary: true, ..Default::default() };
+        let mut enc = TensEncoder::with_options(options);
+        let front_coded = enc.encode(&original);
+
+        assert_eq!(front_coded[4], 0x02 | FLAG_FRONT_CODED_DICT);
+        assert_eq!(enc.string_table_entries()[..3], ["/a/b/c0", "/a/b/c1", "/a/b/c10"]);
+        // "/a/b/c1" after "/a/b/c0": shares 6 bytes, stores "1"
+        assert_eq!(front_coded[6..15], [0, 7, b'/', b'a', b'/', b'b', b'/', b'c', b'0']);
+        assert_eq!(front_coded[15..18], [6, 1, b'1']);
+        assert!(front_coded.len() < plain.len() * 2 / 3, "{} vs {}", front_coded.len(), plain.len());
+
+        assert_eq!(TensDecoder::new().decode(&front_coded).unwrap(), original);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(front_coded.as_slice()).unwrap(), original);
     }
 
+    #[cfg(feature = "compression")]
     #[test]
-    fn test_roundtrip_nested() {
-        let mut enc = TensEncoder::new();
-        let original = json!({
-            "users": [
-                {"name": "Alice", "scores": [100, 95, 88]},
-                {"name": "Bob", "scores": [72, 85]}
-            ],
-            "meta": {"version": 2, "format": "tens"}
-        });
-        let bytes = enc.encode(&original);
-        let mut dec = TensDecoder::new();
-        let decoded = dec.decode(&bytes).unwrap();
+    fn test_front_coded_compressed_dictionary() {
+        let paths: Vec<Value> = (0..20).map(|i| json!(format!("/srv/data/{}.json", i))).collect();
+        let original = Value::Array(paths);
+        let options = EncoderOptions { front_code_dictionary: true, compress_dictionary: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options).encode(&original);
+        assert_eq!(bytes[4], 0x02 | FLAG_FRONT_CODED_DICT | FLAG_COMPRESSED_DICT);
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), original);
+        assert_eq!(TensDecoder::new().decode_reader(bytes.as_slice()).unwrap(), original);
+    }
 
-        assert_eq!(decoded["users"][0]["name"].as_str().unwrap(), "Alice");
-        assert_eq!(decoded["users"][1]["scores"][0].as_i64().unwrap(), 72);
-        assert_eq!(decoded["meta"]["version"].as_i64().unwrap(), 2);
+    #[test]
+    fn test_front_coded_prefix_exceeds_previous() {
+        // Entry 1 claims a 3-byte prefix of the 2-byte entry 0
+        let bytes = b"TENS\x42\x02\x00\x02ab\x03\x01c\x07\x01";
+        assert!(TensDecoder::new().decode(bytes).is_err());
+        // The first entry has no previous entry to share with
+        assert!(TensDecoder::new().decode(b"TENS\x42\x01\x01\x01a\x07\x00").is_err());
     }
 
-    // ── Hash tests ──
+    // ── Validation tests ──
 
     #[test]
-    fn test_hash_deterministic() {
+    fn test_validate_binary_accepts_encoder_output() {
         let mut enc = TensEncoder::new();
-        let bytes1 = enc.encode(&json!({"a": 1}));
-        let bytes2 = enc.encode(&json!({"a": 1}));
-        assert_eq!(hash_tens_binary(&bytes1), hash_tens_binary(&bytes2));
+        let bytes = enc.encode(&json!({"name": "Alice", "tags": ["a", "b"]}));
+        let strict = ValidateOptions { reject_unused_dictionary: true };
+        assert!(validate_binary(&bytes, &strict).is_ok());
     }
 
     #[test]
-    fn test_hash_different_for_different_values() {
-        let mut enc = TensEncoder::new();
-        let bytes1 = enc.encode(&json!({"a": 1}));
-        let bytes2 = enc.encode(&json!({"a": 2}));
-        assert_ne!(hash_tens_binary(&bytes1), hash_tens_binary(&bytes2));
+    fn test_is_stable() {
+        let bytes = TensEncoder::new().encode(&json!({"name": "Alice", "scores": [1, 300, 2.5]}));
+        assert_eq!(is_stable(&bytes), Ok(true));
+
+        // 5 as INT32 instead of the minimal INT8
+        let mut non_minimal = b"TENS\x02\x00".to_vec();
+        non_minimal.push(OP_INT32);
+        non_minimal.extend_from_slice(&5i32.to_le_bytes());
+        assert_eq!(TensDecoder::new().decode(&non_minimal).unwrap(), json!(5));
+        assert_eq!(is_stable(&non_minimal), Ok(false));
+
+        assert!(is_stable(b"NOPE").is_err());
+
+        // Non-default output is stable against the options that wrote it
+        let value = json!({"at": "2024-01-01T00:00:00Z", "n": 300});
+        let options = EncoderOptions { small_ints: true, timestamps: true, ..Default::default() };
+        let bytes = TensEncoder::with_options(options.clone()).encode(&value);
+        assert_eq!(is_stable(&bytes), Ok(false));
+        assert_eq!(is_stable_with(&bytes, &options), Ok(true));
     }
 
     #[test]
-    fn test_hash_length() {
-        let mut enc = TensEncoder::new();
-        let bytes = enc.encode(&json!({"test": "data"}));
-        let hash = hash_tens_binary(&bytes);
-        assert_eq!(hash.len(), 64); // SHA-256 = 32 bytes = 64 hex chars
+    fn test_validate_binary_unused_dictionary_entry() {
+        // Dictionary ["a", "b"], value tree only references "a"
+        let mut bytes = b"TENS\x02".to_vec();
+        bytes.extend_from_slice(&[2, 1, b'a', 1, b'b']);
+        bytes.extend_from_slice(&[OP_STRING_REF, 0]);
+
+        let strict = ValidateOptions { reject_unused_dictionary: true };
+        assert_eq!(validate_binary(&bytes, &strict), Err(DecodeError::UnusedDictionaryEntry(1)));
+        assert!(validate_binary(&bytes, &ValidateOptions::default()).is_ok());
+
+        let mut dec = TensDecoder::new();
+        assert_eq!(dec.decode(&bytes).unwrap(), json!("a"));
     }
 
-    // ── TENS-Text tests ──
+    // ── Decoder error handling ──
 
     #[test]
-    fn test_tens_text_basic() {
-        let data = json!([
-            {"name": "Alice", "age": 30},
-            {"name": "Bob", "age": 25}
-        ]);
-        let text = encode_tens_text(&data, None).unwrap();
-        assert!(text.starts_with("@version 1"));
-        assert!(text.contains("@schema"));
-        assert!(text.contains("Alice"));
-        assert!(text.contains("Bob"));
+    fn test_decode_and_verify() {
+        let value = json!({"id": 7, "name": "Alice"});
+        let bytes = TensEncoder::new().encode(&value);
+        let hash = hash_tens_binary(&bytes);
+        assert_eq!(TensDecoder::new().decode_and_verify(&bytes, &hash).unwrap(), value);
+        assert_eq!(TensDecoder::new().decode_and_verify(&bytes, &hash.to_uppercase()).unwrap(), value);
+
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let err = TensDecoder::new().decode_and_verify(&tampered, &hash).unwrap_err();
+        assert_eq!(err, DecodeError::HashMismatch { expected: hash.clone(), actual: hash_tens_binary(&tampered) });
+        assert!(err.to_string().starts_with("Hash mismatch: expected "));
     }
 
     #[test]
-    fn test_tens_text_dict_dedup() {
-        let data = json!([
-            {"status": "active", "name": "A"},
-            {"status": "active", "name": "B"},
-            {"status": "active", "name": "C"}
-        ]);
-        let text = encode_tens_text(&data, None).unwrap();
-        assert!(text.contains("@dict"));
-        assert!(text.contains("active"));
-        // "active" appears 3 times → should be in dict
-        // Records should reference @0
-        assert!(text.contains("@0"));
+    fn test_custom_format() {
+        let value = json!({"id": 7, "tags": ["a", "b"]});
+        let fork = TensFormat { magic: *b"ACME", version: 0x01 };
+        let options = EncoderOptions { format: fork, front_code_dictionary: true, ..EncoderOptions::default() };
+        let bytes = TensEncoder::with_options(options).encode(&value);
+        assert_eq!(&bytes[..5], b"ACME\x41");
+        let plain = EncoderOptions { format: fork, ..EncoderOptions::default() };
+        assert_eq!(TensEncoder::with_options(plain).encode(&json!(1)), b"ACME\x01\x00\x03\x01");
+
+        assert_eq!(TensDecoder::new().decode(&bytes), Err(DecodeError::from("Invalid TENS header magic")));
+        assert_eq!(TensDecoder::new().with_format(fork).decode(&bytes).unwrap(), value);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().with_format(fork).decode_reader(&bytes[..]).unwrap(), value);
+
+        // Same magic, other version
+        let other = TensFormat { version: 0x02, ..fork };
+        assert!(TensDecoder::new().with_format(other).decode(&bytes).is_err());
+        // And the fork decoder rejects upstream documents
+        let upstream = TensEncoder::new().encode(&value);
+        assert!(TensDecoder::new().with_format(fork).decode(&upstream).is_err());
+        assert_eq!(TensFormat::default(), TensFormat::UPSTREAM);
+
+        // A version overlapping the layout flags is refused on both sides
+        let flagged = TensFormat { version: 0x21, ..fork };
+        let options = EncoderOptions { format: flagged, ..EncoderOptions::default() };
+        for root in [json!(1), value.clone()] {
+            assert_eq!(
+                TensEncoder::with_options(options.clone()).try_encode(&root),
+                Err(EncodeError::InvalidFormat { version: 0x21 })
+            );
+        }
+        let err = TensDecoder::new().with_format(flagged).decode(b"ACME\x21\x00\x00").unwrap_err();
+        assert!(err.to_string().contains("overlaps the layout flags 0x20"), "{}", err);
     }
 
     #[test]
-    fn test_tens_text_roundtrip() {
-        let data = json!([
-            {"name": "Alice", "score": 95},
-            {"name": "Bob", "score": 88}
-        ]);
-        let text = encode_tens_text(&data, None).unwrap();
-        let decoded = decode_tens_text(&text).unwrap();
-        let arr = decoded.as_array().unwrap();
-        assert_eq!(arr.len(), 2);
-        assert_eq!(arr[0]["name"].as_str().unwrap(), "Alice");
-        assert_eq!(arr[1]["score"].as_i64().unwrap(), 88);
+    fn test_feature_header() {
+        let value = json!({"id": 7, "price": 0.1});
+        let plain = TensEncoder::new().encode(&value);
+        assert_eq!(&plain[..5], b"TENS\x02");
+
+        let options = EncoderOptions { feature_header: true, ..EncoderOptions::default() };
+        let bytes = TensEncoder::with_options(options.clone()).encode(&value);
+        assert_eq!(&bytes[..6], b"TENS\x12\x00");
+        assert_eq!(bytes[6..], plain[5..]);
+        let mut decoder = TensDecoder::new();
+        assert_eq!(decoder.decode(&bytes).unwrap(), value);
+        assert_eq!(decoder.features(), 0);
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(&bytes[..]).unwrap(), value);
+        assert!(hex_dump(&bytes).unwrap().contains("0005 00                         features 0x00\n"));
+        assert!(first_mismatch(&bytes, &plain).unwrap().1.contains("a: 0x12, version byte"));
+
+        // The document declares what it needs, not what was enabled
+        let decimal = EncoderOptions { decimal_numbers: true, ..options.clone() };
+        assert_eq!(TensEncoder::with_options(decimal.clone()).encode(&json!({"id": 7}))[5], 0);
+        assert_eq!(TensEncoder::with_options(decimal.clone()).encode(&json!(0.1))[5], FEATURE_DECIMAL_NUMBERS);
+        let bytes = TensEncoder::with_options(decimal).encode(&value);
+        assert_eq!(bytes[5], FEATURE_DECIMAL_NUMBERS);
+        #[cfg(feature = "decimal")]
+        {
+            assert_eq!(decoder.decode(&bytes).unwrap(), value);
+            assert_eq!(decoder.features(), FEATURE_DECIMAL_NUMBERS);
+        }
+        #[cfg(not(feature = "decimal"))]
+        assert!(decoder.decode(&bytes).unwrap_err().to_string().contains("`decimal` feature"));
+
+        let unclaimed = TensEncoder::with_options(options.clone()).with_extension(0xE0, |_| None).encode(&value);
+        assert_eq!(unclaimed, TensEncoder::with_options(options.clone()).encode(&value));
+        let claim_price = |value: &Value| (value.as_f64() == Some(0.1)).then(Vec::new);
+        let tagged = TensEncoder::with_options(options).with_extension(0xE0, claim_price).encode(&value);
+        assert_eq!(tagged[5], FEATURE_USER_OPCODES);
+        assert_eq!(
+            TensDecoder::new().decode(&tagged),
+            Err(DecodeError::from("Document uses user opcodes, but no extension handlers are registered"))
+        );
+        let mut decoder = TensDecoder::new().with_extension(0xE0, |_| Ok((json!(0.1), 0)));
+        assert_eq!(decoder.decode(&tagged).unwrap(), value);
+        assert_eq!(decoder.features(), FEATURE_USER_OPCODES);
+
+        // Features from a newer encoder are refused, not ignored
+        let mut newer = tagged.clone();
+        newer[5] = 0x81;
+        assert_eq!(TensDecoder::new().decode(&newer), Err(DecodeError::from("Unsupported TENS features: 0x80")));
+        assert!(TensDecoder::new().decode(b"TENS\x12").is_err());
     }
 
     #[test]
-    fn test_tens_text_null_value() {
-        let data = json!([{"val": null}]);
-        let text = encode_tens_text(&data, None).unwrap();
-        assert!(text.contains("  val _"));
+    fn test_strict_canonical_decode() {
+        let value = json!({"id": 300, "tags": ["a", "b", "a"], "score": 2.5, "big": 1e12, "none": null});
+        let canonical = TensEncoder::new().encode(&value);
+        let mut strict = TensDecoder::new_strict_canonical();
+        // 1e12 comes back as the integer it canonicalizes to
+        assert_eq!(strict.decode(&canonical).unwrap(), canonicalize(&value));
+
+        #[cfg(feature = "std")]
+        assert_eq!(strict.decode_reader(canonical.as_slice()).unwrap(), canonicalize(&value));
+
+        let reason = |bytes: &[u8]| {
+            // Every document here decodes fine without the strict checks
+            assert!(TensDecoder::new().decode(bytes).is_ok());
+            let result = TensDecoder::new_strict_canonical().decode(bytes);
+            #[cfg(feature = "std")]
+            assert_eq!(TensDecoder::new_strict_canonical().decode_reader(bytes), result);
+            match result {
+                Err(DecodeError::NonCanonical { reason }) => reason,
+                other => panic!("expected NonCanonical, got {:?}", other),
+            }
+        };
+        assert_eq!(reason(b"TENS\x02\x00\x05\x05\x00\x00\x00"), "INT32 for 5, which INT8 holds");
+        let mut float = b"TENS\x02\x00\x06".to_vec();
+        float.extend_from_slice(&3.0f64.to_le_bytes());
+        assert_eq!(reason(&float), "FLOAT64 for the integer 3");
+        let mut float = b"TENS\x02\x00\x06".to_vec();
+        float.extend_from_slice(&1e12f64.to_le_bytes());
+        assert_eq!(reason(&float), "FLOAT64 for the integer 1000000000000");
+        let mut int64 = b"TENS\x02\x00\x13".to_vec();
+        int64.extend_from_slice(&(-5i64).to_le_bytes());
+        assert_eq!(reason(&int64), "INT64 for -5, which INT32 holds");
+        // {"b": 1, "a": 2} written in that order
+        assert_eq!(
+            reason(b"TENS\x02\x02\x01b\x01a\x09\x02\x00\x03\x01\x01\x03\x02"),
+            "key \"a\" follows \"b\": keys are not in ascending order"
+        );
+        // {"a": 1, "b": 2} with the dictionary sorted the other way round
+        assert_eq!(
+            reason(b"TENS\x02\x02\x01b\x01a\x09\x02\x01\x03\x01\x00\x03\x02"),
+            "string ref 1 is used before ref 0: the dictionary is not in order of first use"
+        );
+        assert_eq!(
+            reason(b"TENS\x02\x02\x01a\x02zz\x09\x01\x00\x03\x01"),
+            "dictionary entry 1 (\"zz\") is never referenced"
+        );
+        assert_eq!(reason(b"TENS\x02\x01\x03a  \x07\x00"), "string \"a  \" is not normalized");
+        let mut trailing = canonical.clone();
+        trailing.push(OP_NULL);
+        assert_eq!(reason(&trailing), "1 trailing bytes after the value tree");
+
+        let front_coded = EncoderOptions { front_code_dictionary: true, ..EncoderOptions::default() };
+        assert_eq!(reason(&TensEncoder::with_options(front_coded).encode(&value)), "header flags 0x40");
+        let packed = EncoderOptions { int_arrays: IntArrayEncoding::Fixed, ..EncoderOptions::default() };
+        assert_eq!(
+            reason(&TensEncoder::with_options(packed).encode(&json!([1, 2]))),
+            "opcode 0x0d (INT_ARRAY) is not used by the canonical encoding"
+        );
+        // An overlong dictionary count only shows up on re-encoding
+        let overlong = reason(b"TENS\x02\x80\x00\x00");
+        assert!(overlong.starts_with("re-encoding differs at byte 5 ("), "{}", overlong);
     }
 
-    // ── Decoder error handling ──
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(&TensEncoder::new().encode(&json!({"a": 1}))).unwrap();
+        assert_eq!(dump, concat!(
+            "0000 54 45 4e 53                magic \"TENS\"\n",
+            "0004 02                         version 2, flags 0x00\n",
+            "0005 01                         dictionary count=1\n",
+            "0006 01 61                      dict[0]=\"a\"\n",
+            "0008 09 01                      OP_OBJECT_START count=1\n",
+            "000a 00                           key dict[0]=\"a\"\n",
+            "000b 03 01                        OP_INT8 1\n",
+        ));
+
+        let value = json!({"name": "Ada", "tags": ["x", "x", null, null, null], "n": 2.5, "big": 70000});
+        let options = EncoderOptions {
+            repeat_string_refs: true,
+            null_run_threshold: Some(2),
+            front_code_dictionary: true,
+            ..Default::default()
+        };
+        let dump = hex_dump(&TensEncoder::with_options(options).encode(&value)).unwrap();
+        for expected in [
+            "version 2, flags 0x40",
+            "OP_OBJECT_START count=4",
+            "key dict[",
+            "OP_INT32 70000",
+            "OP_FLOAT64 2.5",
+            "OP_ARRAY_START count=5",
+            "OP_SAME_AS_PREVIOUS",
+            "OP_NULL_RUN run=3",
+            "(shared prefix 1)",
+        ] {
+            assert!(dump.contains(expected), "missing {:?} in\n{}", expected, dump);
+        }
+
+        let records = json!([{"id": 1, "v": [1, 200]}, {"id": 2, "v": [3]}]);
+        let options = EncoderOptions { columnar_arrays: true, int_arrays: IntArrayEncoding::Fixed, ..Default::default() };
+        let dump = hex_dump(&TensEncoder::with_options(options).encode(&records)).unwrap();
+        assert!(dump.contains("OP_COLUMNAR_ARRAY count=2 schema=1 defines [id, v]"), "{}", dump);
+        assert!(dump.contains("OP_INT_ARRAY count=2 width=2 [1, 200]"), "{}", dump);
+
+        let bytes = TensEncoder::new().encode(&json!([1, 2]));
+        assert!(hex_dump(&bytes[..bytes.len() - 1]).is_err());
+        assert!(hex_dump(b"TENS\x02\x00\x0C\x03").is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated_object_names_field() {
+        let bytes = TensEncoder::new().encode(&json!({"a": 1, "b": 2, "c": 3}));
+        // Drop field "c": its key ref and INT8 value
+        let truncated = &bytes[..bytes.len() - 3];
+        let err = TensDecoder::new().decode(truncated).unwrap_err().to_string();
+        assert_eq!(err, "Unexpected end of input (object field 2 of 3, after keys [a, b])");
+        #[cfg(feature = "std")]
+        assert_eq!(TensDecoder::new().decode_reader(truncated).unwrap_err().to_string(), err);
+
+        // Cut inside a nested object: one suffix per level, innermost first
+        let bytes = TensEncoder::new().encode(&json!({"outer": {"x": "y", "z": 1}}));
+        let err = TensDecoder::new().decode(&bytes[..bytes.len() - 1]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "INT8: missing byte (object field 1 of 2, after keys [x]) (object field 0 of 1, after keys [])"
+        );
+    }
 
     #[test]
     fn test_decode_empty_input() {
@@ -1248,6 +9332,45 @@ mod tests {
         assert!(dec.decode(&[]).is_err());
     }
 
+    #[test]
+    fn test_decode_ref_into_empty_dictionary() {
+        let out_of_bounds = Err(DecodeError::StringRefOutOfBounds { id: 0, len: 0 });
+        // dict_count = 0, root = OP_STRING_REF 0
+        let string_ref = b"TENS\x02\x00\x07\x00";
+        assert_eq!(TensDecoder::new().decode(string_ref), out_of_bounds);
+        // dict_count = 0, root = object {key ref 0: null}
+        let key_ref = b"TENS\x02\x00\x09\x01\x00\x00";
+        assert_eq!(TensDecoder::new().decode(key_ref), out_of_bounds);
+        #[cfg(feature = "std")]
+        {
+            assert_eq!(TensDecoder::new().decode_reader(&string_ref[..]), out_of_bounds);
+            assert_eq!(TensDecoder::new().decode_reader(&key_ref[..]), out_of_bounds);
+        }
+        assert_eq!(
+            TensDecoder::new().decode(b"TENS\x02\x01\x01a\x07\x05"),
+            Err(DecodeError::StringRefOutOfBounds { id: 5, len: 1 })
+        );
+    }
+
+    #[test]
+    fn test_lenient_unknown_extension() {
+        // [ext type 123 with a 3-byte payload, 7]
+        let bytes = b"TENS\x02\x00\x08\x02\x10\x7B\x03abc\x03\x07";
+        let expected = json!([{"__unknown_opcode__": 123}, 7]);
+
+        assert_eq!(TensDecoder::new_lenient().decode(bytes).unwrap(), expected);
+        assert!(TensDecoder::new().decode(bytes).is_err());
+        #[cfg(feature = "std")]
+        {
+            assert_eq!(TensDecoder::new_lenient().decode_reader(&bytes[..]).unwrap(), expected);
+            assert!(TensDecoder::new().decode_reader(&bytes[..]).is_err());
+        }
+
+        // Truncated payload and bare unknown opcodes fail even when lenient
+        assert!(TensDecoder::new_lenient().decode(b"TENS\x02\x00\x10\x7B\x03ab").is_err());
+        assert!(TensDecoder::new_lenient().decode(b"TENS\x02\x00\x7F").is_err());
+    }
+
     #[test]
     fn test_decode_bad_magic() {
         let mut dec = TensDecoder::new();
@@ -0,0 +1,118 @@
+use crate::encoder::{DecodeError, EncodeError};
+
+/// Size of the fixed little-endian u32 length field around a framed document.
+pub const FRAME_LENGTH_BYTES: usize = 4;
+
+/// Where a framed document carries its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStyle {
+    /// `len(u32 LE) + document` — read forward from the start of a stream.
+    LengthPrefix,
+    /// `document + len(u32 LE)` — read backward from EOF to find the last record.
+    SuffixLength,
+}
+
+/// Wrap an encoded document with its length in the given frame style. Fails
+/// with `EncodeError::LengthOverflow` for documents of 4 GiB or more.
+pub fn frame(document: &[u8], style: FrameStyle) -> Result<Vec<u8>, EncodeError> {
+    let len = frame_length(document.len())?;
+
+    let mut out = Vec::with_capacity(document.len() + FRAME_LENGTH_BYTES);
+    match style {
+        FrameStyle::LengthPrefix => {
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(document);
+        }
+        FrameStyle::SuffixLength => {
+            out.extend_from_slice(document);
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+    }
+    Ok(out)
+}
+
+/// `len` as the u32 length field of a frame.
+fn frame_length(len: usize) -> Result<u32, EncodeError> {
+    u32::try_from(len).map_err(|_| EncodeError::LengthOverflow(len))
+}
+
+/// Split the first prefix-framed document off `bytes`, returning (document, rest).
+pub fn read_framed(bytes: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+    if bytes.len() < FRAME_LENGTH_BYTES {
+        return Err("Frame too short for length prefix".into());
+    }
+    let (len_bytes, rest) = bytes.split_at(FRAME_LENGTH_BYTES);
+    let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+    if len > rest.len() {
+        return Err(format!("Frame length {} exceeds remaining {} bytes", len, rest.len()).into());
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Split the last suffix-framed document off the end of `bytes`, returning
+/// (preceding, document). Call repeatedly on `preceding` to walk backward.
+pub fn read_last_framed(bytes: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+    if bytes.len() < FRAME_LENGTH_BYTES {
+        return Err("Frame too short for length suffix".into());
+    }
+    let (body, len_bytes) = bytes.split_at(bytes.len() - FRAME_LENGTH_BYTES);
+    let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+    if len > body.len() {
+        return Err(format!("Frame length {} exceeds preceding {} bytes", len, body.len()).into());
+    }
+    Ok(body.split_at(body.len() - len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{TensDecoder, TensEncoder};
+    use serde_json::json;
+
+    #[test]
+    fn test_read_last_suffix_framed() {
+        let docs = [json!({"seq": 1}), json!({"seq": 2, "tag": "b"}), json!({"seq": 3, "tag": "last"})];
+        let mut log = Vec::new();
+        for doc in &docs {
+            let bytes = TensEncoder::new().encode(doc);
+            log.extend_from_slice(&frame(&bytes, FrameStyle::SuffixLength).unwrap());
+        }
+
+        let (preceding, last) = read_last_framed(&log).unwrap();
+        assert_eq!(TensDecoder::new().decode(last).unwrap(), docs[2]);
+
+        let (preceding, second) = read_last_framed(preceding).unwrap();
+        assert_eq!(TensDecoder::new().decode(second).unwrap(), docs[1]);
+
+        let (preceding, first) = read_last_framed(preceding).unwrap();
+        assert_eq!(TensDecoder::new().decode(first).unwrap(), docs[0]);
+        assert!(preceding.is_empty());
+    }
+
+    #[test]
+    fn test_read_prefix_framed() {
+        let bytes = TensEncoder::new().encode(&json!([1, 2, 3]));
+        let mut stream = frame(&bytes, FrameStyle::LengthPrefix).unwrap();
+        stream.extend_from_slice(b"tail");
+
+        let (doc, rest) = read_framed(&stream).unwrap();
+        assert_eq!(doc, bytes.as_slice());
+        assert_eq!(rest, b"tail");
+    }
+
+    #[test]
+    fn test_corrupt_frame_length() {
+        let mut framed = frame(b"TENS\x02\x00\x00", FrameStyle::SuffixLength).unwrap();
+        let last = framed.len() - 1;
+        framed[last] = 0xFF;
+        assert!(read_last_framed(&framed).is_err());
+        assert!(read_framed(&[0xFF, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_frame_length_overflow() {
+        assert_eq!(frame_length(u32::MAX as usize), Ok(u32::MAX));
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(frame_length(u32::MAX as usize + 1), Err(EncodeError::LengthOverflow(u32::MAX as usize + 1)));
+    }
+}
@@ -1,6 +1,10 @@
 mod utils;
-mod schema;
-mod encoder;
+mod depth_guard;
+pub mod schema;
+pub mod encoder;
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod writer;
 
 use wasm_bindgen::prelude::*;
 use serde_json::Value;
@@ -17,6 +21,12 @@ pub struct TensEncoder {
     inner: InnerEncoder,
 }
 
+impl Default for TensEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl TensEncoder {
     #[wasm_bindgen(constructor)]
@@ -32,7 +42,18 @@ impl TensEncoder {
         let json_val: Value = serde_wasm_bindgen::from_value(val)
             .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)))?;
 
-        Ok(self.inner.encode(&json_val))
+        self.inner.try_encode(&json_val)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Encode UTF-8 JSON bytes → TENS v2 binary, skipping the JS object and
+    /// string round trip for payloads already held as bytes.
+    ///
+    /// JS: `encodeJsonBytes(json: Uint8Array): Uint8Array`
+    #[wasm_bindgen(js_name = "encodeJsonBytes")]
+    pub fn encode_json_bytes(&mut self, json: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.inner.try_encode_json_slice(json)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
     /// Encode a JavaScript value → TENS-Text format string.
@@ -51,7 +72,8 @@ impl TensEncoder {
         let json_val: Value = serde_wasm_bindgen::from_value(val)
             .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)))?;
 
-        let binary = self.inner.encode(&json_val);
+        let binary = self.inner.try_encode(&json_val)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
         Ok(hash_tens_binary(&binary))
     }
 
@@ -67,12 +89,20 @@ impl TensEncoder {
 pub fn decode_tens(binary: &[u8]) -> Result<JsValue, JsValue> {
     let mut decoder = TensDecoder::new();
     let value = decoder.decode(binary)
-        .map_err(|e| JsValue::from_str(&e))?;
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
 
     serde_wasm_bindgen::to_value(&value)
         .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
 }
 
+/// Whether TENS v2 binary re-encodes to identical bytes with the default
+/// encoder, as `encode` writes it (canonical and lossless).
+#[wasm_bindgen(js_name = "isStable")]
+pub fn is_stable_wasm(binary: &[u8]) -> Result<bool, JsValue> {
+    encoder::is_stable(binary)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
 /// Decode TENS-Text string → JavaScript value.
 #[wasm_bindgen(js_name = "decodeTensText")]
 pub fn decode_tens_text_wasm(text: &str) -> Result<JsValue, JsValue> {
@@ -2,6 +2,8 @@ use std::collections::{BTreeMap, HashMap};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 
 pub type SchemaId = u32;
 
@@ -14,20 +16,37 @@ pub struct Schema {
     pub field_types: Vec<String>,
 }
 
+/// How a registry assigns ids to shapes it has not seen before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaIdStrategy {
+    /// Sequential ids starting at 1, local to one registry.
+    #[default]
+    Sequential,
+    /// SHA-256 of the sorted (key, type) pairs truncated to u32, so identical
+    /// shapes get identical ids in every registry. Collisions probe upward.
+    ContentHash,
+}
+
 pub struct SchemaRegistry {
     /// hash(sorted_keys) → SchemaId
     lookup: HashMap<u64, SchemaId>,
     /// SchemaId → Schema
     schemas: BTreeMap<SchemaId, Schema>,
     next_id: SchemaId,
+    strategy: SchemaIdStrategy,
 }
 
 impl SchemaRegistry {
     pub fn new() -> Self {
+        Self::with_strategy(SchemaIdStrategy::default())
+    }
+
+    pub fn with_strategy(strategy: SchemaIdStrategy) -> Self {
         SchemaRegistry {
             lookup: HashMap::new(),
             schemas: BTreeMap::new(),
             next_id: 1,
+            strategy,
         }
     }
 
@@ -44,6 +63,10 @@ impl SchemaRegistry {
         let sorted_keys: Vec<String> = sorted.iter().map(|p| p.0.clone()).collect();
         let sorted_types: Vec<String> = sorted.iter().map(|p| p.1.clone()).collect();
 
+        if self.strategy == SchemaIdStrategy::ContentHash {
+            return self.register_by_content(sorted_keys, sorted_types);
+        }
+
         let hash = Self::calculate_hash(&sorted_keys);
 
         if let Some(&id) = self.lookup.get(&hash) {
@@ -65,6 +88,36 @@ impl SchemaRegistry {
         (id, true)
     }
 
+    /// Register a shape under its content-derived id, probing past ids taken by
+    /// a different shape. Id 0 is skipped so ids stay in the sequential range.
+    fn register_by_content(&mut self, keys: Vec<String>, types: Vec<String>) -> (SchemaId, bool) {
+        let mut id = Self::content_id(&keys, &types);
+        loop {
+            if id == 0 {
+                id = 1;
+            }
+            match self.schemas.get(&id) {
+                Some(s) if s.keys == keys && s.field_types == types => return (id, false),
+                Some(_) => id = id.wrapping_add(1),
+                None => break,
+            }
+        }
+
+        self.schemas.insert(id, Schema { id, keys, field_types: types });
+        (id, true)
+    }
+
+    /// Stable content hash of sorted (key, type) pairs, truncated to u32.
+    fn content_id(keys: &[String], types: &[String]) -> SchemaId {
+        let mut hasher = Sha256::new();
+        for (key, type_str) in keys.iter().zip(types) {
+            hash_part(&mut hasher, key);
+            hash_part(&mut hasher, type_str);
+        }
+        let digest = hasher.finalize();
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
     pub fn get(&self, id: SchemaId) -> Option<&Schema> {
         self.schemas.get(&id)
     }
@@ -82,3 +135,124 @@ impl SchemaRegistry {
     }
 }
 
+impl Default for SchemaRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Length-prefix each part so ("ab", "c") and ("a", "bc") differ.
+fn hash_part(hasher: &mut Sha256, part: &str) {
+    hasher.update((part.len() as u32).to_le_bytes());
+    hasher.update(part.as_bytes());
+}
+
+/// Stable fingerprint of a document's top-level shape: its sorted key names,
+/// each with the kind of its value (`null`, `bool`, `num`, `str`, `array`,
+/// `object`). Documents of the same shape get the same fingerprint in every
+/// process and build, as SHA-256 truncated to u64. A non-object root
+/// fingerprints its kind alone.
+pub fn schema_fingerprint(value: &Value) -> u64 {
+    fingerprint(value, true)
+}
+
+/// [`schema_fingerprint`] over the key names only, ignoring value kinds.
+pub fn key_fingerprint(value: &Value) -> u64 {
+    fingerprint(value, false)
+}
+
+fn fingerprint(value: &Value, with_kinds: bool) -> u64 {
+    let mut hasher = Sha256::new();
+    hash_part(&mut hasher, value_kind(value));
+    if let Value::Object(obj) = value {
+        let mut fields: Vec<(&String, &Value)> = obj.iter().collect();
+        fields.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, field) in fields {
+            hash_part(&mut hasher, key);
+            if with_kinds {
+                hash_part(&mut hasher, value_kind(field));
+            }
+        }
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "num",
+        Value::String(_) => "str",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_content_hash_ids_stable_across_registries() {
+        let mut a = SchemaRegistry::with_strategy(SchemaIdStrategy::ContentHash);
+        let mut b = SchemaRegistry::with_strategy(SchemaIdStrategy::ContentHash);
+
+        // Register an unrelated shape first in `b` so sequential ids would differ
+        b.get_or_register(&strings(&["x"]), &strings(&["num"]));
+
+        let (id_a, new_a) = a.get_or_register(&strings(&["name", "age"]), &strings(&["str", "num"]));
+        let (id_b, new_b) = b.get_or_register(&strings(&["age", "name"]), &strings(&["num", "str"]));
+        assert_eq!(id_a, id_b);
+        assert!(new_a && new_b);
+
+        let (again, is_new) = a.get_or_register(&strings(&["age", "name"]), &strings(&["num", "str"]));
+        assert_eq!((again, is_new), (id_a, false));
+        assert_eq!(a.get(id_a).unwrap().keys, strings(&["age", "name"]));
+    }
+
+    #[test]
+    fn test_content_hash_collision_probes() {
+        let mut registry = SchemaRegistry::with_strategy(SchemaIdStrategy::ContentHash);
+        let keys = strings(&["a"]);
+        let types = strings(&["num"]);
+        let id = SchemaRegistry::content_id(&keys, &types);
+
+        // Occupy the natural slot with a different shape
+        registry.schemas.insert(id, Schema { id, keys: strings(&["other"]), field_types: strings(&["str"]) });
+
+        let (probed, _) = registry.get_or_register(&keys, &types);
+        assert_eq!(probed, id.wrapping_add(1).max(1));
+        assert_eq!(registry.get_or_register(&keys, &types), (probed, false));
+    }
+
+    #[test]
+    fn test_schema_fingerprint() {
+        let a = json!({"id": 1, "name": "Alice", "tags": ["x"]});
+        let b = json!({"tags": [], "name": "Bob", "id": 2});
+        assert_eq!(schema_fingerprint(&a), schema_fingerprint(&b));
+
+        // An extra or missing key, or a value of another kind, changes the shape
+        assert_ne!(schema_fingerprint(&a), schema_fingerprint(&json!({"id": 1, "name": "Alice"})));
+        assert_ne!(schema_fingerprint(&a), schema_fingerprint(&json!({"id": 1, "name": "Alice", "tags": ["x"], "x": null})));
+        let renumbered = json!({"id": "1", "name": "Alice", "tags": ["x"]});
+        assert_ne!(schema_fingerprint(&a), schema_fingerprint(&renumbered));
+        assert_eq!(key_fingerprint(&a), key_fingerprint(&renumbered));
+
+        // Roots of other kinds differ from each other and from `{}`
+        assert_ne!(schema_fingerprint(&json!({})), schema_fingerprint(&json!([])));
+        assert_ne!(schema_fingerprint(&json!(null)), schema_fingerprint(&json!({})));
+    }
+
+    #[test]
+    fn test_sequential_ids_by_default() {
+        let mut registry = SchemaRegistry::new();
+        assert_eq!(registry.get_or_register(&strings(&["a"]), &strings(&["num"])), (1, true));
+        assert_eq!(registry.get_or_register(&strings(&["b"]), &strings(&["num"])), (2, true));
+    }
+}
@@ -13,7 +13,7 @@ pub fn set_panic_hook() {
 
 #[macro_export]
 macro_rules! console_log {
-    ($($t:tt)*) => (crate::utils::log(&format_args!($($t)*).to_string()))
+    ($($t:tt)*) => ($crate::utils::log(&format_args!($($t)*).to_string()))
 }
 
 // ── LEB128 unsigned varint encoding ──
@@ -36,22 +36,86 @@ pub fn encode_varint(mut val: u32) -> Vec<u8> {
 }
 
 /// Decode a LEB128 unsigned varint from a byte slice, returning (value, bytes_consumed).
+///
+/// Lenient: reading stops at the end of input or after 5 bytes (all a u32
+/// needs), whatever the continuation bits say, and bits past 32 are dropped.
+/// Use [`try_decode_varint`] to reject such input.
+#[cfg(any(test, feature = "compression"))]
 pub fn decode_varint(bytes: &[u8]) -> (u32, usize) {
     let mut val: u32 = 0;
-    let mut shift: u32 = 0;
     let mut i = 0;
-    loop {
-        if i >= bytes.len() {
-            break;
-        }
+    while i < bytes.len() && i < 5 {
         let byte = bytes[i];
-        val |= ((byte & 0x7F) as u32) << shift;
+        val |= ((byte & 0x7F) as u32) << (7 * i);
         i += 1;
         if byte & 0x80 == 0 {
             break;
         }
-        shift += 7;
     }
     (val, i)
 }
 
+/// [`decode_varint`] that rejects malformed input: `None` if the bytes end
+/// while the continuation bit is set, or the varint does not fit a u32.
+pub fn try_decode_varint(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut val: u32 = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(5) {
+        // The fifth byte holds the top 4 bits and ends the varint
+        if i == 4 && byte > 0x0F {
+            return None;
+        }
+        val |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((val, i + 1));
+        }
+    }
+    None
+}
+
+
+// ── Zigzag mapping for signed varints ──
+
+/// Map a signed integer onto an unsigned one so small magnitudes stay short
+/// as varints: 0, -1, 1, -2, ... → 0, 1, 2, 3, ...
+pub fn zigzag_encode(val: i32) -> u32 {
+    ((val << 1) ^ (val >> 31)) as u32
+}
+
+/// Inverse of [`zigzag_encode`].
+pub fn zigzag_decode(val: u32) -> i32 {
+    ((val >> 1) as i32) ^ -((val & 1) as i32)
+}
+
+/// Encode a signed integer as a zigzag LEB128 varint: `(n << 1) ^ (n >> 63)`,
+/// then 7 bits per byte, so -64..=63 take one byte and the i64 extremes ten.
+pub fn encode_varint_i64(val: i64) -> Vec<u8> {
+    let mut zigzag = ((val << 1) ^ (val >> 63)) as u64;
+    let mut out = Vec::new();
+    loop {
+        let byte = (zigzag & 0x7F) as u8;
+        zigzag >>= 7;
+        if zigzag == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Inverse of [`encode_varint_i64`], returning (value, bytes consumed): `None`
+/// if the bytes end while the continuation bit is set, or the varint does
+/// not fit a u64.
+pub fn decode_varint_i64(bytes: &[u8]) -> Option<(i64, usize)> {
+    let mut zigzag: u64 = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        // The tenth byte holds the top bit and ends the varint
+        if i == 9 && byte > 0x01 {
+            return None;
+        }
+        zigzag |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64), i + 1));
+        }
+    }
+    None
+}
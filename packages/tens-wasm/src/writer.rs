@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use serde_json::{Map, Value};
+
+use crate::encoder::{EncodeError, TensEncoder};
+
+/// An open container on the writer stack.
+enum Frame {
+    /// Object under construction and the key set by `field` awaiting its value.
+    Object(Map<String, Value>, Option<String>),
+    Array(Vec<Value>),
+}
+
+/// Incrementally build a TENS v2 document and write it to `W` on `finish`.
+///
+/// The binary format puts the dictionary before the value tree, so nothing can
+/// be emitted until every string is known: the writer buffers the document and
+/// writes it in one go from `finish`. A writer dropped before `finish`, or one
+/// whose containers are unbalanced, therefore writes nothing at all — never a
+/// partial buffer.
+pub struct TensWriter<W: Write> {
+    writer: W,
+    encoder: TensEncoder,
+    stack: Vec<Frame>,
+    root: Option<Value>,
+}
+
+impl<W: Write> TensWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_encoder(writer, TensEncoder::new())
+    }
+
+    /// Use a pre-configured encoder for the final emission.
+    pub fn with_encoder(writer: W, encoder: TensEncoder) -> Self {
+        TensWriter {
+            writer,
+            encoder,
+            stack: Vec::new(),
+            root: None,
+        }
+    }
+
+    pub fn begin_object(&mut self) -> Result<&mut Self, EncodeError> {
+        self.check_slot()?;
+        self.stack.push(Frame::Object(Map::new(), None));
+        Ok(self)
+    }
+
+    pub fn end_object(&mut self) -> Result<&mut Self, EncodeError> {
+        // Checked before popping, so a bad close leaves the container open
+        let Some(Frame::Object(_, None)) = self.stack.last() else {
+            return Err(EncodeError::Unbalanced);
+        };
+        let Some(Frame::Object(map, _)) = self.stack.pop() else {
+            unreachable!("checked above");
+        };
+        self.place(Value::Object(map))
+    }
+
+    pub fn begin_array(&mut self) -> Result<&mut Self, EncodeError> {
+        self.check_slot()?;
+        self.stack.push(Frame::Array(Vec::new()));
+        Ok(self)
+    }
+
+    pub fn end_array(&mut self) -> Result<&mut Self, EncodeError> {
+        let Some(Frame::Array(_)) = self.stack.last() else {
+            return Err(EncodeError::Unbalanced);
+        };
+        let Some(Frame::Array(arr)) = self.stack.pop() else {
+            unreachable!("checked above");
+        };
+        self.place(Value::Array(arr))
+    }
+
+    /// Set the key for the next value inside the current object.
+    pub fn field(&mut self, key: &str) -> Result<&mut Self, EncodeError> {
+        match self.stack.last_mut() {
+            Some(Frame::Object(_, pending @ None)) => {
+                *pending = Some(key.to_string());
+                Ok(self)
+            }
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+
+    /// Write a complete value: an object field value, an array element, or the root.
+    pub fn value(&mut self, value: Value) -> Result<&mut Self, EncodeError> {
+        self.check_slot()?;
+        self.place(value)
+    }
+
+    /// Encode the buffered document and write it, returning the inner writer.
+    /// Fails with `EncodeError::Unbalanced` if containers are still open or
+    /// nothing was written, in which case the output is left untouched.
+    pub fn finish(mut self) -> Result<W, EncodeError> {
+        let root = match (self.stack.is_empty(), self.root.take()) {
+            (true, Some(root)) => root,
+            _ => return Err(EncodeError::Unbalanced),
+        };
+        let bytes = self.encoder.try_encode(&root)?;
+        self.writer.write_all(&bytes)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| EncodeError::Io(e.to_string()))?;
+        Ok(self.writer)
+    }
+
+    /// Whether a value may be placed at the current position.
+    fn check_slot(&self) -> Result<(), EncodeError> {
+        match self.stack.last() {
+            None if self.root.is_some() => Err(EncodeError::Unbalanced),
+            Some(Frame::Object(_, None)) => Err(EncodeError::Unbalanced),
+            _ => Ok(()),
+        }
+    }
+
+    fn place(&mut self, value: Value) -> Result<&mut Self, EncodeError> {
+        match self.stack.last_mut() {
+            None => self.root = Some(value),
+            Some(Frame::Object(map, pending)) => {
+                let key = pending.take().ok_or(EncodeError::Unbalanced)?;
+                map.insert(key, value);
+            }
+            Some(Frame::Array(arr)) => arr.push(value),
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TensDecoder;
+    use serde_json::json;
+
+    #[test]
+    fn test_writer_builds_document() {
+        let mut writer = TensWriter::new(Vec::new());
+        writer.begin_object().unwrap()
+            .field("name").unwrap().value(json!("Alice")).unwrap()
+            .field("scores").unwrap().begin_array().unwrap()
+                .value(json!(95)).unwrap()
+                .begin_object().unwrap().field("bonus").unwrap().value(json!(true)).unwrap().end_object().unwrap()
+            .end_array().unwrap()
+            .end_object().unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let expected = json!({"name": "Alice", "scores": [95, {"bonus": true}]});
+        assert_eq!(bytes, TensEncoder::new().encode(&expected));
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_writer_unbalanced() {
+        let mut writer = TensWriter::new(Vec::new());
+        writer.begin_object().unwrap().field("open").unwrap().begin_array().unwrap();
+        assert_eq!(writer.finish().err(), Some(EncodeError::Unbalanced));
+
+        // Value inside an object without a field name
+        let mut writer = TensWriter::new(Vec::new());
+        writer.begin_object().unwrap();
+        assert_eq!(writer.value(json!(1)).err(), Some(EncodeError::Unbalanced));
+
+        // Mismatched close and a second root
+        let mut writer = TensWriter::new(Vec::new());
+        writer.begin_array().unwrap().value(json!(1)).unwrap();
+        assert_eq!(writer.end_object().err(), Some(EncodeError::Unbalanced));
+        // The rejected close leaves the writer as it was
+        writer.begin_object().unwrap().field("k").unwrap();
+        assert_eq!(writer.end_object().err(), Some(EncodeError::Unbalanced));
+        assert_eq!(writer.end_array().err(), Some(EncodeError::Unbalanced));
+        writer.value(json!(2)).unwrap().end_object().unwrap().end_array().unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(TensDecoder::new().decode(&bytes).unwrap(), json!([1, {"k": 2}]));
+        let mut writer = TensWriter::new(Vec::new());
+        writer.value(json!(1)).unwrap();
+        assert_eq!(writer.value(json!(2)).err(), Some(EncodeError::Unbalanced));
+
+        assert_eq!(TensWriter::new(Vec::new()).finish().err(), Some(EncodeError::Unbalanced));
+    }
+}
@@ -0,0 +1,43 @@
+//! Round trips through the `tens` binary.
+#![cfg(feature = "cli")]
+
+use assert_cmd::Command;
+use contex_tens_wasm::encoder::{hash_tens_binary, TensEncoder};
+use serde_json::{json, Value};
+
+fn tens(args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    let output = Command::cargo_bin("tens").unwrap().args(args).write_stdin(stdin).output().unwrap();
+    assert!(output.status.success(), "tens {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+#[test]
+fn binary_round_trip() {
+    let doc = json!({"name": "Ada", "tags": ["x", "y"], "n": 1.5});
+    let binary = tens(&["encode"], doc.to_string().as_bytes());
+    assert_eq!(binary, TensEncoder::new().encode(&doc));
+
+    let decoded: Value = serde_json::from_slice(&tens(&["decode"], &binary)).unwrap();
+    assert_eq!(decoded, doc);
+
+    let hash = String::from_utf8(tens(&["hash"], doc.to_string().as_bytes())).unwrap();
+    assert_eq!(hash.trim_end(), hash_tens_binary(&binary));
+}
+
+#[test]
+fn text_round_trip() {
+    let doc = json!([{"id": 1, "name": "Ada"}, {"id": 2, "name": "Grace"}]);
+    let text = tens(&["encode", "--text"], doc.to_string().as_bytes());
+    assert_eq!(tens(&["text"], &tens(&["encode"], doc.to_string().as_bytes())), text);
+
+    let decoded: Value = serde_json::from_slice(&tens(&["decode", "--text"], &text)).unwrap();
+    assert_eq!(decoded, doc);
+    assert_eq!(tens(&["hash", "--text"], &text), tens(&["hash"], doc.to_string().as_bytes()));
+}
+
+#[test]
+fn invalid_input_fails() {
+    let output = Command::cargo_bin("tens").unwrap().arg("decode").write_stdin("not tens").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("tens: "));
+}
@@ -0,0 +1,89 @@
+//! Conformance against reference vectors shared with the TS encoder.
+//!
+//! Each fixture in `tests/vectors/` is a pair `<name>.json` (input) and
+//! `<name>.tens.hex` (expected TENS v2 bytes). `encode` must produce the bytes
+//! exactly, and decoding them must give back the canonicalized input.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use contex_tens_wasm::encoder::{canonicalize, first_mismatch, TensDecoder, TensEncoder};
+use serde_json::Value;
+
+fn vectors_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("vectors")
+}
+
+/// Fixture names (file stem without `.json`), sorted for a stable run order.
+fn fixture_names() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(vectors_dir())
+        .expect("tests/vectors is readable")
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name().into_string().ok()?;
+            file_name.strip_suffix(".json").map(str::to_string)
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex.trim().as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            std::str::from_utf8(pair).ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("bad hex byte {} ({:?})", i, String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+/// Check one fixture, describing the first failure.
+fn check_fixture(name: &str) -> Result<(), String> {
+    let dir = vectors_dir();
+    let input_text = fs::read_to_string(dir.join(format!("{}.json", name)))
+        .map_err(|e| format!("reading input: {}", e))?;
+    let input: Value = serde_json::from_str(&input_text).map_err(|e| format!("parsing input: {}", e))?;
+    let hex = fs::read_to_string(dir.join(format!("{}.tens.hex", name)))
+        .map_err(|e| format!("reading expected bytes: {}", e))?;
+    let expected = parse_hex(&hex)?;
+
+    let actual = TensEncoder::new().encode(&input);
+    if let Some((offset, description)) = first_mismatch(&actual, &expected) {
+        return Err(format!(
+            "encode mismatch at byte {} (a = actual, b = expected): {} (lengths {} vs {})",
+            offset,
+            description,
+            actual.len(),
+            expected.len(),
+        ));
+    }
+
+    let decoded = TensDecoder::new().decode(&expected).map_err(|e| format!("decode failed: {}", e))?;
+    let canonical = canonicalize(&input);
+    if decoded != canonical {
+        return Err(format!("decode mismatch: got {}, expected {}", decoded, canonical));
+    }
+    Ok(())
+}
+
+#[test]
+fn conformance_vectors() {
+    let names = fixture_names();
+    assert!(!names.is_empty(), "no fixtures in {}", vectors_dir().display());
+
+    let failures: Vec<String> = names.iter()
+        .filter_map(|name| check_fixture(name).err().map(|e| format!("[{}] {}", name, e)))
+        .collect();
+    assert!(failures.is_empty(), "{} of {} vectors failed:\n{}", failures.len(), names.len(), failures.join("\n"));
+}
+
+#[test]
+fn first_mismatch_reports_offset() {
+    let offset = |a: &[u8], b: &[u8]| first_mismatch(a, b).map(|(offset, _)| offset);
+    assert_eq!(offset(b"TENS", b"TENS"), None);
+    assert_eq!(offset(b"TENT", b"TENS"), Some(3));
+    assert_eq!(offset(b"TEN", b"TENS"), Some(3));
+}